@@ -0,0 +1,127 @@
+//! Benchmarks of the per-block hot paths: ring buffer transfer, capture byte
+//! conversion and format conversion
+//!
+//! Run with `cargo bench` (on Windows, like the rest of the crate). Blocks are 10 ms
+//! of 48 kHz audio, the proxy's default buffer size.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use audio_proxy::audio_stream::{bytes_to_f32, AudioFormat};
+use audio_proxy::convert::{
+    convert_audio, convert_channels, ChannelMix, ConversionSettings, ConversionState, LinearResampler,
+};
+use audio_proxy::ring_buffer::AudioRingBuffer;
+
+const SAMPLE_RATE: u32 = 48000;
+const BLOCK_MS: u32 = 10;
+
+fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+    AudioFormat {
+        sample_rate,
+        channels,
+        bits_per_sample: 32,
+        block_align: channels as u32 * 4,
+    }
+}
+
+/// One block of a sine per channel (different frequencies, so channels aren't identical)
+fn block(sample_rate: u32, channels: usize) -> Vec<f32> {
+    let frames = (sample_rate * BLOCK_MS / 1000) as usize;
+    (0..frames * channels)
+        .map(|i| {
+            let (frame, ch) = (i / channels, i % channels);
+            let freq = 440.0 * (ch + 1) as f32;
+            0.5 * (2.0 * std::f32::consts::PI * freq * frame as f32 / sample_rate as f32).sin()
+        })
+        .collect()
+}
+
+fn ring_buffer(c: &mut Criterion) {
+    let input = block(SAMPLE_RATE, 2);
+    let mut output = vec![0.0f32; input.len()];
+
+    let mut group = c.benchmark_group("ring_buffer");
+    group.throughput(Throughput::Elements(input.len() as u64));
+    // Sized like the proxy's ring buffers: 4x `--buffer-ms` of stereo audio
+    for buffer_ms in [10u32, 20, 50] {
+        let capacity = (SAMPLE_RATE * buffer_ms / 1000) as usize * 2 * 4;
+        let buffer = AudioRingBuffer::new(capacity);
+        group.bench_with_input(BenchmarkId::new("write_read_10ms", buffer_ms), &buffer, |b, buffer| {
+            b.iter(|| {
+                buffer.write(black_box(&input));
+                black_box(buffer.read(&mut output))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn resample(c: &mut Criterion) {
+    let input = block(SAMPLE_RATE, 2);
+    let mut output = Vec::new();
+
+    let mut group = c.benchmark_group("resample_48k_to_44k1");
+    group.throughput(Throughput::Elements(input.len() as u64));
+    group.bench_function("linear", |b| {
+        let mut resampler = LinearResampler::new(SAMPLE_RATE, 44100, 2);
+        b.iter(|| resampler.process(black_box(&input), &mut output))
+    });
+    // What convert_audio picks for this pair
+    group.bench_function("polyphase", |b| {
+        let mut state = ConversionState::new(ConversionSettings::default());
+        let (cap, rnd) = (format(SAMPLE_RATE, 2), format(44100, 2));
+        b.iter(|| black_box(convert_audio(black_box(&input), &cap, &rnd, &mut state).len()))
+    });
+    group.finish();
+}
+
+fn downmix(c: &mut Criterion) {
+    let input = block(SAMPLE_RATE, 6);
+    let mut output = Vec::new();
+
+    let mut group = c.benchmark_group("convert_channels");
+    group.throughput(Throughput::Elements(input.len() as u64));
+    group.bench_function("5.1_to_2.0", |b| {
+        b.iter(|| convert_channels(black_box(&input), 6, 2, ChannelMix::default(), &mut output))
+    });
+    group.finish();
+}
+
+fn capture_bytes(c: &mut Criterion) {
+    let bytes: Vec<u8> = block(SAMPLE_RATE, 2).iter().flat_map(|s| s.to_le_bytes()).collect();
+    let mut output = vec![0.0f32; bytes.len() / 4];
+
+    let mut group = c.benchmark_group("bytes_to_f32");
+    group.throughput(Throughput::Elements(output.len() as u64));
+    group.bench_function("copy", |b| b.iter(|| bytes_to_f32(black_box(&bytes), &mut output)));
+    // The per-sample conversion it replaced, for comparison
+    group.bench_function("per_sample", |b| {
+        b.iter(|| {
+            let bytes = black_box(&bytes);
+            for (i, sample) in output.iter_mut().enumerate() {
+                let offset = i * 4;
+                *sample = f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn passthrough(c: &mut Criterion) {
+    let input = block(SAMPLE_RATE, 2);
+    let fmt = format(SAMPLE_RATE, 2);
+    let mut state = ConversionState::new(ConversionSettings::default());
+
+    let mut group = c.benchmark_group("convert_audio");
+    group.throughput(Throughput::Elements(input.len() as u64));
+    // Matching formats still copy the block, into the state's output buffer
+    group.bench_function("passthrough", |b| {
+        b.iter(|| black_box(convert_audio(black_box(&input), &fmt, &fmt, &mut state).len()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ring_buffer, resample, downmix, capture_bytes, passthrough);
+criterion_main!(benches);
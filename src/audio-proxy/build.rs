@@ -0,0 +1,30 @@
+//! Build info for `--version`: the target triple, and the git commit when built
+//! from a checkout
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rustc-env=AUDIO_PROXY_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|s| s.trim().to_string())
+    };
+    if let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=AUDIO_PROXY_GIT_HASH={}", hash);
+    }
+
+    // Rebuild when a commit moves HEAD, so the hash doesn't go stale
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head_ref);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Loudness matching between the A/B speaker outputs (`--ab-loudness-match`)
+//!
+//! Comparing two outputs with `ToggleOutput` is only fair when both play equally
+//! loud. With the option on, the speaker render loop measures how loud it plays on
+//! each target: the mean power of the audio it renders there, leaving out blocks
+//! below `GATE_DB` so pauses don't drag it down. On every toggle the IPC thread adds
+//! each device's Windows volume in dB, and once both targets have
+//! `MIN_MEASURED_SECS` of audio behind them it sets B's trim to the difference
+//! (within `MAX_TRIM_DB`), which the render loop ramps in. A is the reference and
+//! is never trimmed.
+//!
+//! This only matches what the proxy can see: two devices that play the same level
+//! at different loudness (different speakers, an amp's volume knob) stay apart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audio_stream::AudioFormat;
+use crate::dsp::GainRamp;
+
+/// Largest trim B gets, either way
+pub const MAX_TRIM_DB: f32 = 12.0;
+
+/// Audio each target needs to have played before the trim is set
+const MIN_MEASURED_SECS: f64 = 10.0;
+
+/// Blocks quieter than this (RMS, dBFS) aren't measured
+const GATE_DB: f32 = -60.0;
+
+/// How often the render loop hands its measurement over and picks up a new trim
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time constant of the trim ramp
+const TRIM_RAMP_MS: f32 = 50.0;
+
+/// Measured power of one target
+#[derive(Debug, Clone, Copy, Default)]
+struct Measured {
+    /// Mean square of the measured blocks, weighted by their length in seconds
+    energy: f64,
+    secs: f64,
+}
+
+impl Measured {
+    fn add(&mut self, other: Measured) {
+        self.energy += other.energy;
+        self.secs += other.secs;
+    }
+
+    /// Level in dBFS, `None` until enough audio was measured
+    fn level_db(&self) -> Option<f32> {
+        (self.secs >= MIN_MEASURED_SECS && self.energy > 0.0)
+            .then(|| (10.0 * (self.energy / self.secs).log10()) as f32)
+    }
+}
+
+#[derive(Debug, Default)]
+struct MatchState {
+    measured: HashMap<String, Measured>,
+    /// Trim per device ID; devices without one play as is
+    trims: HashMap<String, f32>,
+}
+
+/// Measurements and trims shared by the speaker render loop and the IPC thread
+#[derive(Debug, Clone, Default)]
+pub struct SharedAbMatch(Arc<Mutex<MatchState>>);
+
+impl SharedAbMatch {
+    fn add(&self, device_id: &str, measured: Measured) {
+        let mut state = self.0.lock().unwrap();
+        state.measured.entry(device_id.to_string()).or_default().add(measured);
+    }
+
+    /// Recompute B's trim against A from what was measured so far, given each
+    /// device's Windows volume in dB. Returns the new trim, or `None` while either
+    /// target hasn't played long enough (the trim stays as it was).
+    pub fn rematch(&self, a: &str, a_volume_db: f32, b: &str, b_volume_db: f32) -> Option<f32> {
+        let mut state = self.0.lock().unwrap();
+        let a_level = state.measured.get(a)?.level_db()? + a_volume_db;
+        let b_level = state.measured.get(b)?.level_db()? + b_volume_db;
+        let trim = (a_level - b_level).clamp(-MAX_TRIM_DB, MAX_TRIM_DB);
+        // A device that used to be B may be A now
+        state.trims.remove(a);
+        state.trims.insert(b.to_string(), trim);
+        Some(trim)
+    }
+
+    /// Trim of `device_id` in dB (0 if it has none)
+    pub fn trim_db(&self, device_id: &str) -> f32 {
+        self.0.lock().unwrap().trims.get(device_id).copied().unwrap_or(0.0)
+    }
+}
+
+/// Render-side measurement and trim, owned by the speaker render loop
+pub struct AbTrim {
+    shared: SharedAbMatch,
+    device_id: String,
+    pending: Measured,
+    next_flush: Instant,
+    trim_db: f32,
+    ramp: GainRamp,
+}
+
+impl AbTrim {
+    pub fn new(shared: SharedAbMatch, sample_rate: u32) -> Self {
+        Self {
+            shared,
+            device_id: String::new(),
+            pending: Measured::default(),
+            next_flush: Instant::now() + FLUSH_INTERVAL,
+            trim_db: 0.0,
+            ramp: GainRamp::new(0.0, TRIM_RAMP_MS, sample_rate),
+        }
+    }
+
+    /// Trim being applied, for the gain ceiling
+    pub fn trim_db(&self) -> f32 {
+        self.trim_db
+    }
+
+    /// Measure a block about to be played on `device_id`, then apply its trim in place
+    pub fn process(&mut self, device_id: &str, samples: &mut [f32], format: &AudioFormat) {
+        let now = Instant::now();
+        if device_id != self.device_id {
+            self.flush(now);
+            self.device_id = device_id.to_string();
+            // The switch fades in anyway, no need to ramp
+            self.trim_db = self.shared.trim_db(device_id);
+            self.ramp.reset(self.trim_db);
+        } else if now >= self.next_flush {
+            self.flush(now);
+            let trim_db = self.shared.trim_db(device_id);
+            if trim_db != self.trim_db {
+                self.trim_db = trim_db;
+                self.ramp.set_target(trim_db);
+            }
+        }
+
+        let channels = format.channels as usize;
+        if channels == 0 || format.sample_rate == 0 || samples.is_empty() {
+            return;
+        }
+        let mean_square = samples.iter().map(|s| (s * s) as f64).sum::<f64>() / samples.len() as f64;
+        if mean_square > 10f64.powf(GATE_DB as f64 / 10.0) {
+            let secs = (samples.len() / channels) as f64 / format.sample_rate as f64;
+            self.pending.add(Measured { energy: mean_square * secs, secs });
+        }
+
+        self.ramp.set_sample_rate(format.sample_rate);
+        self.ramp.process(samples, channels);
+    }
+
+    fn flush(&mut self, now: Instant) {
+        if !self.device_id.is_empty() && self.pending.secs > 0.0 {
+            self.shared.add(&self.device_id, self.pending);
+        }
+        self.pending = Measured::default();
+        self.next_flush = now + FLUSH_INTERVAL;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format() -> AudioFormat {
+        AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 }
+    }
+
+    fn measure(shared: &SharedAbMatch, device_id: &str, rms: f64, secs: f64) {
+        shared.add(device_id, Measured { energy: rms * rms * secs, secs });
+    }
+
+    #[test]
+    fn test_trim_needs_both_targets_measured() {
+        let shared = SharedAbMatch::default();
+        measure(&shared, "a", 0.5, 20.0);
+        assert_eq!(shared.rematch("a", 0.0, "b", 0.0), None);
+        measure(&shared, "b", 0.25, MIN_MEASURED_SECS / 2.0);
+        assert_eq!(shared.rematch("a", 0.0, "b", 0.0), None);
+        assert_eq!(shared.trim_db("b"), 0.0);
+
+        // B plays 6 dB quieter, 2 dB of which its Windows volume makes up
+        measure(&shared, "b", 0.25, MIN_MEASURED_SECS / 2.0);
+        let trim = shared.rematch("a", -4.0, "b", -2.0).unwrap();
+        assert!((trim - 4.0).abs() < 0.05, "{}", trim);
+        assert_eq!(shared.trim_db("b"), trim);
+        assert_eq!(shared.trim_db("a"), 0.0);
+
+        // Swapped roles: the old B becomes the reference
+        let trim = shared.rematch("b", -2.0, "a", -4.0).unwrap();
+        assert!((trim + 4.0).abs() < 0.05, "{}", trim);
+        assert_eq!(shared.trim_db("b"), 0.0);
+    }
+
+    #[test]
+    fn test_trim_is_limited() {
+        let shared = SharedAbMatch::default();
+        measure(&shared, "a", 1.0, 20.0);
+        measure(&shared, "b", 0.001, 20.0);
+        assert_eq!(shared.rematch("a", 0.0, "b", 0.0), Some(MAX_TRIM_DB));
+    }
+
+    #[test]
+    fn test_render_side_measures_and_applies_trim() {
+        let shared = SharedAbMatch::default();
+        let mut trim = AbTrim::new(shared.clone(), 48000);
+
+        // 10 ms blocks at 0.5, with silence in between that the gate leaves out
+        let mut played = 0.0;
+        while played < MIN_MEASURED_SECS + 0.5 {
+            trim.process("b", &mut vec![0.5; 960], &format());
+            trim.process("b", &mut vec![0.0; 960], &format());
+            played += 0.01;
+        }
+        trim.flush(Instant::now());
+        measure(&shared, "a", 1.0, 20.0);
+        let trim_db = shared.rematch("a", 0.0, "b", 0.0).unwrap();
+        assert!((trim_db - 6.02).abs() < 0.05, "{}", trim_db);
+
+        // Picked up when the output switches to B
+        trim.process("a", &mut vec![0.5; 960], &format());
+        let mut block = vec![0.5; 960];
+        trim.process("b", &mut block, &format());
+        assert_eq!(trim.trim_db(), trim_db);
+        assert!((block[0] - 1.0).abs() < 0.01, "{}", block[0]);
+    }
+}
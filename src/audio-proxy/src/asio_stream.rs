@@ -0,0 +1,202 @@
+//! ASIO render backend (only built with the `asio` feature)
+//!
+//! ASIO drivers pull audio from a callback on their own thread, so `write` pushes
+//! into an internal ring buffer that the callback drains, filling with silence
+//! when it runs dry. The device ID is the ASIO driver name (e.g. "Focusrite USB ASIO").
+//! Drivers that take 16-bit samples get them dithered (`--dither`).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use log::{error, info};
+
+use crate::audio_stream::{AudioFormat, RenderBackend};
+use crate::dither::{DitherMode, Ditherer};
+use crate::ring_buffer::AudioRingBuffer;
+
+/// Amount of audio the bridge buffer between `write` and the driver callback can hold
+const BRIDGE_BUFFER_MS: u32 = 100;
+
+/// Audio render stream to an ASIO driver
+pub struct AsioRenderStream {
+    driver_name: String,
+    device: cpal::Device,
+    stream: Option<cpal::Stream>,
+    buffer: Option<Arc<AudioRingBuffer>>,
+    failed: Arc<AtomicBool>,
+    format: Option<AudioFormat>,
+    dither: DitherMode,
+}
+
+impl AsioRenderStream {
+    /// Create a new render stream for the ASIO driver with the given name
+    pub fn new(driver_name: &str) -> Result<Self> {
+        info!("Creating ASIO render stream for driver: {}", driver_name);
+
+        let host = cpal::host_from_id(cpal::HostId::Asio)
+            .map_err(|e| anyhow!("ASIO host unavailable: {}", e))?;
+
+        let mut available = Vec::new();
+        let devices = host.output_devices()
+            .map_err(|e| anyhow!("Failed to enumerate ASIO drivers: {}", e))?;
+        for device in devices {
+            let name = device.name().unwrap_or_default();
+            if name == driver_name {
+                return Ok(Self {
+                    driver_name: name,
+                    device,
+                    stream: None,
+                    buffer: None,
+                    failed: Arc::new(AtomicBool::new(false)),
+                    format: None,
+                    dither: DitherMode::default(),
+                });
+            }
+            available.push(name);
+        }
+
+        Err(anyhow!(
+            "ASIO driver not found: {}. Available drivers: [{}]",
+            driver_name,
+            available.join(", ")
+        ))
+    }
+
+    /// Dither for drivers that take 16-bit samples (takes effect on `start`)
+    pub fn with_dither(mut self, dither: DitherMode) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    fn build_stream<T>(
+        &self,
+        config: &cpal::StreamConfig,
+        buffer: Arc<AudioRingBuffer>,
+        mut ditherer: Option<Ditherer>,
+    ) -> Result<cpal::Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let failed = self.failed.clone();
+        let mut scratch = vec![0.0f32; buffer.capacity()];
+
+        self.device.build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                for chunk in data.chunks_mut(scratch.len()) {
+                    let block = &mut scratch[..chunk.len()];
+                    let read = buffer.read(block);
+                    // Silence when the buffer runs dry, dithered like the rest
+                    block[read..].fill(0.0);
+                    if let Some(ref mut ditherer) = ditherer {
+                        ditherer.process(block);
+                    }
+                    for (out, &sample) in chunk.iter_mut().zip(block.iter()) {
+                        *out = T::from_sample(sample);
+                    }
+                }
+            },
+            move |e| {
+                error!("ASIO stream error: {}", e);
+                failed.store(true, Ordering::SeqCst);
+            },
+            None,
+        ).map_err(|e| anyhow!("Failed to build ASIO output stream: {}", e))
+    }
+}
+
+impl RenderBackend for AsioRenderStream {
+    fn start(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+
+        let supported = self.device.default_output_config()
+            .map_err(|e| anyhow!("Failed to get ASIO output config: {}", e))?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+
+        let format = AudioFormat {
+            sample_rate: config.sample_rate.0,
+            channels: config.channels,
+            bits_per_sample: 32,
+            block_align: config.channels as u32 * 4,
+        };
+
+        info!("ASIO format: {} Hz, {} ch, driver samples {:?}",
+              format.sample_rate, format.channels, sample_format);
+
+        let capacity = (format.sample_rate * BRIDGE_BUFFER_MS / 1000) as usize * format.channels as usize;
+        let buffer = Arc::new(AudioRingBuffer::new(capacity));
+        self.failed.store(false, Ordering::SeqCst);
+
+        let stream = match sample_format {
+            SampleFormat::F32 => self.build_stream::<f32>(&config, buffer.clone(), None),
+            SampleFormat::I32 => self.build_stream::<i32>(&config, buffer.clone(), None),
+            SampleFormat::I16 => {
+                info!("ASIO driver takes 16-bit samples, dither: {:?}", self.dither);
+                let ditherer = Ditherer::new(self.dither, 16, format.channels as usize);
+                self.build_stream::<i16>(&config, buffer.clone(), Some(ditherer))
+            }
+            other => Err(anyhow!("Unsupported ASIO sample format: {:?}", other)),
+        }?;
+
+        stream.play()
+            .map_err(|e| anyhow!("Failed to start ASIO stream: {}", e))
+            .with_context(|| format!("ASIO driver: {}", self.driver_name))?;
+
+        self.stream = Some(stream);
+        self.buffer = Some(buffer);
+        self.format = Some(format);
+        info!("ASIO render stream started");
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // Dropping the stream stops the driver callback
+        if self.stream.take().is_some() {
+            info!("ASIO render stream stopped");
+        }
+        self.buffer = None;
+        Ok(())
+    }
+
+    fn format(&self) -> Option<&AudioFormat> {
+        self.format.as_ref()
+    }
+
+    fn buffered_frames(&self) -> Result<u32> {
+        let buffer = self.buffer.as_ref()
+            .ok_or_else(|| anyhow!("ASIO stream not started"))?;
+        let channels = self.format.as_ref().map(|f| f.channels as usize).unwrap_or(1);
+        Ok((buffer.len() / channels) as u32)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<usize> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(anyhow!("ASIO stream failed"));
+        }
+
+        let buffer = self.buffer.as_ref()
+            .ok_or_else(|| anyhow!("ASIO stream not started"))?;
+        let channels = self.format.as_ref().map(|f| f.channels as usize).unwrap_or(1);
+
+        // Only write whole frames so channels never get out of step
+        let free = buffer.capacity() - buffer.len();
+        let samples_to_write = samples.len().min(free) / channels * channels;
+        if samples_to_write == 0 {
+            return Ok(0);
+        }
+
+        Ok(buffer.write(&samples[..samples_to_write]))
+    }
+}
+
+impl Drop for AsioRenderStream {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
@@ -1,11 +1,269 @@
 //! WASAPI audio stream management for capture and render
 
-use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
 use log::{debug, info, warn};
-use wasapi::{DeviceCollection, Direction, ShareMode};
+use serde::{Deserialize, Serialize};
+use wasapi::{DeviceCollection, DeviceState, Direction, Role, ShareMode};
+use windows::core::{implement, GUID, HRESULT, HSTRING, PCWSTR};
+use windows::Win32::Foundation::S_OK;
+use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
+use windows::Win32::Media::Audio::{
+    eCapture, eCommunications, eConsole, AudioCategory_Communications, AudioCategory_GameEffects, AudioCategory_Media,
+    AudioClientProperties, EDataFlow, ERole, IAudioClient2, IAudioRenderClient, IMMDevice, IMMDeviceEnumerator,
+    IMMNotificationClient, IMMNotificationClient_Impl, MMDeviceEnumerator, AUDCLNT_E_DEVICE_INVALIDATED, DEVICE_STATE,
+    AUDCLNT_E_DEVICE_IN_USE, AUDCLNT_E_EXCLUSIVE_MODE_ONLY, AUDCLNT_E_UNSUPPORTED_FORMAT, AUDCLNT_SHAREMODE, AUDCLNT_SHAREMODE_EXCLUSIVE,
+    AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM, AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY,
+    AUDCLNT_STREAMOPTIONS_NONE, AUDIO_STREAM_CATEGORY, WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVEFORMATEXTENSIBLE_0,
+};
+use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_ALL, COINIT, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED,
+};
+
+/// Errors from the WASAPI stream layer, so callers can tell a missing device from an
+/// unusable format from a device that's merely busy
+#[derive(Debug)]
+pub enum StreamError {
+    /// No endpoint matched the requested ID or name
+    DeviceNotFound { device_id: String, kind: &'static str, available: Vec<String> },
+    /// A partial name matched several devices, so none was picked
+    AmbiguousDevice { device_id: String, kind: &'static str, candidates: Vec<String> },
+    /// The device's format can't be handled by the proxy
+    UnsupportedFormat(String),
+    /// Another application holds the device in exclusive mode, which locks out
+    /// shared-mode streams (loopback included) until it lets go
+    DeviceInUse,
+    /// The endpoint was removed, disabled or reconfigured while in use
+    DeviceInvalidated,
+    /// A capture endpoint went away mid-read (unplugged or disabled), so reopening it
+    /// can't work until it's back
+    DeviceLost,
+    /// A WASAPI call failed with an HRESULT
+    InitFailed { context: &'static str, source: windows::core::Error },
+    /// A WASAPI call failed without an HRESULT we could recover
+    Other { context: &'static str, message: String },
+    /// The stream was used before `start` succeeded
+    NotStarted,
+    /// A write that isn't a whole number of frames, which would leave the channels of
+    /// everything after it out of step
+    PartialFrame { samples: usize, channels: usize },
+}
+
+/// Result type of the WASAPI stream layer
+pub type StreamResult<T> = std::result::Result<T, StreamError>;
+
+impl StreamError {
+    /// Whether retrying (possibly after the device comes back) can help. An unsupported
+    /// format won't change by reopening the same device, and an ambiguous name needs a
+    /// more specific one, so recovery should give up.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, StreamError::UnsupportedFormat(_) | StreamError::AmbiguousDevice { .. })
+    }
+
+    /// Classify an error returned by the wasapi crate.
+    ///
+    /// wasapi is built against a different windows-rs version than this crate, so its
+    /// errors can't be downcast to our `windows::core::Error`; the HRESULT is recovered
+    /// from the message instead ("... (0x88890004)").
+    fn wasapi(context: &'static str, err: Box<dyn std::error::Error>) -> Self {
+        let message = err.to_string();
+        Self::classify(context, parse_hresult(&message), message)
+    }
+
+    /// Classify an error from a direct windows-rs call
+    fn windows(context: &'static str, err: windows::core::Error) -> Self {
+        Self::classify(context, Some(err.code()), err.message())
+    }
+
+    fn classify(context: &'static str, code: Option<HRESULT>, message: String) -> Self {
+        match code {
+            Some(code) if code == AUDCLNT_E_DEVICE_INVALIDATED => StreamError::DeviceInvalidated,
+            // EXCLUSIVE_MODE_ONLY is what a shared-mode capture of a device taken in
+            // exclusive mode gets on some drivers, DEVICE_IN_USE on others
+            Some(code) if code == AUDCLNT_E_DEVICE_IN_USE || code == AUDCLNT_E_EXCLUSIVE_MODE_ONLY => {
+                StreamError::DeviceInUse
+            }
+            Some(code) if code == AUDCLNT_E_UNSUPPORTED_FORMAT => {
+                StreamError::UnsupportedFormat(format!("{}: {}", context, message))
+            }
+            Some(code) => StreamError::InitFailed { context, source: code.into() },
+            None => StreamError::Other { context, message },
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::DeviceNotFound { device_id, kind, available } => write!(
+                f, "Device not found: '{}'\nAvailable {} devices:\n{}", device_id, kind, available.join("\n")
+            ),
+            StreamError::AmbiguousDevice { device_id, kind, candidates } => write!(
+                f, "'{}' matches several {} devices, use a longer name or the device ID:\n{}",
+                device_id, kind, candidates.join("\n")
+            ),
+            StreamError::UnsupportedFormat(msg) => write!(f, "Unsupported format: {}", msg),
+            StreamError::DeviceInUse => write!(
+                f, "Device is held in exclusive mode by another application; shared-mode and loopback \
+                    streams can't open it until that application releases it or drops exclusive mode"
+            ),
+            StreamError::DeviceInvalidated => write!(f, "Device was removed or reconfigured"),
+            StreamError::DeviceLost => write!(f, "Device was unplugged or disabled"),
+            StreamError::InitFailed { context, source } => write!(f, "{}: {}", context, source),
+            StreamError::Other { context, message } => write!(f, "{}: {}", context, message),
+            StreamError::NotStarted => write!(f, "Stream not started"),
+            StreamError::PartialFrame { samples, channels } => write!(
+                f, "Write of {} samples is not a whole number of {}-channel frames", samples, channels
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::InitFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Find the first "0x" followed by 8 hex digits in an error message
+fn parse_hresult(message: &str) -> Option<HRESULT> {
+    message.match_indices("0x").find_map(|(i, _)| {
+        let hex = message.get(i + 2..i + 10)?;
+        let code = u32::from_str_radix(hex, 16).ok()?;
+        Some(HRESULT(code as i32))
+    })
+}
+
+/// Device buffer requested from WASAPI for each stream unless set with
+/// `with_buffer_ms` (`--device-buffer-ms`).
+///
+/// Three buffers sit between a capture and its render device: the WASAPI buffer of
+/// each stream (this one), the ring buffer between the capture and render threads
+/// (`--buffer`), which only absorbs bursts, and the silence queued on the render
+/// device at start (`--prefill-ms`), which sets the steady-state latency. The prefill
+/// is written into the render device buffer, so whatever doesn't fit is dropped. In
+/// shared mode WASAPI may hand out a larger buffer than requested, never a smaller
+/// one than the engine period.
+pub const DEFAULT_DEVICE_BUFFER_MS: u32 = 10;
+
+/// Device buffer duration in the 100 ns units WASAPI takes
+fn buffer_duration(ms: u32) -> i64 {
+    ms as i64 * 10_000
+}
+
+/// Prefix of the `index:<n>` device selector
+const INDEX_SELECTOR_PREFIX: &str = "index:";
+
+/// Prefix of the `default:<role>` device selector
+const DEFAULT_SELECTOR_PREFIX: &str = "default:";
+
+/// `WAVE_FORMAT_EXTENSIBLE` format tag
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// `KSDATAFORMAT_SUBTYPE_IEEE_FLOAT` from ksmedia.h (not worth the KernelStreaming feature)
+const KSDATAFORMAT_SUBTYPE_IEEE_FLOAT: GUID = GUID::from_u128(0x00000003_0000_0010_8000_00aa00389b71);
+
+/// `KSDATAFORMAT_SUBTYPE_PCM` from ksmedia.h
+const KSDATAFORMAT_SUBTYPE_PCM: GUID = GUID::from_u128(0x00000001_0000_0010_8000_00aa00389b71);
+
+/// Sample rates, channel counts and sample sizes `probe_supported_formats` tries
+const PROBE_SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176_400, 192_000];
+const PROBE_CHANNELS: [u16; 4] = [1, 2, 6, 8];
+const PROBE_BITS: [u16; 3] = [16, 24, 32];
+
+/// Audio session category of a render stream. Windows chooses ducking and effect
+/// processing by category; a Communications stream can duck other audio, including
+/// the proxy's own output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamCategory {
+    #[default]
+    Media,
+    Game,
+    Communications,
+}
+
+impl StreamCategory {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "media" => Ok(StreamCategory::Media),
+            "game" => Ok(StreamCategory::Game),
+            "comms" | "communications" => Ok(StreamCategory::Communications),
+            _ => Err(anyhow!("Unknown output category: {} (expected game, media or comms)", s)),
+        }
+    }
+
+    fn audio_category(self) -> AUDIO_STREAM_CATEGORY {
+        match self {
+            StreamCategory::Media => AudioCategory_Media,
+            StreamCategory::Game => AudioCategory_GameEffects,
+            StreamCategory::Communications => AudioCategory_Communications,
+        }
+    }
+}
+
+/// COM threading model the audio and IPC threads initialize with. MTA is the default
+/// and the one to use standalone: WASAPI objects are free-threaded and nothing has to
+/// pump messages. STA is for hosting the stream code in an app whose threads already
+/// joined a single-threaded apartment, where asking for MTA fails with
+/// `RPC_E_CHANGED_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ComModel {
+    #[default]
+    Mta,
+    Sta,
+    /// Library API only: the proxy never calls `CoInitializeEx` or `CoUninitialize`
+    /// and leaves COM to the host. The contract is that COM is usable on every thread
+    /// the proxy runs on: the thread calling `Proxy::start` must be initialized, and
+    /// the threads the proxy spawns rely on the implicit MTA, so the host has to keep
+    /// the process MTA alive (`CoIncrementMTAUsage`, or one of its own threads
+    /// initialized as MTA) until the proxy has stopped.
+    External,
+}
+
+impl ComModel {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mta" => Ok(ComModel::Mta),
+            "sta" => Ok(ComModel::Sta),
+            _ => Err(anyhow!("Unknown COM model: {} (expected mta or sta)", s)),
+        }
+    }
+
+    fn coinit(self) -> Option<COINIT> {
+        match self {
+            ComModel::Mta => Some(COINIT_MULTITHREADED),
+            ComModel::Sta => Some(COINIT_APARTMENTTHREADED),
+            ComModel::External => None,
+        }
+    }
+
+    /// Initialize COM on the calling thread (nothing for `External`); pair with
+    /// `uninitialize`
+    pub fn initialize(self) -> windows::core::Result<()> {
+        match self.coinit() {
+            Some(coinit) => unsafe { CoInitializeEx(None, coinit).ok() },
+            None => Ok(()),
+        }
+    }
+
+    /// Undo a successful `initialize` on the calling thread
+    pub fn uninitialize(self) {
+        if self.coinit().is_some() {
+            unsafe { CoUninitialize() }
+        }
+    }
+}
 
 /// Audio format information from the device
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AudioFormat {
     pub sample_rate: u32,
     pub channels: u16,
@@ -13,43 +271,147 @@ pub struct AudioFormat {
     pub block_align: u32, // bytes per frame
 }
 
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz, {} ch, {}-bit", self.sample_rate, self.channels, self.bits_per_sample)
+    }
+}
+
+/// Render format asked for over IPC, to avoid a resample when the source already
+/// runs at a known rate. Always 32-bit float, like the mix format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestedFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl RequestedFormat {
+    /// Check that the format is one a WASAPI endpoint could plausibly open
+    pub fn validate(&self) -> Result<()> {
+        validate_sample_rate(self.sample_rate)?;
+        if !(1..=8).contains(&self.channels) {
+            return Err(anyhow!("Invalid channel count: {}", self.channels));
+        }
+        Ok(())
+    }
+
+    /// Whether an opened stream ended up at this format
+    pub fn matches(&self, format: &AudioFormat) -> bool {
+        format.sample_rate == self.sample_rate && format.channels == self.channels
+    }
+}
+
+/// Check that a sample rate is one a WASAPI endpoint could plausibly open
+pub fn validate_sample_rate(sample_rate: u32) -> Result<()> {
+    if !(8000..=384_000).contains(&sample_rate) {
+        return Err(anyhow!("Invalid sample rate: {} Hz", sample_rate));
+    }
+    Ok(())
+}
+
+/// Whether a device is used for capture or render. Mirrors `wasapi::Direction`, which
+/// isn't serializable, so the IPC protocol doesn't depend on the wasapi crate's types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceDirection {
+    Capture,
+    Render,
+}
+
+impl From<DeviceDirection> for Direction {
+    fn from(direction: DeviceDirection) -> Self {
+        match direction {
+            DeviceDirection::Capture => Direction::Capture,
+            DeviceDirection::Render => Direction::Render,
+        }
+    }
+}
+
+impl From<Direction> for DeviceDirection {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Capture => DeviceDirection::Capture,
+            Direction::Render => DeviceDirection::Render,
+        }
+    }
+}
+
+/// A format a device accepts, and in which share modes. 32-bit formats are float,
+/// 16 and 24-bit ones integer PCM.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SupportedFormat {
+    #[serde(flatten)]
+    pub format: AudioFormat,
+    pub shared: bool,
+    pub exclusive: bool,
+}
+
+impl fmt::Display for RequestedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz, {} ch", self.sample_rate, self.channels)
+    }
+}
+
 /// Audio capture stream from a device (e.g., VB-Cable)
 pub struct CaptureStream {
     device: wasapi::Device,
     client: Option<wasapi::AudioClient>,
     capture_client: Option<wasapi::AudioCaptureClient>,
     format: Option<AudioFormat>,
+    buffer_ms: u32,
+    /// Most frames one `read` returns (0 for no limit)
+    max_read_frames: usize,
+    /// Samples of the last packet not yet returned by `read`
+    pending: Vec<f32>,
     started: bool,
+    discontinuity: bool,
 }
 
 impl CaptureStream {
     /// Create a new capture stream for the specified device
-    pub fn new(device_id: &str) -> Result<Self> {
+    pub fn new(device_id: &str) -> StreamResult<Self> {
         info!("Creating capture stream for device: {}", device_id);
 
-        let device = find_device_by_id(device_id, Direction::Capture)
-            .context("Failed to find capture device")?;
+        let device = find_device_by_id(device_id, Direction::Capture)?;
 
         Ok(Self {
             device,
             client: None,
             capture_client: None,
             format: None,
+            buffer_ms: DEFAULT_DEVICE_BUFFER_MS,
+            max_read_frames: 0,
+            pending: Vec::new(),
             started: false,
+            discontinuity: false,
         })
     }
 
+    /// Set the device buffer the stream is opened with (takes effect on `start`)
+    pub fn with_buffer_ms(mut self, ms: u32) -> Self {
+        self.buffer_ms = ms;
+        self
+    }
+
+    /// Cap the frames one `read` returns (0, the default, returns all that's available).
+    /// WASAPI only releases whole packets, so the rest of a packet is kept for the next
+    /// calls, which return it before asking the device for more.
+    pub fn with_max_read_frames(mut self, frames: usize) -> Self {
+        self.max_read_frames = frames;
+        self
+    }
+
     /// Start capturing audio
-    pub fn start(&mut self) -> Result<()> {
+    pub fn start(&mut self) -> StreamResult<()> {
         if self.started {
             return Ok(());
         }
 
         let mut client = self.device.get_iaudioclient()
-            .map_err(|e| anyhow!("Failed to get audio client: {}", e))?;
+            .map_err(|e| StreamError::wasapi("Failed to get audio client", e))?;
 
         let wave_format = client.get_mixformat()
-            .map_err(|e| anyhow!("Failed to get mix format: {}", e))?;
+            .map_err(|e| StreamError::wasapi("Failed to get mix format", e))?;
 
         let format = AudioFormat {
             sample_rate: wave_format.get_samplespersec(),
@@ -62,43 +424,44 @@ impl CaptureStream {
               format.sample_rate, format.channels, format.bits_per_sample, format.block_align);
 
         if format.bits_per_sample != 32 {
-            return Err(anyhow!(
-                "Unsupported capture format: {}-bit (only 32-bit float supported in shared mode)",
+            return Err(StreamError::UnsupportedFormat(format!(
+                "capture is {}-bit (only 32-bit float supported in shared mode)",
                 format.bits_per_sample
-            ));
+            )));
         }
 
         client.initialize_client(
             &wave_format,
-            100_000, // 10ms buffer in 100ns units
+            buffer_duration(self.buffer_ms),
             &Direction::Capture,
             &ShareMode::Shared,
             false,
-        ).map_err(|e| anyhow!("Failed to initialize capture client: {}", e))?;
+        ).map_err(|e| StreamError::wasapi("Failed to initialize capture client", e))?;
 
         let capture_client = client.get_audiocaptureclient()
-            .map_err(|e| anyhow!("Failed to get capture client: {}", e))?;
+            .map_err(|e| StreamError::wasapi("Failed to get capture client", e))?;
 
         client.start_stream()
-            .map_err(|e| anyhow!("Failed to start capture stream: {}", e))?;
+            .map_err(|e| StreamError::wasapi("Failed to start capture stream", e))?;
 
         self.client = Some(client);
         self.capture_client = Some(capture_client);
         self.format = Some(format);
+        self.pending.clear();
         self.started = true;
         info!("Capture stream started");
         Ok(())
     }
 
     /// Stop capturing audio
-    pub fn stop(&mut self) -> Result<()> {
+    pub fn stop(&mut self) -> StreamResult<()> {
         if !self.started {
             return Ok(());
         }
 
         if let Some(ref mut client) = self.client {
             client.stop_stream()
-                .map_err(|e| anyhow!("Failed to stop capture stream: {}", e))?;
+                .map_err(|e| StreamError::wasapi("Failed to stop capture stream", e))?;
         }
 
         self.started = false;
@@ -113,14 +476,22 @@ impl CaptureStream {
 
     /// Read audio samples from the capture buffer
     /// Returns the number of f32 samples read (samples = frames * channels)
-    pub fn read(&mut self, buffer: &mut [f32]) -> Result<usize> {
+    pub fn read(&mut self, buffer: &mut [f32]) -> StreamResult<usize> {
         let capture_client = self.capture_client.as_mut()
-            .ok_or_else(|| anyhow!("Capture client not initialized"))?;
+            .ok_or(StreamError::NotStarted)?;
         let format = self.format.as_ref()
-            .ok_or_else(|| anyhow!("Format not initialized"))?;
+            .ok_or(StreamError::NotStarted)?;
+        let max_samples = match self.max_read_frames {
+            0 => buffer.len(),
+            frames => buffer.len().min(frames * format.channels as usize),
+        };
+
+        if !self.pending.is_empty() {
+            return Ok(take_pending(&mut self.pending, &mut buffer[..max_samples]));
+        }
 
         let available_frames = match capture_client.get_next_nbr_frames()
-            .map_err(|e| anyhow!("Failed to get frame count: {}", e))? {
+            .map_err(|e| capture_read_error(&self.device, "Failed to get frame count", e))? {
             Some(frames) => frames as usize,
             None => return Ok(0),
         };
@@ -129,17 +500,56 @@ impl CaptureStream {
             return Ok(0);
         }
 
+        // WASAPI packets are around 10 ms; a second or more means the count is garbage,
+        // and reading it would allocate whatever it says. Reopening the stream is safer.
+        if available_frames > format.sample_rate as usize {
+            return Err(StreamError::Other {
+                context: "Capture packet size",
+                message: format!("device reported {} frames in one packet", available_frames),
+            });
+        }
+
         let bytes_per_frame = format.block_align as usize;
         let mut byte_buffer = vec![0u8; available_frames * bytes_per_frame];
-        let (frames_read, _flags) = capture_client.read_from_device(&mut byte_buffer)
-            .map_err(|e| anyhow!("Failed to read from device: {}", e))?;
+        let (frames_read, flags) = capture_client.read_from_device(&mut byte_buffer)
+            .map_err(|e| capture_read_error(&self.device, "Failed to read from device", e))?;
+        if flags.data_discontinuity {
+            self.discontinuity = true;
+        }
 
         let actual_bytes = frames_read as usize * bytes_per_frame;
-        let samples_read = bytes_to_f32(&byte_buffer[..actual_bytes], buffer);
+        debug!("Captured {} frames", frames_read);
+        if max_samples == buffer.len() {
+            return Ok(bytes_to_f32(&byte_buffer[..actual_bytes], buffer));
+        }
+        self.pending.resize(actual_bytes / 4, 0.0);
+        bytes_to_f32(&byte_buffer[..actual_bytes], &mut self.pending);
+        Ok(take_pending(&mut self.pending, &mut buffer[..max_samples]))
+    }
+
+    /// Returns true (once) if WASAPI flagged a data discontinuity since the last call
+    pub fn take_discontinuity(&mut self) -> bool {
+        std::mem::take(&mut self.discontinuity)
+    }
+}
+
+/// Move samples from the front of `pending` into `buffer`, as many as fit
+fn take_pending(pending: &mut Vec<f32>, buffer: &mut [f32]) -> usize {
+    let count = pending.len().min(buffer.len());
+    buffer[..count].copy_from_slice(&pending[..count]);
+    pending.drain(..count);
+    count
+}
 
-        debug!("Captured {} samples ({} frames)", samples_read, frames_read);
-        Ok(samples_read)
+/// Classify a failed capture read. WASAPI reports an unplugged endpoint with the same
+/// `AUDCLNT_E_DEVICE_INVALIDATED` as a reconfigured one; the endpoint's state tells a
+/// device that's gone from one that only needs reopening.
+fn capture_read_error(device: &wasapi::Device, context: &'static str, err: Box<dyn std::error::Error>) -> StreamError {
+    let error = StreamError::wasapi(context, err);
+    if matches!(error, StreamError::DeviceInvalidated) && !matches!(device.get_state(), Ok(DeviceState::Active)) {
+        return StreamError::DeviceLost;
     }
+    error
 }
 
 impl Drop for CaptureStream {
@@ -149,22 +559,41 @@ impl Drop for CaptureStream {
 }
 
 /// Audio render stream to a device
+///
+/// The client is activated through windows-rs rather than the wasapi crate, which
+/// doesn't expose `IAudioClient2` (needed to set the stream category).
 pub struct RenderStream {
     device: wasapi::Device,
-    client: Option<wasapi::AudioClient>,
-    render_client: Option<wasapi::AudioRenderClient>,
+    client: Option<IAudioClient2>,
+    render_client: Option<IAudioRenderClient>,
     buffer_frame_count: u32,
     format: Option<AudioFormat>,
+    /// Format to try before falling back to the mix format
+    requested_format: Option<RequestedFormat>,
+    /// Rate to open at when no format is requested (`--force-rate`)
+    forced_rate: Option<u32>,
+    /// Fail instead of substituting the closest rate the device supports (`--strict-rate`)
+    strict_rate: bool,
+    category: StreamCategory,
+    buffer_ms: u32,
     started: bool,
+    /// Whether a bad buffer frame count has been logged (once per stream is enough)
+    bad_frame_count_logged: bool,
 }
 
 impl RenderStream {
     /// Create a new render stream for the specified device
-    pub fn new(device_id: &str) -> Result<Self> {
+    pub fn new(device_id: &str) -> StreamResult<Self> {
+        Self::with_requested_format(device_id, None)
+    }
+
+    /// Create a render stream that opens at `requested` if the device supports it in
+    /// shared mode, and at the mix format otherwise. Check `format()` after `start`
+    /// to see which one was used.
+    pub fn with_requested_format(device_id: &str, requested: Option<RequestedFormat>) -> StreamResult<Self> {
         info!("Creating render stream for device: {}", device_id);
 
-        let device = find_device_by_id(device_id, Direction::Render)
-            .context("Failed to find render device")?;
+        let device = find_device_by_id(device_id, Direction::Render)?;
 
         Ok(Self {
             device,
@@ -172,55 +601,112 @@ impl RenderStream {
             render_client: None,
             buffer_frame_count: 0,
             format: None,
+            requested_format: requested,
+            forced_rate: None,
+            strict_rate: false,
+            category: StreamCategory::default(),
+            buffer_ms: DEFAULT_DEVICE_BUFFER_MS,
             started: false,
+            bad_frame_count_logged: false,
         })
     }
 
+    /// Set the session category the stream is opened with (takes effect on `start`)
+    pub fn with_category(mut self, category: StreamCategory) -> Self {
+        self.category = category;
+        self
+    }
+
+    /// Set the device buffer the stream is opened with (takes effect on `start`)
+    pub fn with_buffer_ms(mut self, ms: u32) -> Self {
+        self.buffer_ms = ms;
+        self
+    }
+
+    /// Open at `rate` (with the mix format's channels) unless a format is requested.
+    /// A rate the audio engine doesn't mix at is resampled by the engine; if the
+    /// device refuses that too, the stream opens at the closest rate it suggests, or
+    /// fails to start if `strict`. Takes effect on `start`.
+    pub fn with_forced_rate(mut self, rate: Option<u32>, strict: bool) -> Self {
+        self.forced_rate = rate;
+        self.strict_rate = strict;
+        self
+    }
+
     /// Start rendering audio
-    pub fn start(&mut self) -> Result<()> {
+    pub fn start(&mut self) -> StreamResult<()> {
         if self.started {
             return Ok(());
         }
 
-        let mut client = self.device.get_iaudioclient()
-            .map_err(|e| anyhow!("Failed to get audio client: {}", e))?;
+        let device_id = self.device.get_id()
+            .map_err(|e| StreamError::wasapi("Failed to get device ID", e))?;
+        let mut client = self.activate(&device_id)?;
 
-        let wave_format = client.get_mixformat()
-            .map_err(|e| anyhow!("Failed to get mix format: {}", e))?;
-
-        let format = AudioFormat {
-            sample_rate: wave_format.get_samplespersec(),
-            channels: wave_format.get_nchannels(),
-            bits_per_sample: wave_format.get_bitspersample(),
-            block_align: wave_format.get_blockalign(),
+        let attempts = match (self.requested_format, self.forced_rate) {
+            (Some(requested), _) => {
+                if is_format_supported(&client, &float_wave_format(requested), AUDCLNT_SHAREMODE_SHARED) {
+                    vec![RenderOpen::Exact(requested)]
+                } else {
+                    warn!("Device doesn't support requested format ({}), using mix format", requested);
+                    vec![RenderOpen::Mix]
+                }
+            }
+            (None, Some(rate)) => {
+                let mix_format = MixFormat(
+                    unsafe { client.GetMixFormat() }
+                        .map_err(|e| StreamError::windows("Failed to get mix format", e))?,
+                );
+                // SAFETY: GetMixFormat returned a valid format, freed by MixFormat's drop
+                let channels = unsafe { (*mix_format.0).nChannels };
+                let forced = float_wave_format(RequestedFormat { sample_rate: rate, channels });
+                let (supported, closest) = shared_closest_rate(&client, &forced);
+                // The suggestion may not be float, so only its rate is taken
+                let closest = closest.filter(|&sample_rate| {
+                    shared_closest_rate(&client, &float_wave_format(RequestedFormat { sample_rate, channels })).0
+                });
+                forced_rate_attempts(RequestedFormat { sample_rate: rate, channels }, supported, closest, self.strict_rate)
+            }
+            (None, None) => vec![RenderOpen::Mix],
         };
 
-        info!("Render format: {} Hz, {} ch, {}-bit, {} bytes/frame",
-              format.sample_rate, format.channels, format.bits_per_sample, format.block_align);
+        let mut attempts = attempts.into_iter().peekable();
+        let format = loop {
+            let Some(attempt) = attempts.next() else {
+                unreachable!("every plan has at least one attempt");
+            };
+            match self.initialize(&client, attempt) {
+                Ok(format) => break format,
+                Err(e) => match attempts.peek() {
+                    Some(next) => {
+                        warn!("Failed to open render stream as {}: {}, trying {}", attempt, e, next);
+                        // A client whose Initialize failed can't be initialized again
+                        client = self.activate(&device_id)?;
+                    }
+                    None => match self.forced_rate {
+                        Some(rate) if self.strict_rate && self.requested_format.is_none() => {
+                            return Err(StreamError::UnsupportedFormat(format!(
+                                "output can't open at {} Hz ({}) and --strict-rate rules out another rate",
+                                rate, e
+                            )));
+                        }
+                        _ => return Err(e),
+                    },
+                },
+            }
+        };
 
-        if format.bits_per_sample != 32 {
-            return Err(anyhow!(
-                "Unsupported render format: {}-bit (only 32-bit float supported in shared mode)",
-                format.bits_per_sample
-            ));
+        let buffer_frame_count = unsafe { client.GetBufferSize() }
+            .map_err(|e| StreamError::windows("Failed to get buffer frame count", e))?;
+        if buffer_frame_count == 0 {
+            warn!("Render device reported a 0-frame buffer, will query it again when writing");
         }
 
-        client.initialize_client(
-            &wave_format,
-            100_000, // 10ms buffer in 100ns units
-            &Direction::Render,
-            &ShareMode::Shared,
-            false,
-        ).map_err(|e| anyhow!("Failed to initialize render client: {}", e))?;
+        let render_client: IAudioRenderClient = unsafe { client.GetService() }
+            .map_err(|e| StreamError::windows("Failed to get render client", e))?;
 
-        let buffer_frame_count = client.get_bufferframecount()
-            .map_err(|e| anyhow!("Failed to get buffer frame count: {}", e))?;
-
-        let render_client = client.get_audiorenderclient()
-            .map_err(|e| anyhow!("Failed to get render client: {}", e))?;
-
-        client.start_stream()
-            .map_err(|e| anyhow!("Failed to start render stream: {}", e))?;
+        unsafe { client.Start() }
+            .map_err(|e| StreamError::windows("Failed to start render stream", e))?;
 
         self.client = Some(client);
         self.render_client = Some(render_client);
@@ -231,15 +717,92 @@ impl RenderStream {
         Ok(())
     }
 
+    /// Activate a client for the device, with the stream category set
+    fn activate(&self, device_id: &str) -> StreamResult<IAudioClient2> {
+        let client = activate_audio_client(device_id)?;
+
+        // Must be set before Initialize. Not fatal: the stream still works, just
+        // without the category's ducking/processing behaviour.
+        let properties = AudioClientProperties {
+            cbSize: std::mem::size_of::<AudioClientProperties>() as u32,
+            bIsOffload: false.into(),
+            eCategory: self.category.audio_category(),
+            Options: AUDCLNT_STREAMOPTIONS_NONE,
+        };
+        if let Err(e) = unsafe { client.SetClientProperties(&properties) } {
+            warn!("Failed to set render stream category to {:?}: {}", self.category, e);
+        }
+        Ok(client)
+    }
+
+    /// Initialize `client` in shared mode as `open` says, returning the stream's format
+    fn initialize(&self, client: &IAudioClient2, open: RenderOpen) -> StreamResult<AudioFormat> {
+        let mix_format = MixFormat(
+            unsafe { client.GetMixFormat() }
+                .map_err(|e| StreamError::windows("Failed to get mix format", e))?,
+        );
+
+        let desired;
+        let (wave_format, flags): (*const WAVEFORMATEX, u32) = match open {
+            RenderOpen::Exact(requested) => {
+                desired = float_wave_format(requested);
+                (ptr::addr_of!(desired).cast(), 0)
+            }
+            RenderOpen::Converted(requested) => {
+                desired = float_wave_format(requested);
+                (ptr::addr_of!(desired).cast(), AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY)
+            }
+            RenderOpen::Mix => (mix_format.0, 0),
+        };
+
+        // SAFETY: points at `desired` or the mix format, both alive until the end of initialize
+        let header = unsafe { *wave_format };
+        let format = AudioFormat {
+            sample_rate: header.nSamplesPerSec,
+            channels: header.nChannels,
+            bits_per_sample: header.wBitsPerSample,
+            block_align: header.nBlockAlign as u32,
+        };
+
+        if format.bits_per_sample != 32 {
+            return Err(StreamError::UnsupportedFormat(format!(
+                "render is {}-bit (only 32-bit float supported in shared mode)",
+                format.bits_per_sample
+            )));
+        }
+
+        unsafe {
+            client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                flags,
+                buffer_duration(self.buffer_ms),
+                0,
+                wave_format,
+                None,
+            )
+        }.map_err(|e| StreamError::windows("Failed to initialize render client", e))?;
+
+        info!("Render format: {} Hz, {} ch, {}-bit, {} bytes/frame, category {:?}{}",
+              format.sample_rate, format.channels, format.bits_per_sample, format.block_align, self.category,
+              if flags != 0 { " (converted to the mix format by the audio engine)" } else { "" });
+        if let (Some(rate), None) = (self.forced_rate, self.requested_format) {
+            if format.sample_rate != rate {
+                warn!("Device doesn't take {} Hz in shared mode, using the closest rate it supports ({} Hz)",
+                      rate, format.sample_rate);
+            }
+        }
+        Ok(format)
+    }
+
     /// Stop rendering audio
-    pub fn stop(&mut self) -> Result<()> {
+    pub fn stop(&mut self) -> StreamResult<()> {
         if !self.started {
             return Ok(());
         }
 
-        if let Some(ref mut client) = self.client {
-            client.stop_stream()
-                .map_err(|e| anyhow!("Failed to stop render stream: {}", e))?;
+        if let Some(ref client) = self.client {
+            unsafe { client.Stop() }
+                .map_err(|e| StreamError::windows("Failed to stop render stream", e))?;
         }
 
         self.started = false;
@@ -252,25 +815,53 @@ impl RenderStream {
         self.format.as_ref()
     }
 
+    /// Number of frames currently queued in the device buffer (0 means the device is starving)
+    pub fn buffered_frames(&self) -> StreamResult<u32> {
+        let client = self.client.as_ref()
+            .ok_or(StreamError::NotStarted)?;
+        unsafe { client.GetCurrentPadding() }
+            .map_err(|e| StreamError::windows("Failed to get padding", e))
+    }
+
     /// Write audio samples to the render buffer
-    /// Returns the number of samples written
-    pub fn write(&mut self, samples: &[f32]) -> Result<usize> {
+    /// Returns the number of samples written, always whole frames. `samples` must be
+    /// whole frames too: a trailing partial frame is refused rather than dropped.
+    pub fn write(&mut self, samples: &[f32]) -> StreamResult<usize> {
         let client = self.client.as_ref()
-            .ok_or_else(|| anyhow!("Client not initialized"))?;
-        let render_client = self.render_client.as_mut()
-            .ok_or_else(|| anyhow!("Render client not initialized"))?;
+            .ok_or(StreamError::NotStarted)?;
+        let render_client = self.render_client.as_ref()
+            .ok_or(StreamError::NotStarted)?;
         let format = self.format.as_ref()
-            .ok_or_else(|| anyhow!("Format not initialized"))?;
+            .ok_or(StreamError::NotStarted)?;
+
+        if self.buffer_frame_count == 0 {
+            self.buffer_frame_count = unsafe { client.GetBufferSize() }.unwrap_or(0);
+        }
 
-        let padding = client.get_current_padding()
-            .map_err(|e| anyhow!("Failed to get padding: {}", e))? as usize;
-        let available_frames = self.buffer_frame_count as usize - padding;
+        let padding = unsafe { client.GetCurrentPadding() }
+            .map_err(|e| StreamError::windows("Failed to get padding", e))?;
+        let available_frames = match writable_frames(self.buffer_frame_count, padding) {
+            Some(frames) => frames,
+            None => {
+                // Treat as "no space, try later" rather than trusting the numbers
+                if !self.bad_frame_count_logged {
+                    warn!("Render device reported inconsistent buffer state ({} frames, {} padding)",
+                          self.buffer_frame_count, padding);
+                    self.bad_frame_count_logged = true;
+                }
+                return Ok(0);
+            }
+        };
 
         if available_frames == 0 {
             return Ok(0);
         }
 
         let channels = format.channels as usize;
+        debug_assert!(samples.len().is_multiple_of(channels), "partial frame written to render stream");
+        if !samples.len().is_multiple_of(channels) {
+            return Err(StreamError::PartialFrame { samples: samples.len(), channels });
+        }
         let frames_to_write = (samples.len() / channels).min(available_frames);
         if frames_to_write == 0 {
             return Ok(0);
@@ -282,11 +873,14 @@ impl RenderStream {
         // and all bit patterns are valid.
         let byte_data = f32_as_bytes(&samples[..samples_to_write]);
 
-        render_client.write_to_device(
-            frames_to_write,
-            byte_data,
-            None,
-        ).map_err(|e| anyhow!("Failed to write to device: {}", e))?;
+        let buffer = unsafe { render_client.GetBuffer(frames_to_write as u32) }
+            .map_err(|e| StreamError::windows("Failed to get render buffer", e))?;
+        // SAFETY: GetBuffer returned room for frames_to_write frames of block_align
+        // (= channels * 4) bytes, which is exactly what byte_data holds
+        unsafe {
+            ptr::copy_nonoverlapping(byte_data.as_ptr(), buffer, byte_data.len());
+            render_client.ReleaseBuffer(frames_to_write as u32, 0)
+        }.map_err(|e| StreamError::windows("Failed to write to device", e))?;
 
         debug!("Rendered {} samples ({} frames)", samples_to_write, frames_to_write);
         Ok(samples_to_write)
@@ -299,14 +893,469 @@ impl Drop for RenderStream {
     }
 }
 
-/// Find a device by its ID or name (strict matching)
-fn find_device_by_id(device_id: &str, direction: Direction) -> Result<wasapi::Device> {
+/// Mix format allocated by WASAPI, freed with the COM allocator
+struct MixFormat(*mut WAVEFORMATEX);
+
+impl Drop for MixFormat {
+    fn drop(&mut self) {
+        unsafe { CoTaskMemFree(Some(self.0 as *const _)) }
+    }
+}
+
+/// Open the endpoint with the given ID as a raw windows-rs device.
+/// COM must already be initialized on the calling thread.
+fn open_mm_device(device_id: &str) -> StreamResult<IMMDevice> {
+    unsafe {
+        let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+            .map_err(|e| StreamError::windows("Failed to create device enumerator", e))?;
+        enumerator.GetDevice(&HSTRING::from(device_id))
+            .map_err(|e| StreamError::windows("Failed to open device", e))
+    }
+}
+
+/// Activate an `IAudioClient2` on the endpoint with the given ID
+fn activate_audio_client(device_id: &str) -> StreamResult<IAudioClient2> {
+    unsafe {
+        open_mm_device(device_id)?
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|e| StreamError::windows("Failed to get audio client", e))
+    }
+}
+
+/// Volume control of a render device ID or name (resolved like `RenderStream::new`)
+fn endpoint_volume(device_id: &str) -> StreamResult<IAudioEndpointVolume> {
+    let device = find_device_by_id(device_id, Direction::Render)?;
+    let id = device.get_id()
+        .map_err(|e| StreamError::wasapi("Failed to get device ID", e))?;
+    unsafe {
+        open_mm_device(&id)?
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|e| StreamError::windows("Device doesn't support volume control", e))
+    }
+}
+
+/// Windows master volume of a render endpoint (its volume slider), in percent
+pub fn get_endpoint_volume(device_id: &str) -> StreamResult<f32> {
+    let volume = endpoint_volume(device_id)?;
+    let level = unsafe { volume.GetMasterVolumeLevelScalar() }
+        .map_err(|e| StreamError::windows("Failed to read endpoint volume", e))?;
+    Ok(level * 100.0)
+}
+
+/// Windows master volume of a render endpoint in dB, as the device scales it
+pub fn get_endpoint_volume_db(device_id: &str) -> StreamResult<f32> {
+    let volume = endpoint_volume(device_id)?;
+    unsafe { volume.GetMasterVolumeLevel() }
+        .map_err(|e| StreamError::windows("Failed to read endpoint volume", e))
+}
+
+/// Set the Windows master volume of a render endpoint, in percent (0-100)
+pub fn set_endpoint_volume(device_id: &str, percent: f32) -> StreamResult<()> {
+    let volume = endpoint_volume(device_id)?;
+    unsafe { volume.SetMasterVolumeLevelScalar(percent.clamp(0.0, 100.0) / 100.0, ptr::null()) }
+        .map_err(|e| StreamError::windows("Failed to set endpoint volume", e))
+}
+
+/// Probe which common formats (see `PROBE_SAMPLE_RATES` etc.) a device accepts in
+/// shared and exclusive mode, in ascending order of rate, channels and sample size.
+/// Formats it accepts in neither mode are left out. The device is resolved like
+/// `CaptureStream::new`/`RenderStream::new`.
+pub fn probe_supported_formats(device_id: &str, direction: DeviceDirection) -> StreamResult<Vec<SupportedFormat>> {
+    let device = find_device_by_id(device_id, direction.into())?;
+    let id = device.get_id()
+        .map_err(|e| StreamError::wasapi("Failed to get device ID", e))?;
+    let client = activate_audio_client(&id)?;
+
+    let mut supported = Vec::new();
+    for sample_rate in PROBE_SAMPLE_RATES {
+        for channels in PROBE_CHANNELS {
+            for bits_per_sample in PROBE_BITS {
+                let wave_format = wave_format(sample_rate, channels, bits_per_sample);
+                let shared = is_format_supported(&client, &wave_format, AUDCLNT_SHAREMODE_SHARED);
+                let exclusive = is_format_supported(&client, &wave_format, AUDCLNT_SHAREMODE_EXCLUSIVE);
+                if shared || exclusive {
+                    supported.push(SupportedFormat {
+                        format: AudioFormat {
+                            sample_rate,
+                            channels,
+                            bits_per_sample,
+                            block_align: wave_format.Format.nBlockAlign as u32,
+                        },
+                        shared,
+                        exclusive,
+                    });
+                }
+            }
+        }
+    }
+    Ok(supported)
+}
+
+/// 32-bit float format for a requested rate and channel count, with the standard
+/// speaker layout for that many channels
+fn float_wave_format(requested: RequestedFormat) -> WAVEFORMATEXTENSIBLE {
+    wave_format(requested.sample_rate, requested.channels, 32)
+}
+
+/// Format with the standard speaker layout for `channels`: float for 32-bit samples,
+/// integer PCM otherwise
+fn wave_format(sample_rate: u32, channels: u16, bits_per_sample: u16) -> WAVEFORMATEXTENSIBLE {
+    let block_align = channels * (bits_per_sample / 8);
+    WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_EXTENSIBLE,
+            nChannels: channels,
+            nSamplesPerSec: sample_rate,
+            nAvgBytesPerSec: sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: bits_per_sample,
+            cbSize: (std::mem::size_of::<WAVEFORMATEXTENSIBLE>() - std::mem::size_of::<WAVEFORMATEX>()) as u16,
+        },
+        Samples: WAVEFORMATEXTENSIBLE_0 { wValidBitsPerSample: bits_per_sample },
+        // FL, FR, FC, LFE, BL, BR, ... in order; callers cap channels at 8
+        dwChannelMask: (1u32 << channels) - 1,
+        SubFormat: if bits_per_sample == 32 { KSDATAFORMAT_SUBTYPE_IEEE_FLOAT } else { KSDATAFORMAT_SUBTYPE_PCM },
+    }
+}
+
+/// Whether the client accepts `format` as-is in `mode`
+fn is_format_supported(client: &IAudioClient2, format: &WAVEFORMATEXTENSIBLE, mode: AUDCLNT_SHAREMODE) -> bool {
+    if mode == AUDCLNT_SHAREMODE_EXCLUSIVE {
+        // Exclusive mode never suggests a closest match
+        let format: *const WAVEFORMATEX = ptr::addr_of!(*format).cast();
+        return unsafe { client.IsFormatSupported(mode, format, None) } == S_OK;
+    }
+    shared_closest_rate(client, format).0
+}
+
+/// Whether the client accepts `format` as-is in shared mode and, when it doesn't, the
+/// sample rate of the closest match it suggests
+fn shared_closest_rate(client: &IAudioClient2, format: &WAVEFORMATEXTENSIBLE) -> (bool, Option<u32>) {
+    let format: *const WAVEFORMATEX = ptr::addr_of!(*format).cast();
+    let mut closest: *mut WAVEFORMATEX = ptr::null_mut();
+    let hr = unsafe { client.IsFormatSupported(AUDCLNT_SHAREMODE_SHARED, format, Some(&mut closest)) };
+    let mut closest_rate = None;
+    if !closest.is_null() {
+        // SAFETY: a closest match is a valid format owned by us until freed
+        closest_rate = Some(unsafe { (*closest).nSamplesPerSec });
+        unsafe { CoTaskMemFree(Some(closest as *const _)) }
+    }
+    (hr == S_OK, closest_rate.filter(|_| hr != S_OK))
+}
+
+/// A format `RenderStream::start` opens the stream at, tried in order until one
+/// initializes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenderOpen {
+    /// This format, which the audio engine takes as is
+    Exact(RequestedFormat),
+    /// This format, which the audio engine resamples to its mix format
+    /// (`AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM`)
+    Converted(RequestedFormat),
+    /// The audio engine's mix format
+    Mix,
+}
+
+impl fmt::Display for RenderOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderOpen::Exact(format) => write!(f, "{}", format),
+            RenderOpen::Converted(format) => write!(f, "{} converted by the audio engine", format),
+            RenderOpen::Mix => write!(f, "the mix format"),
+        }
+    }
+}
+
+/// The formats to try for `--force-rate`. A rate the engine mixes at opens as is. Any
+/// other rate opens with the engine resampling it, since shared mode takes nothing
+/// but the mix rate otherwise. If that fails, the engine's `closest` rate is next,
+/// then the mix format, unless `strict` rules out another rate.
+fn forced_rate_attempts(forced: RequestedFormat, supported: bool, closest: Option<u32>, strict: bool) -> Vec<RenderOpen> {
+    if supported {
+        return vec![RenderOpen::Exact(forced)];
+    }
+    let mut attempts = vec![RenderOpen::Converted(forced)];
+    if !strict {
+        attempts.extend(
+            closest
+                .filter(|&sample_rate| sample_rate != forced.sample_rate)
+                .map(|sample_rate| RenderOpen::Exact(RequestedFormat { sample_rate, ..forced })),
+        );
+        attempts.push(RenderOpen::Mix);
+    }
+    attempts
+}
+
+/// Output side of a proxy path, so the render loop doesn't depend on WASAPI directly
+pub trait RenderBackend {
+    /// Open the device and start playback
+    fn start(&mut self) -> Result<()>;
+    /// Stop playback
+    fn stop(&mut self) -> Result<()>;
+    /// Get the audio format (available after start)
+    fn format(&self) -> Option<&AudioFormat>;
+    /// Number of frames queued but not yet played
+    fn buffered_frames(&self) -> Result<u32>;
+    /// Queue interleaved f32 samples, returning how many were accepted
+    fn write(&mut self, samples: &[f32]) -> Result<usize>;
+}
+
+impl RenderBackend for RenderStream {
+    fn start(&mut self) -> Result<()> {
+        Ok(RenderStream::start(self)?)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(RenderStream::stop(self)?)
+    }
+
+    fn format(&self) -> Option<&AudioFormat> {
+        RenderStream::format(self)
+    }
+
+    fn buffered_frames(&self) -> Result<u32> {
+        Ok(RenderStream::buffered_frames(self)?)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<usize> {
+        Ok(RenderStream::write(self, samples)?)
+    }
+}
+
+/// Identity of a resolved endpoint, used to spot input/output pairs that feed each other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointInfo {
+    pub id: String,
+    pub name: String,
+    /// Name of the adapter/driver the endpoint belongs to (e.g. "VB-Audio Virtual Cable")
+    pub interface_name: String,
+}
+
+impl EndpointInfo {
+    /// Whether this endpoint and `other` are on the same device, so audio rendered to
+    /// one comes straight back out of the other (e.g. "CABLE Input" and "CABLE Output")
+    pub fn same_device_as(&self, other: &EndpointInfo) -> bool {
+        self.id == other.id
+            || (!self.interface_name.is_empty() && self.interface_name == other.interface_name)
+    }
+}
+
+/// Resolve a capture device ID or name the same way `CaptureStream::new` does
+pub fn resolve_capture_endpoint(device_id: &str) -> StreamResult<EndpointInfo> {
+    endpoint_info(&find_device_by_id(device_id, Direction::Capture)?)
+}
+
+/// Resolve a render device ID or name the same way `RenderStream::new` does
+pub fn resolve_render_endpoint(device_id: &str) -> StreamResult<EndpointInfo> {
+    endpoint_info(&find_device_by_id(device_id, Direction::Render)?)
+}
+
+/// Enumerate capture endpoints in `index:` selector order
+pub fn list_capture_endpoints() -> StreamResult<Vec<EndpointInfo>> {
+    list_endpoints(DeviceDirection::Capture)
+}
+
+/// Enumerate render endpoints in `index:` selector order
+pub fn list_render_endpoints() -> StreamResult<Vec<EndpointInfo>> {
+    list_endpoints(DeviceDirection::Render)
+}
+
+/// Enumerate the endpoints of one direction in `index:` selector order
+pub fn list_endpoints(direction: DeviceDirection) -> StreamResult<Vec<EndpointInfo>> {
+    let collection = DeviceCollection::new(&direction.into())
+        .map_err(|e| StreamError::wasapi("Failed to get device collection", e))?;
+    let mut endpoints = Vec::new();
+    for device in collection.into_iter() {
+        let device = device.map_err(|e| StreamError::wasapi("Failed to enumerate device", e))?;
+        endpoints.push(endpoint_info(&device)?);
+    }
+    Ok(endpoints)
+}
+
+/// Role of a system default endpoint. Windows keeps separate defaults for general
+/// audio ("console") and for calls ("communications"), e.g. speakers and a headset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultRole {
+    Console,
+    Communications,
+}
+
+impl DefaultRole {
+    pub const ALL: [DefaultRole; 2] = [DefaultRole::Console, DefaultRole::Communications];
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "console" => Some(DefaultRole::Console),
+            "comms" | "communications" => Some(DefaultRole::Communications),
+            _ => None,
+        }
+    }
+
+    /// Device selector that resolves to this role's default endpoint
+    pub fn selector(self) -> &'static str {
+        match self {
+            DefaultRole::Console => "default:console",
+            DefaultRole::Communications => "default:comms",
+        }
+    }
+
+    /// Role of a `default:<role>` selector, `None` for IDs, names and other selectors
+    pub fn from_selector(device_id: &str) -> Option<Self> {
+        device_id.strip_prefix(DEFAULT_SELECTOR_PREFIX).and_then(DefaultRole::parse)
+    }
+
+    fn wasapi_role(self) -> Role {
+        match self {
+            DefaultRole::Console => Role::Console,
+            DefaultRole::Communications => Role::Communications,
+        }
+    }
+
+    fn erole(self) -> ERole {
+        match self {
+            DefaultRole::Console => eConsole,
+            DefaultRole::Communications => eCommunications,
+        }
+    }
+}
+
+/// Notices when Windows changes the default capture device of a role. A stream opened
+/// through a `default:` selector stays on the old device after such a change, so
+/// without this it only catches up the next time the stream is reopened.
+pub struct DefaultCaptureWatcher {
+    enumerator: IMMDeviceEnumerator,
+    client: IMMNotificationClient,
+    changed: Arc<AtomicBool>,
+}
+
+impl DefaultCaptureWatcher {
+    /// Register for default device changes of `role`.
+    /// COM must already be initialized on the calling thread.
+    pub fn new(role: DefaultRole) -> StreamResult<Self> {
+        let changed = Arc::new(AtomicBool::new(false));
+        let client: IMMNotificationClient = DefaultChangeListener { role: role.erole(), changed: changed.clone() }.into();
+        unsafe {
+            let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .map_err(|e| StreamError::windows("Failed to create device enumerator", e))?;
+            enumerator.RegisterEndpointNotificationCallback(&client)
+                .map_err(|e| StreamError::windows("Failed to register for device notifications", e))?;
+            Ok(Self { enumerator, client, changed })
+        }
+    }
+
+    /// Returns true (once) if the default changed since the last call
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Drop for DefaultCaptureWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.enumerator.UnregisterEndpointNotificationCallback(&self.client);
+        }
+    }
+}
+
+/// Endpoint notification sink of `DefaultCaptureWatcher`. Windows calls it on a thread
+/// of its own, so it only raises a flag for the capture loop to pick up.
+#[implement(IMMNotificationClient)]
+struct DefaultChangeListener {
+    role: ERole,
+    changed: Arc<AtomicBool>,
+}
+
+impl IMMNotificationClient_Impl for DefaultChangeListener_Impl {
+    fn OnDefaultDeviceChanged(&self, flow: EDataFlow, role: ERole, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        if flow == eCapture && role == self.role {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn OnDeviceStateChanged(&self, _device_id: &PCWSTR, _state: DEVICE_STATE) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceAdded(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _device_id: &PCWSTR, _key: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Current default capture endpoint for `role`
+pub fn default_capture_endpoint(role: DefaultRole) -> StreamResult<EndpointInfo> {
+    endpoint_info(&default_device(role, Direction::Capture)?)
+}
+
+/// Current default render endpoint for `role`
+pub fn default_render_endpoint(role: DefaultRole) -> StreamResult<EndpointInfo> {
+    endpoint_info(&default_device(role, Direction::Render)?)
+}
+
+fn default_device(role: DefaultRole, direction: Direction) -> StreamResult<wasapi::Device> {
+    wasapi::get_default_device_for_role(&direction, &role.wasapi_role())
+        .map_err(|e| StreamError::wasapi("Failed to get default device", e))
+}
+
+/// Whether `device_id` is a render endpoint ID (`{0.0.0.…}`), i.e. an output device
+/// that could only be captured through loopback
+pub fn is_render_endpoint_id(device_id: &str) -> bool {
+    device_id.starts_with("{0.0.0.")
+}
+
+fn endpoint_info(device: &wasapi::Device) -> StreamResult<EndpointInfo> {
+    Ok(EndpointInfo {
+        id: device.get_id().map_err(|e| StreamError::wasapi("Failed to get device ID", e))?,
+        name: device.get_friendlyname().unwrap_or_default(),
+        interface_name: device.get_interface_friendlyname().unwrap_or_default(),
+    })
+}
+
+/// Find a device by `default:`/`index:` selector, ID or name (strict matching)
+fn find_device_by_id(device_id: &str, direction: Direction) -> StreamResult<wasapi::Device> {
+    // Default selector ("default:comms"): whatever Windows currently has as the default
+    // for that role. Looked up on every stream (re)open, so recoveries follow changes.
+    if let Some(role) = DefaultRole::from_selector(device_id) {
+        let device = default_device(role, direction)
+            .map_err(|_| device_not_found(device_id, direction))?;
+        info!("Found default {:?} device: {} ({})", role,
+              device.get_friendlyname().unwrap_or_default(), device.get_id().unwrap_or_default());
+        return Ok(device);
+    }
+
+    // Index selector ("index:2"): position in the enumeration order. Handy for scripts,
+    // but it shifts when devices are added or removed, so IDs and names stay the default.
+    if let Some(index) = device_id.strip_prefix(INDEX_SELECTOR_PREFIX) {
+        if let Ok(index) = index.trim().parse::<u32>() {
+            let collection = DeviceCollection::new(&direction)
+                .map_err(|e| StreamError::wasapi("Failed to get device collection", e))?;
+            let count = collection.get_nbr_devices()
+                .map_err(|e| StreamError::wasapi("Failed to count devices", e))?;
+            if index < count {
+                let device = collection.get_device_at_index(index)
+                    .map_err(|e| StreamError::wasapi("Failed to get device by index", e))?;
+                info!("Found device by index {}: {} ({})", index,
+                      device.get_friendlyname().unwrap_or_default(), device.get_id().unwrap_or_default());
+                return Ok(device);
+            }
+            return Err(device_not_found(device_id, direction));
+        }
+    }
+
     // First pass: exact ID match
     let collection = DeviceCollection::new(&direction)
-        .map_err(|e| anyhow!("Failed to get device collection: {}", e))?;
+        .map_err(|e| StreamError::wasapi("Failed to get device collection", e))?;
 
     for device in collection.into_iter() {
-        let device = device.map_err(|e| anyhow!("Failed to enumerate device: {}", e))?;
+        let device = device.map_err(|e| StreamError::wasapi("Failed to enumerate device", e))?;
         if let Ok(id) = device.get_id() {
             if id == device_id {
                 info!("Found device by exact ID: {} ({})",
@@ -318,9 +1367,9 @@ fn find_device_by_id(device_id: &str, direction: Direction) -> Result<wasapi::De
 
     // Second pass: exact name match (case-insensitive)
     let collection = DeviceCollection::new(&direction)
-        .map_err(|e| anyhow!("Failed to get device collection: {}", e))?;
+        .map_err(|e| StreamError::wasapi("Failed to get device collection", e))?;
     for device in collection.into_iter() {
-        let device = device.map_err(|e| anyhow!("Failed to enumerate device: {}", e))?;
+        let device = device.map_err(|e| StreamError::wasapi("Failed to enumerate device", e))?;
         if let Ok(name) = device.get_friendlyname() {
             if name.eq_ignore_ascii_case(device_id) {
                 info!("Found device by exact name: {} ({})",
@@ -330,55 +1379,99 @@ fn find_device_by_id(device_id: &str, direction: Direction) -> Result<wasapi::De
         }
     }
 
-    // Third pass: partial name match (case-insensitive)
+    // Third pass: partial name match (case-insensitive), only if it's unambiguous
     let collection = DeviceCollection::new(&direction)
-        .map_err(|e| anyhow!("Failed to get device collection: {}", e))?;
+        .map_err(|e| StreamError::wasapi("Failed to get device collection", e))?;
+    let mut matches = Vec::new();
     for device in collection.into_iter() {
-        let device = device.map_err(|e| anyhow!("Failed to enumerate device: {}", e))?;
+        let device = device.map_err(|e| StreamError::wasapi("Failed to enumerate device", e))?;
         if let Ok(name) = device.get_friendlyname() {
             if name.to_lowercase().contains(&device_id.to_lowercase()) {
-                warn!("Found device by partial name match: '{}' matched '{}'",
-                      device_id, name);
-                return Ok(device);
+                matches.push((name, device));
             }
         }
     }
+    if matches.len() > 1 {
+        let kind = if matches!(direction, Direction::Capture) { "capture" } else { "render" };
+        let candidates = matches.iter()
+            .map(|(name, device)| format!("  '{}' ({})", name, device.get_id().unwrap_or_default()))
+            .collect();
+        return Err(StreamError::AmbiguousDevice { device_id: device_id.to_string(), kind, candidates });
+    }
+    if let Some((name, device)) = matches.pop() {
+        warn!("Found device by partial name match: '{}' matched '{}'", device_id, name);
+        return Ok(device);
+    }
+
+    Err(device_not_found(device_id, direction))
+}
 
-    // List available devices for debugging
+/// Build a not-found error listing the available devices for debugging, with the mix
+/// format of each so it's clear which one would need resampling
+fn device_not_found(device_id: &str, direction: Direction) -> StreamError {
     let dir_name = if matches!(direction, Direction::Capture) { "capture" } else { "render" };
-    let collection = DeviceCollection::new(&direction)
-        .map_err(|e| anyhow!("Failed to get device collection: {}", e))?;
     let mut available = Vec::new();
-    for device in collection.into_iter() {
-        if let Ok(device) = device {
-            let name = device.get_friendlyname().unwrap_or_default();
-            let id = device.get_id().unwrap_or_default();
-            available.push(format!("  '{}' ({})", name, id));
+    if let Ok(collection) = DeviceCollection::new(&direction) {
+        for (index, device) in collection.into_iter().enumerate() {
+            if let Ok(device) = device {
+                let name = device.get_friendlyname().unwrap_or_default();
+                let id = device.get_id().unwrap_or_default();
+                available.push(describe_device(index, &name, &id, mix_format(&device).as_ref()));
+            }
         }
     }
 
-    Err(anyhow!(
-        "Device not found: '{}'\nAvailable {} devices:\n{}",
-        device_id, dir_name, available.join("\n")
-    ))
+    StreamError::DeviceNotFound { device_id: device_id.to_string(), kind: dir_name, available }
+}
+
+/// Shared-mode mix format of a device, from a transient client that is never
+/// initialized; `None` if the device doesn't report one (e.g. it's disabled)
+fn mix_format(device: &wasapi::Device) -> Option<AudioFormat> {
+    let wave_format = device.get_iaudioclient().ok()?.get_mixformat().ok()?;
+    Some(AudioFormat {
+        sample_rate: wave_format.get_samplespersec(),
+        channels: wave_format.get_nchannels(),
+        bits_per_sample: wave_format.get_bitspersample(),
+        block_align: wave_format.get_blockalign(),
+    })
 }
 
-/// Safely convert bytes to f32 samples (handles alignment correctly)
-fn bytes_to_f32(bytes: &[u8], output: &mut [f32]) -> usize {
-    let num_floats = bytes.len() / 4;
-    let count = num_floats.min(output.len());
-    for i in 0..count {
-        let offset = i * 4;
-        output[i] = f32::from_le_bytes([
-            bytes[offset],
-            bytes[offset + 1],
-            bytes[offset + 2],
-            bytes[offset + 3],
-        ]);
+/// One line of the not-found device listing
+fn describe_device(index: usize, name: &str, id: &str, format: Option<&AudioFormat>) -> String {
+    match format {
+        Some(format) => format!("  [{}] '{}' ({}) - {}", index, name, id, format),
+        None => format!("  [{}] '{}' ({}) - format unknown", index, name, id),
+    }
+}
+
+/// Convert little-endian f32 bytes (the shared-mode capture format) to samples, as
+/// many as fit in `output`. A trailing partial sample is left out. Returns the number
+/// of samples written.
+pub fn bytes_to_f32(bytes: &[u8], output: &mut [f32]) -> usize {
+    let count = (bytes.len() / 4).min(output.len());
+    // On little-endian targets (every Windows one) the bytes already are the samples'
+    // memory layout, so one copy does it. Copying bytes doesn't care how the capture
+    // buffer is aligned, and any bit pattern is a valid f32.
+    #[cfg(target_endian = "little")]
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), output.as_mut_ptr().cast::<u8>(), count * 4);
+    }
+    #[cfg(not(target_endian = "little"))]
+    for (sample, bytes) in output[..count].iter_mut().zip(bytes.chunks_exact(4)) {
+        *sample = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
     }
     count
 }
 
+/// Frames free in the render buffer, or `None` if the device reported a state that
+/// makes no sense (a 0-frame buffer, or more padding than buffer)
+fn writable_frames(buffer_frame_count: u32, padding: u32) -> Option<usize> {
+    if buffer_frame_count == 0 || padding > buffer_frame_count {
+        return None;
+    }
+    Some((buffer_frame_count - padding) as usize)
+}
+
 /// View f32 slice as bytes (zero-copy, always safe since u8 has alignment 1)
 fn f32_as_bytes(floats: &[f32]) -> &[u8] {
     unsafe {
@@ -388,3 +1481,160 @@ fn f32_as_bytes(floats: &[f32]) -> &[u8] {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_to_f32() {
+        let samples = [0.5f32, -1.0, 0.25, f32::MIN_POSITIVE];
+        let mut bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        // A trailing partial sample, and an offset so the samples aren't 4-byte aligned
+        bytes.extend_from_slice(&[0xAB, 0xCD]);
+        let unaligned: Vec<u8> = std::iter::once(0).chain(bytes.iter().copied()).collect();
+
+        let mut output = [0.0f32; 8];
+        assert_eq!(bytes_to_f32(&unaligned[1..], &mut output), 4);
+        assert_eq!(output[..4], samples);
+        assert_eq!(output[4..], [0.0; 4]);
+
+        // Stops where the output ends
+        let mut output = [0.0f32; 2];
+        assert_eq!(bytes_to_f32(&bytes, &mut output), 2);
+        assert_eq!(output, [0.5, -1.0]);
+        assert_eq!(bytes_to_f32(&bytes[..3], &mut output), 0);
+    }
+
+    #[test]
+    fn test_take_pending_keeps_the_rest() {
+        let mut pending = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        let mut buffer = [0.0; 2];
+        assert_eq!(take_pending(&mut pending, &mut buffer), 2);
+        assert_eq!(buffer, [1.0, 2.0]);
+        assert_eq!(pending, vec![3.0, 4.0, 5.0]);
+
+        let mut buffer = [0.0; 4];
+        assert_eq!(take_pending(&mut pending, &mut buffer), 3);
+        assert_eq!(buffer[..3], [3.0, 4.0, 5.0]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_writable_frames() {
+        assert_eq!(writable_frames(480, 100), Some(380));
+        assert_eq!(writable_frames(480, 480), Some(0));
+    }
+
+    #[test]
+    fn test_writable_frames_rejects_inconsistent_counts() {
+        // Padding larger than the buffer used to underflow the subtraction
+        assert_eq!(writable_frames(480, 481), None);
+        assert_eq!(writable_frames(0, 0), None);
+        assert_eq!(writable_frames(0, 10), None);
+    }
+
+    #[test]
+    fn test_exclusive_mode_errors_are_device_in_use() {
+        for code in [AUDCLNT_E_DEVICE_IN_USE, AUDCLNT_E_EXCLUSIVE_MODE_ONLY] {
+            let e = StreamError::classify("Failed to initialize capture client", Some(code), String::new());
+            assert!(matches!(e, StreamError::DeviceInUse));
+            assert!(e.is_retryable());
+        }
+    }
+
+    #[test]
+    fn test_describe_device() {
+        let format = AudioFormat { sample_rate: 44100, channels: 2, bits_per_sample: 32, block_align: 8 };
+        assert_eq!(
+            describe_device(1, "Speakers", "{0.0.0.1}", Some(&format)),
+            "  [1] 'Speakers' ({0.0.0.1}) - 44100 Hz, 2 ch, 32-bit"
+        );
+        assert_eq!(describe_device(0, "Cable", "{0.0.1.2}", None), "  [0] 'Cable' ({0.0.1.2}) - format unknown");
+    }
+
+    #[test]
+    fn test_parse_default_role() {
+        assert_eq!(DefaultRole::parse("comms"), Some(DefaultRole::Communications));
+        assert_eq!(DefaultRole::parse("Console"), Some(DefaultRole::Console));
+        assert_eq!(DefaultRole::parse("headset"), None);
+        for role in DefaultRole::ALL {
+            let name = role.selector().strip_prefix(DEFAULT_SELECTOR_PREFIX).unwrap();
+            assert_eq!(DefaultRole::parse(name), Some(role));
+            assert_eq!(DefaultRole::from_selector(role.selector()), Some(role));
+        }
+        // Only the selector follows the default; names and other selectors don't
+        assert_eq!(DefaultRole::from_selector("comms"), None);
+        assert_eq!(DefaultRole::from_selector("index:0"), None);
+    }
+
+    #[test]
+    fn test_parse_stream_category() {
+        assert_eq!(StreamCategory::parse("Game").unwrap(), StreamCategory::Game);
+        assert_eq!(StreamCategory::parse("comms").unwrap(), StreamCategory::Communications);
+        assert_eq!(StreamCategory::parse("media").unwrap(), StreamCategory::Media);
+        assert!(StreamCategory::parse("movie").is_err());
+    }
+
+    #[test]
+    fn test_device_direction_round_trips() {
+        for direction in [DeviceDirection::Capture, DeviceDirection::Render] {
+            assert_eq!(DeviceDirection::from(Direction::from(direction)), direction);
+        }
+        assert_eq!(Direction::from(DeviceDirection::Render), Direction::Render);
+    }
+
+    #[test]
+    fn test_buffer_duration() {
+        assert_eq!(buffer_duration(DEFAULT_DEVICE_BUFFER_MS), 100_000);
+        assert_eq!(buffer_duration(2000), 20_000_000);
+    }
+
+    #[test]
+    fn test_parse_com_model() {
+        assert_eq!(ComModel::parse("MTA").unwrap(), ComModel::Mta);
+        assert_eq!(ComModel::parse("sta").unwrap(), ComModel::Sta);
+        assert_eq!(ComModel::default(), ComModel::Mta);
+        assert!(ComModel::parse("both").is_err());
+    }
+
+    #[test]
+    fn test_float_wave_format_layout() {
+        let format = float_wave_format(RequestedFormat { sample_rate: 48000, channels: 6 });
+        assert_eq!({ format.Format.nBlockAlign }, 24);
+        assert_eq!({ format.Format.nAvgBytesPerSec }, 48000 * 24);
+        assert_eq!({ format.Format.cbSize }, 22);
+        assert_eq!({ format.dwChannelMask }, 0x3F);
+    }
+
+    #[test]
+    fn test_pcm_wave_format_layout() {
+        let format = wave_format(44100, 2, 24);
+        assert_eq!({ format.Format.nBlockAlign }, 6);
+        assert_eq!({ format.Format.nAvgBytesPerSec }, 44100 * 6);
+        assert_eq!({ format.Format.wBitsPerSample }, 24);
+        assert_eq!({ format.SubFormat }, KSDATAFORMAT_SUBTYPE_PCM);
+    }
+
+    #[test]
+    fn test_forced_rate_attempts() {
+        let at = |sample_rate| RequestedFormat { sample_rate, channels: 2 };
+
+        // A rate the engine mixes at needs nothing else
+        assert_eq!(forced_rate_attempts(at(48000), true, None, false), [RenderOpen::Exact(at(48000))]);
+
+        // Any other rate is resampled by the engine before falling back to its suggestion
+        assert_eq!(
+            forced_rate_attempts(at(96000), false, Some(48000), false),
+            [RenderOpen::Converted(at(96000)), RenderOpen::Exact(at(48000)), RenderOpen::Mix]
+        );
+        assert_eq!(
+            forced_rate_attempts(at(96000), false, None, false),
+            [RenderOpen::Converted(at(96000)), RenderOpen::Mix]
+        );
+
+        // Strict: the engine resampling still plays at the forced rate, nothing else does
+        assert_eq!(forced_rate_attempts(at(96000), false, Some(48000), true), [RenderOpen::Converted(at(96000))]);
+        assert_eq!(forced_rate_attempts(at(48000), true, None, true), [RenderOpen::Exact(at(48000))]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Zero-config device selection (`--auto`)
+//!
+//! The usual setup is a game playing into a virtual cable and the proxy forwarding
+//! the cable to the real output. `--auto` looks for that cable among the capture
+//! devices by name and uses the current default render device as the output. It only
+//! picks when the choice is clear: exactly one virtual cable, and a default output
+//! that isn't virtual itself (which it often is, when the game follows the default).
+//! Otherwise it explains what it found, and `--speaker-in`/`--speaker-out` decide.
+//! Explicit options always win over what `--auto` would pick.
+
+use anyhow::{anyhow, Result};
+
+use crate::audio_stream::EndpointInfo;
+
+/// Name fragments (lowercase) of virtual audio devices, with the product they belong to.
+/// Matched against the endpoint and the adapter name.
+const VIRTUAL_DEVICES: &[(&str, &str)] = &[
+    ("vb-audio virtual cable", "VB-Cable"),
+    ("vb-audio hi-fi cable", "VB-Audio Hi-Fi Cable"),
+    ("vb-audio cable", "VB-Cable"),
+    ("voicemeeter", "VoiceMeeter"),
+    ("virtual audio cable", "Virtual Audio Cable"),
+];
+
+/// Where to look when `--auto` can't decide
+const GUIDANCE: &str = "Pass --speaker-in and --speaker-out instead (--list-devices shows the choices).";
+
+/// Product name of a virtual audio device, `None` for anything else
+pub fn virtual_product(endpoint: &EndpointInfo) -> Option<&'static str> {
+    let name = endpoint.name.to_ascii_lowercase();
+    let interface = endpoint.interface_name.to_ascii_lowercase();
+    VIRTUAL_DEVICES.iter()
+        .find(|(fragment, _)| name.contains(fragment) || interface.contains(fragment))
+        .map(|&(_, product)| product)
+}
+
+/// The one virtual cable among the capture devices, as the speaker input
+pub fn pick_speaker_in(captures: &[EndpointInfo]) -> Result<&EndpointInfo> {
+    let cables: Vec<&EndpointInfo> = captures.iter().filter(|e| virtual_product(e).is_some()).collect();
+    match cables[..] {
+        [cable] => Ok(cable),
+        [] => Err(anyhow!(
+            "--auto found no virtual cable among the capture devices. Install one (e.g. VB-Cable), \
+             set the game to play into it, and start again. {}", GUIDANCE
+        )),
+        _ => {
+            let names: Vec<&str> = cables.iter().map(|e| e.name.as_str()).collect();
+            Err(anyhow!(
+                "--auto found several virtual cables and can't tell which one the game plays into: {}. {}",
+                names.join(", "), GUIDANCE
+            ))
+        }
+    }
+}
+
+/// The default render device as the speaker output, unless it's virtual
+pub fn pick_speaker_out(default_render: Option<&EndpointInfo>) -> Result<&EndpointInfo> {
+    let output = default_render.ok_or_else(|| anyhow!("--auto found no default output device. {}", GUIDANCE))?;
+    if let Some(product) = virtual_product(output) {
+        return Err(anyhow!(
+            "--auto can't use the default output '{}': it's a {} device, probably the one the game \
+             plays into. {}", output.name, product, GUIDANCE
+        ));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(name: &str, interface_name: &str) -> EndpointInfo {
+        EndpointInfo { id: format!("{{id of {}}}", name), name: name.into(), interface_name: interface_name.into() }
+    }
+
+    #[test]
+    fn test_virtual_product() {
+        let cable = endpoint("CABLE Output (VB-Audio Virtual Cable)", "VB-Audio Virtual Cable");
+        assert_eq!(virtual_product(&cable), Some("VB-Cable"));
+        // Renamed in Sound settings, still known by its adapter
+        assert_eq!(virtual_product(&endpoint("Game", "VB-Audio VoiceMeeter VAIO")), Some("VoiceMeeter"));
+        assert_eq!(virtual_product(&endpoint("Speakers", "Realtek(R) Audio")), None);
+    }
+
+    #[test]
+    fn test_pick_speaker_in() {
+        let mic = endpoint("Microphone", "Realtek(R) Audio");
+        let cable = endpoint("CABLE Output", "VB-Audio Virtual Cable");
+        let captures = vec![mic.clone(), cable.clone()];
+        assert_eq!(pick_speaker_in(&captures).unwrap().id, cable.id);
+
+        assert!(pick_speaker_in(std::slice::from_ref(&mic)).is_err());
+        let voicemeeter = endpoint("VoiceMeeter Output", "VB-Audio VoiceMeeter VAIO");
+        let err = pick_speaker_in(&[mic, cable, voicemeeter]).unwrap_err().to_string();
+        assert!(err.contains("CABLE Output, VoiceMeeter Output"), "{}", err);
+    }
+
+    #[test]
+    fn test_pick_speaker_out() {
+        let speakers = endpoint("Speakers", "Realtek(R) Audio");
+        assert_eq!(pick_speaker_out(Some(&speakers)).unwrap().id, speakers.id);
+        assert!(pick_speaker_out(Some(&endpoint("CABLE Input", "VB-Audio Virtual Cable"))).is_err());
+        assert!(pick_speaker_out(None).is_err());
+    }
+}
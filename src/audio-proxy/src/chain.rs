@@ -0,0 +1,232 @@
+//! Order of each render path's processing stages (`SetProcessingChain`)
+//!
+//! A render path mixes in its second source, runs the audio through its stages and
+//! applies the start fade last. Which stages a path has, in their default order:
+//!
+//! - speaker: `eq`, `ab_trim`, `ceiling`, `delay`, `solo`, `swap_lr`
+//! - mic: `ceiling`, `delay`
+//!
+//! A chain lists the stages to run, in order. A stage left out doesn't run, whatever
+//! its own command says: without `swap_lr`, `SetChannelSwap` has no effect. The
+//! `ceiling` is the exception, since it's the `--max-output-db` safety cap: left out,
+//! it's added at the end. The mic gains (`--mic-gain-db`, `--mic-in2-gain-db`) apply
+//! to each mic before the two are mixed, so they aren't part of the chain, and the
+//! mic path has no EQ of its own yet.
+//!
+//! Every stage is linear, so any order gives the same audio once gains have
+//! settled. The ceiling in particular doesn't measure the audio, it caps the gain
+//! the enabled stages are set to add, so it works the same ahead of them as after.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Render path a processing chain belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderPath {
+    Speaker,
+    Mic,
+}
+
+impl RenderPath {
+    /// Stages the path has, in their default order
+    pub fn stages(self) -> &'static [Stage] {
+        match self {
+            RenderPath::Speaker => &[Stage::Eq, Stage::AbTrim, Stage::Ceiling, Stage::Delay, Stage::Solo, Stage::SwapLr],
+            RenderPath::Mic => &[Stage::Ceiling, Stage::Delay],
+        }
+    }
+}
+
+/// One processing stage of a render path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Parametric EQ (`SetEq`)
+    Eq,
+    /// Output B's loudness trim (`--ab-loudness-match`)
+    AbTrim,
+    /// Gain cap (`--max-output-db`)
+    Ceiling,
+    /// Output delay (`SetDelay`)
+    Delay,
+    /// Speaker mute of `SoloMic`
+    Solo,
+    /// Left/right swap (`--swap-lr`, `SetChannelSwap`)
+    SwapLr,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Eq => "eq",
+            Stage::AbTrim => "ab_trim",
+            Stage::Ceiling => "ceiling",
+            Stage::Delay => "delay",
+            Stage::Solo => "solo",
+            Stage::SwapLr => "swap_lr",
+        }
+    }
+}
+
+/// Stage names of a chain, e.g. "eq, ceiling, delay"
+pub fn chain_names(stages: &[Stage]) -> String {
+    stages.iter().map(|s| s.name()).collect::<Vec<_>>().join(", ")
+}
+
+/// Check a chain for `path` and return the one that runs: no stage twice, only
+/// stages the path has, and the ceiling added at the end if it was left out
+pub fn validate_chain(path: RenderPath, stages: &[Stage]) -> Result<Vec<Stage>> {
+    let available = path.stages();
+    let mut chain = Vec::with_capacity(available.len());
+    for &stage in stages {
+        if !available.contains(&stage) {
+            return Err(anyhow!(
+                "{} isn't a stage of the {:?} path (it has {})", stage.name(), path, chain_names(available)
+            ));
+        }
+        if chain.contains(&stage) {
+            return Err(anyhow!("{} is in the chain twice", stage.name()));
+        }
+        chain.push(stage);
+    }
+    if !chain.contains(&Stage::Ceiling) {
+        chain.push(Stage::Ceiling);
+    }
+    Ok(chain)
+}
+
+/// A path's chain, shared between the IPC thread and the render thread
+#[derive(Debug)]
+pub struct SharedChain {
+    path: RenderPath,
+    stages: RwLock<Vec<Stage>>,
+    version: AtomicU64,
+}
+
+impl SharedChain {
+    /// The default chain of `path`
+    pub fn new(path: RenderPath) -> Self {
+        Self { path, stages: RwLock::new(path.stages().to_vec()), version: AtomicU64::new(0) }
+    }
+
+    /// Replace the chain, returning the one that runs (see `validate_chain`)
+    pub fn set(&self, stages: &[Stage]) -> Result<Vec<Stage>> {
+        let chain = validate_chain(self.path, stages)?;
+        *self.stages.write().unwrap() = chain.clone();
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(chain)
+    }
+
+    /// Copy of the current chain
+    pub fn stages(&self) -> Vec<Stage> {
+        self.stages.read().unwrap().clone()
+    }
+}
+
+/// Render-thread copy of a chain, updated only when the shared one changes
+pub struct Chain {
+    stages: Vec<Stage>,
+    version: u64,
+}
+
+impl Chain {
+    pub fn new(shared: &SharedChain) -> Self {
+        Self { stages: shared.stages(), version: shared.version.load(Ordering::Acquire) }
+    }
+
+    /// Pick up a chain set over IPC
+    pub fn sync(&mut self, shared: &SharedChain) {
+        let version = shared.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.version = version;
+            self.stages = shared.stages();
+        }
+    }
+
+    /// The stages to run, in order
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    pub fn has(&self, stage: Stage) -> bool {
+        self.stages.contains(&stage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_stream::AudioFormat;
+    use crate::dsp::GainCeiling;
+    use crate::eq::{EqBand, EqBandType, Equalizer, SharedEq};
+
+    #[test]
+    fn test_validate_chain() {
+        use Stage::*;
+        assert_eq!(validate_chain(RenderPath::Speaker, RenderPath::Speaker.stages()).unwrap(), RenderPath::Speaker.stages());
+        assert_eq!(validate_chain(RenderPath::Mic, &[Delay, Ceiling]).unwrap(), [Delay, Ceiling]);
+
+        // The safety cap can't be left out
+        assert_eq!(validate_chain(RenderPath::Speaker, &[SwapLr, Eq]).unwrap(), [SwapLr, Eq, Ceiling]);
+        assert_eq!(validate_chain(RenderPath::Mic, &[]).unwrap(), [Ceiling]);
+
+        let err = validate_chain(RenderPath::Mic, &[Eq]).unwrap_err().to_string();
+        assert!(err.contains("it has ceiling, delay"), "{}", err);
+        assert!(validate_chain(RenderPath::Speaker, &[Delay, Delay]).is_err());
+
+        let stages: Vec<Stage> = serde_json::from_str(r#"["swap_lr","ab_trim"]"#).unwrap();
+        assert_eq!(stages, [SwapLr, AbTrim]);
+    }
+
+    #[test]
+    fn test_chain_sync() {
+        let shared = SharedChain::new(RenderPath::Speaker);
+        let mut chain = Chain::new(&shared);
+        assert!(chain.has(Stage::Solo));
+
+        shared.set(&[Stage::Delay]).unwrap();
+        chain.sync(&shared);
+        assert_eq!(chain.stages(), [Stage::Delay, Stage::Ceiling]);
+        assert!(!chain.has(Stage::Solo));
+
+        // A rejected chain leaves the running one alone
+        assert!(shared.set(&[Stage::Delay, Stage::Delay]).is_err());
+        chain.sync(&shared);
+        assert_eq!(chain.stages(), [Stage::Delay, Stage::Ceiling]);
+    }
+
+    #[test]
+    fn test_ceiling_ahead_of_gain_still_caps() {
+        // A 12 dB EQ boost under a 6 dB cap, with the cap first and last
+        let format = AudioFormat { sample_rate: 48000, channels: 1, bits_per_sample: 32, block_align: 4 };
+        let shared_eq = SharedEq::default();
+        shared_eq.set(vec![EqBand { band_type: EqBandType::Peak, frequency: 1000.0, gain_db: 12.0, q: 1.0 }]);
+        let input: Vec<f32> = (0..4800).map(|i| (i as f32 * 0.13).sin() * 0.1).collect();
+
+        let run = |ceiling_first: bool| {
+            let mut eq = Equalizer::default();
+            eq.sync(&shared_eq);
+            let mut ceiling = GainCeiling::new("Speaker", 6.0, format.sample_rate);
+            ceiling.update(eq.max_boost_db());
+            let mut samples = input.clone();
+            if ceiling_first {
+                ceiling.process(&mut samples, 1);
+                eq.process(&mut samples, &format);
+            } else {
+                eq.process(&mut samples, &format);
+                ceiling.process(&mut samples, 1);
+            }
+            samples
+        };
+
+        let (first, last) = (run(true), run(false));
+        assert!(first.iter().zip(&last).all(|(a, b)| (a - b).abs() < 1e-5));
+        // Both hold the 12 dB boost to 6 dB: at most twice the input level
+        let peak = first.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= 0.1 * 2.0 * 1.1, "{}", peak);
+    }
+}
@@ -0,0 +1,217 @@
+//! Adjustable delay on the render paths
+//!
+//! In two-PC streaming setups the mic and the game audio take different routes and
+//! arrive at the encoder misaligned. `SetDelay` holds back either path by up to
+//! `MAX_DELAY_MS` so they can be lined up by hand. Each render loop runs its output
+//! through a `DelayLine`, whose history is sized for the maximum delay up front so a
+//! change never needs a reallocation; changes crossfade from the old delay to the new
+//! one instead of jumping, which would click.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::audio_stream::AudioFormat;
+
+/// Longest delay `SetDelay` accepts
+pub const MAX_DELAY_MS: u32 = 1000;
+
+/// Length of the crossfade when the delay changes
+const CROSSFADE_MS: u32 = 20;
+
+/// Render path a delay applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayTarget {
+    Speaker,
+    Mic,
+}
+
+/// Delay in milliseconds, shared with the IPC thread
+#[derive(Debug, Clone, Default)]
+pub struct SharedDelay(Arc<AtomicU32>);
+
+impl SharedDelay {
+    pub fn ms(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Change the delay (at most `MAX_DELAY_MS`); the render loop crossfades to it
+    pub fn set_ms(&self, ms: u32) -> Result<()> {
+        if ms > MAX_DELAY_MS {
+            anyhow::bail!("Delay must be at most {} ms: {}", MAX_DELAY_MS, ms);
+        }
+        self.0.store(ms, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Crossfade in progress from an old delay to the current one
+struct Crossfade {
+    from_frames: usize,
+    position: usize,
+    length: usize,
+}
+
+/// Delay line owned by a render loop
+pub struct DelayLine {
+    shared: SharedDelay,
+    /// Interleaved ring of the last `MAX_DELAY_MS` (plus one frame) of audio
+    history: Vec<f32>,
+    history_frames: usize,
+    write_frame: usize,
+    /// Format `history` was sized for
+    format: Option<AudioFormat>,
+    delay_frames: usize,
+    crossfade: Option<Crossfade>,
+}
+
+impl DelayLine {
+    pub fn new(shared: SharedDelay) -> Self {
+        Self {
+            shared,
+            history: Vec::new(),
+            history_frames: 0,
+            write_frame: 0,
+            format: None,
+            delay_frames: 0,
+            crossfade: None,
+        }
+    }
+
+    /// Delay interleaved samples in place. The history is kept up to date even at zero
+    /// delay, so raising it later plays real audio instead of silence.
+    pub fn process(&mut self, samples: &mut [f32], format: &AudioFormat) {
+        let channels = format.channels as usize;
+        if channels == 0 || format.sample_rate == 0 {
+            return;
+        }
+        let to_frames = |ms: u32| (format.sample_rate as u64 * ms as u64 / 1000) as usize;
+
+        if self.format.as_ref() != Some(format) {
+            // New stream format: start over, the old history can't be played anyway
+            self.history_frames = to_frames(MAX_DELAY_MS) + 1;
+            self.history = vec![0.0; self.history_frames * channels];
+            self.write_frame = 0;
+            self.delay_frames = to_frames(self.shared.ms());
+            self.crossfade = None;
+            self.format = Some(format.clone());
+        }
+
+        let target = to_frames(self.shared.ms());
+        if target != self.delay_frames && self.crossfade.is_none() {
+            self.crossfade = Some(Crossfade {
+                from_frames: self.delay_frames,
+                position: 0,
+                length: to_frames(CROSSFADE_MS).max(1),
+            });
+            self.delay_frames = target;
+        }
+
+        for frame in samples.chunks_exact_mut(channels) {
+            let write = self.write_frame * channels;
+            self.history[write..write + channels].copy_from_slice(frame);
+
+            let current = self.read_offset(self.delay_frames, channels);
+            let old = self.crossfade.as_ref().map(|fade| self.read_offset(fade.from_frames, channels));
+            match (&mut self.crossfade, old) {
+                (Some(fade), Some(old)) => {
+                    let gain = fade.position as f32 / fade.length as f32;
+                    for (ch, sample) in frame.iter_mut().enumerate() {
+                        *sample = self.history[old + ch] * (1.0 - gain) + self.history[current + ch] * gain;
+                    }
+                    fade.position += 1;
+                    if fade.position >= fade.length {
+                        self.crossfade = None;
+                    }
+                }
+                _ => frame.copy_from_slice(&self.history[current..current + channels]),
+            }
+
+            self.write_frame = (self.write_frame + 1) % self.history_frames;
+        }
+    }
+
+    /// Sample offset of the frame written `delay_frames` frames before the current one
+    fn read_offset(&self, delay_frames: usize, channels: usize) -> usize {
+        (self.write_frame + self.history_frames - delay_frames) % self.history_frames * channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+        AudioFormat {
+            sample_rate,
+            channels,
+            bits_per_sample: 32,
+            block_align: channels as u32 * 4,
+        }
+    }
+
+    #[test]
+    fn test_zero_delay_passes_through() {
+        let mut delay = DelayLine::new(SharedDelay::default());
+        let mut samples = vec![0.1, 0.2, 0.3, 0.4];
+        delay.process(&mut samples, &format(1000, 2));
+        assert_eq!(samples, vec![0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn test_delays_by_whole_frames() {
+        let shared = SharedDelay::default();
+        shared.set_ms(3).unwrap();
+        // 3 ms at 1 kHz = 3 frames
+        let mut delay = DelayLine::new(shared);
+        let mut samples: Vec<f32> = (1..=6).map(|i| i as f32).collect();
+        delay.process(&mut samples, &format(1000, 1));
+        assert_eq!(samples, vec![0.0, 0.0, 0.0, 1.0, 2.0, 3.0]);
+
+        // Carries over between blocks
+        let mut next = vec![7.0, 8.0];
+        delay.process(&mut next, &format(1000, 1));
+        assert_eq!(next, vec![4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_change_crossfades_without_jumps() {
+        let shared = SharedDelay::default();
+        let mut delay = DelayLine::new(shared.clone());
+        let fmt = format(48000, 1);
+        // 50 Hz sine: consecutive samples differ by at most ~0.0066
+        let mut phase = 0usize;
+        let mut block = || -> Vec<f32> {
+            let block: Vec<f32> = (phase..phase + 480)
+                .map(|i| (2.0 * std::f32::consts::PI * 50.0 * i as f32 / 48000.0).sin())
+                .collect();
+            phase += 480;
+            block
+        };
+
+        let mut last = 0.0f32;
+        let mut max_step = 0.0f32;
+        for i in 0..40 {
+            if i == 10 {
+                shared.set_ms(7).unwrap();
+            }
+            let mut samples = block();
+            delay.process(&mut samples, &fmt);
+            for &s in &samples {
+                max_step = max_step.max((s - last).abs());
+                last = s;
+            }
+        }
+        assert!(max_step < 0.02, "step {}", max_step);
+    }
+
+    #[test]
+    fn test_rejects_too_long_delay() {
+        let shared = SharedDelay::default();
+        assert!(shared.set_ms(MAX_DELAY_MS + 1).is_err());
+        assert_eq!(shared.ms(), 0);
+    }
+}
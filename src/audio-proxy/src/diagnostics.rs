@@ -0,0 +1,110 @@
+//! Full state snapshot for bug reports
+//!
+//! `Diagnostics` returns in one reply what `GetStatus`, `GetFormats`, `GetMetrics`,
+//! `GetRecentErrors` and the settings commands report piecemeal, plus the version and
+//! command line, so a user can attach a single JSON file to a report instead of
+//! answering a round of questions. The reply can run to tens of kilobytes, more than
+//! the pipe buffers hold; `IpcClient` reads a message in as many pieces as it takes.
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_stream::AudioFormat;
+use crate::eq::EqBand;
+use crate::ipc::RecoveryPolicyInfo;
+use crate::meter::MeterSettings;
+use crate::metrics::{HeartbeatAges, MetricsSnapshot};
+use crate::recent_errors::ErrorEntry;
+
+/// Options whose value is replaced in the reported command line
+const SECRET_OPTIONS: &[&str] = &["--ipc-token"];
+
+/// Everything `Diagnostics` reports
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// Proxy version (`CARGO_PKG_VERSION`)
+    pub version: String,
+    /// Arguments the proxy was started with, secrets redacted
+    pub command_line: Vec<String>,
+    pub running: bool,
+    pub paused: bool,
+    pub speaker: PathDiagnostics,
+    /// `None` when the mic proxy isn't configured
+    pub mic: Option<PathDiagnostics>,
+    pub settings: SettingsDiagnostics,
+    /// Counters and buffer fill of both paths
+    pub metrics: MetricsSnapshot,
+    pub heartbeat_age_ms: HeartbeatAges,
+    pub recent_errors: Vec<ErrorEntry>,
+}
+
+/// Device selection and formats of one path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathDiagnostics {
+    pub input_device: String,
+    pub output_device: String,
+    /// Not reported for the speaker path, which has no on/off switch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Formats negotiated with the devices, `None` while a stream isn't open
+    pub capture_format: Option<AudioFormat>,
+    pub render_format: Option<AudioFormat>,
+    pub converting: bool,
+    pub delay_ms: u32,
+}
+
+/// Settings that can change at runtime, as they are now
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SettingsDiagnostics {
+    pub prefill_ms: u32,
+    /// Which speaker target is playing: "a" or "b"
+    pub active_output: String,
+    pub output_device_b: Option<String>,
+    pub monitor_device: Option<String>,
+    pub eq_bands: Vec<EqBand>,
+    pub silence_threshold_db: f32,
+    /// Speaker output left/right channel swap
+    pub swap_lr: bool,
+    /// Speaker output muted by `SoloMic`
+    pub solo_mic: bool,
+    /// Processing stages engaged, as in `GetStatus`
+    pub active_features: Vec<String>,
+    pub recovery_policy: RecoveryPolicyInfo,
+    pub meter: MeterSettings,
+}
+
+/// Command line of this process for the report
+pub fn command_line() -> Vec<String> {
+    redact(std::env::args())
+}
+
+/// Replace the values of `SECRET_OPTIONS`, so a report can be shared as is
+fn redact(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut secret_next = false;
+    args.into_iter()
+        .map(|arg| {
+            let redacted = if secret_next { "<redacted>".to_string() } else { arg.clone() };
+            secret_next = SECRET_OPTIONS.contains(&arg.as_str());
+            redacted
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_redacts_secrets() {
+        let redacted = redact(args(&["audio-proxy", "--ipc-tcp", "0.0.0.0:51234", "--ipc-token", "hunter2", "--buffer", "20"]));
+        assert_eq!(
+            redacted,
+            args(&["audio-proxy", "--ipc-tcp", "0.0.0.0:51234", "--ipc-token", "<redacted>", "--buffer", "20"])
+        );
+        // A trailing option without its value leaves nothing to redact
+        assert_eq!(redact(args(&["audio-proxy", "--ipc-token"])), args(&["audio-proxy", "--ipc-token"]));
+    }
+}
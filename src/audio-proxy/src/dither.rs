@@ -0,0 +1,173 @@
+//! Dither for bit-depth reduction (`--dither`)
+//!
+//! The proxy hands f32 samples to the output, which is lossless except where the
+//! driver takes fewer bits: an ASIO driver with 16-bit samples rounds every sample to
+//! the 16-bit grid, and on quiet passages that rounding error follows the signal and
+//! is heard as distortion. Dither trades it for a steady noise floor:
+//!
+//! - `tpdf` (default): triangular noise of up to 1 LSB either way added before
+//!   rounding, which makes the error independent of the signal
+//! - `shaped`: TPDF plus second-order error feedback, which moves most of the noise
+//!   up towards Nyquist, where hearing is least sensitive
+//! - `none`: the driver's plain conversion, for bit-perfect paths
+
+use anyhow::{anyhow, Result};
+
+/// Dither applied when the output has fewer bits than the f32 samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DitherMode {
+    None,
+    #[default]
+    Tpdf,
+    Shaped,
+}
+
+impl DitherMode {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DitherMode::None),
+            "tpdf" => Ok(DitherMode::Tpdf),
+            "shaped" => Ok(DitherMode::Shaped),
+            _ => Err(anyhow!("Unknown dither: {} (expected none, tpdf or shaped)", s)),
+        }
+    }
+}
+
+/// Quantizes interleaved f32 samples to an integer grid, dithered
+pub struct Ditherer {
+    mode: DitherMode,
+    /// Full scale in steps of the target grid (2^(bits - 1))
+    scale: f32,
+    channels: usize,
+    /// Channel of the next sample, so blocks can end mid-frame
+    channel: usize,
+    /// Last two quantization errors of each channel in LSBs, for noise shaping
+    errors: Vec<[f32; 2]>,
+    rng: u32,
+}
+
+impl Ditherer {
+    pub fn new(mode: DitherMode, bits: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        Self {
+            mode,
+            scale: (1u64 << (bits.clamp(2, 32) - 1)) as f32,
+            channels,
+            channel: 0,
+            errors: vec![[0.0; 2]; channels],
+            rng: 0x9E37_79B9,
+        }
+    }
+
+    /// Round `samples` in place to the grid, so the integer conversion after it is
+    /// exact. `DitherMode::None` leaves them untouched.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.mode == DitherMode::None {
+            return;
+        }
+        let (min, max) = (-self.scale, self.scale - 1.0);
+        for sample in samples {
+            let x = *sample * self.scale;
+            let errors = self.errors[self.channel];
+            // Feeding back the last errors puts the noise through (1 - z^-1)^2
+            let target = match self.mode {
+                DitherMode::Shaped => x - 2.0 * errors[0] + errors[1],
+                _ => x,
+            };
+            let q = (target + self.tpdf()).round().clamp(min, max);
+            if self.mode == DitherMode::Shaped {
+                // Bounded so a clipped sample can't wind the feedback up
+                self.errors[self.channel] = [(q - target).clamp(-2.0, 2.0), errors[0]];
+            }
+            *sample = q / self.scale;
+            self.channel = (self.channel + 1) % self.channels;
+        }
+    }
+
+    /// Triangular noise in (-1, 1) LSB: the sum of two uniform values
+    fn tpdf(&mut self) -> f32 {
+        (self.next_uniform() + self.next_uniform()) * 0.5
+    }
+
+    /// Uniform value in [-1, 1) from a xorshift32 generator
+    fn next_uniform(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB: f32 = 1.0 / 32768.0;
+
+    /// Quantization error of each sample of a fixed input, in LSBs
+    fn errors(mode: DitherMode, input: f32, len: usize) -> Vec<f32> {
+        let mut ditherer = Ditherer::new(mode, 16, 1);
+        let mut samples = vec![input; len];
+        ditherer.process(&mut samples);
+        samples.iter().map(|s| (s - input) / LSB).collect()
+    }
+
+    #[test]
+    fn test_tpdf_statistics() {
+        // A quarter LSB, which plain rounding would always lose
+        let input = 0.25 * LSB;
+        let errors = errors(DitherMode::Tpdf, input, 200_000);
+
+        // On the grid, never more than 1.5 LSB off
+        let mut ditherer = Ditherer::new(DitherMode::Tpdf, 16, 1);
+        let mut samples = vec![input; 1000];
+        ditherer.process(&mut samples);
+        assert!(samples.iter().all(|s| (s * 32768.0).fract() == 0.0));
+        assert!(errors.iter().all(|e| e.abs() <= 1.5));
+
+        // Unbiased, so the quarter LSB survives on average, with the error power of TPDF
+        // plus rounding: 1/6 + 1/12 = 1/4 LSB²
+        let n = errors.len() as f32;
+        let mean = errors.iter().sum::<f32>() / n;
+        let variance = errors.iter().map(|e| (e - mean) * (e - mean)).sum::<f32>() / n;
+        assert!(mean.abs() < 0.01, "mean {}", mean);
+        assert!((variance - 0.25).abs() < 0.01, "variance {}", variance);
+
+        // Uncorrelated from one sample to the next, i.e. white
+        let lag1 = errors.windows(2).map(|w| (w[0] - mean) * (w[1] - mean)).sum::<f32>() / n;
+        assert!(lag1.abs() < 0.01, "lag-1 covariance {}", lag1);
+    }
+
+    #[test]
+    fn test_shaped_moves_noise_up() {
+        // Low-frequency noise power: the power of 64-sample averages of the error
+        let low_band = |errors: &[f32]| {
+            let means: Vec<f32> = errors.chunks_exact(64).map(|c| c.iter().sum::<f32>() / 64.0).collect();
+            means.iter().map(|m| m * m).sum::<f32>() / means.len() as f32
+        };
+        let tpdf = low_band(&errors(DitherMode::Tpdf, 0.25 * LSB, 64_000));
+        let shaped = low_band(&errors(DitherMode::Shaped, 0.25 * LSB, 64_000));
+        assert!(shaped < tpdf / 8.0, "shaped {} tpdf {}", shaped, tpdf);
+    }
+
+    #[test]
+    fn test_none_and_clipping() {
+        let mut samples = vec![0.123_456, -0.5];
+        Ditherer::new(DitherMode::None, 16, 2).process(&mut samples);
+        assert_eq!(samples, vec![0.123_456, -0.5]);
+
+        // Full scale stays within the integer range
+        let mut samples = vec![1.0, -1.0, 1.5, -1.5];
+        Ditherer::new(DitherMode::Shaped, 16, 2).process(&mut samples);
+        assert!(samples.iter().all(|s| (-1.0..=1.0 - LSB).contains(s)), "{:?}", samples);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(DitherMode::parse("TPDF").unwrap(), DitherMode::Tpdf);
+        assert_eq!(DitherMode::parse("shaped").unwrap(), DitherMode::Shaped);
+        assert_eq!(DitherMode::parse("none").unwrap(), DitherMode::None);
+        assert_eq!(DitherMode::default(), DitherMode::Tpdf);
+        assert!(DitherMode::parse("triangular").is_err());
+    }
+}
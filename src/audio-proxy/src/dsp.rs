@@ -0,0 +1,280 @@
+//! Small DSP building blocks shared by the processing stages
+//!
+//! `GainRamp` smooths gain changes: jumping straight to a new gain mid-block causes
+//! audible zipper noise and clicks, so every stage that changes level at runtime
+//! (monitor level, mute, ...) runs its audio through one instead of multiplying by
+//! the new gain directly. `GainCeiling` caps the combined gain of a path's stages.
+//! `flush_denormal` keeps recursive (IIR) filter state out of the subnormal range.
+
+use log::{info, warn};
+
+/// Default `--max-output-db`
+pub const DEFAULT_MAX_OUTPUT_DB: f32 = 12.0;
+
+/// How fast `GainCeiling` lets go once the gain is back under the cap
+const CEILING_RELEASE_MS: f32 = 10.0;
+
+/// Linear gain for a level in dB (`-inf` is silence)
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Once the gain is this close to the target it snaps to it and the ramp stops
+const SETTLE_EPSILON: f32 = 1e-6;
+
+/// Offset `flush_denormal` adds and subtracts again, far below anything audible
+const DENORMAL_GUARD: f64 = 1e-20;
+
+/// Round values that have decayed far below audibility to exactly zero.
+///
+/// Once its input goes quiet, an IIR filter's state decays exponentially and ends up
+/// in the subnormal float range, where many x86 CPUs are around 100 times slower. An
+/// EQ idling on a silent input could then starve the render thread. Adding and
+/// subtracting `DENORMAL_GUARD` leaves audio-range values unchanged but rounds
+/// anything below about 1e-36 to zero (or to a multiple of it, never subnormal). This is deliberate, not a no-op: Rust never
+/// reassociates float math, so the pair is not folded away, and it must stay on every
+/// recursive state update.
+#[inline]
+pub fn flush_denormal(x: f64) -> f64 {
+    (x + DENORMAL_GUARD) - DENORMAL_GUARD
+}
+
+/// Per-sample smoothed gain. The gain moves toward the target exponentially with the
+/// configured time constant (about 99% of the way after five of them), so it never
+/// overshoots, and carries over between blocks.
+#[derive(Debug, Clone)]
+pub struct GainRamp {
+    current: f32,
+    target: f32,
+    time_constant_ms: f32,
+    /// Per-frame smoothing coefficient for the current sample rate
+    coeff: f32,
+}
+
+impl GainRamp {
+    /// A ramp settled at `db`
+    pub fn new(db: f32, time_constant_ms: f32, sample_rate: u32) -> Self {
+        let gain = db_to_gain(db);
+        let mut ramp = Self {
+            current: gain,
+            target: gain,
+            time_constant_ms,
+            coeff: 0.0,
+        };
+        ramp.set_sample_rate(sample_rate);
+        ramp
+    }
+
+    /// Ramp toward `db` from wherever the gain is now; `f32::NEG_INFINITY` mutes
+    pub fn set_target(&mut self, db: f32) {
+        self.target = db_to_gain(db);
+    }
+
+    /// Jump to `db` without ramping, e.g. for a stream that hasn't played yet
+    pub fn reset(&mut self, db: f32) {
+        self.current = db_to_gain(db);
+        self.target = self.current;
+    }
+
+    /// Recompute the smoothing for a new stream format
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let frames = self.time_constant_ms / 1000.0 * sample_rate as f32;
+        self.coeff = if frames > 0.0 { (-1.0 / frames).exp() } else { 0.0 };
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+
+    /// Apply the gain to interleaved samples in place, advancing the ramp once per frame
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        if channels == 0 {
+            return;
+        }
+        if self.is_settled() {
+            if self.current != 1.0 {
+                samples.iter_mut().for_each(|s| *s *= self.current);
+            }
+            return;
+        }
+
+        for frame in samples.chunks_exact_mut(channels) {
+            let next = self.target + (self.current - self.target) * self.coeff;
+            // Near unity the step can round away entirely before reaching the epsilon
+            if (next - self.target).abs() < SETTLE_EPSILON || next == self.current {
+                self.current = self.target;
+            } else {
+                self.current = next;
+            }
+            for sample in frame.iter_mut() {
+                *sample *= self.current;
+            }
+        }
+    }
+}
+
+/// Hard cap on the combined gain of a path's gain stages (`--max-output-db`), so a
+/// misconfigured gain can't play at full blast. It's a policy on the settings, not a
+/// limiter: it never looks at the audio, it takes whatever the stages add up to
+/// beyond the cap off the whole signal.
+pub struct GainCeiling {
+    path: &'static str,
+    max_db: f32,
+    ramp: GainRamp,
+    /// Combined gain of the stages as of the last `update`
+    applied_db: f32,
+}
+
+impl GainCeiling {
+    pub fn new(path: &'static str, max_db: f32, sample_rate: u32) -> Self {
+        Self {
+            path,
+            max_db,
+            ramp: GainRamp::new(0.0, CEILING_RELEASE_MS, sample_rate),
+            applied_db: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.ramp.set_sample_rate(sample_rate);
+    }
+
+    /// Set the gain in dB the path's stages add up to now. Tightening the cap takes
+    /// effect at once; loosening it ramps.
+    pub fn update(&mut self, applied_db: f32) {
+        if applied_db == self.applied_db {
+            return;
+        }
+        let was_capped = self.is_capped();
+        self.applied_db = applied_db;
+        if self.is_capped() {
+            warn!(
+                "{} gain stages add up to {:+.1} dB, capped at {:+.1} dB (--max-output-db)",
+                self.path, applied_db, self.max_db
+            );
+            self.ramp.reset(self.max_db - applied_db);
+        } else {
+            if was_capped {
+                info!("{} gain back under --max-output-db ({:+.1} dB)", self.path, applied_db);
+            }
+            self.ramp.set_target(0.0);
+        }
+    }
+
+    pub fn is_capped(&self) -> bool {
+        self.applied_db > self.max_db
+    }
+
+    /// Apply the cap to interleaved samples in place
+    pub fn process(&mut self, samples: &mut [f32], channels: usize) {
+        self.ramp.process(samples, channels);
+    }
+}
+
+/// Check a `--max-output-db` cap
+pub fn validate_max_output_db(db: f32) -> anyhow::Result<()> {
+    if !db.is_finite() {
+        anyhow::bail!("--max-output-db must be a level in dB: {}", db);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(ramp: &mut GainRamp, frames: usize) -> Vec<f32> {
+        let mut samples = vec![1.0f32; frames];
+        ramp.process(&mut samples, 1);
+        samples
+    }
+
+    #[test]
+    fn test_ramp_is_monotonic() {
+        let mut ramp = GainRamp::new(f32::NEG_INFINITY, 5.0, 48000);
+        ramp.set_target(0.0);
+        let up = run(&mut ramp, 4800);
+        assert!(up.windows(2).all(|w| w[1] >= w[0]));
+        assert!(up[0] > 0.0 && up[4799] <= 1.0);
+
+        ramp.set_target(-20.0);
+        let down = run(&mut ramp, 4800);
+        assert!(down.windows(2).all(|w| w[1] <= w[0]));
+        assert!(down[4799] >= 0.1);
+    }
+
+    #[test]
+    fn test_settles_within_five_time_constants() {
+        // 10 ms at 48 kHz = 480 frames per time constant
+        let mut ramp = GainRamp::new(0.0, 10.0, 48000);
+        ramp.set_target(f32::NEG_INFINITY);
+        let out = run(&mut ramp, 5 * 480);
+        assert!(out[479] > 0.3 && out[479] < 0.4, "{}", out[479]);
+        assert!(out[5 * 480 - 1] < 0.01);
+
+        run(&mut ramp, 48000);
+        assert!(ramp.is_settled());
+        assert_eq!(run(&mut ramp, 4), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_state_carries_across_blocks() {
+        let mut whole = GainRamp::new(-40.0, 2.0, 48000);
+        let mut split = whole.clone();
+        whole.set_target(0.0);
+        split.set_target(0.0);
+
+        let expected = run(&mut whole, 300);
+        let mut got = run(&mut split, 100);
+        got.extend(run(&mut split, 200));
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_flush_denormal() {
+        assert_eq!(flush_denormal(1e-310), 0.0);
+        assert_eq!(flush_denormal(-1e-40), 0.0);
+        assert_eq!(flush_denormal(0.5), 0.5);
+        assert_eq!(flush_denormal(-1e-3), -1e-3);
+    }
+
+    #[test]
+    fn test_ceiling_caps_combined_gain() {
+        let mut ceiling = GainCeiling::new("Test", 12.0, 48000);
+        ceiling.update(6.0);
+        assert!(!ceiling.is_capped());
+        let mut samples = vec![0.5f32; 8];
+        ceiling.process(&mut samples, 2);
+        assert_eq!(samples, vec![0.5; 8]);
+
+        // 18 dB of stages against a 12 dB cap: 6 dB off straight away
+        ceiling.update(18.0);
+        assert!(ceiling.is_capped());
+        let mut samples = vec![1.0f32; 8];
+        ceiling.process(&mut samples, 2);
+        assert!(samples.iter().all(|&s| (s - db_to_gain(-6.0)).abs() < 1e-6));
+
+        // Back under the cap, the gain returns to unity
+        ceiling.update(0.0);
+        assert!(!ceiling.is_capped());
+        let mut samples = vec![1.0f32; 48000];
+        ceiling.process(&mut samples, 1);
+        assert_eq!(samples[47999], 1.0);
+    }
+
+    #[test]
+    fn test_validate_max_output_db() {
+        assert!(validate_max_output_db(DEFAULT_MAX_OUTPUT_DB).is_ok());
+        assert!(validate_max_output_db(-6.0).is_ok());
+        assert!(validate_max_output_db(f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_frames_share_one_gain() {
+        let mut ramp = GainRamp::new(-12.0, 1.0, 48000);
+        ramp.set_target(0.0);
+        let mut samples = vec![1.0f32; 64];
+        ramp.process(&mut samples, 2);
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+}
@@ -0,0 +1,338 @@
+//! Parametric EQ for the speaker output
+//!
+//! Bands are set over IPC and published through `SharedEq`; the render thread's
+//! `Equalizer` notices the version bump, recomputes the biquad coefficients
+//! (RBJ audio EQ cookbook) and applies one filter per band per channel. Filter
+//! state carries across blocks and is only reset when the bands or the render
+//! format change. With no active bands the stage is skipped entirely. The filter
+//! state is flushed of denormals on every update (see `dsp::flush_denormal`).
+
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::audio_stream::AudioFormat;
+use crate::dsp::flush_denormal;
+
+/// Largest boost or cut accepted for a single band
+const MAX_GAIN_DB: f32 = 24.0;
+
+/// Filter shape of an EQ band
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EqBandType {
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+/// One band of the parametric EQ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EqBand {
+    #[serde(rename = "type")]
+    pub band_type: EqBandType,
+    /// Center (peak) or corner (shelf) frequency in Hz
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl EqBand {
+    /// Check that the band parameters are usable
+    pub fn validate(&self) -> Result<()> {
+        if !self.frequency.is_finite() || self.frequency <= 0.0 {
+            return Err(anyhow!("Invalid EQ frequency: {}", self.frequency));
+        }
+        if !self.q.is_finite() || self.q <= 0.0 {
+            return Err(anyhow!("Invalid EQ Q: {}", self.q));
+        }
+        if !self.gain_db.is_finite() || self.gain_db.abs() > MAX_GAIN_DB {
+            return Err(anyhow!("Invalid EQ gain: {} dB (limit is +/-{} dB)", self.gain_db, MAX_GAIN_DB));
+        }
+        Ok(())
+    }
+}
+
+/// EQ bands shared between the IPC thread and the render thread
+#[derive(Default)]
+pub struct SharedEq {
+    bands: RwLock<Vec<EqBand>>,
+    version: AtomicU64,
+}
+
+impl SharedEq {
+    /// Replace the band list (an empty list means flat)
+    pub fn set(&self, bands: Vec<EqBand>) {
+        *self.bands.write().unwrap() = bands;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Copy of the current bands
+    pub fn bands(&self) -> Vec<EqBand> {
+        self.bands.read().unwrap().clone()
+    }
+}
+
+/// Normalized biquad coefficients (a0 == 1)
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn new(band: &EqBand, sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        // Keep the frequency below Nyquist so a band set for 48 kHz still works at 44.1 kHz
+        let freq = (band.frequency as f64).min(rate * 0.49);
+        let a = 10f64.powf(band.gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * freq / rate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * band.q as f64);
+
+        let (b0, b1, b2, a0, a1, a2) = match band.band_type {
+            EqBandType::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            EqBandType::LowShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+            EqBandType::HighShelf => {
+                let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha,
+                )
+            }
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// Transposed direct form II biquad state for one channel
+#[derive(Debug, Clone, Copy, Default)]
+struct BiquadState {
+    z1: f64,
+    z2: f64,
+}
+
+impl BiquadState {
+    #[inline]
+    fn process(&mut self, c: &Coefficients, x: f64) -> f64 {
+        let y = c.b0 * x + self.z1;
+        // Decay tails would otherwise end up subnormal and slow every sample down
+        self.z1 = flush_denormal(c.b1 * x - c.a1 * y + self.z2);
+        self.z2 = flush_denormal(c.b2 * x - c.a2 * y);
+        y
+    }
+}
+
+/// Render-thread side of the EQ: coefficients plus per-channel filter state
+#[derive(Default)]
+pub struct Equalizer {
+    version: u64,
+    bands: Vec<EqBand>,
+    sample_rate: u32,
+    channels: usize,
+    coefficients: Vec<Coefficients>,
+    /// One state per band per channel, band-major
+    states: Vec<BiquadState>,
+}
+
+impl Equalizer {
+    /// Pick up band changes published through `shared`
+    pub fn sync(&mut self, shared: &SharedEq) {
+        let version = shared.version.load(Ordering::Acquire);
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+        // Bands at 0 dB are exactly flat, so leave them out of the chain
+        self.bands = shared.bands.read().unwrap()
+            .iter()
+            .filter(|b| b.gain_db != 0.0)
+            .cloned()
+            .collect();
+        self.sample_rate = 0; // force a rebuild
+    }
+
+    /// Most the bands can raise any frequency by, in dB. Cascaded filters multiply,
+    /// so overlapping boosts stack: the sum of the boosts bounds it.
+    pub fn max_boost_db(&self) -> f32 {
+        self.bands.iter().map(|b| b.gain_db.max(0.0)).sum()
+    }
+
+    /// Filter interleaved samples in place
+    pub fn process(&mut self, samples: &mut [f32], format: &AudioFormat) {
+        if self.bands.is_empty() || format.channels == 0 {
+            return;
+        }
+
+        let channels = format.channels as usize;
+        if format.sample_rate != self.sample_rate || channels != self.channels {
+            self.rebuild(format.sample_rate, channels);
+        }
+
+        for frame in samples.chunks_exact_mut(channels) {
+            for (ch, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample as f64;
+                for (band, c) in self.coefficients.iter().enumerate() {
+                    x = self.states[band * channels + ch].process(c, x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+
+    fn rebuild(&mut self, sample_rate: u32, channels: usize) {
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        self.coefficients = self.bands.iter().map(|b| Coefficients::new(b, sample_rate)).collect();
+        self.states = vec![BiquadState::default(); self.bands.len() * channels];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+        AudioFormat {
+            sample_rate,
+            channels,
+            bits_per_sample: 32,
+            block_align: channels as u32 * 4,
+        }
+    }
+
+    fn test_signal(len: usize) -> Vec<f32> {
+        (0..len).map(|i| ((i as f32 * 0.37).sin() * 0.8) + (i % 7) as f32 * 1e-7).collect()
+    }
+
+    fn band(band_type: EqBandType, frequency: f32, gain_db: f32) -> EqBand {
+        EqBand { band_type, frequency, gain_db, q: 0.707 }
+    }
+
+    #[test]
+    fn test_flat_eq_is_bit_exact() {
+        let shared = SharedEq::default();
+        let mut eq = Equalizer::default();
+        let input = test_signal(4800);
+
+        // Default: no bands
+        let mut output = input.clone();
+        eq.sync(&shared);
+        eq.process(&mut output, &format(48000, 2));
+        assert_eq!(output, input);
+
+        // Bands at 0 dB are also flat
+        shared.set(vec![
+            band(EqBandType::Peak, 1000.0, 0.0),
+            band(EqBandType::LowShelf, 100.0, 0.0),
+            band(EqBandType::HighShelf, 8000.0, 0.0),
+        ]);
+        eq.sync(&shared);
+        assert!(eq.bands.is_empty());
+        let mut output = input.clone();
+        eq.process(&mut output, &format(48000, 2));
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_peak_boosts_center_frequency() {
+        let shared = SharedEq::default();
+        shared.set(vec![EqBand { band_type: EqBandType::Peak, frequency: 1000.0, gain_db: 6.0, q: 1.0 }]);
+        let mut eq = Equalizer::default();
+        eq.sync(&shared);
+
+        let rate = 48000.0;
+        let mut samples: Vec<f32> = (0..48000)
+            .map(|i| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / rate).sin() * 0.25)
+            .collect();
+        // Process in blocks to exercise state carried across calls
+        for block in samples.chunks_mut(480) {
+            eq.process(block, &format(48000, 1));
+        }
+
+        let peak = samples[24000..].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        let expected = 0.25 * 10f32.powf(6.0 / 20.0);
+        assert!((peak - expected).abs() < 0.01, "peak {} expected {}", peak, expected);
+    }
+
+    #[test]
+    fn test_max_boost_sums_boosts() {
+        let shared = SharedEq::default();
+        shared.set(vec![
+            band(EqBandType::LowShelf, 100.0, 6.0),
+            band(EqBandType::Peak, 120.0, 4.5),
+            band(EqBandType::HighShelf, 8000.0, -10.0),
+        ]);
+        let mut eq = Equalizer::default();
+        assert_eq!(eq.max_boost_db(), 0.0);
+        eq.sync(&shared);
+        assert_eq!(eq.max_boost_db(), 10.5);
+    }
+
+    #[test]
+    fn test_decay_tail_never_goes_subnormal() {
+        let shared = SharedEq::default();
+        shared.set(vec![band(EqBandType::Peak, 1000.0, 6.0)]);
+        let mut eq = Equalizer::default();
+        eq.sync(&shared);
+        let fmt = format(48000, 1);
+
+        // Unflushed, this tail passes through the subnormal range after ~11000 samples
+        let mut impulse = [1.0f32];
+        eq.process(&mut impulse, &fmt);
+        let mut silence = [0.0f32];
+        for _ in 0..20000 {
+            silence[0] = 0.0;
+            eq.process(&mut silence, &fmt);
+            let s = eq.states[0];
+            assert!(!s.z1.is_subnormal() && !s.z2.is_subnormal(), "{:?}", s);
+        }
+        // What's left is far below audibility
+        assert!(silence[0].abs() < 1e-30);
+    }
+
+    #[test]
+    fn test_band_json_shape() {
+        let json = r#"{"type":"low_shelf","frequency":120.0,"gain_db":-3.0,"q":0.7}"#;
+        let parsed: EqBand = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.band_type, EqBandType::LowShelf);
+        assert!(parsed.validate().is_ok());
+
+        let bad = EqBand { q: 0.0, ..parsed };
+        assert!(bad.validate().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Fade-in for freshly opened render streams
+//!
+//! After the prefilled silence the first real audio usually starts mid-waveform,
+//! which clicks. The render loops run their first block of audio after every
+//! (re)open through `FadeIn`, ramping the gain from 0 to 1 over a few milliseconds.
+
+use crate::audio_stream::AudioFormat;
+
+/// Linear gain ramp applied to the start of the audio written to a new stream
+pub struct FadeIn {
+    duration_ms: u32,
+    /// Ramp length in frames, worked out from the render format on first use
+    length: usize,
+    /// Frames already faded
+    position: usize,
+    active: bool,
+}
+
+impl FadeIn {
+    /// Create a fade that is armed for the stream that was just started
+    /// (0 ms disables fading)
+    pub fn new(duration_ms: u32) -> Self {
+        let mut fade = Self {
+            duration_ms,
+            length: 0,
+            position: 0,
+            active: false,
+        };
+        fade.restart();
+        fade
+    }
+
+    /// Arm the fade again after the stream was reopened
+    pub fn restart(&mut self) {
+        self.length = 0;
+        self.position = 0;
+        self.active = self.duration_ms > 0;
+    }
+
+    /// Apply the ramp to interleaved samples in place; a no-op once it has finished
+    pub fn apply(&mut self, samples: &mut [f32], format: &AudioFormat) {
+        if !self.active || format.channels == 0 {
+            return;
+        }
+        if self.length == 0 {
+            self.length = (format.sample_rate as u64 * self.duration_ms as u64 / 1000).max(1) as usize;
+        }
+
+        for frame in samples.chunks_exact_mut(format.channels as usize) {
+            if self.position >= self.length {
+                self.active = false;
+                return;
+            }
+            let gain = self.position as f32 / self.length as f32;
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+            self.position += 1;
+        }
+        if self.position >= self.length {
+            self.active = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+        AudioFormat {
+            sample_rate,
+            channels,
+            bits_per_sample: 32,
+            block_align: channels as u32 * 4,
+        }
+    }
+
+    #[test]
+    fn test_ramps_across_blocks_then_passes_through() {
+        // 10 ms at 1 kHz = 10 frames
+        let mut fade = FadeIn::new(10);
+        let fmt = format(1000, 2);
+
+        let mut first = vec![1.0f32; 8];
+        fade.apply(&mut first, &fmt);
+        assert_eq!(first, vec![0.0, 0.0, 0.1, 0.1, 0.2, 0.2, 0.3, 0.3]);
+
+        let mut rest = vec![1.0f32; 20];
+        fade.apply(&mut rest, &fmt);
+        assert!((rest[0] - 0.4).abs() < 1e-6);
+        assert!((rest[11] - 0.9).abs() < 1e-6);
+        assert!(rest[12..].iter().all(|&s| s == 1.0));
+
+        let mut after = vec![1.0f32; 8];
+        fade.apply(&mut after, &fmt);
+        assert!(after.iter().all(|&s| s == 1.0));
+
+        // Reopening the stream fades again
+        fade.restart();
+        let mut again = vec![1.0f32; 2];
+        fade.apply(&mut again, &fmt);
+        assert_eq!(again, vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_zero_duration_is_disabled() {
+        let mut fade = FadeIn::new(0);
+        let mut samples = vec![0.5f32; 16];
+        fade.apply(&mut samples, &format(48000, 2));
+        assert!(samples.iter().all(|&s| s == 0.5));
+    }
+}
@@ -0,0 +1,90 @@
+//! Forced catch-up when the ring buffer stays too full (`--max-fill-ms`)
+//!
+//! After a stretch of overflow (a capture clock running fast, a render device that
+//! stalled and came back) the ring buffer can sit near full, and that latency never
+//! drains by itself: the render loop only reads what the device takes. With
+//! `--max-fill-ms`, a fill that stays above the limit for `SUSTAIN` makes the render
+//! loop skip the oldest audio down to the prefill, trading one audible jump for the
+//! latency it was configured for. Short spikes, which play out on their own, are left
+//! alone. Skips are counted in the `forced_skips` metric.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audio_stream::AudioFormat;
+
+/// How long the fill has to stay above the limit before it's cut back
+pub const SUSTAIN: Duration = Duration::from_millis(500);
+
+/// Tracks how long the ring buffer has been over the limit
+pub struct FillLimit {
+    max_fill_ms: u32,
+    /// Fill a skip leaves behind
+    target_ms: u32,
+    over_since: Option<Instant>,
+}
+
+impl FillLimit {
+    pub fn new(max_fill_ms: u32, target_ms: u32) -> Self {
+        Self { max_fill_ms, target_ms, over_since: None }
+    }
+
+    /// Samples to skip now, given the ring buffer fill in samples of `format`: 0 unless
+    /// it has been over the limit for `SUSTAIN`, else whole frames down to the target
+    pub fn check(&mut self, now: Instant, buffered_samples: usize, format: &AudioFormat) -> usize {
+        let channels = format.channels as usize;
+        let samples_for = |ms: u32| (format.sample_rate as u64 * ms as u64 / 1000) as usize * channels;
+        if channels == 0 || buffered_samples <= samples_for(self.max_fill_ms) {
+            self.over_since = None;
+            return 0;
+        }
+        let since = *self.over_since.get_or_insert(now);
+        if now.duration_since(since) < SUSTAIN {
+            return 0;
+        }
+        self.over_since = None;
+        buffered_samples - samples_for(self.target_ms).min(buffered_samples)
+    }
+}
+
+/// Check a `--max-fill-ms` limit against the prefill it skips back to
+pub fn validate_max_fill_ms(max_fill_ms: u32, prefill_ms: u32) -> Result<()> {
+    if max_fill_ms <= prefill_ms {
+        anyhow::bail!("--max-fill-ms must be above the prefill ({} ms): {} ms", prefill_ms, max_fill_ms);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format() -> AudioFormat {
+        AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 }
+    }
+
+    #[test]
+    fn test_skips_only_sustained_overfill() {
+        let mut limit = FillLimit::new(100, 20);
+        let now = Instant::now();
+        // 150 ms buffered, over the 100 ms limit
+        let buffered = 7200 * 2;
+        assert_eq!(limit.check(now, buffered, &format()), 0);
+        assert_eq!(limit.check(now + SUSTAIN / 2, buffered, &format()), 0);
+
+        // A dip below the limit starts the wait over
+        assert_eq!(limit.check(now + SUSTAIN / 2, 960 * 2, &format()), 0);
+        assert_eq!(limit.check(now + SUSTAIN, buffered, &format()), 0);
+
+        // Back down to the 20 ms target, in whole frames
+        assert_eq!(limit.check(now + SUSTAIN * 2, buffered, &format()), (7200 - 960) * 2);
+        assert_eq!(limit.check(now + SUSTAIN * 2, 960 * 2, &format()), 0);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate_max_fill_ms(200, 20).is_ok());
+        assert!(validate_max_fill_ms(20, 20).is_err());
+    }
+}
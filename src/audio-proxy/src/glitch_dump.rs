@@ -0,0 +1,237 @@
+//! Rolling capture history that is written to a WAV file when a glitch is detected
+//!
+//! The capture loop pushes every block it reads into a fixed-size circular history.
+//! When an overflow, underrun or discontinuity is reported, a dump is scheduled for
+//! half a history window later so the file contains audio from both before and after
+//! the glitch. Writing happens on a short-lived thread to keep disk I/O off the
+//! audio path.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::audio_stream::AudioFormat;
+use crate::wav;
+
+/// Minimum time between two dumps, so a sustained problem doesn't flood the disk
+const MIN_DUMP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Kind of glitch that triggered a dump (used in the file name)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlitchKind {
+    Overflow,
+    Underrun,
+    Discontinuity,
+}
+
+impl GlitchKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GlitchKind::Overflow => "overflow",
+            GlitchKind::Underrun => "underrun",
+            GlitchKind::Discontinuity => "discontinuity",
+        }
+    }
+}
+
+/// Circular f32 history of the most recent captured samples
+pub struct SampleHistory {
+    samples: Vec<f32>,
+    pos: usize,
+    filled: bool,
+}
+
+impl SampleHistory {
+    /// Create a history holding `capacity` interleaved samples
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: vec![0.0; capacity],
+            pos: 0,
+            filled: false,
+        }
+    }
+
+    /// Append samples, overwriting the oldest ones once full
+    pub fn push(&mut self, input: &[f32]) {
+        let capacity = self.samples.len();
+        if capacity == 0 {
+            return;
+        }
+
+        // Only the last `capacity` samples of a large block can survive
+        let input = &input[input.len().saturating_sub(capacity)..];
+        for &sample in input {
+            self.samples[self.pos] = sample;
+            self.pos += 1;
+            if self.pos == capacity {
+                self.pos = 0;
+                self.filled = true;
+            }
+        }
+    }
+
+    /// Copy the history out in chronological order (oldest first)
+    pub fn snapshot(&self) -> Vec<f32> {
+        if self.filled {
+            let mut out = Vec::with_capacity(self.samples.len());
+            out.extend_from_slice(&self.samples[self.pos..]);
+            out.extend_from_slice(&self.samples[..self.pos]);
+            out
+        } else {
+            self.samples[..self.pos].to_vec()
+        }
+    }
+
+    /// Number of valid samples currently held
+    pub fn len(&self) -> usize {
+        if self.filled { self.samples.len() } else { self.pos }
+    }
+
+    /// Whether the history holds no samples
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Glitch-triggered WAV dumper owned by the speaker capture loop
+pub struct GlitchDumper {
+    dir: PathBuf,
+    history_secs: u32,
+    history: SampleHistory,
+    format: Option<AudioFormat>,
+    pending: Option<(GlitchKind, Instant)>,
+    last_dump: Option<Instant>,
+    underrun_signal: Arc<AtomicBool>,
+}
+
+impl GlitchDumper {
+    /// Create a dumper writing files into `dir`, keeping `history_secs` of audio
+    pub fn new(dir: PathBuf, history_secs: u32) -> Self {
+        Self {
+            dir,
+            history_secs,
+            history: SampleHistory::new(0),
+            format: None,
+            pending: None,
+            last_dump: None,
+            underrun_signal: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag the render thread sets when the device starves; picked up by `poll`
+    pub fn underrun_signal(&self) -> Arc<AtomicBool> {
+        self.underrun_signal.clone()
+    }
+
+    /// Set the capture format; resets the history if the format changed
+    pub fn set_format(&mut self, format: &AudioFormat) {
+        let unchanged = self.format.as_ref().is_some_and(|f| {
+            f.sample_rate == format.sample_rate && f.channels == format.channels
+        });
+        if unchanged {
+            return;
+        }
+
+        let capacity = (format.sample_rate * self.history_secs) as usize * format.channels as usize;
+        self.history = SampleHistory::new(capacity);
+        self.format = Some(format.clone());
+    }
+
+    /// Record a captured block
+    pub fn push(&mut self, samples: &[f32]) {
+        self.history.push(samples);
+    }
+
+    /// Report a glitch; a dump is scheduled unless one is already pending or was
+    /// written too recently
+    pub fn trigger(&mut self, kind: GlitchKind) {
+        if self.pending.is_some() {
+            return;
+        }
+        if let Some(last) = self.last_dump {
+            if last.elapsed() < MIN_DUMP_INTERVAL {
+                return;
+            }
+        }
+
+        let delay = Duration::from_millis(self.history_secs as u64 * 500);
+        info!("Glitch detected ({}), dumping capture history in {:?}", kind.as_str(), delay);
+        self.pending = Some((kind, Instant::now() + delay));
+    }
+
+    /// Check for render-side underruns and write the pending dump once its
+    /// post-glitch window has elapsed
+    pub fn poll(&mut self) {
+        if self.underrun_signal.swap(false, Ordering::Relaxed) {
+            self.trigger(GlitchKind::Underrun);
+        }
+
+        let (kind, due) = match self.pending {
+            Some(p) => p,
+            None => return,
+        };
+        if Instant::now() < due {
+            return;
+        }
+        self.pending = None;
+        self.last_dump = Some(Instant::now());
+
+        let format = match self.format {
+            Some(ref f) => f.clone(),
+            None => return,
+        };
+        if self.history.is_empty() {
+            return;
+        }
+
+        let samples = self.history.snapshot();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path = self.dir.join(format!("glitch-{}-{}.wav", timestamp, kind.as_str()));
+
+        let spawned = thread::Builder::new().name("glitch-dump".into()).spawn(move || {
+            match wav::write_wav_f32(&path, &samples, format.sample_rate, format.channels) {
+                Ok(()) => info!("Wrote glitch dump: {}", path.display()),
+                Err(e) => error!("Failed to write glitch dump: {}", e),
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Failed to spawn glitch dump writer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_partial() {
+        let mut history = SampleHistory::new(8);
+        history.push(&[1.0, 2.0, 3.0]);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.snapshot(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_history_wraps_in_order() {
+        let mut history = SampleHistory::new(4);
+        history.push(&[1.0, 2.0, 3.0]);
+        history.push(&[4.0, 5.0, 6.0]);
+        assert_eq!(history.len(), 4);
+        assert_eq!(history.snapshot(), vec![3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_history_oversized_block() {
+        let mut history = SampleHistory::new(3);
+        history.push(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(history.snapshot(), vec![3.0, 4.0, 5.0]);
+    }
+}
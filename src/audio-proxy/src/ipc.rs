@@ -1,54 +1,384 @@
 //! IPC communication via named pipes for controlling the audio proxy
+//!
+//! The named pipe is the local transport. `--ipc-tcp` additionally accepts the same
+//! JSON commands over TCP for remote control, one object per line, each carrying the
+//! shared `--ipc-token`.
+//!
+//! Each proxy listens on `GAutoSwitchAudioProxy`, or on `GAutoSwitchAudioProxy_<name>`
+//! when started with `--instance <name>`, so several can run side by side (e.g. one
+//! per game). `IpcClient::list_instances` finds the running ones by that convention.
+//!
+//! Clients built against older or newer versions of this module have to keep
+//! working, so the JSON only ever grows:
+//!
+//! - New `IpcResponse` fields are `Option`s left out when `None`. An old client
+//!   skips the fields it doesn't know, and a new client reads their absence from an
+//!   old proxy as `None`.
+//! - New commands are new variants, and new `data` fields of a command take
+//!   `#[serde(default)]`, so what an old client sends still parses.
+//! - Renaming or removing a field, or changing its type, breaks old clients. It
+//!   needs a new `SCHEMA_VERSION`, which every response carries as `schema_version`
+//!   so a client can tell what it's talking to.
+//!
+//! The compatibility tests hold frozen copies of older responses and commands; they
+//! fail when a change would break either direction.
 
 use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::os::windows::ffi::OsStrExt;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use log::debug;
 use serde::{Deserialize, Serialize};
-use windows::core::PCWSTR;
-use windows::Win32::Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE, GENERIC_READ, GENERIC_WRITE};
+use windows::core::{HRESULT, PCWSTR};
+use windows::Win32::Foundation::{
+    CloseHandle, ERROR_ACCESS_DENIED, ERROR_MORE_DATA, ERROR_NO_DATA, ERROR_PIPE_CONNECTED, ERROR_PIPE_LISTENING, HANDLE,
+    INVALID_HANDLE_VALUE, GENERIC_READ, GENERIC_WRITE,
+};
 use windows::Win32::Storage::FileSystem::{
-    CreateFileW, ReadFile, WriteFile, FILE_SHARE_NONE, OPEN_EXISTING, PIPE_ACCESS_DUPLEX,
+    CreateFileW, FindClose, FindFirstFileW, FindNextFileW, FlushFileBuffers, ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE,
+    FILE_SHARE_NONE, OPEN_EXISTING, PIPE_ACCESS_DUPLEX, WIN32_FIND_DATAW,
 };
 use windows::Win32::System::Pipes::{
     ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, SetNamedPipeHandleState,
-    PIPE_READMODE_MESSAGE, PIPE_TYPE_MESSAGE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    PIPE_NOWAIT, PIPE_READMODE_MESSAGE, PIPE_TYPE_MESSAGE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
 };
 
-/// Named pipe path for IPC
+use crate::audio_stream::{AudioFormat, DeviceDirection, EndpointInfo, SupportedFormat};
+use crate::chain::{chain_names, RenderPath, Stage};
+use crate::delay::DelayTarget;
+use crate::diagnostics::Diagnostics;
+use crate::meter::{Levels, MeterMode};
+use crate::mixer::MicSource;
+use crate::eq::EqBand;
+use crate::metrics::{HeartbeatAges, MetricsSnapshot};
+use crate::recent_errors::ErrorEntry;
+use crate::recovery::RecoveryPolicy;
+use crate::spectrum::Spectrum;
+
+/// Named pipe path for IPC (of the default, unnamed instance)
 pub const PIPE_NAME: &str = r"\\.\pipe\GAutoSwitchAudioProxy";
 
+/// File name of the default pipe; named instances append `_<name>`
+const PIPE_FILE_NAME: &str = "GAutoSwitchAudioProxy";
+
+/// Longest name `--instance` accepts
+const MAX_INSTANCE_NAME_LEN: usize = 64;
+
+/// Pipe path of a proxy instance (`None` is the default pipe)
+pub fn pipe_name(instance: Option<&str>) -> String {
+    match instance {
+        Some(name) => format!(r"\\.\pipe\{}_{}", PIPE_FILE_NAME, name),
+        None => PIPE_NAME.to_string(),
+    }
+}
+
+/// Check that an instance name can be used in a pipe name
+pub fn validate_instance_name(name: &str) -> Result<()> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.len() > MAX_INSTANCE_NAME_LEN || !name.chars().all(valid_char) {
+        return Err(anyhow!(
+            "Instance name must be 1 to {} letters, digits, '-', '_' or '.': {:?}", MAX_INSTANCE_NAME_LEN, name
+        ));
+    }
+    Ok(())
+}
+
+/// Instance a file in `\\.\pipe\` belongs to: `Some(None)` for the default pipe,
+/// `Some(Some(name))` for a named instance, `None` for anyone else's pipe
+fn parse_pipe_file_name(file_name: &str) -> Option<Option<&str>> {
+    if file_name == PIPE_FILE_NAME {
+        return Some(None);
+    }
+    file_name.strip_prefix(PIPE_FILE_NAME)?
+        .strip_prefix('_')
+        .filter(|name| !name.is_empty())
+        .map(Some)
+}
+
+/// How often `accept_with_timeout` re-checks the pipe instances (or TCP listener) for a client
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Longest request accepted (a TCP line or a pipe message), so a bad client can't
+/// make us buffer forever
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Size of the pipe buffers and of each read; longer messages take several reads
+const PIPE_BUFFER_SIZE: u32 = 4096;
+
+/// How long a TCP client gets to send its command and read the response
+const TCP_IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most TCP clients kept waiting for their command at once; a new one pushes out the
+/// oldest, so silent connections can't pile up
+const MAX_PENDING_TCP_CLIENTS: usize = 8;
+
+/// Version of the IPC response layout, raised only by changes old clients can't parse
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Layout version an `IpcResponse` was built with: `SCHEMA_VERSION` for responses of
+/// this proxy, 0 for ones from a proxy that predates versioning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SchemaVersion(pub u32);
+
+impl SchemaVersion {
+    fn unversioned() -> Self {
+        Self(0)
+    }
+}
+
+impl Default for SchemaVersion {
+    fn default() -> Self {
+        Self(SCHEMA_VERSION)
+    }
+}
+
 /// Commands that can be sent to the audio proxy
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "command", content = "data")]
 pub enum IpcCommand {
-    /// Set the speaker output device
+    /// Set the speaker output device (the A target) and switch to it at its mix format
     SetOutput { device_id: String },
     /// Get the current status
     GetStatus,
     /// Stop the proxy
     Stop,
+    /// Stop all streams and release the devices (so another app can open them, e.g.
+    /// in exclusive mode) but keep the process running
+    Pause,
+    /// Reopen the streams released by `Pause` with the current device selections
+    Resume,
     /// Set the microphone input device (hot-swap physical mic)
     SetMicInput { device_id: String },
     /// Enable or disable the microphone proxy
     EnableMic { enabled: bool },
+    /// Mute or unmute one of the mics summed into the mic output (`secondary` needs
+    /// `--mic-in2`); unlike `EnableMic` the other mic keeps playing
+    SetMicSourceEnabled { source: MicSource, enabled: bool },
+    /// Set the microphone output device (hot-swap the virtual cable the mic renders to)
+    SetMicOutput { device_id: String },
+    /// Get the negotiated capture/render formats of the speaker and mic paths
+    GetFormats,
+    /// Set the speaker output EQ bands (an empty list disables the EQ)
+    SetEq { bands: Vec<EqBand> },
+    /// Register a secondary (B) speaker output for A/B comparison
+    SetOutputB { device_id: String },
+    /// Switch the speaker output between the A and B targets
+    ToggleOutput,
+    /// Like `SetOutput`, but open the device at the given format instead of its mix
+    /// format when it supports it. The response says which one was used.
+    SetOutputWithFormat { device_id: String, sample_rate: u32, channels: u16 },
+    /// Get the most recent warnings and errors, oldest first
+    GetRecentErrors,
+    /// Get the overflow/underrun/recovery counters and buffer gauges of both paths
+    GetMetrics,
+    /// Like `GetMetrics`, but zero the counters as they are read, so polling for
+    /// rates doesn't miss or double-count events between a read and a reset
+    GetAndResetMetrics,
+    /// Change how patiently the audio loops retry a failed device, without restarting.
+    /// `max_backoff_ms` keeps its current value when omitted.
+    SetRecoveryPolicy {
+        max_attempts: u32,
+        backoff_ms: u64,
+        #[serde(default)]
+        max_backoff_ms: Option<u64>,
+    },
+    /// Set the input peak level (dBFS, at most 0) below which audio counts as silence
+    SetSilenceThreshold { db: f32 },
+    /// Delay the speaker or mic output by `ms` (up to 1000), e.g. to line up game and
+    /// mic audio that reach a streaming PC by different routes
+    SetDelay { target: DelayTarget, ms: u32 },
+    /// Get the Windows volume of the speaker output device
+    GetEndpointVolume,
+    /// Set the Windows volume of the speaker output device (0-100), independent of
+    /// any gain applied by the proxy
+    SetEndpointVolume { percent: f32 },
+    /// Also play the captured speaker audio on `device_id` (e.g. a headset to listen
+    /// along), at `level_db` (default 0, at most +12). Failures of the monitor device
+    /// don't affect the main output.
+    SetMonitor {
+        device_id: String,
+        #[serde(default)]
+        level_db: f32,
+    },
+    /// Stop the monitor output
+    ClearMonitor,
+    /// Endpoints of one direction, in `index:` selector order
+    ListDevices { direction: DeviceDirection },
+    /// Probe which common formats a device accepts in shared and exclusive mode
+    GetSupportedFormats { device_id: String, direction: DeviceDirection },
+    /// Spectrum of the latest `fft_size` frames of speaker capture (a power of two,
+    /// 256 to 8192), in log-spaced bands for a UI analyzer
+    GetSpectrum { fft_size: usize },
+    /// Build up `ms` (up to 500) of extra speaker buffering for a stall the client
+    /// sees coming, e.g. alt-tabbing out of a game. The extra latency goes away again
+    /// a few seconds later. The response has the temporary target.
+    PrepareForStall { ms: u32 },
+    /// Input levels of the speaker and mic captures for a UI meter, shaped by the
+    /// meter set with `SetMeter`. Doesn't change any settings: the meter fields older
+    /// clients still send here are refused, rather than answered in another mode.
+    GetLevels {
+        #[serde(default)]
+        mode: Option<MeterMode>,
+        #[serde(default)]
+        attack_ms: Option<f32>,
+        #[serde(default)]
+        release_ms: Option<f32>,
+        #[serde(default)]
+        hold_ms: Option<f32>,
+        #[serde(default)]
+        decay_db_per_sec: Option<f32>,
+    },
+    /// Set the meter the capture loops shape `GetLevels` with, for every client.
+    /// `mode` (default `peak`) selects the ballistics; `attack_ms`/`release_ms`
+    /// override the `vu` and `ppm` time constants, and `hold_ms`/`decay_db_per_sec`
+    /// the peak hold's hold time and fall rate.
+    SetMeter {
+        #[serde(default)]
+        mode: MeterMode,
+        #[serde(default)]
+        attack_ms: Option<f32>,
+        #[serde(default)]
+        release_ms: Option<f32>,
+        #[serde(default)]
+        hold_ms: Option<f32>,
+        #[serde(default)]
+        decay_db_per_sec: Option<f32>,
+    },
+    /// Swap the left and right channels of the speaker output, for a device or cable
+    /// wired the wrong way round. No effect on mono outputs.
+    SetChannelSwap { enabled: bool },
+    /// Mute the speaker output for a mic check, e.g. to hear only the voice app's
+    /// monitoring. Unlike `Pause`, the speaker stream keeps running (the audio is
+    /// read and dropped), so turning it off again is instant. The mic path carries on
+    /// as usual.
+    SoloMic { enabled: bool },
+    /// Everything about the proxy's state in one reply, for attaching to a bug report:
+    /// version, command line, devices, formats, settings, metrics and recent errors
+    Diagnostics,
+    /// Fake a stall of the speaker source for testing underrun handling: the capture
+    /// drops what it reads for `ms` (up to 10000), so the render loop runs dry. Only
+    /// accepted with `--debug-commands`.
+    InjectSilence { ms: u32 },
+    /// Set which processing stages of a render path run, and in which order (see the
+    /// `chain` module for each path's stages). The response has the chain that runs,
+    /// which always includes the `ceiling`.
+    SetProcessingChain { path: RenderPath, stages: Vec<Stage> },
+}
+
+/// Command as sent over TCP: the usual `command`/`data` fields plus the shared token
+#[derive(Debug, Serialize, Deserialize)]
+struct TcpRequest {
+    token: String,
+    #[serde(flatten)]
+    command: IpcCommand,
+}
+
+/// Recovery policy as reported over IPC
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryPolicyInfo {
+    pub max_attempts: u32,
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl From<&RecoveryPolicy> for RecoveryPolicyInfo {
+    fn from(policy: &RecoveryPolicy) -> Self {
+        Self {
+            max_attempts: policy.max_attempts,
+            backoff_ms: policy.initial_backoff.as_millis() as u64,
+            max_backoff_ms: policy.max_backoff.as_millis() as u64,
+        }
+    }
 }
 
 /// Response from the audio proxy
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IpcResponse {
     pub success: bool,
     pub message: String,
+    #[serde(default = "SchemaVersion::unversioned")]
+    pub schema_version: SchemaVersion,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub running: Option<bool>,
+    /// Whether the streams are released by `Pause`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+    /// Whether the speaker or mic path resamples or remixes channels because its
+    /// capture and render formats differ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub converting: Option<bool>,
+    /// Time since each audio loop last went round, to spot a hung thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_age_ms: Option<HeartbeatAges>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output_device: Option<String>,
+    /// Whether the speaker output's left and right channels are swapped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap_lr: Option<bool>,
+    /// Whether `SoloMic` has the speaker output muted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solo_mic: Option<bool>,
+    /// Processing stages currently engaged, with their key parameters (e.g. "eq (3 bands)")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_features: Option<Vec<String>>,
+    /// Which speaker target is playing: "a" or "b"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_device_b: Option<String>,
+    /// Trim output B plays with under `--ab-loudness-match`, in dB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ab_trim_db: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mic_enabled: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mic_input_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_output_device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker_capture_format: Option<AudioFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker_render_format: Option<AudioFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_capture_format: Option<AudioFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_render_format: Option<AudioFormat>,
+    /// Whether the output opened at the format asked for by `SetOutputWithFormat`
+    /// (false means it fell back to the mix format)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_format_applied: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_errors: Option<Vec<ErrorEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_policy: Option<RecoveryPolicyInfo>,
+    /// Windows volume of the speaker output device in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint_volume: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<EndpointInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_formats: Option<Vec<SupportedFormat>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spectrum: Option<Spectrum>,
+    /// Buffering target while a `PrepareForStall` reserve is held
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stall_target_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker_levels: Option<Levels>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_levels: Option<Levels>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Diagnostics>,
+    /// Stages a render path runs, in order, after `SetProcessingChain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing_chain: Option<Vec<Stage>>,
 }
 
 impl IpcResponse {
@@ -56,10 +386,7 @@ impl IpcResponse {
         Self {
             success: true,
             message: message.to_string(),
-            running: None,
-            output_device: None,
-            mic_enabled: None,
-            mic_input_device: None,
+            ..Default::default()
         }
     }
 
@@ -67,10 +394,7 @@ impl IpcResponse {
         Self {
             success: false,
             message: message.to_string(),
-            running: None,
-            output_device: None,
-            mic_enabled: None,
-            mic_input_device: None,
+            ..Default::default()
         }
     }
 
@@ -80,8 +404,7 @@ impl IpcResponse {
             message: "Status retrieved".to_string(),
             running: Some(running),
             output_device: Some(output_device.to_string()),
-            mic_enabled: None,
-            mic_input_device: None,
+            ..Default::default()
         }
     }
 
@@ -90,6 +413,7 @@ impl IpcResponse {
         output_device: &str,
         mic_enabled: bool,
         mic_input_device: Option<&str>,
+        mic_output_device: Option<&str>,
     ) -> Self {
         Self {
             success: true,
@@ -98,148 +422,640 @@ impl IpcResponse {
             output_device: Some(output_device.to_string()),
             mic_enabled: Some(mic_enabled),
             mic_input_device: mic_input_device.map(|s| s.to_string()),
+            mic_output_device: mic_output_device.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn formats(
+        speaker_capture: Option<AudioFormat>,
+        speaker_render: Option<AudioFormat>,
+        mic_capture: Option<AudioFormat>,
+        mic_render: Option<AudioFormat>,
+    ) -> Self {
+        Self {
+            success: true,
+            message: "Formats retrieved".to_string(),
+            speaker_capture_format: speaker_capture,
+            speaker_render_format: speaker_render,
+            mic_capture_format: mic_capture,
+            mic_render_format: mic_render,
+            ..Default::default()
+        }
+    }
+
+    pub fn recent_errors(entries: Vec<ErrorEntry>) -> Self {
+        Self {
+            success: true,
+            message: format!("{} recent errors", entries.len()),
+            recent_errors: Some(entries),
+            ..Default::default()
+        }
+    }
+
+    pub fn metrics(metrics: MetricsSnapshot) -> Self {
+        Self {
+            success: true,
+            message: "Metrics retrieved".to_string(),
+            metrics: Some(metrics),
+            ..Default::default()
+        }
+    }
+
+    pub fn recovery_policy(policy: &RecoveryPolicy) -> Self {
+        Self {
+            success: true,
+            message: "Recovery policy updated".to_string(),
+            recovery_policy: Some(policy.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn endpoint_volume(percent: f32) -> Self {
+        Self {
+            success: true,
+            message: format!("Endpoint volume is {:.0}%", percent),
+            endpoint_volume: Some(percent),
+            ..Default::default()
+        }
+    }
+
+    pub fn devices(devices: Vec<EndpointInfo>) -> Self {
+        Self {
+            success: true,
+            message: format!("{} devices", devices.len()),
+            devices: Some(devices),
+            ..Default::default()
         }
     }
+
+    pub fn supported_formats(formats: Vec<SupportedFormat>) -> Self {
+        Self {
+            success: true,
+            message: format!("{} supported formats", formats.len()),
+            supported_formats: Some(formats),
+            ..Default::default()
+        }
+    }
+
+    pub fn spectrum(spectrum: Spectrum) -> Self {
+        Self {
+            success: true,
+            message: format!("{} bands from a {}-point FFT", spectrum.bands.len(), spectrum.fft_size),
+            spectrum: Some(spectrum),
+            ..Default::default()
+        }
+    }
+
+    pub fn levels(speaker: Levels, mic: Option<Levels>) -> Self {
+        Self {
+            success: true,
+            message: "Levels retrieved".to_string(),
+            speaker_levels: Some(speaker),
+            mic_levels: mic,
+            ..Default::default()
+        }
+    }
+
+    pub fn stall_target(target_ms: u32) -> Self {
+        Self {
+            success: true,
+            message: format!("Buffering {} ms until the stall has passed", target_ms),
+            stall_target_ms: Some(target_ms),
+            ..Default::default()
+        }
+    }
+
+    pub fn processing_chain(path: RenderPath, stages: Vec<Stage>) -> Self {
+        Self {
+            success: true,
+            message: format!("{:?} chain: {}", path, chain_names(&stages)),
+            processing_chain: Some(stages),
+            ..Default::default()
+        }
+    }
+
+    pub fn diagnostics(diagnostics: Diagnostics) -> Self {
+        Self {
+            success: true,
+            message: format!("Diagnostics of audio proxy {}", diagnostics.version),
+            diagnostics: Some(diagnostics),
+            ..Default::default()
+        }
+    }
+}
+
+/// A transport the IPC thread receives commands on. Each accepted command gets exactly
+/// one response, after which the client is disconnected.
+pub trait IpcTransport {
+    /// Wait up to `timeout` for a client and receive its command
+    fn accept_with_timeout(&mut self, timeout: Duration) -> Result<Option<IpcCommand>>;
+    /// Send a response to the client whose command was returned by the last accept
+    fn send_response(&mut self, response: &IpcResponse) -> Result<()>;
+}
+
+/// Number of pipe instances the server keeps listening, so a client polling status
+/// doesn't make a one-shot command wait for a free instance
+const PIPE_INSTANCES: usize = 4;
+
+/// One listening instance of the server pipe
+struct PipeInstance {
+    handle: HANDLE,
+    connected: bool,
+}
+
+/// Outcome of a non-blocking `ConnectNamedPipe` on an instance
+#[derive(Debug, PartialEq)]
+enum ConnectState {
+    /// A client is connected (possibly since before the call)
+    Connected,
+    /// No client yet
+    Listening,
+    /// A client connected and closed its end before we got to it
+    ClientGone,
+}
+
+/// Classify the result of `ConnectNamedPipe` on a `PIPE_NOWAIT` instance, which reports
+/// the usual states as errors; anything else is a genuine failure
+fn connect_state(result: windows::core::Result<()>) -> Result<ConnectState> {
+    let Err(e) = result else {
+        return Ok(ConnectState::Connected);
+    };
+    match e.code() {
+        code if code == HRESULT::from_win32(ERROR_PIPE_CONNECTED.0) => Ok(ConnectState::Connected),
+        code if code == HRESULT::from_win32(ERROR_PIPE_LISTENING.0) => Ok(ConnectState::Listening),
+        code if code == HRESULT::from_win32(ERROR_NO_DATA.0) => Ok(ConnectState::ClientGone),
+        _ => Err(anyhow::Error::new(e).context("ConnectNamedPipe failed")),
+    }
 }
 
 /// Named pipe server for receiving commands
+///
+/// The instances are created in non-blocking mode so `accept_with_timeout` can poll
+/// them in turn (starting after the one served last, so clients are served fairly)
+/// and return when the timeout expires. Once a client is connected its instance is
+/// switched to blocking mode for the request/response exchange.
+///
+/// The first instance is created with `FILE_FLAG_FIRST_PIPE_INSTANCE`, so a second
+/// proxy with the same pipe name fails to start instead of sharing clients with the
+/// first one.
 pub struct IpcServer {
-    pipe_handle: HANDLE,
-    connected: bool,
+    name: String,
+    instances: Vec<PipeInstance>,
+    /// Instance holding the client whose command was returned by the last accept
+    current: Option<usize>,
+    /// Where the next accept starts polling
+    next: usize,
 }
 
 impl IpcServer {
-    /// Create a new IPC server
-    pub fn new() -> Result<Self> {
-        let pipe_name = to_wide_string(PIPE_NAME);
+    /// Create the IPC server of an instance (`None` listens on the default pipe)
+    pub fn new(instance: Option<&str>) -> Result<Self> {
+        Self::with_pipe_name(&pipe_name(instance))
+    }
 
-        let handle = unsafe {
-            CreateNamedPipeW(
-                PCWSTR(pipe_name.as_ptr()),
-                PIPE_ACCESS_DUPLEX,
-                PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_WAIT,
-                PIPE_UNLIMITED_INSTANCES,
-                4096,
-                4096,
-                0,
-                None,
-            )
-        };
+    fn with_pipe_name(name: &str) -> Result<Self> {
+        let pipe_name = to_wide_string(name);
+        let mut instances: Vec<PipeInstance> = Vec::with_capacity(PIPE_INSTANCES);
 
-        if handle == INVALID_HANDLE_VALUE {
-            return Err(anyhow!("Failed to create named pipe"));
+        for i in 0..PIPE_INSTANCES {
+            let open_mode = if i == 0 { PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE } else { PIPE_ACCESS_DUPLEX };
+            let handle = unsafe {
+                CreateNamedPipeW(
+                    PCWSTR(pipe_name.as_ptr()),
+                    open_mode,
+                    PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_NOWAIT,
+                    PIPE_UNLIMITED_INSTANCES,
+                    PIPE_BUFFER_SIZE,
+                    PIPE_BUFFER_SIZE,
+                    0,
+                    None,
+                )
+            };
+
+            if handle == INVALID_HANDLE_VALUE {
+                let error = std::io::Error::last_os_error();
+                for instance in &instances {
+                    unsafe {
+                        let _ = CloseHandle(instance.handle);
+                    }
+                }
+                if i == 0 && error.raw_os_error() == Some(ERROR_ACCESS_DENIED.0 as i32) {
+                    return Err(anyhow!(
+                        "Pipe {} is already in use, probably by another proxy; start this one with a different --instance",
+                        name
+                    ));
+                }
+                return Err(anyhow!("Failed to create named pipe {}: {}", name, error));
+            }
+
+            instances.push(PipeInstance { handle, connected: false });
         }
 
         Ok(Self {
-            pipe_handle: handle,
-            connected: false,
+            name: name.to_string(),
+            instances,
+            current: None,
+            next: 0,
         })
     }
 
-    /// Accept a connection and receive a command with timeout
-    pub fn accept_with_timeout(&mut self, _timeout: Duration) -> Result<Option<IpcCommand>> {
-        if !self.connected {
-            // Wait for a client to connect
-            let result = unsafe { ConnectNamedPipe(self.pipe_handle, None) };
-            if result.is_err() {
-                // If error is ERROR_PIPE_CONNECTED, a client connected before we called ConnectNamedPipe
-                let err = std::io::Error::last_os_error();
-                if err.raw_os_error() != Some(535) {
-                    // ERROR_PIPE_CONNECTED = 535
+    /// Name of the pipe the server listens on
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Wait up to `timeout` for a client on any instance and receive its command
+    pub fn accept_with_timeout(&mut self, timeout: Duration) -> Result<Option<IpcCommand>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            for k in 0..self.instances.len() {
+                let index = (self.next + k) % self.instances.len();
+                if let Some(command) = self.try_accept(index)? {
+                    self.next = (index + 1) % self.instances.len();
+                    return Ok(Some(command));
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+    }
+
+    /// Check one instance for a connected client and read its command
+    fn try_accept(&mut self, index: usize) -> Result<Option<IpcCommand>> {
+        let instance = &mut self.instances[index];
+
+        if !instance.connected {
+            // Non-blocking: succeeds or fails immediately
+            let result = unsafe { ConnectNamedPipe(instance.handle, None) };
+            match connect_state(result) {
+                Ok(ConnectState::Connected) => {}
+                Ok(ConnectState::Listening) => return Ok(None),
+                Ok(ConnectState::ClientGone) => {
+                    // Reset the instance so it can take the next client
+                    unsafe {
+                        let _ = DisconnectNamedPipe(instance.handle);
+                    }
                     return Ok(None);
                 }
+                Err(e) => {
+                    unsafe {
+                        let _ = DisconnectNamedPipe(instance.handle);
+                    }
+                    return Err(e.context(format!("Failed to accept on IPC pipe instance {}", index)));
+                }
+            }
+            instance.connected = true;
+            debug!("Client connected to IPC pipe instance {}", index);
+
+            // Block for the request/response exchange with this client
+            let mode = PIPE_READMODE_MESSAGE | PIPE_WAIT;
+            unsafe {
+                let _ = SetNamedPipeHandleState(instance.handle, Some(&mode), None, None);
             }
-            self.connected = true;
-            debug!("Client connected to IPC pipe");
         }
 
         // Read command from pipe
-        let mut buffer = [0u8; 4096];
-        let mut bytes_read = 0u32;
-
-        let result = unsafe {
-            ReadFile(
-                self.pipe_handle,
-                Some(&mut buffer),
-                Some(&mut bytes_read),
-                None,
-            )
+        let data = match read_message(instance.handle, MAX_REQUEST_BYTES) {
+            Ok(data) if !data.is_empty() => data,
+            _ => {
+                // Client disconnected (or sent more than we take)
+                self.disconnect(index);
+                return Ok(None);
+            }
         };
 
-        if result.is_err() || bytes_read == 0 {
-            // Client disconnected
-            self.disconnect();
-            return Ok(None);
+        let parsed = serde_json::from_slice(&data).context("Failed to parse IPC command");
+        if parsed.is_err() {
+            self.disconnect(index);
         }
-
-        let data = &buffer[..bytes_read as usize];
-        let command: IpcCommand = serde_json::from_slice(data)
-            .context("Failed to parse IPC command")?;
+        let command: IpcCommand = parsed?;
 
         debug!("Received IPC command: {:?}", command);
+        self.current = Some(index);
         Ok(Some(command))
     }
 
-    /// Send a response to the client
+    /// Send a response to the client whose command was returned by the last accept
     pub fn send_response(&mut self, response: &IpcResponse) -> Result<()> {
-        if !self.connected {
-            return Err(anyhow!("Not connected to client"));
-        }
+        let index = self.current.take()
+            .ok_or_else(|| anyhow!("Not connected to client"))?;
 
         let data = serde_json::to_vec(response)?;
         let mut bytes_written = 0u32;
 
         let result = unsafe {
             WriteFile(
-                self.pipe_handle,
+                self.instances[index].handle,
                 Some(&data),
                 Some(&mut bytes_written),
                 None,
             )
         };
 
-        if result.is_err() {
-            self.disconnect();
-            return Err(anyhow!("Failed to write to pipe"));
+        // Let the client read it all first: disconnecting discards whatever is still in
+        // the pipe, which for a response larger than the pipe buffer is most of it
+        if result.is_ok() {
+            unsafe {
+                let _ = FlushFileBuffers(self.instances[index].handle);
+            }
         }
 
         // Disconnect after response to allow next client
-        self.disconnect();
+        self.disconnect(index);
+
+        if result.is_err() {
+            return Err(anyhow!("Failed to write to pipe"));
+        }
 
         Ok(())
     }
 
-    fn disconnect(&mut self) {
-        if self.connected {
+    fn disconnect(&mut self, index: usize) {
+        let instance = &mut self.instances[index];
+        if instance.connected {
+            let mode = PIPE_READMODE_MESSAGE | PIPE_NOWAIT;
             unsafe {
-                let _ = DisconnectNamedPipe(self.pipe_handle);
+                let _ = DisconnectNamedPipe(instance.handle);
+                let _ = SetNamedPipeHandleState(instance.handle, Some(&mode), None, None);
             }
-            self.connected = false;
-            debug!("Client disconnected from IPC pipe");
+            instance.connected = false;
+            debug!("Client disconnected from IPC pipe instance {}", index);
         }
     }
 }
 
+impl IpcTransport for IpcServer {
+    fn accept_with_timeout(&mut self, timeout: Duration) -> Result<Option<IpcCommand>> {
+        IpcServer::accept_with_timeout(self, timeout)
+    }
+
+    fn send_response(&mut self, response: &IpcResponse) -> Result<()> {
+        IpcServer::send_response(self, response)
+    }
+}
+
+// SAFETY: pipe handles can be used from any thread, and the server is only ever used
+// by the thread that owns it
+unsafe impl Send for IpcServer {}
+
 impl Drop for IpcServer {
     fn drop(&mut self) {
-        self.disconnect();
-        unsafe {
-            let _ = CloseHandle(self.pipe_handle);
+        for index in 0..self.instances.len() {
+            self.disconnect(index);
+            unsafe {
+                let _ = CloseHandle(self.instances[index].handle);
+            }
+        }
+    }
+}
+
+/// TCP server for remote control, answering one client at a time.
+///
+/// Clients are read without blocking, so one that connects and stays silent doesn't
+/// hold up the named pipe served on the same thread; it's dropped once it has taken
+/// `TCP_IO_TIMEOUT` without sending a whole command.
+///
+/// Every request must carry the shared token; anything else is answered with an
+/// error and dropped. The token isn't encryption, so only expose this on a trusted
+/// network.
+pub struct TcpIpcServer {
+    listener: TcpListener,
+    token: String,
+    /// Connected clients whose command hasn't fully arrived, oldest first
+    pending: Vec<PendingClient>,
+    /// Client whose command was returned by the last accept
+    current: Option<TcpStream>,
+}
+
+/// A TCP client that connected but hasn't sent a whole request line yet
+struct PendingClient {
+    stream: TcpStream,
+    peer: SocketAddr,
+    request: Vec<u8>,
+    connected_at: Instant,
+}
+
+/// What a `PendingClient::poll` found
+enum PendingRead {
+    /// Nothing more yet
+    Waiting,
+    /// The request line, without its newline
+    Line(Vec<u8>),
+    /// The client left, sent too much or took too long
+    Closed,
+}
+
+impl PendingClient {
+    /// Read whatever has arrived without blocking
+    fn poll(&mut self, now: Instant) -> PendingRead {
+        let mut chunk = [0u8; 1024];
+        loop {
+            match (&self.stream).read(&mut chunk) {
+                // A request without a trailing newline still counts once the client
+                // closes its end
+                Ok(0) if self.request.is_empty() => return PendingRead::Closed,
+                Ok(0) => return PendingRead::Line(std::mem::take(&mut self.request)),
+                Ok(n) => {
+                    self.request.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = self.request.iter().position(|&b| b == b'\n') {
+                        self.request.truncate(end);
+                        return PendingRead::Line(std::mem::take(&mut self.request));
+                    }
+                    if self.request.len() > MAX_REQUEST_BYTES {
+                        debug!("Dropping IPC client {}: request longer than {} bytes", self.peer, MAX_REQUEST_BYTES);
+                        return PendingRead::Closed;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => return PendingRead::Closed,
+            }
+        }
+        if now.duration_since(self.connected_at) >= TCP_IO_TIMEOUT {
+            debug!("Dropping IPC client {}: no command within {:?}", self.peer, TCP_IO_TIMEOUT);
+            return PendingRead::Closed;
+        }
+        PendingRead::Waiting
+    }
+}
+
+impl TcpIpcServer {
+    /// Listen on `addr` (e.g. "0.0.0.0:51234"), accepting commands carrying `token`
+    pub fn bind(addr: &str, token: &str) -> Result<Self> {
+        if token.is_empty() {
+            return Err(anyhow!("An IPC token is required for the TCP transport"));
+        }
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to bind IPC TCP listener to {}", addr))?;
+        // Non-blocking so accept_with_timeout can give up
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            token: token.to_string(),
+            pending: Vec::new(),
+            current: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Queue every client waiting on the listener
+    fn accept_pending(&mut self) -> Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    debug!("IPC client connected over TCP from {}", peer);
+                    stream.set_nonblocking(true)?;
+                    if self.pending.len() >= MAX_PENDING_TCP_CLIENTS {
+                        let oldest = self.pending.remove(0);
+                        debug!("Dropping IPC client {}: too many clients waiting", oldest.peer);
+                    }
+                    self.pending.push(PendingClient { stream, peer, request: Vec::new(), connected_at: Instant::now() });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Check the waiting clients for a whole request, dropping the ones that are done
+    fn poll_pending(&mut self) -> Result<Option<IpcCommand>> {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.pending.len() {
+            match self.pending[i].poll(now) {
+                PendingRead::Waiting => i += 1,
+                PendingRead::Closed => {
+                    self.pending.remove(i);
+                }
+                PendingRead::Line(line) => {
+                    let client = self.pending.remove(i);
+                    if let Some(command) = self.read_command(client.stream, client.peer, &line)? {
+                        return Ok(Some(command));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Parse a client's request line and check its token
+    fn read_command(&mut self, stream: TcpStream, peer: SocketAddr, line: &[u8]) -> Result<Option<IpcCommand>> {
+        // Only the answer is left, and it's small enough to go out at once
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(TCP_IO_TIMEOUT))?;
+
+        if line.trim_ascii().is_empty() {
+            // Connected and left without a command
+            return Ok(None);
+        }
+
+        let request: TcpRequest = match serde_json::from_slice(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = write_line(&stream, &IpcResponse::error("Failed to parse IPC command"));
+                return Err(anyhow!("Failed to parse IPC command from {}: {}", peer, e));
+            }
+        };
+        if !tokens_match(&self.token, &request.token) {
+            let _ = write_line(&stream, &IpcResponse::error("Invalid IPC token"));
+            return Err(anyhow!("Rejected IPC command from {}: invalid token", peer));
+        }
+
+        debug!("Received IPC command over TCP from {}: {:?}", peer, request.command);
+        self.current = Some(stream);
+        Ok(Some(request.command))
+    }
+}
+
+impl IpcTransport for TcpIpcServer {
+    fn accept_with_timeout(&mut self, timeout: Duration) -> Result<Option<IpcCommand>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            self.accept_pending()?;
+            if let Some(command) = self.poll_pending()? {
+                return Ok(Some(command));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(ACCEPT_POLL_INTERVAL);
         }
     }
+
+    fn send_response(&mut self, response: &IpcResponse) -> Result<()> {
+        // Dropping the stream afterwards closes the connection
+        let stream = self.current.take()
+            .ok_or_else(|| anyhow!("Not connected to client"))?;
+        write_line(&stream, response).context("Failed to write to TCP client")
+    }
+}
+
+/// Write `value` as one line of JSON
+fn write_line(mut stream: &TcpStream, value: &impl Serialize) -> Result<()> {
+    let mut data = serde_json::to_vec(value)?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+/// Compare tokens without returning early on the first differing byte
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// How an `IpcClient` reaches the server
+enum Connection {
+    Pipe(HANDLE),
+    Tcp { reader: BufReader<TcpStream>, token: String },
 }
 
-/// Named pipe client for sending commands
+/// IPC client for sending commands, over the named pipe or TCP
 #[allow(dead_code)]
 pub struct IpcClient {
-    pipe_handle: HANDLE,
+    connection: Connection,
 }
 
 #[allow(dead_code)]
 impl IpcClient {
     /// Connect to the IPC server
     pub fn connect() -> Result<Self> {
-        let pipe_name = to_wide_string(PIPE_NAME);
+        Self::connect_to(PIPE_NAME)
+    }
+
+    /// Connect to the proxy started with `--instance <name>` (`None` is the default one)
+    pub fn connect_instance(instance: Option<&str>) -> Result<Self> {
+        Self::connect_to(&pipe_name(instance))
+    }
+
+    /// Find the running proxies by their pipes and ask each for its status
+    pub fn list_instances() -> Result<Vec<ProxyInstance>> {
+        let mut instances = Vec::new();
+        for file_name in list_pipes()? {
+            let Some(name) = parse_pipe_file_name(&file_name) else {
+                continue;
+            };
+            let status = Self::connect_instance(name)
+                .and_then(|mut client| client.send_command(&IpcCommand::GetStatus))
+                .map_err(|e| debug!("No status from {}: {}", file_name, e))
+                .ok();
+            instances.push(ProxyInstance { name: name.map(str::to_string), status });
+        }
+        Ok(instances)
+    }
+
+    fn connect_to(name: &str) -> Result<Self> {
+        let pipe_name = to_wide_string(name);
 
         let handle = unsafe {
             CreateFileW(
@@ -251,7 +1067,7 @@ impl IpcClient {
                 Default::default(),
                 None,
             )
-        }.map_err(|e| anyhow!("Failed to connect to named pipe: {}", e))?;
+        }.map_err(|e| anyhow!("Failed to connect to named pipe {}: {}", name, e))?;
 
         if handle == INVALID_HANDLE_VALUE {
             return Err(anyhow!("Failed to connect to named pipe"));
@@ -264,45 +1080,112 @@ impl IpcClient {
                 .map_err(|e| anyhow!("Failed to set pipe mode: {}", e))?;
         }
 
-        Ok(Self { pipe_handle: handle })
+        Ok(Self { connection: Connection::Pipe(handle) })
+    }
+
+    /// Connect to a server started with `--ipc-tcp`, authenticating with `token`
+    pub fn connect_tcp(addr: &str, token: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .with_context(|| format!("Failed to connect to IPC server at {}", addr))?;
+        stream.set_read_timeout(Some(TCP_IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(TCP_IO_TIMEOUT))?;
+
+        Ok(Self {
+            connection: Connection::Tcp { reader: BufReader::new(stream), token: token.to_string() },
+        })
     }
 
     /// Send a command and receive a response
     pub fn send_command(&mut self, command: &IpcCommand) -> Result<IpcResponse> {
+        let pipe_handle = match self.connection {
+            Connection::Pipe(handle) => handle,
+            Connection::Tcp { ref mut reader, ref token } => {
+                let request = TcpRequest { token: token.clone(), command: command.clone() };
+                write_line(reader.get_ref(), &request).context("Failed to write to IPC server")?;
+
+                let mut line = String::new();
+                reader.read_line(&mut line).context("Failed to read from IPC server")?;
+                return Ok(serde_json::from_str(&line)?);
+            }
+        };
+
         let data = serde_json::to_vec(command)?;
         let mut bytes_written = 0u32;
 
         unsafe {
             WriteFile(
-                self.pipe_handle,
+                pipe_handle,
                 Some(&data),
                 Some(&mut bytes_written),
                 None,
             ).map_err(|e| anyhow!("Failed to write to pipe: {}", e))?;
         }
 
-        // Read response
-        let mut buffer = [0u8; 4096];
-        let mut bytes_read = 0u32;
-
-        unsafe {
-            ReadFile(
-                self.pipe_handle,
-                Some(&mut buffer),
-                Some(&mut bytes_read),
-                None,
-            ).map_err(|e| anyhow!("Failed to read from pipe: {}", e))?;
-        }
-
-        let response: IpcResponse = serde_json::from_slice(&buffer[..bytes_read as usize])?;
+        // Read response, which can be larger than the pipe buffer (e.g. `Diagnostics`)
+        let data = read_message(pipe_handle, usize::MAX)?;
+        let response: IpcResponse = serde_json::from_slice(&data)?;
         Ok(response)
     }
 }
 
 impl Drop for IpcClient {
     fn drop(&mut self) {
-        unsafe {
-            let _ = CloseHandle(self.pipe_handle);
+        if let Connection::Pipe(handle) = self.connection {
+            unsafe {
+                let _ = CloseHandle(handle);
+            }
+        }
+    }
+}
+
+/// A running proxy found by `IpcClient::list_instances`
+#[derive(Debug, Clone)]
+pub struct ProxyInstance {
+    /// `--instance` name, `None` for the default pipe
+    pub name: Option<String>,
+    /// Reply to `GetStatus`, `None` if it didn't answer (e.g. all its pipe instances
+    /// were busy, or it was shutting down)
+    pub status: Option<IpcResponse>,
+}
+
+/// File names of all named pipes on this machine
+fn list_pipes() -> Result<Vec<String>> {
+    let pattern = to_wide_string(r"\\.\pipe\*");
+    let mut data = WIN32_FIND_DATAW::default();
+    let handle = unsafe { FindFirstFileW(PCWSTR(pattern.as_ptr()), &mut data) }
+        .map_err(|e| anyhow!("Failed to list named pipes: {}", e))?;
+
+    let mut names = Vec::new();
+    loop {
+        let len = data.cFileName.iter().position(|&c| c == 0).unwrap_or(data.cFileName.len());
+        names.push(String::from_utf16_lossy(&data.cFileName[..len]));
+        if unsafe { FindNextFileW(handle, &mut data) }.is_err() {
+            break;
+        }
+    }
+    unsafe {
+        let _ = FindClose(handle);
+    }
+    Ok(names)
+}
+
+/// Read one whole message from a message-mode pipe. A read into a buffer too small
+/// for the message fails with `ERROR_MORE_DATA` and the next one continues it.
+fn read_message(handle: HANDLE, max_len: usize) -> Result<Vec<u8>> {
+    let mut message = Vec::new();
+    let mut buffer = [0u8; PIPE_BUFFER_SIZE as usize];
+    loop {
+        let mut bytes_read = 0u32;
+        let result = unsafe { ReadFile(handle, Some(&mut buffer), Some(&mut bytes_read), None) };
+        message.extend_from_slice(&buffer[..bytes_read as usize]);
+        match result {
+            Ok(()) => return Ok(message),
+            Err(e) if e.code() == HRESULT::from_win32(ERROR_MORE_DATA.0) => {
+                if message.len() > max_len {
+                    return Err(anyhow!("Pipe message longer than {} bytes", max_len));
+                }
+            }
+            Err(e) => return Err(anyhow!("Failed to read from pipe: {}", e)),
         }
     }
 }
@@ -318,6 +1201,7 @@ fn to_wide_string(s: &str) -> Vec<u16> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::spectrum::SpectrumBand;
 
     #[test]
     fn test_command_serialization() {
@@ -343,4 +1227,597 @@ mod tests {
         assert_eq!(parsed.running, Some(true));
         assert_eq!(parsed.output_device, Some("device-123".to_string()));
     }
+
+    #[test]
+    fn test_formats_response_omits_missing_streams() {
+        let format = AudioFormat {
+            sample_rate: 44100,
+            channels: 2,
+            bits_per_sample: 32,
+            block_align: 8,
+        };
+        let resp = IpcResponse::formats(Some(format), None, None, None);
+        let json = serde_json::to_string(&resp).unwrap();
+
+        assert!(json.contains("speaker_capture_format"));
+        assert!(!json.contains("speaker_render_format"));
+        assert!(!json.contains("mic_capture_format"));
+
+        let parsed: IpcResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.speaker_capture_format.unwrap().sample_rate, 44100);
+    }
+
+    #[test]
+    fn test_ab_command_format() {
+        let json = r#"{"command":"SetOutputB","data":{"device_id":"dev-b"}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetOutputB { device_id } => assert_eq!(device_id, "dev-b"),
+            _ => panic!("Wrong command type"),
+        }
+
+        let json = r#"{"command":"ToggleOutput"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::ToggleOutput));
+    }
+
+    #[test]
+    fn test_pause_resume_commands() {
+        let json = r#"{"command":"Pause"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::Pause));
+        let json = r#"{"command":"Resume"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::Resume));
+
+        let mut resp = IpcResponse::status(true, "device-123");
+        resp.paused = Some(true);
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""paused":true"#));
+        assert!(!json.contains("converting"));
+        resp.converting = Some(true);
+        assert!(serde_json::to_string(&resp).unwrap().contains(r#""converting":true"#));
+    }
+
+    #[test]
+    fn test_get_and_reset_metrics_command() {
+        let json = r#"{"command":"GetAndResetMetrics"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::GetAndResetMetrics));
+    }
+
+    #[test]
+    fn test_set_channel_swap_command() {
+        let json = r#"{"command":"SetChannelSwap","data":{"enabled":true}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SetChannelSwap { enabled: true }
+        ));
+
+        let mut resp = IpcResponse::status(true, "device-123");
+        assert!(!serde_json::to_string(&resp).unwrap().contains("swap_lr"));
+        resp.swap_lr = Some(false);
+        assert!(serde_json::to_string(&resp).unwrap().contains(r#""swap_lr":false"#));
+    }
+
+    #[test]
+    fn test_solo_mic_command() {
+        let json = r#"{"command":"SoloMic","data":{"enabled":true}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SoloMic { enabled: true }
+        ));
+
+        let mut resp = IpcResponse::status(true, "device-123");
+        assert!(!serde_json::to_string(&resp).unwrap().contains("solo_mic"));
+        resp.solo_mic = Some(true);
+        assert!(serde_json::to_string(&resp).unwrap().contains(r#""solo_mic":true"#));
+    }
+
+    #[test]
+    fn test_active_features_in_status() {
+        let mut resp = IpcResponse::status(true, "device-123");
+        assert!(!serde_json::to_string(&resp).unwrap().contains("active_features"));
+        resp.active_features = Some(vec!["swap_lr".into(), "eq (3 bands)".into()]);
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""active_features":["swap_lr","eq (3 bands)"]"#), "{}", json);
+    }
+
+    #[test]
+    fn test_ab_trim_only_reported_when_matching() {
+        let mut resp = IpcResponse::success("Switched to output B");
+        assert!(!serde_json::to_string(&resp).unwrap().contains("ab_trim_db"));
+        resp.ab_trim_db = Some(-3.5);
+        assert!(serde_json::to_string(&resp).unwrap().contains(r#""ab_trim_db":-3.5"#));
+    }
+
+    #[test]
+    fn test_status_heartbeats_omit_idle_loops() {
+        let mut resp = IpcResponse::status(true, "device-123");
+        resp.heartbeat_age_ms = Some(HeartbeatAges {
+            speaker_capture: Some(3),
+            speaker_render: Some(12),
+            ..Default::default()
+        });
+        let json = serde_json::to_string(&resp).unwrap();
+        assert!(json.contains(r#""heartbeat_age_ms":{"speaker_capture":3,"speaker_render":12}"#));
+    }
+
+    #[test]
+    fn test_set_recovery_policy_optional_max_backoff() {
+        let json = r#"{"command":"SetRecoveryPolicy","data":{"max_attempts":50,"backoff_ms":1000}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetRecoveryPolicy { max_attempts, backoff_ms, max_backoff_ms } => {
+                assert_eq!(max_attempts, 50);
+                assert_eq!(backoff_ms, 1000);
+                assert_eq!(max_backoff_ms, None);
+            }
+            _ => panic!("Wrong command type"),
+        }
+    }
+
+    #[test]
+    fn test_set_output_with_format_command() {
+        let json = r#"{"command":"SetOutputWithFormat","data":{"device_id":"dev","sample_rate":44100,"channels":2}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetOutputWithFormat { device_id, sample_rate, channels } => {
+                assert_eq!(device_id, "dev");
+                assert_eq!(sample_rate, 44100);
+                assert_eq!(channels, 2);
+            }
+            _ => panic!("Wrong command type"),
+        }
+    }
+
+    #[test]
+    fn test_set_delay_command() {
+        let json = r#"{"command":"SetDelay","data":{"target":"mic","ms":120}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetDelay { target, ms } => {
+                assert_eq!(target, DelayTarget::Mic);
+                assert_eq!(ms, 120);
+            }
+            _ => panic!("Wrong command type"),
+        }
+    }
+
+    #[test]
+    fn test_set_mic_source_enabled_command() {
+        let json = r#"{"command":"SetMicSourceEnabled","data":{"source":"secondary","enabled":false}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SetMicSourceEnabled { source: MicSource::Secondary, enabled: false }
+        ));
+    }
+
+    #[test]
+    fn test_monitor_commands() {
+        let json = r#"{"command":"SetMonitor","data":{"device_id":"headset","level_db":-6.0}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetMonitor { device_id, level_db } => {
+                assert_eq!(device_id, "headset");
+                assert_eq!(level_db, -6.0);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let json = r#"{"command":"SetMonitor","data":{"device_id":"headset"}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SetMonitor { level_db, .. } if level_db == 0.0
+        ));
+
+        let json = r#"{"command":"ClearMonitor"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::ClearMonitor));
+    }
+
+    #[test]
+    fn test_list_devices_command() {
+        let json = r#"{"command":"ListDevices","data":{"direction":"capture"}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::ListDevices { direction: DeviceDirection::Capture }
+        ));
+
+        let devices = vec![EndpointInfo {
+            id: "{0.0.1.00000000}.{abc}".to_string(),
+            name: "CABLE Output".to_string(),
+            interface_name: "VB-Audio Virtual Cable".to_string(),
+        }];
+        let json = serde_json::to_string(&IpcResponse::devices(devices)).unwrap();
+        assert!(json.contains(r#""devices":[{"id":"{0.0.1.00000000}.{abc}","name":"CABLE Output","#));
+    }
+
+    #[test]
+    fn test_supported_formats_command() {
+        let json = r#"{"command":"GetSupportedFormats","data":{"device_id":"dev","direction":"render"}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::GetSupportedFormats { device_id, direction } => {
+                assert_eq!(device_id, "dev");
+                assert_eq!(direction, DeviceDirection::Render);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let formats = vec![SupportedFormat {
+            format: AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 24, block_align: 6 },
+            shared: false,
+            exclusive: true,
+        }];
+        let json = serde_json::to_string(&IpcResponse::supported_formats(formats)).unwrap();
+        assert!(json.contains(
+            r#""supported_formats":[{"sample_rate":48000,"channels":2,"bits_per_sample":24,"block_align":6,"shared":false,"exclusive":true}]"#
+        ));
+    }
+
+    #[test]
+    fn test_spectrum_command() {
+        let json = r#"{"command":"GetSpectrum","data":{"fft_size":2048}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::GetSpectrum { fft_size: 2048 }
+        ));
+
+        let spectrum = Spectrum {
+            sample_rate: 48000,
+            fft_size: 2048,
+            bands: vec![SpectrumBand { low_hz: 20.0, high_hz: 25.0, db: -42.5 }],
+        };
+        let json = serde_json::to_string(&IpcResponse::spectrum(spectrum)).unwrap();
+        assert!(json.contains(
+            r#""spectrum":{"sample_rate":48000,"fft_size":2048,"bands":[{"low_hz":20.0,"high_hz":25.0,"db":-42.5}]}"#
+        ));
+    }
+
+    #[test]
+    fn test_set_meter_command() {
+        let json = r#"{"command":"SetMeter","data":{"mode":"ppm","release_ms":1500.0,"hold_ms":2000.0}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SetMeter {
+                mode: MeterMode::Ppm, attack_ms: None, release_ms: Some(ms), hold_ms: Some(hold), decay_db_per_sec: None
+            } if ms == 1500.0 && hold == 2000.0
+        ));
+        let json = r#"{"command":"SetMeter","data":{}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::SetMeter { mode: MeterMode::Peak, .. }
+        ));
+    }
+
+    #[test]
+    fn test_get_levels_command() {
+        let json = r#"{"command":"GetLevels","data":{}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::GetLevels { mode: None, attack_ms: None, release_ms: None, hold_ms: None, decay_db_per_sec: None }
+        ));
+        // Meter settings from older clients parse, so the proxy can refuse them
+        let json = r#"{"command":"GetLevels","data":{"mode":"ppm","release_ms":1500.0}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::GetLevels { mode: Some(MeterMode::Ppm), release_ms: Some(1500.0), .. }
+        ));
+
+        let levels = Levels { mode: MeterMode::Vu, level_db: -18.0, peak_db: -3.5, peak_hold_db: -1.5 };
+        let json = serde_json::to_string(&IpcResponse::levels(levels, None)).unwrap();
+        assert!(json.contains(
+            r#""speaker_levels":{"mode":"vu","level_db":-18.0,"peak_db":-3.5,"peak_hold_db":-1.5}"#
+        ));
+        assert!(!json.contains("mic_levels"));
+    }
+
+    #[test]
+    fn test_prepare_for_stall_command() {
+        let json = r#"{"command":"PrepareForStall","data":{"ms":200}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::PrepareForStall { ms: 200 }
+        ));
+
+        let json = serde_json::to_string(&IpcResponse::stall_target(210)).unwrap();
+        assert!(json.contains(r#""stall_target_ms":210"#));
+    }
+
+    #[test]
+    fn test_inject_silence_command() {
+        let json = r#"{"command":"InjectSilence","data":{"ms":500}}"#;
+        assert!(matches!(
+            serde_json::from_str::<IpcCommand>(json).unwrap(),
+            IpcCommand::InjectSilence { ms: 500 }
+        ));
+    }
+
+    #[test]
+    fn test_set_processing_chain_command() {
+        let json = r#"{"command":"SetProcessingChain","data":{"path":"speaker","stages":["delay","eq"]}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetProcessingChain { path, stages } => {
+                assert_eq!(path, RenderPath::Speaker);
+                assert_eq!(stages, [Stage::Delay, Stage::Eq]);
+            }
+            _ => panic!("Wrong command type"),
+        }
+        assert!(serde_json::from_str::<IpcCommand>(
+            r#"{"command":"SetProcessingChain","data":{"path":"mic","stages":["limiter"]}}"#
+        ).is_err());
+
+        let response = IpcResponse::processing_chain(RenderPath::Mic, vec![Stage::Delay, Stage::Ceiling]);
+        assert_eq!(response.message, "Mic chain: delay, ceiling");
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains(r#""processing_chain":["delay","ceiling"]"#), "{}", json);
+    }
+
+    #[test]
+    fn test_diagnostics_command() {
+        use crate::diagnostics::{PathDiagnostics, SettingsDiagnostics};
+        use crate::meter::MeterSettings;
+
+        let json = r#"{"command":"Diagnostics"}"#;
+        assert!(matches!(serde_json::from_str::<IpcCommand>(json).unwrap(), IpcCommand::Diagnostics));
+
+        let format = AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 };
+        let error = ErrorEntry {
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            level: "WARN".to_string(),
+            thread: "speaker-render".to_string(),
+            message: "Speaker render error (attempt 1): Device was invalidated".to_string(),
+        };
+        let diagnostics = Diagnostics {
+            version: "0.1.0".to_string(),
+            command_line: vec!["audio-proxy".to_string(), "--buffer".to_string(), "20".to_string()],
+            running: true,
+            paused: false,
+            speaker: PathDiagnostics {
+                input_device: "cable".to_string(),
+                output_device: "speakers".to_string(),
+                enabled: None,
+                capture_format: Some(format.clone()),
+                render_format: Some(format),
+                converting: false,
+                delay_ms: 0,
+            },
+            mic: None,
+            settings: SettingsDiagnostics {
+                prefill_ms: 20,
+                active_output: "a".to_string(),
+                output_device_b: None,
+                monitor_device: None,
+                eq_bands: Vec::new(),
+                silence_threshold_db: -60.0,
+                swap_lr: false,
+                solo_mic: false,
+                active_features: Vec::new(),
+                recovery_policy: RecoveryPolicyInfo { max_attempts: 10, backoff_ms: 500, max_backoff_ms: 5000 },
+                meter: MeterSettings::default(),
+            },
+            metrics: MetricsSnapshot::default(),
+            heartbeat_age_ms: HeartbeatAges::default(),
+            // A full error history alone is more than one pipe read
+            recent_errors: vec![error; 32],
+        };
+
+        let json = serde_json::to_string(&IpcResponse::diagnostics(diagnostics.clone())).unwrap();
+        assert!(json.len() > PIPE_BUFFER_SIZE as usize);
+        assert!(json.contains(r#""message":"Diagnostics of audio proxy 0.1.0""#));
+        assert!(json.contains(r#""mic":null"#));
+        let parsed: IpcResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.diagnostics, Some(diagnostics));
+    }
+
+    #[test]
+    fn test_endpoint_volume_command() {
+        let json = r#"{"command":"SetEndpointVolume","data":{"percent":42.5}}"#;
+        match serde_json::from_str::<IpcCommand>(json).unwrap() {
+            IpcCommand::SetEndpointVolume { percent } => assert_eq!(percent, 42.5),
+            _ => panic!("Wrong command type"),
+        }
+
+        let json = serde_json::to_string(&IpcResponse::endpoint_volume(42.5)).unwrap();
+        assert!(json.contains(r#""endpoint_volume":42.5"#));
+    }
+
+    #[test]
+    fn test_connect_state() {
+        use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
+
+        let error = |code: u32| Err(windows::core::Error::from(HRESULT::from_win32(code)));
+        assert_eq!(connect_state(Ok(())).unwrap(), ConnectState::Connected);
+        assert_eq!(connect_state(error(ERROR_PIPE_CONNECTED.0)).unwrap(), ConnectState::Connected);
+        assert_eq!(connect_state(error(ERROR_PIPE_LISTENING.0)).unwrap(), ConnectState::Listening);
+        assert_eq!(connect_state(error(ERROR_NO_DATA.0)).unwrap(), ConnectState::ClientGone);
+        assert!(connect_state(error(ERROR_ACCESS_DENIED.0)).is_err());
+    }
+
+    #[test]
+    fn test_tcp_requires_token() {
+        let mut server = TcpIpcServer::bind("127.0.0.1:0", "secret").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        let client = {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                IpcClient::connect_tcp(&addr, "secret").unwrap().send_command(&IpcCommand::GetStatus).unwrap()
+            })
+        };
+        let command = server.accept_with_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(command, Some(IpcCommand::GetStatus)));
+        server.send_response(&IpcResponse::status(true, "device-123")).unwrap();
+        assert_eq!(client.join().unwrap().output_device, Some("device-123".to_string()));
+
+        let intruder = thread::spawn(move || {
+            IpcClient::connect_tcp(&addr, "guess").unwrap().send_command(&IpcCommand::Stop).unwrap()
+        });
+        assert!(server.accept_with_timeout(Duration::from_secs(5)).is_err());
+        let response = intruder.join().unwrap();
+        assert!(!response.success);
+        assert_eq!(response.message, "Invalid IPC token");
+    }
+
+    #[test]
+    fn test_silent_tcp_client_doesnt_block() {
+        let mut server = TcpIpcServer::bind("127.0.0.1:0", "secret").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        // Connects and never sends anything: accept still returns on time
+        let _silent = TcpStream::connect(&addr).unwrap();
+        let started = Instant::now();
+        assert!(server.accept_with_timeout(Duration::from_millis(50)).unwrap().is_none());
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // And a client behind it is served while it's still connected
+        let client = thread::spawn(move || {
+            IpcClient::connect_tcp(&addr, "secret").unwrap().send_command(&IpcCommand::GetStatus).unwrap()
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut command = None;
+        while command.is_none() && Instant::now() < deadline {
+            command = server.accept_with_timeout(Duration::from_millis(50)).unwrap();
+        }
+        assert!(matches!(command, Some(IpcCommand::GetStatus)));
+        server.send_response(&IpcResponse::status(true, "device-123")).unwrap();
+        assert!(client.join().unwrap().success);
+    }
+
+    #[test]
+    fn test_instance_pipe_names() {
+        assert_eq!(pipe_name(None), PIPE_NAME);
+        assert_eq!(pipe_name(Some("game-1")), r"\\.\pipe\GAutoSwitchAudioProxy_game-1");
+
+        assert_eq!(parse_pipe_file_name("GAutoSwitchAudioProxy"), Some(None));
+        assert_eq!(parse_pipe_file_name("GAutoSwitchAudioProxy_game-1"), Some(Some("game-1")));
+        assert_eq!(parse_pipe_file_name("GAutoSwitchAudioProxy_"), None);
+        assert_eq!(parse_pipe_file_name("GAutoSwitchAudioProxyTest-42"), None);
+        assert_eq!(parse_pipe_file_name("mojo.1234"), None);
+
+        assert!(validate_instance_name("game-1").is_ok());
+        assert!(validate_instance_name("").is_err());
+        assert!(validate_instance_name(r"a\b").is_err());
+        assert!(validate_instance_name(&"x".repeat(MAX_INSTANCE_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_serves_concurrent_clients() {
+        let name = format!(r"\\.\pipe\GAutoSwitchAudioProxyTest-{}", std::process::id());
+        let mut server = IpcServer::with_pipe_name(&name).unwrap();
+
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                let name = name.clone();
+                thread::spawn(move || {
+                    let mut client = IpcClient::connect_to(&name).unwrap();
+                    client.send_command(&IpcCommand::GetStatus).unwrap()
+                })
+            })
+            .collect();
+
+        let mut served = 0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while served < clients.len() && Instant::now() < deadline {
+            if let Some(command) = server.accept_with_timeout(Duration::from_millis(100)).unwrap() {
+                assert!(matches!(command, IpcCommand::GetStatus));
+                server.send_response(&IpcResponse::status(true, "device-123")).unwrap();
+                served += 1;
+            }
+        }
+        assert_eq!(served, 2);
+
+        for client in clients {
+            let response = client.join().unwrap();
+            assert_eq!(response.output_device, Some("device-123".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_pipe_name_taken() {
+        let name = format!(r"\\.\pipe\GAutoSwitchAudioProxyTest-taken-{}", std::process::id());
+        let server = IpcServer::with_pipe_name(&name).unwrap();
+        // A second proxy on the same name doesn't get to share its clients
+        assert!(IpcServer::with_pipe_name(&name).is_err());
+        drop(server);
+        assert!(IpcServer::with_pipe_name(&name).is_ok());
+    }
+
+    /// `IpcResponse` as schema version 1 clients know it. Frozen: don't add fields
+    /// here when `IpcResponse` grows, that's what the tests guard against.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct ResponseV1 {
+        success: bool,
+        message: String,
+        schema_version: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        running: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_device: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mic_enabled: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        speaker_capture_format: Option<AudioFormat>,
+    }
+
+    #[test]
+    fn test_old_clients_parse_new_responses() {
+        let format = AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 };
+        let mut resp = IpcResponse::status_full(true, "device-123", false, Some("mic"), None);
+        resp.speaker_capture_format = Some(format.clone());
+        resp.active_features = Some(vec!["swap_lr".to_string()]);
+        resp.stall_target_ms = Some(120);
+
+        let old: ResponseV1 = serde_json::from_str(&serde_json::to_string(&resp).unwrap()).unwrap();
+        assert!(old.success);
+        assert_eq!(old.schema_version, SCHEMA_VERSION);
+        assert_eq!(old.running, Some(true));
+        assert_eq!(old.output_device.as_deref(), Some("device-123"));
+        assert_eq!(old.mic_enabled, Some(false));
+        assert_eq!(old.speaker_capture_format, Some(format));
+
+        let old: ResponseV1 = serde_json::from_str(&serde_json::to_string(&IpcResponse::error("No")).unwrap()).unwrap();
+        assert!(!old.success);
+        assert_eq!(old.message, "No");
+    }
+
+    #[test]
+    fn test_new_clients_parse_old_responses() {
+        // From a proxy that predates schema versioning
+        let json = r#"{"success":true,"message":"Status retrieved","running":true,"output_device":"device-123"}"#;
+        let resp: IpcResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(resp.schema_version, SchemaVersion(0));
+        assert_eq!(resp.running, Some(true));
+        assert!(resp.paused.is_none() && resp.metrics.is_none() && resp.diagnostics.is_none());
+
+        let old = ResponseV1 {
+            success: true,
+            message: "Status retrieved".to_string(),
+            schema_version: 1,
+            running: Some(false),
+            output_device: None,
+            mic_enabled: Some(true),
+            speaker_capture_format: None,
+        };
+        let resp: IpcResponse = serde_json::from_str(&serde_json::to_string(&old).unwrap()).unwrap();
+        assert_eq!(resp.schema_version, SchemaVersion(1));
+        assert_eq!(resp.mic_enabled, Some(true));
+        assert!(resp.output_device.is_none() && resp.active_features.is_none());
+    }
+
+    #[test]
+    fn test_unset_response_fields_are_left_out() {
+        // Every field added later has to be optional and skipped when unset, or a
+        // plain response would carry it
+        let json = serde_json::to_value(IpcResponse::success("ok")).unwrap();
+        let mut keys: Vec<&str> = json.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["message", "schema_version", "success"]);
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_old_commands_still_parse() {
+        // Sent by clients written before these commands gained optional fields
+        for json in [
+            r#"{"command":"GetStatus"}"#,
+            r#"{"command":"SetOutput","data":{"device_id":"device-123"}}"#,
+            r#"{"command":"SetMonitor","data":{"device_id":"headset"}}"#,
+            r#"{"command":"GetLevels","data":{}}"#,
+            r#"{"command":"GetLevels","data":{"mode":"vu","attack_ms":100.0}}"#,
+            r#"{"command":"SetRecoveryPolicy","data":{"max_attempts":5,"backoff_ms":100}}"#,
+        ] {
+            assert!(serde_json::from_str::<IpcCommand>(json).is_ok(), "{}", json);
+        }
+    }
 }
@@ -0,0 +1,79 @@
+//! Idle fill for the render loops
+//!
+//! When there's nothing to play the render loops keep the device fed with silence.
+//! Some receivers treat digital silence as "no signal" and mute their outputs,
+//! cutting off the start of the next sound while they re-engage. With `--keep-alive`
+//! the idle blocks carry very low-level noise instead, which is inaudible but keeps
+//! the device's signal detection engaged.
+
+/// Default keep-alive noise level in dBFS
+pub const DEFAULT_KEEP_ALIVE_DB: f32 = -80.0;
+
+/// Source of the samples written while the render loop is idle
+pub struct IdleFill {
+    /// Peak amplitude of the noise (0.0 writes pure silence)
+    amplitude: f32,
+    rng: u32,
+}
+
+impl IdleFill {
+    /// Create an idle fill; `None` means plain digital silence
+    pub fn new(keep_alive_db: Option<f32>) -> Self {
+        Self {
+            amplitude: keep_alive_db.map(|db| 10f32.powf(db / 20.0)).unwrap_or(0.0),
+            rng: 0x9E37_79B9,
+        }
+    }
+
+    /// Fill `out` with the idle signal
+    pub fn fill(&mut self, out: &mut [f32]) {
+        if self.amplitude == 0.0 {
+            out.fill(0.0);
+            return;
+        }
+
+        // Triangular (TPDF) noise: the sum of two uniform values, peak = amplitude
+        for sample in out.iter_mut() {
+            let a = self.next_uniform();
+            let b = self.next_uniform();
+            *sample = (a + b) * 0.5 * self.amplitude;
+        }
+    }
+
+    /// Uniform value in [-1, 1) from a xorshift32 generator
+    fn next_uniform(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_is_silent() {
+        let mut fill = IdleFill::new(None);
+        let mut out = vec![1.0f32; 256];
+        fill.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_keep_alive_level() {
+        let mut fill = IdleFill::new(Some(-80.0));
+        let mut out = vec![0.0f32; 48000];
+        fill.fill(&mut out);
+
+        let limit = 10f32.powf(-80.0 / 20.0);
+        assert!(out.iter().all(|s| s.abs() <= limit));
+        assert!(out.iter().any(|&s| s != 0.0));
+
+        // TPDF with peak A has RMS A/sqrt(6)
+        let rms = (out.iter().map(|s| s * s).sum::<f32>() / out.len() as f32).sqrt();
+        let expected = limit / 6f32.sqrt();
+        assert!((rms - expected).abs() < expected * 0.1, "rms {} expected {}", rms, expected);
+    }
+}
@@ -0,0 +1,42 @@
+//! Audio Proxy library: the building blocks of the `audio-proxy` binary
+//!
+//! `proxy` wires them together into a running proxy, and the binary is a command
+//! line front end to it. They're exposed as a library so the proxy can be embedded
+//! in another app, and so integration tests (and other tools) can use the pieces,
+//! e.g. to drive the IPC protocol through a real named pipe.
+
+#[cfg(feature = "asio")]
+pub mod asio_stream;
+pub mod ab_match;
+pub mod audio_stream;
+pub mod auto_detect;
+pub mod chain;
+pub mod convert;
+pub mod delay;
+pub mod diagnostics;
+pub mod dither;
+pub mod dsp;
+pub mod eq;
+pub mod fill_limit;
+pub mod fade;
+pub mod glitch_dump;
+pub mod ipc;
+pub mod keep_alive;
+pub mod meter;
+pub mod metrics;
+pub mod mixer;
+pub mod monitor;
+pub mod power;
+pub mod profile;
+pub mod proxy;
+pub mod recent_errors;
+pub mod reblock;
+pub mod recovery;
+pub mod ring_buffer;
+pub mod rtp;
+pub mod session_end;
+pub mod silence;
+pub mod spectrum;
+pub mod stall;
+pub mod test_signal;
+pub mod wav;
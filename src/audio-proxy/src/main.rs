@@ -7,46 +7,109 @@
 //! Microphone proxy support: Captures from physical mic and renders to VB-Cable Input
 //! so that apps capturing from VB-Cable Output get the audio.
 
-mod audio_stream;
-mod ipc;
-mod ring_buffer;
+use audio_proxy::{audio_stream, auto_detect, convert, dither, dsp, ipc, keep_alive, metrics, profile, proxy, recent_errors, recovery, rtp, session_end, silence, test_signal};
 
+use std::io::Write;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use log::{error, info, warn};
-use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_MULTITHREADED};
-
-use audio_stream::{AudioFormat, CaptureStream, RenderStream};
-use ipc::{IpcCommand, IpcServer};
-use ring_buffer::AudioRingBuffer;
-
-/// Default buffer size in milliseconds
-const DEFAULT_BUFFER_MS: u32 = 10;
-
-/// Default sample rate for buffer size estimation (actual rate comes from device)
-const DEFAULT_SAMPLE_RATE: u32 = 48000;
-
-/// Default channel count for buffer size estimation
-const DEFAULT_CHANNELS: u16 = 2;
-
-/// Max consecutive errors before giving up on stream recovery
-const MAX_RECOVERY_ATTEMPTS: u32 = 5;
+use log::{info, warn};
+use windows::Win32::System::Com::CoUninitialize;
+
+use audio_stream::{
+    default_capture_endpoint, default_render_endpoint, list_capture_endpoints, list_render_endpoints,
+    probe_supported_formats, ComModel, DefaultRole, DeviceDirection, EndpointInfo, StreamCategory,
+    StreamError, DEFAULT_DEVICE_BUFFER_MS,
+};
+use convert::{ChannelMap, ResampleQuality, UpmixMode};
+use dither::DitherMode;
+use dsp::DEFAULT_MAX_OUTPUT_DB;
+use ipc::IpcClient;
+use keep_alive::DEFAULT_KEEP_ALIVE_DB;
+use metrics::METRICS;
+use profile::Profile;
+use proxy::{
+    create_and_start_capture, create_and_start_render, OutputBackend, Proxy, ProxyConfig, ProxyHandle, RunMode,
+    DEFAULT_DRAIN_MS, DEFAULT_GLITCH_DUMP_SECS, DEFAULT_START_FADE_MS,
+};
+use recent_errors::{ErrorEntry, RECENT_ERRORS};
+use recovery::RecoveryPolicy;
+use rtp::RtpCodec;
+use silence::DEFAULT_SILENCE_THRESHOLD_DB;
 
 /// Parsed command line arguments
 struct Args {
-    speaker_in: String,
-    speaker_out: String,
-    mic_in: Option<String>,
-    mic_out: Option<String>,
-    buffer_ms: u32,
+    config: ProxyConfig,
+    /// Preset the buffer, chunk and recovery values were taken from (where not given)
+    profile: Profile,
+    measure_latency: bool,
+    /// How often to log a status line (`--status-interval`)
+    status_interval: Option<Duration>,
 }
 
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Include the thread name so lines from the four audio loops can be told apart.
+    // Warnings and errors are also kept for GetRecentErrors.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let timestamp = buf.timestamp();
+            let current = thread::current();
+            let thread_name = current.name().unwrap_or("unnamed");
+            if record.level() <= log::Level::Warn {
+                RECENT_ERRORS.push(ErrorEntry {
+                    timestamp: timestamp.to_string(),
+                    level: record.level().to_string(),
+                    thread: thread_name.to_string(),
+                    message: record.args().to_string(),
+                });
+            }
+
+            let level_style = buf.default_level_style(record.level());
+            writeln!(
+                buf,
+                "[{} {level_style}{:<5}{level_style:#} {}] {}",
+                timestamp,
+                record.level(),
+                thread_name,
+                record.args()
+            )
+        })
+        .init();
+
+    // Listing devices needs none of the other arguments but the COM model
+    let cli: Vec<String> = std::env::args().collect();
+    let com_model = match cli.iter().position(|a| a == "--com-model") {
+        Some(pos) => ComModel::parse(cli.get(pos + 1).context("Missing value for --com-model")?)?,
+        None => ComModel::default(),
+    };
+    if cli.iter().skip(1).any(|a| a == "--list-devices") {
+        com_model.initialize().context("Failed to initialize COM")?;
+        let result = print_device_list();
+        unsafe {
+            CoUninitialize();
+        }
+        return result;
+    }
+
+    // Neither does probing a device's formats
+    if let Some(pos) = cli.iter().position(|a| a == "--device-formats") {
+        let device_id = cli.get(pos + 1).context("--device-formats needs a device ID or name")?;
+        com_model.initialize().context("Failed to initialize COM")?;
+        let result = print_device_formats(device_id);
+        unsafe {
+            CoUninitialize();
+        }
+        return result;
+    }
+
+    // Or listing the proxies already running
+    if cli.iter().any(|a| a == "--list-instances") {
+        return print_instance_list();
+    }
 
     let args = match parse_args() {
         Ok(args) => args,
@@ -59,22 +122,121 @@ fn main() -> Result<()> {
     };
 
     info!("Audio Proxy starting...");
-    info!("  Speaker input:  {}", args.speaker_in);
-    info!("  Speaker output: {}", args.speaker_out);
-    if let Some(ref mic_in) = args.mic_in {
+    if !args.config.speaker_in.is_empty() {
+        info!("  Speaker input:  {}", args.config.speaker_in);
+    }
+    if let Some(ref speaker_in2) = args.config.speaker_in2 {
+        info!("  Mixed with:     {}", speaker_in2);
+    }
+    if !args.config.speaker_out.is_empty() {
+        info!("  Speaker output: {}", args.config.speaker_out);
+    }
+    if let Some(ref mic_in) = args.config.mic_in {
         info!("  Mic input:      {}", mic_in);
+        if args.config.mic_follow_default {
+            if DefaultRole::from_selector(mic_in).is_some() {
+                info!("                  following default device changes");
+            } else {
+                warn!("--mic-follow-default only applies to \"default:\" inputs, {} stays pinned", mic_in);
+            }
+        }
     }
-    if let Some(ref mic_out) = args.mic_out {
+    if let Some(ref mic_in2) = args.config.mic_in2 {
+        info!("  Mixed with:     {}", mic_in2);
+    }
+    if let Some(ref mic_out) = args.config.mic_out {
         info!("  Mic output:     {}", mic_out);
     }
-    info!("  Buffer size:    {}ms", args.buffer_ms);
+    if args.profile != Profile::default() {
+        info!("  Profile:        {}", args.profile.name());
+    }
+    info!("  Buffer size:    {}ms", args.config.buffer_ms);
+    if args.config.prefill_ms != args.config.buffer_ms {
+        info!("  Prefill:        {}ms", args.config.prefill_ms);
+    }
+    if let Some(ms) = args.config.max_fill_ms {
+        info!("  Max fill:       {}ms, then skip back to the prefill", ms);
+    }
+    if args.config.device_buffer_ms != DEFAULT_DEVICE_BUFFER_MS {
+        info!("  Device buffer:  {}ms", args.config.device_buffer_ms);
+    }
+    if args.config.capture_chunk_frames > 0 {
+        info!("  Capture chunk:  {} frames", args.config.capture_chunk_frames);
+    }
+    if args.config.prefill_ms > args.config.device_buffer_ms {
+        warn!("Prefill ({} ms) is larger than the device buffer ({} ms); only what fits is queued",
+              args.config.prefill_ms, args.config.device_buffer_ms);
+    }
+    if let Some(db) = args.config.keep_alive_db {
+        info!("  Keep-alive:     {} dBFS noise while idle", db);
+    }
+    if args.config.max_output_db != DEFAULT_MAX_OUTPUT_DB {
+        info!("  Max output gain: {:+.1} dB", args.config.max_output_db);
+    }
+    if args.config.no_convert {
+        info!("  Conversion:     off, mismatched formats stop the stream");
+    }
+    match args.config.run_mode {
+        RunMode::Full => {}
+        RunMode::CaptureOnly => info!("  Mode:           capture only, the outputs aren't opened"),
+        RunMode::RenderOnly => info!("  Mode:           render only, the inputs aren't opened"),
+    }
+    if let Some(map) = args.config.channel_map {
+        info!("  Channel map:    {}", map);
+    }
+    if args.config.resample_quality != ResampleQuality::default() {
+        info!("  Resampling:     {:?}", args.config.resample_quality);
+        if args.config.resample_quality == ResampleQuality::Best && !ResampleQuality::best_available() {
+            warn!("Built without the src-libsamplerate feature, --resample-quality best uses the sinc resampler");
+        }
+    }
+    if args.config.no_resample_clamp {
+        info!("  Resampling:     overshoot past full scale not clamped");
+    }
+    if args.config.output_backend != OutputBackend::Wasapi {
+        info!("  Output backend: {:?}", args.config.output_backend);
+    }
+    if args.config.dither != DitherMode::default() {
+        info!("  Dither:         {:?}", args.config.dither);
+    }
+    if args.config.output_category != StreamCategory::Media {
+        info!("  Output category: {:?}", args.config.output_category);
+    }
+    if let Some(rate) = args.config.force_rate {
+        let fallback = if args.config.strict_rate { "strict" } else { "or the closest supported" };
+        info!("  Output rate:    {} Hz ({})", rate, fallback);
+    }
+    if args.config.swap_lr {
+        info!("  Channel swap:   left and right swapped");
+    }
+    if args.config.ab_loudness_match {
+        info!("  A/B outputs:    loudness matched");
+    }
+    if args.config.com_model != ComModel::default() {
+        info!("  COM model:      {:?}", args.config.com_model);
+    }
+    if let Some(ref addr) = args.config.rtp_out {
+        info!("  RTP stream:     {} ({})", addr, args.config.rtp_codec);
+    }
+    if let Some(ref dir) = args.config.glitch_dump_dir {
+        info!("  Glitch dumps:   {} ({}s history)", dir.display(), args.config.glitch_dump_secs);
+    }
+    if let Some(interval) = args.status_interval {
+        info!("  Status line:    every {}s", interval.as_secs());
+    }
+    if args.config.debug_commands {
+        warn!("  Debug commands: enabled, IPC clients can fake stalls");
+    }
 
     // Initialize COM for this thread
-    unsafe {
-        CoInitializeEx(None, COINIT_MULTITHREADED).ok().context("Failed to initialize COM")?;
-    }
+    let com_model = args.config.com_model;
+    com_model.initialize().context("Failed to initialize COM")?;
 
-    let result = run_proxy(&args);
+    let result = if args.measure_latency {
+        run_latency_measurement(&args.config)
+    } else {
+        run_proxy(args.config, args.status_interval)
+    };
 
     unsafe {
         CoUninitialize();
@@ -83,41 +245,357 @@ fn main() -> Result<()> {
     result
 }
 
+/// Run the proxy until Ctrl+C, logoff or an IPC `Stop`
+fn run_proxy(config: ProxyConfig, status_interval: Option<Duration>) -> Result<()> {
+    let handle = Proxy::start(config)?;
+
+    ctrlc_handler(handle.running());
+    // And stop cleanly on logoff/shutdown instead of being killed mid-write
+    if let Err(e) = session_end::install(handle.running()) {
+        warn!("{}", e);
+    }
+
+    if let Some(interval) = status_interval {
+        log_status_until_stopped(&handle, interval);
+    }
+    handle.wait();
+    session_end::mark_stopped();
+    Ok(())
+}
+
+/// Log a status line every `interval` until the proxy stops, for headless monitoring
+fn log_status_until_stopped(handle: &ProxyHandle, interval: Duration) {
+    let mut previous = METRICS.snapshot();
+    let mut next = Instant::now() + interval;
+    while handle.is_running() {
+        thread::sleep(Duration::from_millis(100));
+        if Instant::now() >= next {
+            let current = METRICS.snapshot();
+            info!("{}", current.status_line(&previous, &METRICS.heartbeat_ages()));
+            previous = current;
+            next += interval;
+        }
+    }
+}
+
+/// Version line for `--version`, e.g. "audio-proxy 0.1.0 (3f2c1ab, x86_64-pc-windows-msvc)"
+fn version_info() -> String {
+    let target = env!("AUDIO_PROXY_TARGET");
+    match option_env!("AUDIO_PROXY_GIT_HASH") {
+        Some(hash) => format!("audio-proxy {} ({}, {})", env!("CARGO_PKG_VERSION"), hash, target),
+        None => format!("audio-proxy {} ({})", env!("CARGO_PKG_VERSION"), target),
+    }
+}
+
 fn print_usage() {
-    eprintln!("Usage: audio-proxy --speaker-in <id> --speaker-out <id> [--mic-in <id>] [--mic-out <id>] [--buffer <ms>]");
+    eprintln!("Usage: audio-proxy --speaker-in <id> --speaker-out <id> [--mic-in <id> --mic-out <id>] [--buffer <ms>] [--glitch-dump <dir>]");
     eprintln!();
     eprintln!("Arguments:");
     eprintln!("  --speaker-in <id>   ID of the virtual audio device for speaker capture (e.g., VB-Cable Output)");
+    eprintln!("  --speaker-in2 <id>  Second capture device (e.g. a voice chat cable) mixed into the");
+    eprintln!("                      speaker output (optional)");
     eprintln!("  --speaker-out <id>  ID of the real output device for speaker playback");
-    eprintln!("  --mic-in <id>       ID of the physical microphone for mic capture (optional)");
+    eprintln!("  --auto              Pick the speaker devices not given: the virtual cable (VB-Cable,");
+    eprintln!("                      VoiceMeeter, ...) as input and the default output device as");
+    eprintln!("                      output; stops with a hint when the choice isn't clear");
+    eprintln!("  --mic-in <id>       ID of the physical microphone for mic capture (optional,");
+    eprintln!("                      together with --mic-out)");
     eprintln!("  --mic-out <id>      ID of the virtual input device for mic output (e.g., VB-Cable Input)");
-    eprintln!("  --buffer <ms>       Buffer size in milliseconds (default: 10)");
+    eprintln!("  --mic-in2 <id>      Second microphone summed with --mic-in into the mic output (optional)");
+    eprintln!("  --mic-gain-db <dB>  Gain of --mic-in before mixing, at most +12 (default: 0)");
+    eprintln!("  --mic-in2-gain-db <dB>  Gain of --mic-in2 before mixing, at most +12 (default: 0)");
+    eprintln!("  --mic-follow-default  With a \"default:\" mic input, switch to the new default as soon");
+    eprintln!("                      as Windows changes it; device IDs and names stay pinned");
+    eprintln!("  --profile <low-latency|balanced|reliable>  Preset for --buffer, --render-chunk-ms and");
+    eprintln!("                      the recovery options; given options still override it. low-latency:");
+    eprintln!("                      3 ms buffer, quick recovery; reliable: 50 ms buffer, 10 ms chunks,");
+    eprintln!("                      20 recovery attempts up to 30 s apart (default: balanced)");
+    eprintln!("  --buffer <ms>       Buffer size in milliseconds, 1 to 2000 (default: 10). Sizes the");
+    eprintln!("                      ring buffer that absorbs capture bursts");
+    eprintln!("  --prefill-ms <ms>   Silence queued on the output at start, which sets the steady-state");
+    eprintln!("                      latency, 1 to 2000 (default: the buffer size). Only as much as fits");
+    eprintln!("                      in the device buffer is queued");
+    eprintln!("  --max-fill-ms <ms>  When the ring buffer stays above <ms> for half a second, skip");
+    eprintln!("                      back to the prefill instead of keeping the extra latency");
+    eprintln!("                      (default: off; must be above the prefill)");
+    eprintln!("  --device-buffer-ms <ms>  WASAPI buffer of each capture and render stream, 1 to 2000");
+    eprintln!("                      (default: 10). Windows may round it up to its engine period");
+    eprintln!("  --glitch-dump <dir> Write a WAV snapshot of recent speaker audio to <dir> on overflow,");
+    eprintln!("                      underrun or discontinuity (default: off)");
+    eprintln!("  --glitch-dump-secs <s>  Seconds of audio kept for glitch dumps (default: 5)");
+    eprintln!("  --drain-ms <ms>     Time allowed to play out buffered audio on shutdown (default: 200)");
+    eprintln!("  --start-fade-ms <ms>  Fade in the first audio after an output (re)opens, to avoid");
+    eprintln!("                      a click (default: 10, 0 disables)");
+    eprintln!("  --render-chunk-ms <ms>  Batch render writes into chunks of at least <ms>, trading");
+    eprintln!("                      a little latency for much lower CPU use (default: 0, off)");
+    eprintln!("  --capture-chunk-frames <n>  Read at most <n> frames from the capture device at a");
+    eprintln!("                      time, for a smoother flow into the ring buffer at the cost of");
+    eprintln!("                      more loop iterations (default: 0, all that's available)");
+    eprintln!("  --upmix <silent|duplicate>  Fill for output channels the input lacks (e.g. stereo to");
+    eprintln!("                      5.1): silent keeps the stereo image, duplicate copies the first");
+    eprintln!("                      channel into all of them (default: silent)");
+    eprintln!("  --lfe-downmix-db <dB>  Fold the LFE (subwoofer) channel into left/right at this level");
+    eprintln!("                      when downmixing surround, at most +10 (default: left out)");
+    eprintln!("  --channel-map <map>  Route speaker capture channels to output channels instead of");
+    eprintln!("                      up/downmixing, as <capture>:<render> pairs counted from 0,");
+    eprintln!("                      e.g. 0:2,1:3; unmapped output channels are silent, and an");
+    eprintln!("                      output the map doesn't fit gets the default up/downmix");
+    eprintln!("  --resample-quality <linear|sinc|best>  Resampler for mismatched sample rates");
+    eprintln!("                      (default: sinc); best needs a build with the src-libsamplerate");
+    eprintln!("                      feature and adds about 3ms of latency");
+    eprintln!("  --no-resample-clamp  Let sinc resampling ring past full scale instead of clamping");
+    eprintln!("                      it to ±1.0, for measurements");
+    eprintln!("  --output-backend <wasapi|asio>  Speaker output API (default: wasapi); with asio,");
+    eprintln!("                      --speaker-out is the ASIO driver name");
+    eprintln!("  --dither <none|tpdf|shaped>  Dither for outputs that take 16-bit samples (ASIO");
+    eprintln!("                      drivers); shaped moves the noise up in frequency, none keeps");
+    eprintln!("                      the conversion bit-exact (default: tpdf)");
+    eprintln!("  --output-category <game|media|comms>  Audio session category of the speaker output,");
+    eprintln!("                      which decides Windows' ducking and effects (default: media)");
+    eprintln!("  --force-rate <hz>   Open the speaker output at this sample rate, which Windows");
+    eprintln!("                      resamples to the device's mix rate if needed; a device that");
+    eprintln!("                      refuses it opens at the closest rate it supports");
+    eprintln!("  --strict-rate       Fail instead of using another rate than --force-rate");
+    eprintln!("  --swap-lr           Swap the left and right channels of the speaker output, for a");
+    eprintln!("                      device or cable wired the wrong way round");
+    eprintln!("  --ab-loudness-match  Trim output B to the level output A plays at, so toggling");
+    eprintln!("                      between them compares like with like; changes B's output level");
+    eprintln!("  --com-model <mta|sta>  COM threading model of the audio and IPC threads (default: mta,");
+    eprintln!("                      recommended standalone); sta is for hosts whose threads are");
+    eprintln!("                      already single-threaded apartments");
+    eprintln!("  --force             Start even if an input and its output are the same device");
+    eprintln!("  --capture-only      Only capture: discard the input audio and log its level every");
+    eprintln!("                      second, to check the inputs without the outputs");
+    eprintln!("  --render-only       Only render: play a 440 Hz test tone instead of the inputs, to");
+    eprintln!("                      check the outputs without the inputs");
+    eprintln!("  --no-convert        Never resample or remix: stop a stream whose capture and render");
+    eprintln!("                      formats differ instead (see GetFormats for what was negotiated)");
+    eprintln!("  --keep-alive        Play inaudible noise instead of digital silence while idle, for");
+    eprintln!("                      receivers that mute on silence (default: off)");
+    eprintln!("  --keep-alive-db <dB>  Keep-alive noise level in dBFS (default: -80)");
+    eprintln!("  --silence-threshold-db <dB>  Input peak level below which audio counts as silence,");
+    eprintln!("                      set above the device's noise floor (default: -60)");
+    eprintln!("  --clip-ceiling-db <dB>  Input level counted as clipping in the metrics (default: 0)");
+    eprintln!("  --max-output-db <dB>  Hard cap on the combined gain of a render path (EQ boosts,");
+    eprintln!("                      mic gain, LFE fold-in); anything beyond it is taken off the");
+    eprintln!("                      whole signal (default: +12)");
+    eprintln!("  --process-block-frames <n>  Pass captured audio on in fixed blocks of <n> frames");
+    eprintln!("                      instead of whatever each device read returns (default: 0, off)");
+    eprintln!("  --max-recovery-attempts <n>  Consecutive stream errors before giving up (default: 5)");
+    eprintln!("  --recovery-backoff-ms <ms>   Delay before the first recovery attempt, doubling on");
+    eprintln!("                      each further failure (default: 250)");
+    eprintln!("  --recovery-max-backoff-ms <ms>  Upper bound for the recovery delay (default: 4000)");
+    eprintln!("  --measure-latency   Play a test chirp to --speaker-out, find it in --speaker-in and");
+    eprintln!("                      print the round-trip latency (output must be looped back to input)");
+    eprintln!("  --metrics-addr <host:port>  Serve Prometheus metrics at http://<host:port>/metrics");
+    eprintln!("                      (default: off)");
+    eprintln!("  --status-interval <s>  Log a status line every <s> seconds: buffer fill, latency,");
+    eprintln!("                      over/underruns and recoveries since the last line, and loop");
+    eprintln!("                      health, as key=value pairs (default: off)");
+    eprintln!("  --rtp-out <host:port>  Also stream the speaker capture as RTP over UDP to <host:port>,");
+    eprintln!("                      as 48 kHz stereo; the SDP for the receiver is logged at startup");
+    eprintln!("  --rtp-codec <l16|opus>  RTP payload (default: l16, uncompressed); opus needs a build");
+    eprintln!("                      with the rtp-opus feature");
+    eprintln!("  --ipc-tcp <addr>    Also accept IPC commands over TCP (e.g. 0.0.0.0:51234), for");
+    eprintln!("                      remote control from another PC; requires --ipc-token");
+    eprintln!("  --ipc-token <token>  Shared secret every TCP command must carry");
+    eprintln!("  --instance <name>   Listen on pipe GAutoSwitchAudioProxy_<name> instead of the");
+    eprintln!("                      default one, to run several proxies side by side");
+    eprintln!("  --debug-commands    Accept IPC commands for testing, like InjectSilence, which");
+    eprintln!("                      fakes a speaker source stall");
+    eprintln!("  --list-instances    Print the running proxies with their status");
+    eprintln!("  --list-devices      Print the render and capture devices with their IDs and indices");
+    eprintln!("  --device-formats <id>  Print the common formats a device supports in shared and");
+    eprintln!("                      exclusive mode");
+    eprintln!("  --version, -V       Print the version, git commit and target, then exit");
+    eprintln!();
+    eprintln!("Devices can be given by ID, by name, or as \"index:<n>\" (position in --list-devices).");
+    eprintln!("Indices change when devices are added or removed, so use them for quick tests only.");
+    eprintln!("\"default:console\" and \"default:comms\" pick the current Windows default device for");
+    eprintln!("general audio or for calls, looked up again whenever the stream is reopened.");
     eprintln!();
     eprintln!("Legacy usage (deprecated):");
     eprintln!("  audio-proxy <input_device_id> <output_device_id> [buffer_ms]");
 }
 
+/// Print render and capture devices in `index:` selector order
+fn print_device_list() -> Result<()> {
+    let sections = [
+        (
+            "Render devices (--speaker-out, --mic-out)",
+            list_render_endpoints()?,
+            default_endpoint_ids(default_render_endpoint),
+        ),
+        (
+            "Capture devices (--speaker-in, --mic-in)",
+            list_capture_endpoints()?,
+            default_endpoint_ids(default_capture_endpoint),
+        ),
+    ];
+    for (title, endpoints, defaults) in sections {
+        println!("{}:", title);
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let selectors: Vec<&str> = defaults.iter()
+                .filter(|(_, id)| *id == endpoint.id)
+                .map(|(role, _)| role.selector())
+                .collect();
+            if selectors.is_empty() {
+                println!("  [{}] {}", index, endpoint.name);
+            } else {
+                println!("  [{}] {}  ({})", index, endpoint.name, selectors.join(", "));
+            }
+            println!("      {}", endpoint.id);
+        }
+        println!();
+    }
+    println!("Indices change when devices are added or removed; prefer IDs or names in saved configs.");
+    Ok(())
+}
+
+/// Print the formats a device accepts, for each direction it exists in
+fn print_device_formats(device_id: &str) -> Result<()> {
+    let mut not_found = None;
+    let mut found = false;
+    for direction in [DeviceDirection::Render, DeviceDirection::Capture] {
+        let formats = match probe_supported_formats(device_id, direction) {
+            Ok(formats) => formats,
+            Err(e @ StreamError::DeviceNotFound { .. }) => {
+                not_found = Some(e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        found = true;
+
+        println!("{:?} formats of {} (16/24-bit integer, 32-bit float):", direction, device_id);
+        if formats.is_empty() {
+            println!("  none of the probed formats");
+        }
+        for supported in &formats {
+            let modes = match (supported.shared, supported.exclusive) {
+                (true, true) => "shared, exclusive",
+                (true, false) => "shared",
+                _ => "exclusive",
+            };
+            println!("  {:<24} {}", supported.format.to_string(), modes);
+        }
+        println!();
+    }
+
+    match not_found {
+        Some(e) if !found => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Print the running proxies and what each is playing to
+fn print_instance_list() -> Result<()> {
+    let instances = IpcClient::list_instances()?;
+    if instances.is_empty() {
+        println!("No audio proxies running.");
+    }
+    for instance in instances {
+        let name = instance.name.as_deref().unwrap_or("(default)");
+        match instance.status {
+            Some(status) => println!(
+                "  {:<24} {:<8} {}",
+                name,
+                if status.paused == Some(true) { "paused" } else { "playing" },
+                status.output_device.unwrap_or_default(),
+            ),
+            None => println!("  {:<24} not responding", name),
+        }
+    }
+    Ok(())
+}
+
+/// IDs of the current default endpoints per role. Missing defaults (e.g. no capture
+/// devices at all) are left out, so they just go unmarked in the listing.
+fn default_endpoint_ids<E>(lookup: impl Fn(DefaultRole) -> std::result::Result<EndpointInfo, E>) -> Vec<(DefaultRole, String)> {
+    DefaultRole::ALL.iter()
+        .filter_map(|&role| lookup(role).ok().map(|endpoint| (role, endpoint.id)))
+        .collect()
+}
+
 fn parse_args() -> Result<Args> {
     let args: Vec<String> = std::env::args().collect();
 
     // Check for legacy positional arguments (backwards compatibility)
     if args.len() >= 3 && !args[1].starts_with("--") {
-        let buffer_ms = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_BUFFER_MS);
-        return Ok(Args {
+        let buffer_ms = match args.get(3) {
+            Some(val) => val.parse().map_err(|_| anyhow::anyhow!("Invalid value for --buffer: {}", val))?,
+            None => Profile::default().preset().buffer_ms,
+        };
+        let config = ProxyConfig {
             speaker_in: args[1].clone(),
             speaker_out: args[2].clone(),
-            mic_in: None,
-            mic_out: None,
             buffer_ms,
-        });
+            prefill_ms: buffer_ms,
+            ipc_pipe: true,
+            ..Default::default()
+        };
+        config.validate()?;
+        return Ok(Args { config, profile: Profile::default(), measure_latency: false, status_interval: None });
     }
 
     // Parse named arguments
     let mut speaker_in: Option<String> = None;
+    let mut speaker_in2: Option<String> = None;
     let mut speaker_out: Option<String> = None;
     let mut mic_in: Option<String> = None;
+    let mut mic_in2: Option<String> = None;
     let mut mic_out: Option<String> = None;
-    let mut buffer_ms = DEFAULT_BUFFER_MS;
+    let mut mic_gain_db = 0.0;
+    let mut mic_in2_gain_db = 0.0;
+    let mut mic_follow_default = false;
+    let mut profile = Profile::default();
+    // Options a profile sets; None until given explicitly
+    let mut buffer_ms: Option<u32> = None;
+    let mut prefill_ms: Option<u32> = None;
+    let mut max_fill_ms: Option<u32> = None;
+    let mut device_buffer_ms = DEFAULT_DEVICE_BUFFER_MS;
+    let mut glitch_dump_dir: Option<PathBuf> = None;
+    let mut glitch_dump_secs = DEFAULT_GLITCH_DUMP_SECS;
+    let mut drain_ms = DEFAULT_DRAIN_MS;
+    let mut start_fade_ms = DEFAULT_START_FADE_MS;
+    let mut render_chunk_ms: Option<u32> = None;
+    let mut capture_chunk_frames = 0;
+    let mut upmix = UpmixMode::default();
+    let mut lfe_downmix_db: Option<f32> = None;
+    let mut channel_map: Option<ChannelMap> = None;
+    let mut resample_quality = ResampleQuality::default();
+    let mut no_resample_clamp = false;
+    let mut output_backend = OutputBackend::Wasapi;
+    let mut dither = DitherMode::default();
+    let mut output_category = StreamCategory::Media;
+    let mut force_rate: Option<u32> = None;
+    let mut strict_rate = false;
+    let mut swap_lr = false;
+    let mut ab_loudness_match = false;
+    let mut com_model = ComModel::default();
+    let mut force = false;
+    let mut auto = false;
+    let mut capture_only = false;
+    let mut render_only = false;
+    let mut no_convert = false;
+    let mut keep_alive = false;
+    let mut keep_alive_db = DEFAULT_KEEP_ALIVE_DB;
+    let mut silence_threshold_db = DEFAULT_SILENCE_THRESHOLD_DB;
+    let mut clip_ceiling_db = 0.0;
+    let mut max_output_db = DEFAULT_MAX_OUTPUT_DB;
+    let mut process_block_frames = 0;
+    let mut max_recovery_attempts: Option<u32> = None;
+    let mut recovery_backoff_ms: Option<u64> = None;
+    let mut recovery_max_backoff_ms: Option<u64> = None;
+    let mut measure_latency = false;
+    let mut status_interval = None;
+    let mut metrics_addr: Option<String> = None;
+    let mut rtp_out: Option<String> = None;
+    let mut rtp_codec = RtpCodec::default();
+    let mut ipc_tcp: Option<String> = None;
+    let mut ipc_token: Option<String> = None;
+    let mut debug_commands = false;
+    let mut instance: Option<String> = None;
 
     let mut i = 1;
     while i < args.len() {
@@ -126,6 +604,10 @@ fn parse_args() -> Result<Args> {
                 i += 1;
                 speaker_in = args.get(i).cloned();
             }
+            "--speaker-in2" => {
+                i += 1;
+                speaker_in2 = args.get(i).cloned();
+            }
             "--speaker-out" => {
                 i += 1;
                 speaker_out = args.get(i).cloned();
@@ -138,756 +620,489 @@ fn parse_args() -> Result<Args> {
                 i += 1;
                 mic_out = args.get(i).cloned();
             }
-            "--buffer" => {
+            "--mic-in2" => {
+                i += 1;
+                mic_in2 = args.get(i).cloned();
+            }
+            "--mic-gain-db" => {
                 i += 1;
                 if let Some(val) = args.get(i) {
-                    buffer_ms = val.parse().unwrap_or(DEFAULT_BUFFER_MS);
+                    mic_gain_db = val.parse().unwrap_or(0.0);
                 }
             }
-            "--help" | "-h" => {
-                print_usage();
-                std::process::exit(0);
+            "--mic-in2-gain-db" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    mic_in2_gain_db = val.parse().unwrap_or(0.0);
+                }
             }
-            _ => {
-                return Err(anyhow::anyhow!("Unknown argument: {}", args[i]));
+            "--mic-follow-default" => {
+                mic_follow_default = true;
             }
-        }
-        i += 1;
-    }
-
-    let speaker_in = speaker_in.ok_or_else(|| anyhow::anyhow!("Missing required argument: --speaker-in"))?;
-    let speaker_out = speaker_out.ok_or_else(|| anyhow::anyhow!("Missing required argument: --speaker-out"))?;
-
-    Ok(Args {
-        speaker_in,
-        speaker_out,
-        mic_in,
-        mic_out,
-        buffer_ms,
-    })
-}
-
-/// Shared state for microphone proxy
-struct MicState {
-    buffer: Arc<AudioRingBuffer>,
-    input_id: Arc<RwLock<String>>,
-    output_id: String,
-    enabled: Arc<AtomicBool>,
-    capture_format: Arc<RwLock<Option<AudioFormat>>>,
-}
-
-fn run_proxy(args: &Args) -> Result<()> {
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
-
-    // Set up Ctrl+C handler
-    ctrlc_handler(running.clone());
-
-    // Calculate buffer size in samples (estimate - actual format comes from device)
-    let buffer_samples = (DEFAULT_SAMPLE_RATE * args.buffer_ms / 1000) as usize * DEFAULT_CHANNELS as usize;
-
-    // Create ring buffer for speaker audio data
-    let speaker_buffer = Arc::new(AudioRingBuffer::new(buffer_samples * 4));
-
-    // Create output device ID holder for hot-swapping
-    let current_output_id = Arc::new(RwLock::new(args.speaker_out.clone()));
-
-    // Shared capture format so render thread can do conversion if needed
-    let speaker_capture_format: Arc<RwLock<Option<AudioFormat>>> = Arc::new(RwLock::new(None));
-
-    // Create mic state if mic proxy is configured
-    let mic_state = if let (Some(mic_in), Some(mic_out)) = (&args.mic_in, &args.mic_out) {
-        let mic_buffer = Arc::new(AudioRingBuffer::new(buffer_samples * 4));
-        Some(MicState {
-            buffer: mic_buffer,
-            input_id: Arc::new(RwLock::new(mic_in.clone())),
-            output_id: mic_out.clone(),
-            enabled: Arc::new(AtomicBool::new(true)),
-            capture_format: Arc::new(RwLock::new(None)),
-        })
-    } else {
-        None
-    };
-
-    // Start IPC server
-    let ipc_running = running.clone();
-    let ipc_output_id = current_output_id.clone();
-    let ipc_mic_input_id = mic_state.as_ref().map(|s| s.input_id.clone());
-    let ipc_mic_enabled = mic_state.as_ref().map(|s| s.enabled.clone());
-    let _ipc_handle = thread::spawn(move || {
-        if let Err(e) = run_ipc_server(ipc_running, ipc_output_id, ipc_mic_input_id, ipc_mic_enabled) {
-            error!("IPC server error: {}", e);
-        }
-    });
-
-    // Start speaker capture thread
-    let capture_running = running.clone();
-    let capture_buffer = speaker_buffer.clone();
-    let capture_input_id = args.speaker_in.clone();
-    let capture_format_shared = speaker_capture_format.clone();
-    let capture_handle = thread::spawn(move || {
-        unsafe {
-            if CoInitializeEx(None, COINIT_MULTITHREADED).is_err() {
-                error!("Failed to initialize COM in speaker capture thread");
-                return;
+            "--profile" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --profile"))?;
+                profile = Profile::parse(val)?;
             }
-        }
-
-        if let Err(e) = run_speaker_capture_loop(
-            &capture_input_id, capture_buffer, capture_running, capture_format_shared,
-        ) {
-            error!("Speaker capture loop error: {}", e);
-        }
-
-        unsafe { CoUninitialize(); }
-    });
-
-    // Start speaker render thread
-    let render_running = running.clone();
-    let render_buffer = speaker_buffer.clone();
-    let render_output_id = current_output_id.clone();
-    let render_capture_format = speaker_capture_format.clone();
-    let buffer_ms = args.buffer_ms;
-    let render_handle = thread::spawn(move || {
-        unsafe {
-            if CoInitializeEx(None, COINIT_MULTITHREADED).is_err() {
-                error!("Failed to initialize COM in speaker render thread");
-                return;
+            "--buffer" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --buffer"))?;
+                buffer_ms = Some(val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --buffer: {}", val))?);
             }
-        }
-
-        if let Err(e) = run_speaker_render_loop(
-            render_buffer, render_output_id, render_running, buffer_ms, render_capture_format,
-        ) {
-            error!("Speaker render loop error: {}", e);
-        }
-
-        unsafe { CoUninitialize(); }
-    });
-
-    // Start mic threads if configured
-    let mic_handles = if let Some(ref mic) = mic_state {
-        let mic_capture_running = running.clone();
-        let mic_capture_buffer = mic.buffer.clone();
-        let mic_capture_input_id = mic.input_id.clone();
-        let mic_capture_enabled = mic.enabled.clone();
-        let mic_capture_format = mic.capture_format.clone();
-        let mic_capture_handle = thread::spawn(move || {
-            unsafe {
-                if CoInitializeEx(None, COINIT_MULTITHREADED).is_err() {
-                    error!("Failed to initialize COM in mic capture thread");
-                    return;
-                }
+            "--prefill-ms" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --prefill-ms"))?;
+                prefill_ms = Some(val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --prefill-ms: {}", val))?);
             }
-
-            if let Err(e) = run_mic_capture_loop(
-                mic_capture_input_id, mic_capture_buffer, mic_capture_running,
-                mic_capture_enabled, mic_capture_format,
-            ) {
-                error!("Mic capture loop error: {}", e);
+            "--max-fill-ms" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --max-fill-ms"))?;
+                max_fill_ms = Some(val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --max-fill-ms: {}", val))?);
             }
-
-            unsafe { CoUninitialize(); }
-        });
-
-        let mic_render_running = running.clone();
-        let mic_render_buffer = mic.buffer.clone();
-        let mic_render_output_id = mic.output_id.clone();
-        let mic_render_enabled = mic.enabled.clone();
-        let mic_render_capture_format = mic.capture_format.clone();
-        let mic_render_handle = thread::spawn(move || {
-            unsafe {
-                if CoInitializeEx(None, COINIT_MULTITHREADED).is_err() {
-                    error!("Failed to initialize COM in mic render thread");
-                    return;
-                }
+            "--device-buffer-ms" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --device-buffer-ms"))?;
+                device_buffer_ms = val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --device-buffer-ms: {}", val))?;
             }
-
-            if let Err(e) = run_mic_render_loop(
-                &mic_render_output_id, mic_render_buffer, mic_render_running,
-                mic_render_enabled, buffer_ms, mic_render_capture_format,
-            ) {
-                error!("Mic render loop error: {}", e);
+            "--glitch-dump" => {
+                i += 1;
+                glitch_dump_dir = args.get(i).map(PathBuf::from);
             }
-
-            unsafe { CoUninitialize(); }
-        });
-
-        Some((mic_capture_handle, mic_render_handle))
-    } else {
-        None
-    };
-
-    // Wait for shutdown signal
-    while running_clone.load(Ordering::SeqCst) {
-        thread::sleep(Duration::from_millis(100));
-    }
-
-    info!("Shutting down...");
-
-    // Wait for audio threads to finish (they check the running flag)
-    let _ = capture_handle.join();
-    let _ = render_handle.join();
-    if let Some((mic_capture, mic_render)) = mic_handles {
-        let _ = mic_capture.join();
-        let _ = mic_render.join();
-    }
-    // IPC thread is detached (_ipc_handle dropped) - it may be blocked in
-    // ConnectNamedPipe, so we let it be cleaned up on process exit.
-
-    info!("Audio Proxy stopped.");
-    Ok(())
-}
-
-// ── Audio format conversion utilities ──────────────────────────────────────
-
-/// Convert channel count: upmix, downmix, or passthrough
-fn convert_channels(input: &[f32], in_ch: usize, out_ch: usize, output: &mut Vec<f32>) {
-    let frames = input.len() / in_ch;
-    output.clear();
-    output.reserve(frames * out_ch);
-
-    for frame in 0..frames {
-        let in_start = frame * in_ch;
-        if out_ch <= in_ch {
-            // Downmix: take first out_ch channels (simple truncation)
-            // For stereo->mono, average L+R
-            if in_ch == 2 && out_ch == 1 {
-                output.push((input[in_start] + input[in_start + 1]) * 0.5);
-            } else {
-                for ch in 0..out_ch {
-                    output.push(input[in_start + ch]);
+            "--glitch-dump-secs" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    glitch_dump_secs = val.parse().unwrap_or(DEFAULT_GLITCH_DUMP_SECS);
                 }
             }
-        } else {
-            // Upmix: copy available channels, duplicate first for the rest
-            for ch in 0..out_ch {
-                if ch < in_ch {
-                    output.push(input[in_start + ch]);
-                } else {
-                    output.push(input[in_start]); // duplicate first channel
+            "--drain-ms" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    drain_ms = val.parse().unwrap_or(DEFAULT_DRAIN_MS);
                 }
             }
-        }
-    }
-}
-
-/// Resample audio using linear interpolation
-fn resample(input: &[f32], in_rate: u32, out_rate: u32, channels: usize, output: &mut Vec<f32>) {
-    let in_frames = input.len() / channels;
-    if in_frames == 0 {
-        output.clear();
-        return;
-    }
-
-    let ratio = out_rate as f64 / in_rate as f64;
-    let out_frames = (in_frames as f64 * ratio).ceil() as usize;
-    output.clear();
-    output.reserve(out_frames * channels);
-
-    for frame in 0..out_frames {
-        let src_pos = frame as f64 / ratio;
-        let src_idx = src_pos as usize;
-        let frac = (src_pos - src_idx as f64) as f32;
-
-        let idx0 = src_idx.min(in_frames - 1);
-        let idx1 = (src_idx + 1).min(in_frames - 1);
-
-        for ch in 0..channels {
-            let s0 = input[idx0 * channels + ch];
-            let s1 = input[idx1 * channels + ch];
-            output.push(s0 + frac * (s1 - s0));
-        }
-    }
-}
-
-/// Check if two formats need conversion
-fn formats_need_conversion(cap: &AudioFormat, rnd: &AudioFormat) -> bool {
-    cap.sample_rate != rnd.sample_rate || cap.channels != rnd.channels
-}
-
-/// Convert audio from capture format to render format.
-/// Uses pre-allocated scratch buffer to avoid repeated allocations.
-fn convert_audio(
-    input: &[f32],
-    cap_fmt: &AudioFormat,
-    rnd_fmt: &AudioFormat,
-    scratch: &mut Vec<f32>,
-) -> Vec<f32> {
-    let mut current = input;
-    let mut temp = Vec::new();
-
-    // Channel conversion first (if needed)
-    if cap_fmt.channels != rnd_fmt.channels {
-        convert_channels(current, cap_fmt.channels as usize, rnd_fmt.channels as usize, scratch);
-        std::mem::swap(scratch, &mut temp);
-        current = &temp;
-    }
-
-    // Then resample (if needed)
-    if cap_fmt.sample_rate != rnd_fmt.sample_rate {
-        resample(current, cap_fmt.sample_rate, rnd_fmt.sample_rate, rnd_fmt.channels as usize, scratch);
-        return std::mem::take(scratch);
-    }
-
-    current.to_vec()
-}
-
-// ── Stream creation with error recovery ────────────────────────────────────
-
-fn create_and_start_capture(device_id: &str) -> Result<CaptureStream> {
-    let mut capture = CaptureStream::new(device_id)
-        .context("Failed to create capture stream")?;
-    capture.start().context("Failed to start capture")?;
-    Ok(capture)
-}
-
-fn create_and_start_render(device_id: &str) -> Result<RenderStream> {
-    let mut render = RenderStream::new(device_id)
-        .context("Failed to create render stream")?;
-    render.start().context("Failed to start render")?;
-    Ok(render)
-}
-
-// ── Speaker loops ──────────────────────────────────────────────────────────
-
-fn run_speaker_capture_loop(
-    input_device_id: &str,
-    buffer: Arc<AudioRingBuffer>,
-    running: Arc<AtomicBool>,
-    capture_format: Arc<RwLock<Option<AudioFormat>>>,
-) -> Result<()> {
-    info!("Starting speaker capture from device: {}", input_device_id);
-
-    let mut capture = create_and_start_capture(input_device_id)?;
-
-    // Share the format with the render thread
-    if let Some(fmt) = capture.format() {
-        *capture_format.write().unwrap() = Some(fmt.clone());
-    }
-
-    let mut temp_buffer = vec![0.0f32; 4096];
-    let mut error_count: u32 = 0;
-
-    while running.load(Ordering::SeqCst) {
-        match capture.read(&mut temp_buffer) {
-            Ok(samples_read) if samples_read > 0 => {
-                error_count = 0;
-                let written = buffer.write(&temp_buffer[..samples_read]);
-                if written < samples_read {
-                    warn!("Speaker ring buffer overflow: {} samples dropped", samples_read - written);
+            "--start-fade-ms" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    start_fade_ms = val.parse().unwrap_or(DEFAULT_START_FADE_MS);
                 }
             }
-            Ok(_) => {
-                thread::sleep(Duration::from_micros(500));
+            "--render-chunk-ms" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    render_chunk_ms = val.parse().ok();
+                }
             }
-            Err(e) => {
-                error_count += 1;
-                error!("Speaker capture error (attempt {}): {}", error_count, e);
-
-                if error_count >= MAX_RECOVERY_ATTEMPTS {
-                    return Err(e.context("Too many consecutive capture errors, giving up"));
+            "--capture-chunk-frames" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --capture-chunk-frames"))?;
+                capture_chunk_frames = val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --capture-chunk-frames: {}", val))?;
+            }
+            "--upmix" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --upmix"))?;
+                upmix = UpmixMode::parse(val)?;
+            }
+            "--lfe-downmix-db" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    lfe_downmix_db = val.parse().ok();
                 }
-
-                warn!("Attempting to recover speaker capture stream...");
-                thread::sleep(Duration::from_secs(1));
-                match create_and_start_capture(input_device_id) {
-                    Ok(new_capture) => {
-                        capture = new_capture;
-                        if let Some(fmt) = capture.format() {
-                            *capture_format.write().unwrap() = Some(fmt.clone());
-                        }
-                        info!("Speaker capture stream recovered");
-                    }
-                    Err(e) => {
-                        error!("Failed to recover speaker capture: {}", e);
-                    }
+            }
+            "--channel-map" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --channel-map"))?;
+                channel_map = Some(ChannelMap::parse(val)?);
+            }
+            "--resample-quality" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --resample-quality"))?;
+                resample_quality = ResampleQuality::parse(val)?;
+            }
+            "--no-resample-clamp" => {
+                no_resample_clamp = true;
+            }
+            "--output-backend" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --output-backend"))?;
+                output_backend = OutputBackend::parse(val)?;
+            }
+            "--dither" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --dither"))?;
+                dither = DitherMode::parse(val)?;
+            }
+            "--output-category" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --output-category"))?;
+                output_category = StreamCategory::parse(val)?;
+            }
+            "--force-rate" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --force-rate"))?;
+                force_rate = Some(val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --force-rate: {}", val))?);
+            }
+            "--strict-rate" => {
+                strict_rate = true;
+            }
+            "--swap-lr" => {
+                swap_lr = true;
+            }
+            "--ab-loudness-match" => {
+                ab_loudness_match = true;
+            }
+            "--com-model" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --com-model"))?;
+                com_model = ComModel::parse(val)?;
+            }
+            "--force" => {
+                force = true;
+            }
+            "--auto" => {
+                auto = true;
+            }
+            "--capture-only" => {
+                capture_only = true;
+            }
+            "--render-only" => {
+                render_only = true;
+            }
+            "--no-convert" => {
+                no_convert = true;
+            }
+            "--keep-alive" => {
+                keep_alive = true;
+            }
+            "--keep-alive-db" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    keep_alive_db = val.parse().unwrap_or(DEFAULT_KEEP_ALIVE_DB);
                 }
             }
-        }
-    }
-
-    capture.stop()?;
-    info!("Speaker capture loop stopped.");
-    Ok(())
-}
-
-fn run_speaker_render_loop(
-    buffer: Arc<AudioRingBuffer>,
-    output_device_id: Arc<RwLock<String>>,
-    running: Arc<AtomicBool>,
-    buffer_ms: u32,
-    capture_format: Arc<RwLock<Option<AudioFormat>>>,
-) -> Result<()> {
-    let device_id = output_device_id.read().unwrap().clone();
-    info!("Starting speaker render to device: {}", device_id);
-
-    let mut render = create_and_start_render(&device_id)?;
-    let mut current_device_id = device_id;
-    let mut temp_buffer = vec![0.0f32; 4096];
-    let mut conversion_scratch = Vec::new();
-    let mut error_count: u32 = 0;
-
-    // Pre-fill buffer with silence
-    let render_channels = render.format().map(|f| f.channels as usize).unwrap_or(2);
-    let render_rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
-    let prefill_samples = (render_rate * buffer_ms / 1000) as usize * render_channels;
-    let silence = vec![0.0f32; prefill_samples];
-    let _ = render.write(&silence);
-
-    while running.load(Ordering::SeqCst) {
-        // Check if output device changed (hot-swap)
-        {
-            let new_device_id = output_device_id.read().unwrap().clone();
-            if new_device_id != current_device_id {
-                info!("Switching speaker output to: {}", new_device_id);
-                render.stop()?;
-
-                match create_and_start_render(&new_device_id) {
-                    Ok(new_render) => {
-                        render = new_render;
-                        current_device_id = new_device_id;
-                        error_count = 0;
-                        info!("Speaker output switched successfully");
-                    }
-                    Err(e) => {
-                        error!("Failed to switch speaker output: {}", e);
-                        // Try to restart with old device
-                        render = create_and_start_render(&current_device_id)
-                            .context("Failed to restart render with previous device")?;
-                    }
+            "--silence-threshold-db" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    silence_threshold_db = val.parse().unwrap_or(DEFAULT_SILENCE_THRESHOLD_DB);
                 }
             }
-        }
-
-        // Read from ring buffer and write to output
-        let samples_read = buffer.read(&mut temp_buffer);
-        if samples_read > 0 {
-            // Check if format conversion is needed
-            let cap_fmt = capture_format.read().unwrap().clone();
-            let rnd_fmt = render.format().cloned();
-
-            let write_result = if let (Some(ref cf), Some(ref rf)) = (cap_fmt, rnd_fmt) {
-                if formats_need_conversion(cf, rf) {
-                    let converted = convert_audio(
-                        &temp_buffer[..samples_read], cf, rf, &mut conversion_scratch,
-                    );
-                    render.write(&converted)
-                } else {
-                    render.write(&temp_buffer[..samples_read])
+            "--clip-ceiling-db" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    clip_ceiling_db = val.parse::<f32>().unwrap_or(0.0).min(0.0);
                 }
-            } else {
-                render.write(&temp_buffer[..samples_read])
-            };
-
-            if let Err(e) = write_result {
-                error_count += 1;
-                error!("Speaker render error (attempt {}): {}", error_count, e);
-
-                if error_count >= MAX_RECOVERY_ATTEMPTS {
-                    return Err(e.context("Too many consecutive render errors, giving up"));
+            }
+            "--max-output-db" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --max-output-db"))?;
+                max_output_db = val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --max-output-db: {}", val))?;
+            }
+            "--process-block-frames" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    process_block_frames = val.parse().unwrap_or(0);
                 }
-
-                warn!("Attempting to recover speaker render stream...");
-                thread::sleep(Duration::from_secs(1));
-                match create_and_start_render(&current_device_id) {
-                    Ok(new_render) => {
-                        render = new_render;
-                        info!("Speaker render stream recovered");
-                    }
-                    Err(re) => {
-                        error!("Failed to recover speaker render: {}", re);
-                    }
+            }
+            "--max-recovery-attempts" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    max_recovery_attempts = val.parse().ok().map(|n: u32| n.max(1));
                 }
-            } else {
-                error_count = 0;
-            }
-        } else {
-            // No data available - write silence to prevent underrun
-            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
-            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
-            let silence_samples = (rate / 1000) as usize * ch; // 1ms of silence
-            let silence = vec![0.0f32; silence_samples];
-            let _ = render.write(&silence);
-            thread::sleep(Duration::from_micros(500));
-        }
-    }
-
-    render.stop()?;
-    info!("Speaker render loop stopped.");
-    Ok(())
-}
-
-// ── Microphone loops ───────────────────────────────────────────────────────
-
-fn run_mic_capture_loop(
-    mic_input_id: Arc<RwLock<String>>,
-    buffer: Arc<AudioRingBuffer>,
-    running: Arc<AtomicBool>,
-    mic_enabled: Arc<AtomicBool>,
-    capture_format: Arc<RwLock<Option<AudioFormat>>>,
-) -> Result<()> {
-    let device_id = mic_input_id.read().unwrap().clone();
-    info!("Starting mic capture from device: {}", device_id);
-
-    let mut capture = create_and_start_capture(&device_id)?;
-
-    if let Some(fmt) = capture.format() {
-        *capture_format.write().unwrap() = Some(fmt.clone());
-    }
-
-    let mut current_device_id = device_id;
-    let mut temp_buffer = vec![0.0f32; 4096];
-    let mut error_count: u32 = 0;
-
-    while running.load(Ordering::SeqCst) {
-        if !mic_enabled.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_millis(50));
-            continue;
-        }
-
-        // Check if input device changed (hot-swap)
-        {
-            let new_device_id = mic_input_id.read().unwrap().clone();
-            if new_device_id != current_device_id {
-                info!("Switching mic input to: {}", new_device_id);
-                capture.stop()?;
-
-                match create_and_start_capture(&new_device_id) {
-                    Ok(new_capture) => {
-                        capture = new_capture;
-                        if let Some(fmt) = capture.format() {
-                            *capture_format.write().unwrap() = Some(fmt.clone());
-                        }
-                        current_device_id = new_device_id;
-                        error_count = 0;
-                        info!("Mic input switched successfully");
-                    }
-                    Err(e) => {
-                        error!("Failed to switch mic input: {}", e);
-                        capture = create_and_start_capture(&current_device_id)
-                            .context("Failed to restart mic capture with previous device")?;
-                    }
+            }
+            "--recovery-backoff-ms" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    recovery_backoff_ms = val.parse().ok();
                 }
             }
-        }
-
-        match capture.read(&mut temp_buffer) {
-            Ok(samples_read) if samples_read > 0 => {
-                error_count = 0;
-                let written = buffer.write(&temp_buffer[..samples_read]);
-                if written < samples_read {
-                    warn!("Mic ring buffer overflow: {} samples dropped", samples_read - written);
+            "--recovery-max-backoff-ms" => {
+                i += 1;
+                if let Some(val) = args.get(i) {
+                    recovery_max_backoff_ms = val.parse().ok();
                 }
             }
-            Ok(_) => {
-                thread::sleep(Duration::from_micros(500));
+            "--measure-latency" => {
+                measure_latency = true;
             }
-            Err(e) => {
-                error_count += 1;
-                error!("Mic capture error (attempt {}): {}", error_count, e);
-
-                if error_count >= MAX_RECOVERY_ATTEMPTS {
-                    return Err(e.context("Too many consecutive mic capture errors, giving up"));
-                }
-
-                warn!("Attempting to recover mic capture stream...");
-                thread::sleep(Duration::from_secs(1));
-                match create_and_start_capture(&current_device_id) {
-                    Ok(new_capture) => {
-                        capture = new_capture;
-                        if let Some(fmt) = capture.format() {
-                            *capture_format.write().unwrap() = Some(fmt.clone());
-                        }
-                        info!("Mic capture stream recovered");
-                    }
-                    Err(re) => {
-                        error!("Failed to recover mic capture: {}", re);
-                    }
+            "--metrics-addr" => {
+                i += 1;
+                metrics_addr = args.get(i).cloned();
+            }
+            "--status-interval" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --status-interval"))?;
+                let secs: u64 = val.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for --status-interval: {}", val))?;
+                if secs == 0 {
+                    anyhow::bail!("--status-interval must be at least 1 second");
                 }
+                status_interval = Some(Duration::from_secs(secs));
+            }
+            "--rtp-out" => {
+                i += 1;
+                rtp_out = args.get(i).cloned();
+            }
+            "--rtp-codec" => {
+                i += 1;
+                let val = args.get(i)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for --rtp-codec"))?;
+                rtp_codec = RtpCodec::parse(val)?;
+            }
+            "--ipc-tcp" => {
+                i += 1;
+                ipc_tcp = args.get(i).cloned();
+            }
+            "--ipc-token" => {
+                i += 1;
+                ipc_token = args.get(i).cloned();
+            }
+            "--instance" => {
+                i += 1;
+                instance = args.get(i).cloned();
+            }
+            "--debug-commands" => {
+                debug_commands = true;
+            }
+            "--help" | "-h" => {
+                print_usage();
+                std::process::exit(0);
+            }
+            "--version" | "-V" => {
+                println!("{}", version_info());
+                std::process::exit(0);
+            }
+            _ => {
+                return Err(anyhow::anyhow!("Unknown argument: {}", args[i]));
             }
         }
+        i += 1;
     }
 
-    capture.stop()?;
-    info!("Mic capture loop stopped.");
-    Ok(())
-}
-
-fn run_mic_render_loop(
-    mic_output_id: &str,
-    buffer: Arc<AudioRingBuffer>,
-    running: Arc<AtomicBool>,
-    mic_enabled: Arc<AtomicBool>,
-    buffer_ms: u32,
-    capture_format: Arc<RwLock<Option<AudioFormat>>>,
-) -> Result<()> {
-    info!("Starting mic render to device: {}", mic_output_id);
-
-    let mut render = create_and_start_render(mic_output_id)?;
-    let mut temp_buffer = vec![0.0f32; 4096];
-    let mut conversion_scratch = Vec::new();
-    let mut error_count: u32 = 0;
-
-    let render_channels = render.format().map(|f| f.channels as usize).unwrap_or(2);
-    let render_rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
-    let prefill_samples = (render_rate * buffer_ms / 1000) as usize * render_channels;
-    let silence = vec![0.0f32; prefill_samples];
-    let _ = render.write(&silence);
-
-    while running.load(Ordering::SeqCst) {
-        if !mic_enabled.load(Ordering::SeqCst) {
-            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
-            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
-            let silence_samples = (rate / 1000) as usize * ch;
-            let silence = vec![0.0f32; silence_samples];
-            let _ = render.write(&silence);
-            thread::sleep(Duration::from_millis(10));
-            continue;
+    let run_mode = match (capture_only, render_only) {
+        (true, true) => return Err(anyhow::anyhow!("--capture-only and --render-only can't be combined")),
+        (true, false) => RunMode::CaptureOnly,
+        (false, true) => RunMode::RenderOnly,
+        (false, false) => RunMode::Full,
+    };
+    // Explicit --speaker-in/--speaker-out win; --auto only fills in what's missing
+    if auto {
+        if let Err(e) = auto_select(com_model, &mut speaker_in, &mut speaker_out) {
+            // Guidance rather than a usage error, so no usage text after it
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    }
+    // Checked by `ProxyConfig::validate`, which knows which the run mode needs
+    let speaker_in = speaker_in.unwrap_or_default();
+    let speaker_out = speaker_out.unwrap_or_default();
+
+    // Explicit options override the profile's choices
+    let preset = profile.preset();
+    let buffer_ms = buffer_ms.unwrap_or(preset.buffer_ms);
+    let prefill_ms = prefill_ms.unwrap_or(buffer_ms);
+    let render_chunk_ms = render_chunk_ms.unwrap_or(preset.render_chunk_ms);
+    let recovery = RecoveryPolicy {
+        max_attempts: max_recovery_attempts.unwrap_or(preset.recovery.max_attempts),
+        initial_backoff: recovery_backoff_ms.map(Duration::from_millis).unwrap_or(preset.recovery.initial_backoff),
+        max_backoff: recovery_max_backoff_ms.map(Duration::from_millis).unwrap_or(preset.recovery.max_backoff),
+    };
 
-        let samples_read = buffer.read(&mut temp_buffer);
-        if samples_read > 0 {
-            let cap_fmt = capture_format.read().unwrap().clone();
-            let rnd_fmt = render.format().cloned();
-
-            let write_result = if let (Some(ref cf), Some(ref rf)) = (cap_fmt, rnd_fmt) {
-                if formats_need_conversion(cf, rf) {
-                    let converted = convert_audio(
-                        &temp_buffer[..samples_read], cf, rf, &mut conversion_scratch,
-                    );
-                    render.write(&converted)
-                } else {
-                    render.write(&temp_buffer[..samples_read])
-                }
-            } else {
-                render.write(&temp_buffer[..samples_read])
-            };
-
-            if let Err(e) = write_result {
-                error_count += 1;
-                error!("Mic render error (attempt {}): {}", error_count, e);
+    let config = ProxyConfig {
+        speaker_in,
+        speaker_in2,
+        speaker_out,
+        mic_in,
+        mic_in2,
+        mic_out,
+        mic_gain_db,
+        mic_in2_gain_db,
+        mic_follow_default,
+        buffer_ms,
+        prefill_ms,
+        max_fill_ms,
+        device_buffer_ms,
+        glitch_dump_dir,
+        glitch_dump_secs,
+        drain_ms,
+        start_fade_ms,
+        render_chunk_ms,
+        capture_chunk_frames,
+        upmix,
+        lfe_downmix_db,
+        channel_map,
+        resample_quality,
+        no_resample_clamp,
+        output_backend,
+        dither,
+        output_category,
+        force_rate,
+        strict_rate,
+        swap_lr,
+        ab_loudness_match,
+        com_model,
+        force,
+        run_mode,
+        no_convert,
+        keep_alive_db: keep_alive.then_some(keep_alive_db),
+        silence_threshold_db,
+        clip_ceiling_db,
+        max_output_db,
+        process_block_frames,
+        recovery,
+        metrics_addr,
+        rtp_out,
+        rtp_codec,
+        ipc_pipe: true,
+        ipc_tcp,
+        ipc_token,
+        instance,
+        debug_commands,
+    };
+    config.validate()?;
 
-                if error_count >= MAX_RECOVERY_ATTEMPTS {
-                    return Err(e.context("Too many consecutive mic render errors, giving up"));
-                }
+    Ok(Args { config, profile, measure_latency, status_interval })
+}
 
-                warn!("Attempting to recover mic render stream...");
-                thread::sleep(Duration::from_secs(1));
-                match create_and_start_render(mic_output_id) {
-                    Ok(new_render) => {
-                        render = new_render;
-                        info!("Mic render stream recovered");
-                    }
-                    Err(re) => {
-                        error!("Failed to recover mic render: {}", re);
-                    }
-                }
-            } else {
-                error_count = 0;
-            }
-        } else {
-            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
-            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
-            let silence_samples = (rate / 1000) as usize * ch;
-            let silence = vec![0.0f32; silence_samples];
-            let _ = render.write(&silence);
-            thread::sleep(Duration::from_micros(500));
-        }
+/// Fill in the speaker devices `--auto` picks, for those not given on the command line
+fn auto_select(com_model: ComModel, speaker_in: &mut Option<String>, speaker_out: &mut Option<String>) -> Result<()> {
+    if speaker_in.is_some() && speaker_out.is_some() {
+        return Ok(());
     }
-
-    render.stop()?;
-    info!("Mic render loop stopped.");
-    Ok(())
+    com_model.initialize().context("Failed to initialize COM")?;
+    let result = pick_auto_devices(speaker_in, speaker_out);
+    unsafe {
+        CoUninitialize();
+    }
+    result
 }
 
-// ── IPC server ─────────────────────────────────────────────────────────────
-
-fn run_ipc_server(
-    running: Arc<AtomicBool>,
-    output_device_id: Arc<RwLock<String>>,
-    mic_input_id: Option<Arc<RwLock<String>>>,
-    mic_enabled: Option<Arc<AtomicBool>>,
-) -> Result<()> {
-    let mut server = IpcServer::new()?;
-    info!("IPC server started on pipe: {}", ipc::PIPE_NAME);
-
-    while running.load(Ordering::SeqCst) {
-        match server.accept_with_timeout(Duration::from_millis(100)) {
-            Ok(Some(command)) => {
-                let response = handle_ipc_command(
-                    command,
-                    &output_device_id,
-                    &running,
-                    mic_input_id.as_ref(),
-                    mic_enabled.as_ref(),
-                );
-                if let Err(e) = server.send_response(&response) {
-                    warn!("Failed to send IPC response: {}", e);
-                }
-            }
-            Ok(None) => {
-                // Timeout or no client, continue loop
-            }
-            Err(e) => {
-                warn!("IPC accept error: {}", e);
-                thread::sleep(Duration::from_millis(100));
-            }
-        }
+fn pick_auto_devices(speaker_in: &mut Option<String>, speaker_out: &mut Option<String>) -> Result<()> {
+    if speaker_in.is_none() {
+        let captures = list_capture_endpoints()?;
+        let cable = auto_detect::pick_speaker_in(&captures)?;
+        info!("--auto picked speaker input: {} ({})", cable.name,
+              auto_detect::virtual_product(cable).unwrap_or_default());
+        *speaker_in = Some(cable.id.clone());
+    }
+    if speaker_out.is_none() {
+        let default_render = default_render_endpoint(DefaultRole::Console).ok();
+        let output = auto_detect::pick_speaker_out(default_render.as_ref())?;
+        info!("--auto picked speaker output: {} (the default output device)", output.name);
+        *speaker_out = Some(output.id.clone());
     }
-
     Ok(())
 }
 
-fn handle_ipc_command(
-    command: IpcCommand,
-    output_device_id: &Arc<RwLock<String>>,
-    running: &Arc<AtomicBool>,
-    mic_input_id: Option<&Arc<RwLock<String>>>,
-    mic_enabled: Option<&Arc<AtomicBool>>,
-) -> ipc::IpcResponse {
-    match command {
-        IpcCommand::SetOutput { device_id } => {
-            info!("IPC: Setting speaker output device to: {}", device_id);
-            *output_device_id.write().unwrap() = device_id;
-            ipc::IpcResponse::success("Output device updated")
-        }
-        IpcCommand::GetStatus => {
-            let current_output = output_device_id.read().unwrap().clone();
-            let is_running = running.load(Ordering::SeqCst);
-
-            if let (Some(mic_id), Some(mic_en)) = (mic_input_id, mic_enabled) {
-                let mic_input = mic_id.read().unwrap().clone();
-                let mic_is_enabled = mic_en.load(Ordering::SeqCst);
-                ipc::IpcResponse::status_full(is_running, &current_output, mic_is_enabled, Some(&mic_input))
-            } else {
-                ipc::IpcResponse::status(is_running, &current_output)
+// ── Latency measurement ────────────────────────────────────────────────────
+
+/// Number of chirps played; the median of the detected delays is reported
+const LATENCY_RUNS: usize = 3;
+
+/// Length of the measurement chirp
+const LATENCY_CHIRP_MS: u32 = 100;
+
+/// How long to listen for each chirp before giving up
+const LATENCY_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Play a chirp on the speaker output and time how long it takes to show up on the
+/// speaker input. Needs the output looped back into the input (cable or VB-Cable).
+///
+/// The delay is measured from the moment the chirp is queued on the render device,
+/// so it includes the render prefill (`--prefill-ms`), both device buffers and any
+/// hardware/driver latency, i.e. what the proxy adds on top of the game's own output.
+fn run_latency_measurement(config: &ProxyConfig) -> Result<()> {
+    info!("Measuring latency: {} -> {}", config.speaker_out, config.speaker_in);
+
+    let mut capture = create_and_start_capture(&config.speaker_in, config.device_buffer_ms, config.capture_chunk_frames)?;
+    let mut render = create_and_start_render(&config.speaker_out, config.device_buffer_ms)?;
+    let cap_fmt = capture.format().cloned().context("Capture format unavailable")?;
+    let rnd_fmt = render.format().cloned().context("Render format unavailable")?;
+    let cap_channels = cap_fmt.channels as usize;
+    let rnd_channels = rnd_fmt.channels as usize;
+
+    // Same sweep at both rates: one to play, one to search for
+    let played: Vec<f32> = test_signal::chirp(rnd_fmt.sample_rate, LATENCY_CHIRP_MS, 0.5)
+        .iter()
+        .flat_map(|&s| std::iter::repeat_n(s, rnd_channels))
+        .collect();
+    let reference = test_signal::chirp(cap_fmt.sample_rate, LATENCY_CHIRP_MS, 0.5);
+
+    let prefill = (rnd_fmt.sample_rate * config.prefill_ms / 1000) as usize * rnd_channels;
+    let mut temp_buffer = vec![0.0f32; 4096];
+    let mut results = Vec::new();
+
+    for run in 1..=LATENCY_RUNS {
+        // Start from a quiet, flushed state like the render loop does
+        let _ = render.write(&vec![0.0f32; prefill]);
+        thread::sleep(Duration::from_millis(300));
+        while capture.read(&mut temp_buffer)? > 0 {}
+
+        // Mono (first channel) recording, starting at the moment the chirp is queued
+        let mut recorded: Vec<f32> = Vec::new();
+        let mut queued = 0;
+        let started = Instant::now();
+
+        while started.elapsed() < LATENCY_TIMEOUT {
+            if queued < played.len() {
+                queued += render.write(&played[queued..])?;
             }
-        }
-        IpcCommand::Stop => {
-            info!("IPC: Stop command received");
-            running.store(false, Ordering::SeqCst);
-            ipc::IpcResponse::success("Stopping proxy")
-        }
-        IpcCommand::SetMicInput { device_id } => {
-            if let Some(mic_id) = mic_input_id {
-                info!("IPC: Setting mic input device to: {}", device_id);
-                *mic_id.write().unwrap() = device_id;
-                ipc::IpcResponse::success("Mic input device updated")
-            } else {
-                ipc::IpcResponse::error("Mic proxy not configured")
+
+            let samples_read = capture.read(&mut temp_buffer)?;
+            if samples_read == 0 {
+                thread::sleep(Duration::from_millis(1));
+                continue;
             }
+            recorded.extend(temp_buffer[..samples_read].iter().step_by(cap_channels));
         }
-        IpcCommand::EnableMic { enabled } => {
-            if let Some(mic_en) = mic_enabled {
-                info!("IPC: Setting mic enabled to: {}", enabled);
-                mic_en.store(enabled, Ordering::SeqCst);
-                ipc::IpcResponse::success(if enabled { "Mic proxy enabled" } else { "Mic proxy disabled" })
-            } else {
-                ipc::IpcResponse::error("Mic proxy not configured")
+
+        match test_signal::find_delay(&reference, &recorded) {
+            Some((offset, score)) => {
+                let ms = offset as f64 * 1000.0 / cap_fmt.sample_rate as f64;
+                info!("Run {}: {:.1} ms (correlation {:.2})", run, ms, score);
+                results.push(ms);
             }
+            None => warn!("Run {}: test signal not detected", run),
         }
     }
+
+    capture.stop()?;
+    render.stop()?;
+
+    if results.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No correlated signal detected within {:?}. Make sure --speaker-out is looped back \
+             into --speaker-in and neither is muted.",
+            LATENCY_TIMEOUT
+        ));
+    }
+
+    results.sort_by(|a, b| a.total_cmp(b));
+    let median = results[results.len() / 2];
+    println!("Measured round-trip latency: {:.1} ms ({} of {} runs detected)",
+             median, results.len(), LATENCY_RUNS);
+    Ok(())
 }
 
 fn ctrlc_handler(running: Arc<AtomicBool>) {
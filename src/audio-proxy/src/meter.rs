@@ -0,0 +1,414 @@
+//! Input level meters for UI display
+//!
+//! A raw block peak or RMS jumps around too much to read. `SetMeter` picks one of
+//! four meter modes, and the capture loop shapes the level with that mode's
+//! ballistics as it captures, so the reported value can be drawn as is:
+//!
+//! - `peak`, `rms`: the peak or RMS of the latest captured block, unsmoothed
+//! - `vu`: RMS with VU-style 300 ms integration (time constant 65 ms both ways)
+//! - `ppm`: peak programme meter, fast attack (2 ms) and slow fall (about 24 dB in
+//!   2.8 s)
+//!
+//! `vu` and `ppm` take optional attack/release time constants instead of these. The
+//! raw sample peak, held for 2 s, is reported next to the level either way, so a
+//! smoothed meter can't hide clipping. Reading the levels changes nothing, so any
+//! number of clients can poll them.
+//!
+//! Every mode also reports a peak hold, like a hardware meter's hold segment: the
+//! highest block peak stays put for the hold time (1.5 s by default), then falls at
+//! the decay rate (20 dB/s) until a louder block takes over.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Level reported for silence
+pub const METER_FLOOR_DB: f32 = -120.0;
+
+/// Longest attack or release time constant `SetMeter` accepts
+pub const MAX_BALLISTICS_MS: f32 = 10_000.0;
+
+/// Longest peak hold time `SetMeter` accepts
+pub const MAX_PEAK_HOLD_MS: f32 = 10_000.0;
+
+/// Peak hold time unless `SetMeter` sets one
+const DEFAULT_PEAK_HOLD_MS: f32 = 1500.0;
+
+/// Fall rate of the peak hold once the hold time is up, unless `SetMeter` sets one
+const DEFAULT_PEAK_DECAY_DB_PER_SEC: f32 = 20.0;
+
+/// How long the raw sample peak stays before it drops to the latest block's peak,
+/// longer than any sensible UI poll interval so no reader misses a clip
+const RAW_PEAK_HOLD_SECS: f32 = 2.0;
+
+/// Follower values below this read as silence
+const STATE_FLUSH: f32 = 1e-15;
+
+/// How the reported level follows the signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeterMode {
+    #[default]
+    Peak,
+    Rms,
+    Vu,
+    Ppm,
+}
+
+/// Meter mode and time constants the capture loops apply
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct MeterSettings {
+    pub mode: MeterMode,
+    /// Override the `vu`/`ppm` attack time constant
+    pub attack_ms: Option<f32>,
+    /// Override the `vu`/`ppm` release time constant
+    pub release_ms: Option<f32>,
+    /// Override how long the peak hold stays before it falls
+    pub hold_ms: Option<f32>,
+    /// Override how fast the peak hold falls, in dB per second
+    pub decay_db_per_sec: Option<f32>,
+}
+
+impl MeterSettings {
+    pub fn validate(&self) -> Result<()> {
+        for ms in [self.attack_ms, self.release_ms].into_iter().flatten() {
+            if !(0.0..=MAX_BALLISTICS_MS).contains(&ms) {
+                anyhow::bail!("Meter time constants must be between 0 and {} ms: {}", MAX_BALLISTICS_MS, ms);
+            }
+        }
+        if let Some(ms) = self.hold_ms {
+            if !(0.0..=MAX_PEAK_HOLD_MS).contains(&ms) {
+                anyhow::bail!("Peak hold time must be between 0 and {} ms: {}", MAX_PEAK_HOLD_MS, ms);
+            }
+        }
+        if let Some(rate) = self.decay_db_per_sec {
+            if !(rate.is_finite() && rate > 0.0) {
+                anyhow::bail!("Peak hold decay must be above 0 dB/s: {}", rate);
+            }
+        }
+        Ok(())
+    }
+
+    /// Peak hold time in seconds and decay rate in dB/s
+    fn peak_hold(&self) -> (f32, f32) {
+        (
+            self.hold_ms.unwrap_or(DEFAULT_PEAK_HOLD_MS) / 1000.0,
+            self.decay_db_per_sec.unwrap_or(DEFAULT_PEAK_DECAY_DB_PER_SEC),
+        )
+    }
+
+    /// Attack and release time constants in ms (`None` for the block-based modes)
+    fn ballistics(&self) -> Option<(f32, f32)> {
+        let (attack, release) = match self.mode {
+            MeterMode::Peak | MeterMode::Rms => return None,
+            MeterMode::Vu => (65.0, 65.0),
+            MeterMode::Ppm => (2.0, 1000.0),
+        };
+        Some((self.attack_ms.unwrap_or(attack), self.release_ms.unwrap_or(release)))
+    }
+}
+
+/// Meter settings shared with the IPC thread, so `SetMeter` applies to running loops
+#[derive(Debug, Clone, Default)]
+pub struct SharedMeterSettings(Arc<RwLock<MeterSettings>>);
+
+impl SharedMeterSettings {
+    pub fn get(&self) -> MeterSettings {
+        *self.0.read().unwrap()
+    }
+
+    pub fn set(&self, settings: MeterSettings) -> Result<()> {
+        settings.validate()?;
+        *self.0.write().unwrap() = settings;
+        Ok(())
+    }
+}
+
+/// Latest meter values of one path, written by its capture loop
+pub struct MeterReading {
+    /// Bits of the level in dBFS
+    level_db: AtomicU32,
+    /// Bits of the largest absolute sample of the raw peak hold
+    peak: AtomicU32,
+    /// Bits of the peak hold in dBFS
+    peak_hold_db: AtomicU32,
+}
+
+/// Meter values as reported over IPC
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Levels {
+    pub mode: MeterMode,
+    /// Level in dBFS, shaped by the mode's ballistics
+    pub level_db: f32,
+    /// Largest sample of the last 2 s or so, unsmoothed, in dBFS
+    pub peak_db: f32,
+    /// Highest block peak of the last hold time, then falling, in dBFS
+    pub peak_hold_db: f32,
+}
+
+impl MeterReading {
+    pub const fn new() -> Self {
+        Self {
+            // Bits of METER_FLOOR_DB, which `f32::to_bits` can't produce in a const
+            level_db: AtomicU32::new(0xC2F0_0000),
+            peak: AtomicU32::new(0),
+            peak_hold_db: AtomicU32::new(0xC2F0_0000),
+        }
+    }
+
+    /// Current values, leaving them for the next reader
+    pub fn read(&self, mode: MeterMode) -> Levels {
+        Levels {
+            mode,
+            level_db: f32::from_bits(self.level_db.load(Ordering::Relaxed)),
+            peak_db: to_db(f32::from_bits(self.peak.load(Ordering::Relaxed))),
+            peak_hold_db: f32::from_bits(self.peak_hold_db.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Default for MeterReading {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// dBFS of a linear amplitude, floored at `METER_FLOOR_DB`
+fn to_db(amplitude: f32) -> f32 {
+    if amplitude > 0.0 {
+        (20.0 * amplitude.log10()).max(METER_FLOOR_DB)
+    } else {
+        METER_FLOOR_DB
+    }
+}
+
+/// Capture-side meter state
+pub struct LevelMeter {
+    shared: SharedMeterSettings,
+    settings: MeterSettings,
+    /// Follower state: amplitude for `ppm`, mean square for `vu`
+    state: f32,
+    /// Peak hold in dBFS
+    held_db: f32,
+    /// Seconds until the peak hold starts to fall
+    hold_left: f32,
+    /// Largest absolute sample of the raw peak hold
+    raw_peak: f32,
+    /// Seconds until the raw peak drops
+    raw_peak_left: f32,
+}
+
+impl LevelMeter {
+    pub fn new(shared: SharedMeterSettings) -> Self {
+        Self {
+            settings: shared.get(),
+            shared,
+            state: 0.0,
+            held_db: METER_FLOOR_DB,
+            hold_left: 0.0,
+            raw_peak: 0.0,
+            raw_peak_left: 0.0,
+        }
+    }
+
+    /// Meter a captured block and publish the result to `reading`
+    pub fn process(&mut self, samples: &[f32], channels: usize, sample_rate: u32, reading: &MeterReading) {
+        if channels == 0 || sample_rate == 0 || samples.len() < channels {
+            return;
+        }
+        let settings = self.shared.get();
+        if settings != self.settings {
+            self.settings = settings;
+            self.state = 0.0;
+        }
+
+        let block_peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let block_secs = (samples.len() / channels) as f32 / sample_rate as f32;
+        self.hold_raw_peak(block_peak, block_secs);
+        reading.peak.store(self.raw_peak.to_bits(), Ordering::Relaxed);
+        self.hold_peak(to_db(block_peak), block_secs);
+        reading.peak_hold_db.store(self.held_db.to_bits(), Ordering::Relaxed);
+
+        let level = match self.settings.mode {
+            MeterMode::Peak => block_peak,
+            MeterMode::Rms => (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt(),
+            MeterMode::Vu => {
+                self.follow(samples, channels, sample_rate, |frame| {
+                    frame.iter().map(|s| s * s).sum::<f32>() / channels as f32
+                });
+                self.state.sqrt()
+            }
+            MeterMode::Ppm => {
+                self.follow(samples, channels, sample_rate, |frame| {
+                    frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+                });
+                self.state
+            }
+        };
+        reading.level_db.store(to_db(level).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Take a block peak into the peak hold, or let the hold run down by `block_secs`
+    fn hold_peak(&mut self, peak_db: f32, block_secs: f32) {
+        let (hold_secs, decay_db_per_sec) = self.settings.peak_hold();
+        if peak_db >= self.held_db {
+            self.held_db = peak_db;
+            self.hold_left = hold_secs;
+            return;
+        }
+        // Whatever part of the block is past the hold time falls
+        let falling_secs = (block_secs - self.hold_left).max(0.0);
+        self.hold_left = (self.hold_left - block_secs).max(0.0);
+        self.held_db = (self.held_db - decay_db_per_sec * falling_secs).max(peak_db);
+    }
+
+    /// Take a block peak into the raw peak hold, which drops straight to the block's
+    /// peak once `RAW_PEAK_HOLD_SECS` is up instead of falling
+    fn hold_raw_peak(&mut self, peak: f32, block_secs: f32) {
+        self.raw_peak_left -= block_secs;
+        if peak >= self.raw_peak || self.raw_peak_left <= 0.0 {
+            self.raw_peak = peak;
+            self.raw_peak_left = RAW_PEAK_HOLD_SECS;
+        }
+    }
+
+    /// Run the follower over every frame, rising with the attack and falling with the
+    /// release time constant
+    fn follow(&mut self, samples: &[f32], channels: usize, sample_rate: u32, value: impl Fn(&[f32]) -> f32) {
+        let Some((attack_ms, release_ms)) = self.settings.ballistics() else {
+            return;
+        };
+        let coeff = |ms: f32| {
+            let frames = ms / 1000.0 * sample_rate as f32;
+            if frames > 0.0 { 1.0 - (-1.0 / frames).exp() } else { 1.0 }
+        };
+        let (attack, release) = (coeff(attack_ms), coeff(release_ms));
+        for frame in samples.chunks_exact(channels) {
+            let x = value(frame);
+            let k = if x > self.state { attack } else { release };
+            self.state += (x - self.state) * k;
+            // Far below the floor; stops the decay before it reaches subnormals
+            if self.state < STATE_FLUSH {
+                self.state = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter(mode: MeterMode, attack_ms: Option<f32>, release_ms: Option<f32>) -> LevelMeter {
+        let shared = SharedMeterSettings::default();
+        shared.set(MeterSettings { mode, attack_ms, release_ms, ..Default::default() }).unwrap();
+        LevelMeter::new(shared)
+    }
+
+    fn db(amplitude: f32) -> f32 {
+        20.0 * amplitude.log10()
+    }
+
+    #[test]
+    fn test_ppm_step_settles_with_time_constants() {
+        // 10 ms attack, 100 ms release at 48 kHz: one time constant is 480 / 4800 frames
+        let mut meter = meter(MeterMode::Ppm, Some(10.0), Some(100.0));
+        let reading = MeterReading::new();
+
+        meter.process(&[1.0; 480], 1, 48000, &reading);
+        let level = reading.read(MeterMode::Ppm).level_db;
+        assert!((level - db(1.0 - (-1.0f32).exp())).abs() < 0.1, "{}", level);
+        meter.process(&[1.0; 4 * 480], 1, 48000, &reading);
+        assert!(reading.read(MeterMode::Ppm).level_db > db(0.99));
+
+        // Falls back to 1/e of the way after one release time constant
+        meter.process(&[0.0; 4800], 1, 48000, &reading);
+        let level = reading.read(MeterMode::Ppm).level_db;
+        assert!((level - db((-1.0f32).exp())).abs() < 0.2, "{}", level);
+    }
+
+    #[test]
+    fn test_vu_integrates_power() {
+        let mut meter = meter(MeterMode::Vu, None, None);
+        let reading = MeterReading::new();
+        // 300 ms of a full-scale square wave: within 0.1 dB of its 0 dB RMS
+        let square: Vec<f32> = (0..14400).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+        meter.process(&square[..480], 1, 48000, &reading);
+        assert!(reading.read(MeterMode::Vu).level_db < -6.0);
+        meter.process(&square, 1, 48000, &reading);
+        assert!(reading.read(MeterMode::Vu).level_db > -0.1);
+    }
+
+    #[test]
+    fn test_block_modes_and_peak_hold() {
+        let reading = MeterReading::new();
+        assert_eq!(reading.read(MeterMode::Peak).level_db, METER_FLOOR_DB);
+
+        let mut peak = meter(MeterMode::Peak, None, None);
+        peak.process(&[0.5, -0.25, 0.1, 0.0], 2, 48000, &reading);
+        let levels = reading.read(MeterMode::Peak);
+        assert!((levels.level_db - db(0.5)).abs() < 1e-3);
+        assert!((levels.peak_db - db(0.5)).abs() < 1e-3);
+        // Reading leaves the raw peak for the next reader
+        assert_eq!(reading.read(MeterMode::Peak).peak_db, levels.peak_db);
+        // Held over quieter blocks, then down to the latest block's once 2 s are up
+        for _ in 0..19 {
+            peak.process(&[0.1; 9600], 2, 48000, &reading);
+        }
+        assert!((reading.read(MeterMode::Peak).peak_db - db(0.5)).abs() < 1e-3);
+        peak.process(&[0.1; 19200], 2, 48000, &reading);
+        assert!((reading.read(MeterMode::Peak).peak_db - db(0.1)).abs() < 1e-3);
+
+        let mut rms = meter(MeterMode::Rms, None, None);
+        rms.process(&[0.5, -0.5, 0.5, -0.5], 2, 48000, &reading);
+        assert!((reading.read(MeterMode::Rms).level_db - db(0.5)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_peak_hold_holds_then_decays() {
+        let shared = SharedMeterSettings::default();
+        shared.set(MeterSettings { hold_ms: Some(500.0), decay_db_per_sec: Some(10.0), ..Default::default() }).unwrap();
+        let mut meter = LevelMeter::new(shared);
+        let reading = MeterReading::new();
+        assert_eq!(reading.read(MeterMode::Peak).peak_hold_db, METER_FLOOR_DB);
+
+        meter.process(&[0.5; 4800], 1, 48000, &reading);
+        let quiet = [0.05; 4800];
+        // 400 ms in: still held
+        for _ in 0..4 {
+            meter.process(&quiet, 1, 48000, &reading);
+        }
+        assert!((reading.read(MeterMode::Peak).peak_hold_db - db(0.5)).abs() < 1e-3);
+        // 100 ms past the hold time: 1 dB down
+        for _ in 0..2 {
+            meter.process(&quiet, 1, 48000, &reading);
+        }
+        let levels = reading.read(MeterMode::Peak);
+        assert!((levels.peak_hold_db - (db(0.5) - 1.0)).abs() < 1e-3, "{}", levels.peak_hold_db);
+        assert!((levels.level_db - db(0.05)).abs() < 1e-3);
+
+        // Never below the current block, and a louder one takes over straight away
+        for _ in 0..50 {
+            meter.process(&quiet, 1, 48000, &reading);
+        }
+        assert!((reading.read(MeterMode::Peak).peak_hold_db - db(0.05)).abs() < 1e-3);
+        meter.process(&[0.8; 480], 1, 48000, &reading);
+        assert!((reading.read(MeterMode::Peak).peak_hold_db - db(0.8)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_validate() {
+        let settings = |attack_ms| MeterSettings { mode: MeterMode::Vu, attack_ms, ..Default::default() };
+        assert!(settings(Some(300.0)).validate().is_ok());
+        assert!(settings(None).validate().is_ok());
+        assert!(settings(Some(-1.0)).validate().is_err());
+        assert!(settings(Some(f32::NAN)).validate().is_err());
+        let hold = |hold_ms, decay_db_per_sec| MeterSettings { hold_ms, decay_db_per_sec, ..Default::default() };
+        assert!(hold(Some(0.0), Some(40.0)).validate().is_ok());
+        assert!(hold(Some(MAX_PEAK_HOLD_MS + 1.0), None).validate().is_err());
+        assert!(hold(None, Some(0.0)).validate().is_err());
+        assert_eq!(f32::from_bits(0xC2F0_0000), METER_FLOOR_DB);
+    }
+}
@@ -0,0 +1,445 @@
+//! Audio health counters for monitoring
+//!
+//! The audio loops bump the counters in `METRICS` as they go; `GetMetrics` returns a
+//! snapshot over IPC and `--metrics-addr` serves the same values over HTTP in the
+//! Prometheus text format, so they can be graphed in Grafana.
+//!
+//! `GetAndResetMetrics` reads the counters and zeroes them in one swap each, so a
+//! poller computing rates neither misses nor double-counts events that land between
+//! its read and its reset. The counters are shared, so Prometheus sees them restart
+//! too, which `rate()` already handles.
+
+use std::fmt::{Display, Write as _};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::meter::MeterReading;
+
+/// Process-wide metrics the audio loops update
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters and gauges for one audio path (capture -> ring buffer -> render)
+pub struct PathMetrics {
+    /// Capture blocks that didn't fit into the ring buffer
+    pub overflows: AtomicU64,
+    /// Times the render device ran dry
+    pub underruns: AtomicU64,
+    /// Streams reopened after an error or device change
+    pub recoveries: AtomicU64,
+    /// Samples waiting in the ring buffer
+    pub buffer_fill_samples: AtomicU64,
+    /// Ring buffer plus device queue, in microseconds
+    pub latency_us: AtomicU64,
+    /// Whether the last captured block was below the silence threshold
+    pub input_silent: AtomicBool,
+    /// Captured samples at or above the clip ceiling (clipping upstream of the proxy)
+    pub input_clips: AtomicU64,
+    /// Times the ring buffer stayed over `--max-fill-ms` and was skipped forward
+    pub forced_skips: AtomicU64,
+    /// Liveness of the capture and render loops
+    pub capture_heartbeat: Heartbeat,
+    pub render_heartbeat: Heartbeat,
+    /// Input level for `GetLevels`
+    pub meter: MeterReading,
+}
+
+impl PathMetrics {
+    const fn new() -> Self {
+        Self {
+            overflows: AtomicU64::new(0),
+            underruns: AtomicU64::new(0),
+            recoveries: AtomicU64::new(0),
+            buffer_fill_samples: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+            input_silent: AtomicBool::new(false),
+            input_clips: AtomicU64::new(0),
+            forced_skips: AtomicU64::new(0),
+            capture_heartbeat: Heartbeat::new(),
+            render_heartbeat: Heartbeat::new(),
+            meter: MeterReading::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> PathSnapshot {
+        PathSnapshot {
+            overflows: self.overflows.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+            recoveries: self.recoveries.load(Ordering::Relaxed),
+            buffer_fill_samples: self.buffer_fill_samples.load(Ordering::Relaxed),
+            latency_us: self.latency_us.load(Ordering::Relaxed),
+            input_silent: self.input_silent.load(Ordering::Relaxed),
+            input_clips: self.input_clips.load(Ordering::Relaxed),
+            forced_skips: self.forced_skips.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Like `snapshot`, but zero the counters as they are read. The gauges are left
+    /// as they are.
+    pub fn take_snapshot(&self) -> PathSnapshot {
+        PathSnapshot {
+            overflows: self.overflows.swap(0, Ordering::Relaxed),
+            underruns: self.underruns.swap(0, Ordering::Relaxed),
+            recoveries: self.recoveries.swap(0, Ordering::Relaxed),
+            input_clips: self.input_clips.swap(0, Ordering::Relaxed),
+            forced_skips: self.forced_skips.swap(0, Ordering::Relaxed),
+            ..self.snapshot()
+        }
+    }
+}
+
+/// Metrics of the speaker and mic paths
+pub struct Metrics {
+    pub speaker: PathMetrics,
+    pub mic: PathMetrics,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            speaker: PathMetrics::new(),
+            mic: PathMetrics::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            speaker: self.speaker.snapshot(),
+            mic: self.mic.snapshot(),
+        }
+    }
+
+    /// Snapshot of both paths with their counters zeroed (see `PathMetrics::take_snapshot`)
+    pub fn take_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            speaker: self.speaker.take_snapshot(),
+            mic: self.mic.take_snapshot(),
+        }
+    }
+
+    pub fn heartbeat_ages(&self) -> HeartbeatAges {
+        HeartbeatAges {
+            speaker_capture: self.speaker.capture_heartbeat.age_ms(),
+            speaker_render: self.speaker.render_heartbeat.age_ms(),
+            mic_capture: self.mic.capture_heartbeat.age_ms(),
+            mic_render: self.mic.render_heartbeat.age_ms(),
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// When an audio loop last went round, so a supervisor can tell a hung thread (e.g.
+/// stuck in a device write) from a quiet one. Loops beat once per iteration, also
+/// while paused; the age also grows while a loop waits to retry a failed device.
+pub struct Heartbeat(AtomicU64);
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn beat(&self) {
+        self.0.store(monotonic_ms(), Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the last beat, `None` if the loop never ran
+    pub fn age_ms(&self) -> Option<u64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(monotonic_ms().saturating_sub(last)),
+        }
+    }
+}
+
+/// Milliseconds on a monotonic clock, starting at 1 so 0 can mean "never"
+fn monotonic_ms() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64 + 1
+}
+
+/// Heartbeat age of each audio loop in milliseconds, as reported by `GetStatus`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatAges {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker_capture: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker_render: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_capture: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mic_render: Option<u64>,
+}
+
+/// Point-in-time copy of `PathMetrics`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PathSnapshot {
+    pub overflows: u64,
+    pub underruns: u64,
+    pub recoveries: u64,
+    pub buffer_fill_samples: u64,
+    pub latency_us: u64,
+    #[serde(default)]
+    pub input_silent: bool,
+    #[serde(default)]
+    pub input_clips: u64,
+    #[serde(default)]
+    pub forced_skips: u64,
+}
+
+/// Point-in-time copy of `Metrics`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub speaker: PathSnapshot,
+    pub mic: PathSnapshot,
+}
+
+impl MetricsSnapshot {
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let (s, m) = (&self.speaker, &self.mic);
+        let mut out = String::new();
+        write_series(&mut out, "audio_proxy_overflows_total", "counter",
+                     "Capture blocks dropped because the ring buffer was full", s.overflows, m.overflows);
+        write_series(&mut out, "audio_proxy_underruns_total", "counter",
+                     "Times the render device ran out of audio", s.underruns, m.underruns);
+        write_series(&mut out, "audio_proxy_recoveries_total", "counter",
+                     "Streams reopened after an error or device change", s.recoveries, m.recoveries);
+        write_series(&mut out, "audio_proxy_buffer_fill_samples", "gauge",
+                     "Samples waiting in the ring buffer", s.buffer_fill_samples, m.buffer_fill_samples);
+        write_series(&mut out, "audio_proxy_latency_seconds", "gauge",
+                     "Audio queued in the ring buffer and render device",
+                     s.latency_us as f64 / 1_000_000.0, m.latency_us as f64 / 1_000_000.0);
+        write_series(&mut out, "audio_proxy_input_silent", "gauge",
+                     "Whether the captured input is below the silence threshold",
+                     s.input_silent as u8, m.input_silent as u8);
+        write_series(&mut out, "audio_proxy_input_clips_total", "counter",
+                     "Captured samples at or above the clip ceiling", s.input_clips, m.input_clips);
+        write_series(&mut out, "audio_proxy_forced_skips_total", "counter",
+                     "Times an overfull ring buffer was skipped forward (--max-fill-ms)",
+                     s.forced_skips, m.forced_skips);
+        out
+    }
+}
+
+impl MetricsSnapshot {
+    /// One-line summary for `--status-interval`, as `key=value` pairs with the path as
+    /// key prefix. Counters are the change since `previous`; a reset in between counts
+    /// as no change. Keys are only ever added, so a log scraper can rely on them. The
+    /// mic is left out until its loops have run.
+    pub fn status_line(&self, previous: &MetricsSnapshot, ages: &HeartbeatAges) -> String {
+        let mut line = String::from("status");
+        write_status(&mut line, "speaker", &self.speaker, &previous.speaker, ages.speaker_capture, ages.speaker_render);
+        if ages.mic_capture.is_some() || ages.mic_render.is_some() {
+            write_status(&mut line, "mic", &self.mic, &previous.mic, ages.mic_capture, ages.mic_render);
+        }
+        line
+    }
+}
+
+/// Heartbeat age past which the status line reports a loop as stalled
+pub const STALLED_AFTER_MS: u64 = 2000;
+
+/// Append the status of one path
+fn write_status(
+    out: &mut String, path: &str, current: &PathSnapshot, previous: &PathSnapshot,
+    capture_age: Option<u64>, render_age: Option<u64>,
+) {
+    let health = match capture_age.max(render_age) {
+        None => "idle",
+        Some(age) if age > STALLED_AFTER_MS => "stalled",
+        Some(_) => "ok",
+    };
+    let _ = write!(
+        out,
+        " {p}_fill={} {p}_latency_ms={:.1} {p}_overflows={} {p}_underruns={} {p}_recoveries={} {p}_health={}",
+        current.buffer_fill_samples,
+        current.latency_us as f64 / 1000.0,
+        current.overflows.saturating_sub(previous.overflows),
+        current.underruns.saturating_sub(previous.underruns),
+        current.recoveries.saturating_sub(previous.recoveries),
+        health,
+        p = path,
+    );
+}
+
+/// Number of samples whose magnitude reaches `ceiling`
+pub fn count_clips(samples: &[f32], ceiling: f32) -> u64 {
+    samples.iter().filter(|s| s.abs() >= ceiling).count() as u64
+}
+
+/// Append one metric with a sample per path
+fn write_series(out: &mut String, name: &str, kind: &str, help: &str, speaker: impl Display, mic: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{}{{path=\"speaker\"}} {}", name, speaker);
+    let _ = writeln!(out, "{}{{path=\"mic\"}} {}", name, mic);
+}
+
+/// Serve `GET /metrics` on `addr` until `running` is cleared
+pub fn serve(addr: &str, running: Arc<AtomicBool>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to bind metrics endpoint to {}", addr))?;
+    // Non-blocking so the loop notices shutdown
+    listener.set_nonblocking(true)?;
+    info!("Metrics endpoint listening on http://{}/metrics", addr);
+
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("Metrics request from {}", peer);
+                if let Err(e) = handle_request(stream) {
+                    debug!("Metrics request failed: {}", e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => {
+                warn!("Metrics endpoint accept error: {}", e);
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+    // Only the request line matters; a single read covers it for any real client
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request)?;
+    let request_line = String::from_utf8_lossy(&request[..len]);
+    let mut parts = request_line.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.snapshot().to_prometheus()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = Metrics::new();
+        metrics.speaker.overflows.fetch_add(3, Ordering::Relaxed);
+        metrics.mic.underruns.fetch_add(1, Ordering::Relaxed);
+        metrics.speaker.latency_us.store(12_500, Ordering::Relaxed);
+        metrics.mic.input_silent.store(true, Ordering::Relaxed);
+        metrics.speaker.forced_skips.fetch_add(2, Ordering::Relaxed);
+
+        let text = metrics.snapshot().to_prometheus();
+        assert!(text.contains("# TYPE audio_proxy_overflows_total counter\n"));
+        assert!(text.contains("audio_proxy_overflows_total{path=\"speaker\"} 3\n"));
+        assert!(text.contains("audio_proxy_underruns_total{path=\"mic\"} 1\n"));
+        assert!(text.contains("audio_proxy_latency_seconds{path=\"speaker\"} 0.0125\n"));
+        assert!(text.contains("audio_proxy_buffer_fill_samples{path=\"mic\"} 0\n"));
+        assert!(text.contains("audio_proxy_input_silent{path=\"mic\"} 1\n"));
+        assert!(text.contains("audio_proxy_forced_skips_total{path=\"speaker\"} 2\n"));
+    }
+
+    #[test]
+    fn test_take_snapshot_resets_counters() {
+        let metrics = Metrics::new();
+        metrics.speaker.underruns.fetch_add(2, Ordering::Relaxed);
+        metrics.mic.input_clips.fetch_add(5, Ordering::Relaxed);
+        metrics.speaker.buffer_fill_samples.store(960, Ordering::Relaxed);
+
+        let taken = metrics.take_snapshot();
+        assert_eq!(taken.speaker.underruns, 2);
+        assert_eq!(taken.mic.input_clips, 5);
+
+        // Counters start over, gauges keep their value
+        let after = metrics.snapshot();
+        assert_eq!(after.speaker.underruns, 0);
+        assert_eq!(after.mic.input_clips, 0);
+        assert_eq!(after.speaker.buffer_fill_samples, 960);
+    }
+
+    #[test]
+    fn test_status_line() {
+        let metrics = Metrics::new();
+        metrics.speaker.underruns.fetch_add(3, Ordering::Relaxed);
+        let previous = metrics.snapshot();
+        metrics.speaker.underruns.fetch_add(2, Ordering::Relaxed);
+        metrics.speaker.buffer_fill_samples.store(960, Ordering::Relaxed);
+        metrics.speaker.latency_us.store(21_250, Ordering::Relaxed);
+
+        let ages = HeartbeatAges { speaker_capture: Some(5), speaker_render: Some(8), ..Default::default() };
+        assert_eq!(
+            metrics.snapshot().status_line(&previous, &ages),
+            "status speaker_fill=960 speaker_latency_ms=21.2 speaker_overflows=0 speaker_underruns=2 \
+             speaker_recoveries=0 speaker_health=ok",
+        );
+
+        // A loop that stopped beating, and counters reset over IPC since the last line
+        let ages = HeartbeatAges { mic_capture: Some(5), mic_render: Some(STALLED_AFTER_MS + 1), ..ages };
+        let line = metrics.take_snapshot().status_line(&MetricsSnapshot::default(), &ages);
+        assert!(line.ends_with(" mic_underruns=0 mic_recoveries=0 mic_health=stalled"), "{}", line);
+        assert!(metrics.snapshot().status_line(&previous, &ages).contains(" speaker_underruns=0 "));
+        assert!(MetricsSnapshot::default().status_line(&previous, &HeartbeatAges::default()).ends_with("_health=idle"));
+    }
+
+    #[test]
+    fn test_heartbeat_age() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.heartbeat_ages(), HeartbeatAges::default());
+
+        metrics.speaker.render_heartbeat.beat();
+        thread::sleep(Duration::from_millis(20));
+        let age = metrics.heartbeat_ages().speaker_render.unwrap();
+        assert!((20..1000).contains(&age), "{}", age);
+        assert_eq!(metrics.heartbeat_ages().mic_capture, None);
+    }
+
+    #[test]
+    fn test_count_clips() {
+        assert_eq!(count_clips(&[0.5, 1.0, -1.0, 0.99, -1.2], 1.0), 3);
+        assert_eq!(count_clips(&[0.5, 0.9, -0.95], 0.9), 2);
+    }
+
+    #[test]
+    fn test_serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_request(stream).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("audio_proxy_recoveries_total{path=\"speaker\"}"));
+    }
+}
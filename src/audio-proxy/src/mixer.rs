@@ -0,0 +1,298 @@
+//! Second capture source mixed into a render path
+//!
+//! With `--speaker-in2` (or `--mic-in2`) a second capture thread fills its own ring
+//! buffer (e.g. voice chat on a second virtual cable, or a second person's mic). The
+//! render loop converts that audio to the render format and adds it to the primary
+//! source before writing, so the proxy doubles as a simple two-input mixer. Each
+//! source has a `SourceLevel`: a gain and an on/off switch, both ramped so changing
+//! them doesn't click.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::audio_stream::AudioFormat;
+use crate::convert::{convert_audio, formats_need_conversion, ConversionSettings, ConversionState};
+use crate::dsp::GainRamp;
+use crate::ring_buffer::AudioRingBuffer;
+
+/// Highest gain a mixed source accepts
+pub const MAX_SOURCE_GAIN_DB: f32 = 12.0;
+
+/// Time constant of the source gain ramp
+pub const SOURCE_RAMP_MS: f32 = 10.0;
+
+/// Mic input `SetMicSourceEnabled` switches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MicSource {
+    /// `--mic-in`
+    Primary,
+    /// `--mic-in2`
+    Secondary,
+}
+
+/// Check a `--mic-gain-db` / `--mic-in2-gain-db` value
+pub fn validate_source_gain_db(db: f32) -> Result<()> {
+    if !db.is_finite() || db > MAX_SOURCE_GAIN_DB {
+        anyhow::bail!("Source gain must be at most {} dB: {}", MAX_SOURCE_GAIN_DB, db);
+    }
+    Ok(())
+}
+
+/// Gain and on/off switch of one mixed source, shared with the IPC thread
+#[derive(Debug)]
+pub struct SourceLevel {
+    enabled: AtomicBool,
+    /// Bits of the gain in dB
+    gain_db: AtomicU32,
+}
+
+impl SourceLevel {
+    pub fn new(gain_db: f32) -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            gain_db: AtomicU32::new(gain_db.to_bits()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Level the source should play at (`-inf` while switched off)
+    pub fn target_db(&self) -> f32 {
+        if self.is_enabled() {
+            f32::from_bits(self.gain_db.load(Ordering::Relaxed))
+        } else {
+            f32::NEG_INFINITY
+        }
+    }
+}
+
+impl Default for SourceLevel {
+    fn default() -> Self {
+        Self::new(0.0)
+    }
+}
+
+/// Ring buffer and capture format the second capture thread fills
+#[derive(Clone)]
+pub struct SecondarySource {
+    pub buffer: Arc<AudioRingBuffer>,
+    /// Published by the second capture thread (`None` until it has started)
+    pub capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    pub level: Arc<SourceLevel>,
+}
+
+/// Render-side state of the second source
+pub struct SecondaryMix {
+    source: SecondarySource,
+    conversion: ConversionState,
+    read_buffer: Vec<f32>,
+    /// Converted audio not yet written to the device, in `pending_format`
+    pending: Vec<f32>,
+    pending_format: Option<AudioFormat>,
+    /// Samples the last `mix_into` added, the most `consume` may drop
+    mixed: usize,
+    /// Applied as audio is converted into `pending`
+    gain: GainRamp,
+}
+
+impl SecondaryMix {
+    pub fn new(source: SecondarySource, conversion: ConversionSettings) -> Self {
+        Self {
+            gain: GainRamp::new(source.level.target_db(), SOURCE_RAMP_MS, 48000),
+            source,
+            conversion: ConversionState::new(conversion),
+            read_buffer: vec![0.0; 4096],
+            pending: Vec::new(),
+            pending_format: None,
+            mixed: 0,
+        }
+    }
+
+    /// Add second-source audio to `output` (interleaved, in `render_format`), clamping
+    /// the sum to [-1, 1]. Nothing is consumed until `consume`, so audio the device
+    /// didn't accept is mixed in again next time. Returns the number of samples mixed.
+    pub fn mix_into(&mut self, output: &mut [f32], render_format: &AudioFormat) -> usize {
+        if self.pending_format.as_ref() != Some(render_format) {
+            // Output was reopened at another format, what's left can't be used
+            self.pending.clear();
+            self.pending_format = Some(render_format.clone());
+            self.gain.set_sample_rate(render_format.sample_rate);
+        }
+        self.refill(output.len(), render_format);
+
+        self.mixed = self.pending.len().min(output.len());
+        for (out, sample) in output.iter_mut().zip(&self.pending[..self.mixed]) {
+            *out = (*out + sample).clamp(-1.0, 1.0);
+        }
+        self.mixed
+    }
+
+    /// Drop the first `samples` of the last mix once they were written to the device
+    pub fn consume(&mut self, samples: usize) {
+        let samples = samples.min(self.mixed);
+        self.pending.drain(..samples);
+        self.mixed = 0;
+    }
+
+    /// Drop pending and buffered audio, e.g. what was left from before a pause
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.mixed = 0;
+        while self.source.buffer.read(&mut self.read_buffer) > 0 {}
+    }
+
+    /// Convert audio from the ring buffer until `wanted` samples are pending or it runs dry
+    fn refill(&mut self, wanted: usize, render_format: &AudioFormat) {
+        let Some(cf) = self.source.capture_format.read().unwrap().clone() else {
+            return;
+        };
+        let (in_ch, out_ch) = (cf.channels as usize, render_format.channels as usize);
+        if in_ch == 0 || out_ch == 0 || render_format.sample_rate == 0 {
+            return;
+        }
+
+        self.gain.set_target(self.source.level.target_db());
+        while self.pending.len() < wanted {
+            let missing_frames = (wanted - self.pending.len()).div_ceil(out_ch) as u64;
+            let in_frames = (missing_frames * cf.sample_rate as u64).div_ceil(render_format.sample_rate as u64);
+            let len = (in_frames as usize * in_ch).min(self.read_buffer.len() / in_ch * in_ch);
+            let read = self.source.buffer.read(&mut self.read_buffer[..len]);
+            if read == 0 {
+                break;
+            }
+
+            let start = self.pending.len();
+            if formats_need_conversion(&cf, render_format) {
+                let converted = convert_audio(&self.read_buffer[..read], &cf, render_format, &mut self.conversion);
+                self.pending.extend_from_slice(converted);
+            } else {
+                self.pending.extend_from_slice(&self.read_buffer[..read]);
+            }
+            self.gain.process(&mut self.pending[start..], out_ch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+        AudioFormat {
+            sample_rate,
+            channels,
+            bits_per_sample: 32,
+            block_align: channels as u32 * 4,
+        }
+    }
+
+    fn source(samples: &[f32], fmt: AudioFormat) -> SecondaryMix {
+        let buffer = Arc::new(AudioRingBuffer::new(1024));
+        buffer.write(samples);
+        let capture_format = Arc::new(RwLock::new(Some(fmt)));
+        let level = Arc::new(SourceLevel::default());
+        SecondaryMix::new(SecondarySource { buffer, capture_format, level }, ConversionSettings::default())
+    }
+
+    #[test]
+    fn test_sums_and_clamps() {
+        let mut mix = source(&[0.5, -0.5, 0.5, -0.5], format(48000, 2));
+        let mut output = vec![0.7, -0.7, -0.2, 0.2];
+        assert_eq!(mix.mix_into(&mut output, &format(48000, 2)), 4);
+        assert_eq!(output, vec![1.0, -1.0, 0.3, -0.3]);
+    }
+
+    #[test]
+    fn test_unwritten_audio_is_mixed_again() {
+        let mut mix = source(&[0.1, 0.2, 0.3, 0.4], format(48000, 2));
+        let fmt = format(48000, 2);
+
+        let mut output = vec![0.0; 4];
+        mix.mix_into(&mut output, &fmt);
+        // The device only took the first frame
+        mix.consume(2);
+
+        let mut output = vec![0.0; 4];
+        assert_eq!(mix.mix_into(&mut output, &fmt), 2);
+        assert_eq!(output, vec![0.3, 0.4, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_converts_to_render_format() {
+        let mut mix = source(&[0.25, 0.5], format(48000, 1));
+        let mut output = vec![0.0; 4];
+        assert_eq!(mix.mix_into(&mut output, &format(48000, 2)), 4);
+        assert_eq!(output, vec![0.25, 0.25, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_consume_only_drops_what_was_mixed() {
+        let mut mix = source(&[0.1, 0.2, 0.3, 0.4], format(48000, 2));
+        let fmt = format(48000, 2);
+
+        let mut output = vec![0.0; 2];
+        mix.mix_into(&mut output, &fmt);
+        // A larger write (e.g. of a block the source wasn't mixed into) can't skip ahead
+        mix.consume(8);
+
+        let mut output = vec![0.0; 2];
+        mix.mix_into(&mut output, &fmt);
+        assert_eq!(output, vec![0.3, 0.4]);
+    }
+
+    #[test]
+    fn test_level_gain_and_switch() {
+        let buffer = Arc::new(AudioRingBuffer::new(1 << 16));
+        buffer.write(&[0.5; 4]);
+        let capture_format = Arc::new(RwLock::new(Some(format(48000, 2))));
+        let level = Arc::new(SourceLevel::new(-6.0));
+        let source = SecondarySource { buffer: buffer.clone(), capture_format, level: level.clone() };
+        let mut mix = SecondaryMix::new(source, ConversionSettings::default());
+        let fmt = format(48000, 2);
+
+        let mut output = vec![0.0; 4];
+        mix.mix_into(&mut output, &fmt);
+        mix.consume(4);
+        assert!(output.iter().all(|s| (s - 0.25).abs() < 0.01), "{:?}", output);
+
+        // Switched off, the source ramps down and its audio is still used up
+        level.set_enabled(false);
+        assert_eq!(level.target_db(), f32::NEG_INFINITY);
+        buffer.write(&[0.5; 9600]);
+        let mut output = vec![0.0; 9600];
+        assert_eq!(mix.mix_into(&mut output, &fmt), 9600);
+        mix.consume(9600);
+        assert!(output[0] > 0.2 && output[9599].abs() < 1e-4);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_validate_source_gain_db() {
+        assert!(validate_source_gain_db(-20.0).is_ok());
+        assert!(validate_source_gain_db(MAX_SOURCE_GAIN_DB).is_ok());
+        assert!(validate_source_gain_db(13.0).is_err());
+        assert!(validate_source_gain_db(f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_nothing_mixed_before_source_starts() {
+        let buffer = Arc::new(AudioRingBuffer::new(64));
+        buffer.write(&[0.5; 8]);
+        let capture_format = Arc::new(RwLock::new(None));
+        let level = Arc::new(SourceLevel::default());
+        let mut mix = SecondaryMix::new(SecondarySource { buffer, capture_format, level }, ConversionSettings::default());
+        let mut output = vec![0.1; 4];
+        assert_eq!(mix.mix_into(&mut output, &format(48000, 2)), 0);
+        assert_eq!(output, vec![0.1; 4]);
+    }
+}
@@ -0,0 +1,253 @@
+//! Monitor output: a copy of the speaker capture on a second device
+//!
+//! `SetMonitor` tees the captured speaker audio to another render device, e.g. a
+//! headset to listen along while the game plays on the speakers, at its own level.
+//! The capture thread writes every block into a `BroadcastRingBuffer`; the speaker
+//! render loop reads it through its own cursor and writes it to a second
+//! `RenderStream`. The monitor is best effort: when its device fails it is closed and
+//! retried a little later, and the main output carries on untouched. Level changes
+//! and (re)opens ramp through a `GainRamp`, so neither clicks.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::audio_stream::{AudioFormat, RenderStream};
+use crate::convert::{convert_audio, formats_need_conversion, ConversionSettings, ConversionState};
+use crate::dsp::GainRamp;
+use crate::ring_buffer::{BroadcastReader, BroadcastRingBuffer};
+
+/// Highest monitor level `SetMonitor` accepts
+pub const MAX_MONITOR_LEVEL_DB: f32 = 12.0;
+
+/// Size of the capture tap in samples; the render loop drains it every few ms
+const TAP_CAPACITY: usize = 65536;
+
+/// Wait before reopening a monitor device that failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time constant of the monitor level ramp
+const LEVEL_RAMP_MS: f32 = 10.0;
+
+/// Device and level the monitor plays on
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorTarget {
+    pub device_id: String,
+    pub level_db: f32,
+}
+
+impl MonitorTarget {
+    /// Check that the level is usable
+    pub fn validate(&self) -> Result<()> {
+        if !self.level_db.is_finite() || self.level_db > MAX_MONITOR_LEVEL_DB {
+            anyhow::bail!("Monitor level must be at most {} dB: {}", MAX_MONITOR_LEVEL_DB, self.level_db);
+        }
+        Ok(())
+    }
+}
+
+/// Monitor target and capture tap shared by the IPC, capture and render threads
+#[derive(Clone)]
+pub struct SharedMonitor {
+    target: Arc<RwLock<Option<MonitorTarget>>>,
+    tap: Arc<BroadcastRingBuffer>,
+}
+
+impl Default for SharedMonitor {
+    fn default() -> Self {
+        Self {
+            target: Arc::new(RwLock::new(None)),
+            tap: Arc::new(BroadcastRingBuffer::new(TAP_CAPACITY)),
+        }
+    }
+}
+
+impl SharedMonitor {
+    /// Start (or move) the monitor; the render loop opens the device
+    pub fn set(&self, target: MonitorTarget) -> Result<()> {
+        target.validate()?;
+        *self.target.write().unwrap() = Some(target);
+        Ok(())
+    }
+
+    /// Stop the monitor; returns whether one was set
+    pub fn clear(&self) -> bool {
+        self.target.write().unwrap().take().is_some()
+    }
+
+    pub fn target(&self) -> Option<MonitorTarget> {
+        self.target.read().unwrap().clone()
+    }
+
+    /// Ring buffer the speaker capture thread writes its blocks to (also read by
+    /// `GetSpectrum`)
+    pub fn tap(&self) -> Arc<BroadcastRingBuffer> {
+        self.tap.clone()
+    }
+}
+
+/// Render-side state of the monitor, owned by the speaker render loop
+pub struct Monitor {
+    shared: SharedMonitor,
+    reader: BroadcastReader,
+    conversion_settings: ConversionSettings,
+    conversion: ConversionState,
+    read_buffer: Vec<f32>,
+    level: GainRamp,
+    /// Target `stream` was opened for (or is waiting to be retried for)
+    current: Option<MonitorTarget>,
+    stream: Option<RenderStream>,
+    retry_at: Option<Instant>,
+}
+
+impl Monitor {
+    pub fn new(shared: SharedMonitor, conversion_settings: ConversionSettings) -> Self {
+        Self {
+            reader: shared.tap.reader(),
+            shared,
+            conversion_settings,
+            conversion: ConversionState::new(conversion_settings),
+            read_buffer: vec![0.0; 4096],
+            level: GainRamp::new(f32::NEG_INFINITY, LEVEL_RAMP_MS, 48000),
+            current: None,
+            stream: None,
+            retry_at: None,
+        }
+    }
+
+    /// Write newly captured audio (in `capture_format`) to the monitor device, opening
+    /// or switching it first if the device changed. Errors never reach the caller:
+    /// the device is closed and retried after `RETRY_INTERVAL`.
+    pub fn pump(&mut self, capture_format: Option<&AudioFormat>) {
+        let target = self.shared.target();
+        if target.as_ref().map(|t| &t.device_id) != self.current.as_ref().map(|t| &t.device_id) {
+            self.release();
+            self.retry_at = None;
+            self.conversion.reset(self.conversion_settings);
+        }
+        self.current = target;
+        let Some(ref target) = self.current else {
+            return;
+        };
+        // A level change on the same device just ramps
+        self.level.set_target(target.level_db);
+
+        if self.stream.is_none() {
+            if self.retry_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            match open_stream(&target.device_id) {
+                Ok(stream) => {
+                    info!("Monitor output opened on: {} ({:+.1} dB)", target.device_id, target.level_db);
+                    // Fade in rather than start mid-waveform
+                    if let Some(format) = stream.format() {
+                        self.level.set_sample_rate(format.sample_rate);
+                    }
+                    self.level.reset(f32::NEG_INFINITY);
+                    self.level.set_target(target.level_db);
+                    self.stream = Some(stream);
+                    // Start from live audio, not what piled up while closed
+                    self.reader = self.shared.tap.reader();
+                }
+                Err(e) => {
+                    warn!("Failed to open monitor output {}: {}", target.device_id, e);
+                    self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                    return;
+                }
+            }
+        }
+
+        let (Some(cf), Some(stream)) = (capture_format, self.stream.as_mut()) else {
+            return;
+        };
+        let Some(rf) = stream.format().cloned() else {
+            return;
+        };
+        let channels = rf.channels as usize;
+
+        loop {
+            let read = self.reader.read(&mut self.read_buffer);
+            if read == 0 {
+                break;
+            }
+
+            // Whatever the device can't take right now is dropped, the monitor never lags behind
+            let samples = &mut self.read_buffer[..read];
+            let result = if formats_need_conversion(cf, &rf) {
+                let converted = convert_audio(samples, cf, &rf, &mut self.conversion);
+                self.level.process(converted, channels);
+                clamp_to_unity(converted);
+                stream.write(converted)
+            } else {
+                self.level.process(samples, channels);
+                clamp_to_unity(samples);
+                stream.write(samples)
+            };
+
+            if let Err(e) = result {
+                warn!("Monitor output failed, retrying in {:?}: {}", RETRY_INTERVAL, e);
+                self.release();
+                self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                return;
+            }
+        }
+    }
+
+    /// Close the monitor device, e.g. while paused; the next `pump` reopens it
+    pub fn release(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.stop();
+        }
+    }
+}
+
+fn open_stream(device_id: &str) -> Result<RenderStream> {
+    let mut stream = RenderStream::new(device_id)?;
+    stream.start()?;
+    Ok(stream)
+}
+
+/// Keep boosted samples within [-1, 1]
+fn clamp_to_unity(samples: &mut [f32]) {
+    for sample in samples {
+        *sample = sample.clamp(-1.0, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(level_db: f32) -> MonitorTarget {
+        MonitorTarget { device_id: "{monitor}".to_string(), level_db }
+    }
+
+    #[test]
+    fn test_level_validation() {
+        assert!(target(-6.0).validate().is_ok());
+        assert!(target(MAX_MONITOR_LEVEL_DB).validate().is_ok());
+        assert!(target(MAX_MONITOR_LEVEL_DB + 1.0).validate().is_err());
+        assert!(target(f32::NAN).validate().is_err());
+    }
+
+    #[test]
+    fn test_set_and_clear() {
+        let shared = SharedMonitor::default();
+        assert!(shared.set(target(24.0)).is_err());
+        assert_eq!(shared.target(), None);
+
+        shared.set(target(-6.0)).unwrap();
+        assert_eq!(shared.target(), Some(target(-6.0)));
+        assert!(shared.clear());
+        assert!(!shared.clear());
+    }
+
+    #[test]
+    fn test_clamp_to_unity() {
+        let mut samples = vec![1.5, -2.0, 0.25];
+        clamp_to_unity(&mut samples);
+        assert_eq!(samples, vec![1.0, -1.0, 0.25]);
+    }
+}
@@ -0,0 +1,58 @@
+//! Suspend/resume notifications
+//!
+//! Waking from sleep invalidates every open stream. The callback registered here logs
+//! the wake, so the errors that follow read as what they are, and opens
+//! `recovery::RESUME_GATE`, which gives the audio loops staggered turns to reopen
+//! their devices instead of all at once. No window is needed: powrprof calls the
+//! callback on a thread of its own.
+
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use windows::Win32::Foundation::{ERROR_SUCCESS, HANDLE};
+use windows::Win32::System::Power::{PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS};
+use windows::Win32::UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC};
+
+use crate::recovery::RESUME_GATE;
+
+/// Set once the callback is registered
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Register for resume notifications, once per process. The registration lasts until
+/// the process exits, like the streams it looks after.
+pub fn install() -> Result<()> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    // Leaked: it has to outlive the registration
+    let params = Box::leak(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(on_power_event),
+        Context: ptr::null_mut(),
+    }));
+    let mut registration = ptr::null_mut();
+    let status = unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+            &mut registration,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        INSTALLED.store(false, Ordering::SeqCst);
+        return Err(anyhow!("Failed to register for resume notifications: {:?}", status));
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn on_power_event(_context: *const c_void, event: u32, _setting: *const c_void) -> u32 {
+    // Sent on every wake, whether or not someone is at the keyboard
+    if event == PBT_APMRESUMEAUTOMATIC {
+        info!("System resumed from sleep, reopening streams in turn");
+        RESUME_GATE.resumed(Instant::now());
+    }
+    ERROR_SUCCESS.0
+}
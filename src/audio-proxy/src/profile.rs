@@ -0,0 +1,99 @@
+//! Tuning presets for `--profile`
+//!
+//! Getting a good setup means picking a buffer size, render chunking and a recovery
+//! policy that fit together. A `Profile` bundles sensible combinations of those
+//! options; any of them given explicitly on the command line still wins over the
+//! preset. `balanced` is what the proxy uses without `--profile`.
+//!
+//! The presets only cover options the proxy has: there is no adaptive buffer growth
+//! or thread priority setting, so `low-latency` and `reliable` differ in buffer size,
+//! batching and how patiently failed streams are reopened.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::recovery::RecoveryPolicy;
+
+/// Named combination of latency and robustness settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Smallest buffer the devices usually sustain, unbatched writes, and quick
+    /// retries that give up fast instead of playing with a growing delay
+    LowLatency,
+    /// The built-in defaults
+    #[default]
+    Balanced,
+    /// Large buffer and batched writes that ride out scheduling hiccups, and
+    /// recovery that keeps retrying for a long while (e.g. through a USB replug)
+    Reliable,
+}
+
+/// Option values a profile selects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preset {
+    pub buffer_ms: u32,
+    pub render_chunk_ms: u32,
+    pub recovery: RecoveryPolicy,
+}
+
+impl Profile {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "low-latency" => Ok(Profile::LowLatency),
+            "balanced" => Ok(Profile::Balanced),
+            "reliable" => Ok(Profile::Reliable),
+            _ => Err(anyhow::anyhow!("Unknown profile: {} (expected low-latency, balanced or reliable)", s)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::LowLatency => "low-latency",
+            Profile::Balanced => "balanced",
+            Profile::Reliable => "reliable",
+        }
+    }
+
+    pub fn preset(self) -> Preset {
+        let (buffer_ms, render_chunk_ms, max_attempts, backoff_ms, max_backoff_ms) = match self {
+            Profile::LowLatency => (3, 0, 5, 50, 1000),
+            Profile::Balanced => (10, 0, 5, 250, 4000),
+            Profile::Reliable => (50, 10, 20, 500, 30_000),
+        };
+        Preset {
+            buffer_ms,
+            render_chunk_ms,
+            recovery: RecoveryPolicy {
+                max_attempts,
+                initial_backoff: Duration::from_millis(backoff_ms),
+                max_backoff: Duration::from_millis(max_backoff_ms),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Profile::parse("low-latency").unwrap(), Profile::LowLatency);
+        assert_eq!(Profile::parse("Reliable").unwrap(), Profile::Reliable);
+        assert!(Profile::parse("fast").is_err());
+        for profile in [Profile::LowLatency, Profile::Balanced, Profile::Reliable] {
+            assert_eq!(Profile::parse(profile.name()).unwrap(), profile);
+        }
+    }
+
+    #[test]
+    fn test_presets_trade_latency_for_robustness() {
+        let (low, balanced, reliable) =
+            (Profile::LowLatency.preset(), Profile::Balanced.preset(), Profile::Reliable.preset());
+        assert!(low.buffer_ms < balanced.buffer_ms && balanced.buffer_ms < reliable.buffer_ms);
+        assert!(low.recovery.max_backoff < reliable.recovery.max_backoff);
+        assert!(balanced.recovery.max_attempts < reliable.recovery.max_attempts);
+        assert_eq!(low.render_chunk_ms, 0);
+    }
+}
@@ -0,0 +1,3457 @@
+//! The proxy itself: the capture/render threads of the speaker and mic paths and the
+//! IPC server, as a library API
+//!
+//! `Proxy::builder()` (or `Proxy::start` with a full `ProxyConfig`) starts the same
+//! threads the `audio-proxy` binary runs and returns a `ProxyHandle`. The handle
+//! applies commands to the shared state directly, so an app embedding the proxy
+//! needs neither the named pipe nor the binary; the pipe server is only started when
+//! asked for (`ProxyConfig::ipc_pipe`).
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+
+#[cfg(feature = "asio")]
+use crate::asio_stream;
+use crate::chain::{chain_names, Chain, RenderPath, SharedChain, Stage};
+use crate::ab_match::{AbTrim, SharedAbMatch};
+use crate::{convert, diagnostics, dsp, fill_limit, ipc, metrics, mixer, power, reblock, rtp, silence, spectrum, stall};
+use crate::audio_stream::{
+    get_endpoint_volume, get_endpoint_volume_db, is_render_endpoint_id, list_endpoints, probe_supported_formats,
+    resolve_capture_endpoint, resolve_render_endpoint, set_endpoint_volume, validate_sample_rate, AudioFormat, CaptureStream, ComModel,
+    DefaultCaptureWatcher, DefaultRole, RenderBackend, RenderStream, RequestedFormat, StreamCategory, StreamError, DEFAULT_DEVICE_BUFFER_MS,
+};
+use crate::convert::{
+    channel_mix_gain_db, convert_audio, describe_conversion, formats_need_conversion, is_whole_frames, swap_left_right,
+    ChannelMap, ChannelMix, ConversionSettings, ConversionState, ResampleQuality, UpmixMode,
+};
+use crate::delay::{DelayLine, DelayTarget, SharedDelay};
+use crate::dither::DitherMode;
+use crate::diagnostics::{Diagnostics, PathDiagnostics, SettingsDiagnostics};
+use crate::dsp::{GainCeiling, GainRamp, DEFAULT_MAX_OUTPUT_DB};
+use crate::eq::{Equalizer, SharedEq};
+use crate::fade::FadeIn;
+use crate::fill_limit::FillLimit;
+use crate::glitch_dump::{GlitchDumper, GlitchKind};
+use crate::ipc::{IpcCommand, IpcResponse, IpcServer, IpcTransport, TcpIpcServer};
+use crate::keep_alive::IdleFill;
+use crate::meter::{LevelMeter, MeterReading, MeterSettings, SharedMeterSettings};
+use crate::metrics::{count_clips, Heartbeat, PathMetrics, METRICS};
+use crate::mixer::{MicSource, SecondaryMix, SecondarySource, SourceLevel, SOURCE_RAMP_MS};
+use crate::monitor::{Monitor, MonitorTarget, SharedMonitor};
+use crate::recent_errors::RECENT_ERRORS;
+use crate::profile::Profile;
+use crate::reblock::Reblocker;
+use crate::recovery::{Backoff, RecoveryPolicy, SharedRecoveryPolicy, RESUME_GATE};
+use crate::ring_buffer::{AudioRingBuffer, BroadcastRingBuffer};
+use crate::rtp::RtpCodec;
+use crate::silence::{SharedSilenceThreshold, DEFAULT_SILENCE_THRESHOLD_DB};
+use crate::stall::{SharedInjectedStall, SharedStall, StallReserve, StallStep};
+use crate::test_signal::Tone;
+
+/// Range `--buffer` accepts: below 1 ms nothing is prefilled and playback only
+/// underruns, above 2 s the latency is useless for live audio
+pub const MIN_BUFFER_MS: u32 = 1;
+pub const MAX_BUFFER_MS: u32 = 2000;
+
+/// Smallest ring buffer, in samples: one full capture read (the loops read up to 4096
+/// samples at a time), which is more than any shared-mode WASAPI period delivers, plus
+/// the slot an `AudioRingBuffer` keeps empty
+const MIN_RING_SAMPLES: usize = 4096 + 1;
+
+/// Default sample rate for buffer size estimation (actual rate comes from device)
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// Default channel count for buffer size estimation
+const DEFAULT_CHANNELS: u16 = 2;
+
+/// Default length of the capture history written on a glitch, in seconds
+pub const DEFAULT_GLITCH_DUMP_SECS: u32 = 5;
+
+/// Default time allowed to play out buffered speaker audio on graceful shutdown
+pub const DEFAULT_DRAIN_MS: u32 = 200;
+
+/// Default fade-in applied to the first audio after a render stream (re)opens
+pub const DEFAULT_START_FADE_MS: u32 = 10;
+
+/// How long `SetOutputWithFormat` waits for the render loop to reopen the output
+const OUTPUT_SWITCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the render loops refresh the buffer fill and latency gauges
+const METRICS_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often paused loops check whether to resume
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often a capture loop whose device was unplugged checks whether it's back
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Format `RunMode::RenderOnly` generates its tone in, converted to the device's like
+/// captured audio
+const TONE_FORMAT: AudioFormat = AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 };
+
+/// Level of the `RenderOnly` tone: -20 dBFS, clearly audible without being loud
+const TONE_AMPLITUDE: f32 = 0.1;
+
+/// Frequency of the `RenderOnly` tone
+const TONE_HZ: f32 = 440.0;
+
+/// Audio the tone source writes at a time
+const TONE_BLOCK: Duration = Duration::from_millis(10);
+
+/// Time constant of the speaker fade in and out of `SoloMic`
+const SOLO_RAMP_MS: f32 = 10.0;
+
+/// How often `RunMode::CaptureOnly` logs the input levels
+const LEVEL_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which audio API the speaker output renders through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputBackend {
+    #[default]
+    Wasapi,
+    Asio,
+}
+
+impl OutputBackend {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "wasapi" => Ok(OutputBackend::Wasapi),
+            "asio" => Ok(OutputBackend::Asio),
+            _ => Err(anyhow::anyhow!("Unknown output backend: {} (expected wasapi or asio)", s)),
+        }
+    }
+}
+
+/// Which halves of the audio paths run. The partial modes narrow down where a glitch
+/// comes from: `CaptureOnly` reads the inputs and discards the audio (meters and
+/// metrics still update), `RenderOnly` plays a test tone instead of the inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunMode {
+    #[default]
+    Full,
+    CaptureOnly,
+    RenderOnly,
+}
+
+/// Everything a proxy is started with. `Default` has no devices and the defaults of
+/// the command line options; the option each field corresponds to is named where it
+/// isn't obvious.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub speaker_in: String,
+    /// Second capture source mixed into the speaker output
+    pub speaker_in2: Option<String>,
+    pub speaker_out: String,
+    pub mic_in: Option<String>,
+    /// Second mic mixed into the mic output
+    pub mic_in2: Option<String>,
+    pub mic_out: Option<String>,
+    /// Gains of the two mics before they are summed
+    pub mic_gain_db: f32,
+    pub mic_in2_gain_db: f32,
+    /// Reopen a `default:` mic input as soon as Windows changes that default
+    pub mic_follow_default: bool,
+    /// Latency target (`--buffer`), which sizes the ring buffers
+    pub buffer_ms: u32,
+    /// Silence queued on the render device at start, and the fill it's kept at while
+    /// a stall reserve is held
+    pub prefill_ms: u32,
+    /// Ring buffer fill that, held for a while, makes the render loops skip back to
+    /// the prefill (off when `None`)
+    pub max_fill_ms: Option<u32>,
+    /// WASAPI buffer each capture and render stream is opened with
+    pub device_buffer_ms: u32,
+    pub glitch_dump_dir: Option<PathBuf>,
+    pub glitch_dump_secs: u32,
+    pub drain_ms: u32,
+    pub start_fade_ms: u32,
+    /// Minimum amount of audio per render write (0 writes as soon as anything arrives)
+    pub render_chunk_ms: u32,
+    /// Most frames taken from the capture device per read (0 takes all that's available)
+    pub capture_chunk_frames: usize,
+    /// How extra output channels are filled when the output has more than the input
+    pub upmix: UpmixMode,
+    /// Level the LFE is folded into the front channels at when downmixing (`None` drops it)
+    pub lfe_downmix_db: Option<f32>,
+    /// Explicit capture-to-render channel routing of the speaker output, in place of
+    /// the up/downmix
+    pub channel_map: Option<ChannelMap>,
+    /// Resampler used when the input and output sample rates differ
+    pub resample_quality: ResampleQuality,
+    /// Let sinc resampling overshoot full scale instead of clamping it, for measurements
+    pub no_resample_clamp: bool,
+    pub output_backend: OutputBackend,
+    /// Dither for outputs that take fewer bits than f32 (ASIO drivers with 16-bit samples)
+    pub dither: DitherMode,
+    /// Session category of the speaker output (WASAPI only)
+    pub output_category: StreamCategory,
+    /// Sample rate the speaker output opens at, resampled by Windows to the mix rate if
+    /// needed, or the closest one the device supports if it refuses (WASAPI only)
+    pub force_rate: Option<u32>,
+    /// Fail to open the speaker output rather than substitute for `force_rate`
+    pub strict_rate: bool,
+    /// Swap the left and right channels of the speaker output
+    pub swap_lr: bool,
+    /// Trim output B to output A's level (`--ab-loudness-match`)
+    pub ab_loudness_match: bool,
+    /// COM threading model every thread initializes with
+    pub com_model: ComModel,
+    /// Start even when an input and output look like a feedback loop
+    pub force: bool,
+    /// Run only the capture or render half of each path (`--capture-only`, `--render-only`)
+    pub run_mode: RunMode,
+    /// Refuse mismatched capture/render formats instead of converting between them
+    pub no_convert: bool,
+    pub keep_alive_db: Option<f32>,
+    /// Peak level in dBFS below which a captured block counts as silence
+    pub silence_threshold_db: f32,
+    /// Level in dBFS at which a captured sample counts as clipped
+    pub clip_ceiling_db: f32,
+    /// Most the gain stages of a render path may add up to, in dB
+    pub max_output_db: f32,
+    /// Frames per block the capture loops hand on (0 passes reads through as they come)
+    pub process_block_frames: usize,
+    pub recovery: RecoveryPolicy,
+    /// Address to serve Prometheus metrics on (off when `None`)
+    pub metrics_addr: Option<String>,
+    /// Address to stream the speaker capture to as RTP (off when `None`)
+    pub rtp_out: Option<String>,
+    pub rtp_codec: RtpCodec,
+    /// Serve IPC commands on the named pipe of `instance`
+    pub ipc_pipe: bool,
+    /// Address to also accept IPC commands on over TCP (off when `None`)
+    pub ipc_tcp: Option<String>,
+    /// Shared secret TCP clients must send with every command
+    pub ipc_token: Option<String>,
+    /// Suffix of the IPC pipe name, so several proxies can run at once
+    pub instance: Option<String>,
+    /// Accept IPC commands meant for testing, like `InjectSilence`
+    pub debug_commands: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        let preset = Profile::default().preset();
+        Self {
+            speaker_in: String::new(),
+            speaker_in2: None,
+            speaker_out: String::new(),
+            mic_in: None,
+            mic_in2: None,
+            mic_out: None,
+            mic_gain_db: 0.0,
+            mic_in2_gain_db: 0.0,
+            mic_follow_default: false,
+            buffer_ms: preset.buffer_ms,
+            prefill_ms: preset.buffer_ms,
+            max_fill_ms: None,
+            device_buffer_ms: DEFAULT_DEVICE_BUFFER_MS,
+            glitch_dump_dir: None,
+            glitch_dump_secs: DEFAULT_GLITCH_DUMP_SECS,
+            drain_ms: DEFAULT_DRAIN_MS,
+            start_fade_ms: DEFAULT_START_FADE_MS,
+            render_chunk_ms: preset.render_chunk_ms,
+            capture_chunk_frames: 0,
+            upmix: UpmixMode::default(),
+            lfe_downmix_db: None,
+            channel_map: None,
+            resample_quality: ResampleQuality::default(),
+            no_resample_clamp: false,
+            output_backend: OutputBackend::Wasapi,
+            dither: DitherMode::default(),
+            output_category: StreamCategory::Media,
+            force_rate: None,
+            strict_rate: false,
+            swap_lr: false,
+            ab_loudness_match: false,
+            com_model: ComModel::default(),
+            force: false,
+            run_mode: RunMode::Full,
+            no_convert: false,
+            keep_alive_db: None,
+            silence_threshold_db: DEFAULT_SILENCE_THRESHOLD_DB,
+            clip_ceiling_db: 0.0,
+            max_output_db: DEFAULT_MAX_OUTPUT_DB,
+            process_block_frames: 0,
+            recovery: preset.recovery,
+            metrics_addr: None,
+            rtp_out: None,
+            rtp_codec: RtpCodec::default(),
+            ipc_pipe: false,
+            ipc_tcp: None,
+            ipc_token: None,
+            instance: None,
+            debug_commands: false,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Check the values and the combinations of devices; the devices themselves are
+    /// only looked up when the proxy starts
+    pub fn validate(&self) -> Result<()> {
+        // A partial mode doesn't open the device of the half it leaves out
+        if self.speaker_in.is_empty() && self.run_mode != RunMode::RenderOnly {
+            return Err(anyhow::anyhow!("Missing required argument: --speaker-in"));
+        }
+        if self.speaker_out.is_empty() && self.run_mode != RunMode::CaptureOnly {
+            return Err(anyhow::anyhow!("Missing required argument: --speaker-out"));
+        }
+        if self.run_mode != RunMode::Full && (self.speaker_in2.is_some() || self.mic_in2.is_some()) {
+            return Err(anyhow::anyhow!(
+                "--capture-only and --render-only test one device per path, without --speaker-in2 or --mic-in2"
+            ));
+        }
+        if self.ipc_tcp.is_some() && self.ipc_token.as_deref().unwrap_or("").is_empty() {
+            // Without a token anyone who can reach the port could control the proxy
+            return Err(anyhow::anyhow!("--ipc-tcp requires --ipc-token"));
+        }
+        if let Some(ref name) = self.instance {
+            ipc::validate_instance_name(name)?;
+        }
+        if let Some(db) = self.lfe_downmix_db {
+            convert::validate_lfe_downmix_db(db)?;
+        }
+        validate_buffer_ms(self.buffer_ms)?;
+        if !(MIN_BUFFER_MS..=MAX_BUFFER_MS).contains(&self.prefill_ms) {
+            return Err(anyhow::anyhow!(
+                "Prefill must be between {} and {} ms: {} ms", MIN_BUFFER_MS, MAX_BUFFER_MS, self.prefill_ms
+            ));
+        }
+        if !(MIN_BUFFER_MS..=MAX_BUFFER_MS).contains(&self.device_buffer_ms) {
+            return Err(anyhow::anyhow!(
+                "Device buffer must be between {} and {} ms: {} ms", MIN_BUFFER_MS, MAX_BUFFER_MS, self.device_buffer_ms
+            ));
+        }
+        if let Some(ms) = self.max_fill_ms {
+            fill_limit::validate_max_fill_ms(ms, self.prefill_ms)?;
+        }
+        silence::validate_db(self.silence_threshold_db)?;
+        reblock::validate_block_frames(self.process_block_frames)?;
+        if self.no_convert && self.speaker_in2.is_some() {
+            return Err(anyhow::anyhow!("--speaker-in2 mixes audio, which --no-convert rules out"));
+        }
+        if let Some(rate) = self.force_rate {
+            validate_sample_rate(rate)?;
+            if self.output_backend == OutputBackend::Asio {
+                return Err(anyhow::anyhow!("--force-rate needs WASAPI output: ASIO opens at the driver's configured rate"));
+            }
+        }
+        if self.strict_rate && self.force_rate.is_none() {
+            return Err(anyhow::anyhow!("--strict-rate needs --force-rate"));
+        }
+        if self.no_convert && self.channel_map.is_some() {
+            return Err(anyhow::anyhow!("--channel-map converts audio, which --no-convert rules out"));
+        }
+        if self.mic_in.is_some() != self.mic_out.is_some() {
+            let (given, missing) =
+                if self.mic_in.is_some() { ("--mic-in", "--mic-out") } else { ("--mic-out", "--mic-in") };
+            return Err(anyhow::anyhow!(
+                "{} was given without {}: the mic proxy is optional, but it needs both to run \
+                 (leave both out to proxy only the speakers)",
+                given, missing
+            ));
+        }
+        if self.mic_follow_default && self.mic_in.is_none() {
+            return Err(anyhow::anyhow!("--mic-follow-default needs --mic-in"));
+        }
+        if self.mic_in2.is_some() && self.mic_in.is_none() {
+            return Err(anyhow::anyhow!("--mic-in2 is mixed with --mic-in, which isn't set"));
+        }
+        if self.no_convert && self.mic_in2.is_some() {
+            return Err(anyhow::anyhow!("--mic-in2 mixes audio, which --no-convert rules out"));
+        }
+        mixer::validate_source_gain_db(self.mic_gain_db)?;
+        mixer::validate_source_gain_db(self.mic_in2_gain_db)?;
+        dsp::validate_max_output_db(self.max_output_db)?;
+        Ok(())
+    }
+}
+
+/// Reject buffer sizes outside `MIN_BUFFER_MS..=MAX_BUFFER_MS`
+pub fn validate_buffer_ms(buffer_ms: u32) -> Result<()> {
+    if !(MIN_BUFFER_MS..=MAX_BUFFER_MS).contains(&buffer_ms) {
+        return Err(anyhow::anyhow!(
+            "Buffer size must be between {} and {} ms: {} ms", MIN_BUFFER_MS, MAX_BUFFER_MS, buffer_ms
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse input/output pairs that would feed the render target straight back into
+/// its own capture (speaker-in == speaker-out, mic-in == mic-out, or loopback of the
+/// output). Devices that can't be resolved are left for the audio threads to report.
+fn check_feedback_loops(config: &ProxyConfig) -> Result<()> {
+    let mut problems = Vec::new();
+
+    if config.output_backend == OutputBackend::Wasapi {
+        let speaker_inputs = std::iter::once(("--speaker-in", "speaker input", &config.speaker_in))
+            .chain(config.speaker_in2.as_ref().map(|id| ("--speaker-in2", "second speaker input", id)));
+        for (flag, label, speaker_in) in speaker_inputs {
+            if is_render_endpoint_id(speaker_in) {
+                if let Ok(output) = resolve_render_endpoint(&config.speaker_out) {
+                    if output.id == *speaker_in {
+                        problems.push(format!(
+                            "{} is a loopback of the speaker output '{}'", flag, output.name
+                        ));
+                    }
+                }
+            } else if let (Ok(input), Ok(output)) = (
+                resolve_capture_endpoint(speaker_in),
+                resolve_render_endpoint(&config.speaker_out),
+            ) {
+                if input.same_device_as(&output) {
+                    problems.push(format!(
+                        "{} '{}' and output '{}' are the same device", label, input.name, output.name
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(mic_out) = &config.mic_out {
+        let mic_inputs = config.mic_in.iter().map(|id| ("mic input", id))
+            .chain(config.mic_in2.iter().map(|id| ("second mic input", id)));
+        for (label, mic_in) in mic_inputs {
+            if let (Ok(input), Ok(output)) = (resolve_capture_endpoint(mic_in), resolve_render_endpoint(mic_out)) {
+                if input.same_device_as(&output) {
+                    problems.push(format!(
+                        "{} '{}' and output '{}' are the same device", label, input.name, output.name
+                    ));
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    if config.force {
+        for problem in &problems {
+            warn!("Feedback loop likely: {} (continuing because of --force)", problem);
+        }
+        return Ok(());
+    }
+
+    Err(anyhow::anyhow!(
+        "Refusing to start, this would create a feedback loop:\n  {}\nUse --force to start anyway.",
+        problems.join("\n  ")
+    ))
+}
+
+/// Static settings shared by the audio loops (fixed for the lifetime of the proxy)
+#[derive(Debug, Clone)]
+struct LoopSettings {
+    prefill_ms: u32,
+    max_fill_ms: Option<u32>,
+    device_buffer_ms: u32,
+    drain_ms: u32,
+    start_fade_ms: u32,
+    render_chunk_ms: u32,
+    capture_chunk_frames: usize,
+    mic_follow_default: bool,
+    conversion: ConversionSettings,
+    /// `--channel-map`, which only the speaker output is routed with
+    channel_map: Option<ChannelMap>,
+    output_backend: OutputBackend,
+    /// Only the ASIO backend reduces the bit depth
+    #[cfg_attr(not(feature = "asio"), allow(dead_code))]
+    dither: DitherMode,
+    output_category: StreamCategory,
+    force_rate: Option<u32>,
+    strict_rate: bool,
+    no_convert: bool,
+    keep_alive_db: Option<f32>,
+    /// Linear level at which a captured sample counts as clipped
+    clip_ceiling: f32,
+    max_output_db: f32,
+    process_block_frames: usize,
+    /// Shared with the IPC thread so `SetRecoveryPolicy` applies to running loops
+    recovery: SharedRecoveryPolicy,
+    /// Shared with the IPC thread so `SetSilenceThreshold` applies to running loops
+    silence: SharedSilenceThreshold,
+    meter: SharedMeterSettings,
+    /// Render path delays, set over IPC with `SetDelay`
+    speaker_delay: SharedDelay,
+    mic_delay: SharedDelay,
+    /// Render path stage orders, set over IPC with `SetProcessingChain`
+    speaker_chain: Arc<SharedChain>,
+    mic_chain: Arc<SharedChain>,
+    /// Set by the IPC `Pause`/`Resume` commands; the loops release their devices while set
+    paused: Arc<AtomicBool>,
+    /// Speaker source stall faked with `InjectSilence`
+    injected_stall: SharedInjectedStall,
+}
+
+impl LoopSettings {
+    /// Conversion of the speaker output, the one path `--channel-map` applies to
+    fn speaker_conversion(&self) -> ConversionSettings {
+        ConversionSettings { mix: ChannelMix { map: self.channel_map, ..self.conversion.mix }, ..self.conversion }
+    }
+}
+
+/// State the speaker render loop shares with the other threads besides the audio itself
+#[derive(Clone, Default)]
+struct RenderControls {
+    /// EQ bands, set over IPC
+    eq: Arc<SharedEq>,
+    /// Raised when the device starves, for the glitch dumper
+    underrun_signal: Option<Arc<AtomicBool>>,
+    /// Format pinned with `SetOutputWithFormat` (`None` opens at the mix format)
+    requested_format: Arc<RwLock<Option<RequestedFormat>>>,
+    /// What the render loop last opened, so the IPC handler can tell when a switch is done
+    opened: Arc<RwLock<OutputTarget>>,
+    /// Second capture source mixed in (`--speaker-in2`)
+    secondary: Option<SecondarySource>,
+    /// Monitor device the captured audio is also played on, set over IPC
+    monitor: SharedMonitor,
+    /// Reserve requested with `PrepareForStall`
+    stall: SharedStall,
+    /// Swap left and right (`--swap-lr`, `SetChannelSwap`)
+    swap_lr: Arc<AtomicBool>,
+    /// Speaker output muted for a mic check (`SoloMic`)
+    solo_mic: Arc<AtomicBool>,
+    /// A/B loudness measurements and trims (`--ab-loudness-match`)
+    ab_match: Option<SharedAbMatch>,
+}
+
+/// Device and requested format the speaker output is (to be) opened with
+#[derive(Debug, Clone, Default, PartialEq)]
+struct OutputTarget {
+    device_id: String,
+    format: Option<RequestedFormat>,
+}
+
+/// Shared state for microphone proxy
+struct MicState {
+    buffer: Arc<AudioRingBuffer>,
+    input_id: Arc<RwLock<String>>,
+    /// Input of the second mic (`--mic-in2`), which can't be switched at runtime
+    input2_id: Option<String>,
+    output_id: Arc<RwLock<String>>,
+    controls: MicRenderControls,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    render_format: Arc<RwLock<Option<AudioFormat>>>,
+}
+
+/// Mic render state shared with the IPC thread
+#[derive(Clone)]
+struct MicRenderControls {
+    /// Whether the mic proxy runs at all (`EnableMic`)
+    enabled: Arc<AtomicBool>,
+    /// Gain and switch of `--mic-in`
+    level: Arc<SourceLevel>,
+    /// Second mic mixed in (`--mic-in2`)
+    secondary: Option<SecondarySource>,
+}
+
+/// A/B speaker output targets; the active one is mirrored into the render loop's device ID
+struct OutputSelection {
+    a: String,
+    b: Option<String>,
+    b_active: bool,
+}
+
+impl OutputSelection {
+    fn active_id(&self) -> &str {
+        match (&self.b, self.b_active) {
+            (Some(b), true) => b,
+            _ => &self.a,
+        }
+    }
+
+    fn active_label(&self) -> &'static str {
+        if self.b_active { "b" } else { "a" }
+    }
+}
+
+/// Shared state read and updated by the IPC server
+struct IpcState {
+    running: Arc<AtomicBool>,
+    /// Speaker capture device, for `Diagnostics`
+    speaker_in: String,
+    output_device_id: Arc<RwLock<String>>,
+    output_selection: Mutex<OutputSelection>,
+    speaker_capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    speaker_render_format: Arc<RwLock<Option<AudioFormat>>>,
+    speaker_controls: RenderControls,
+    mic_input_id: Option<Arc<RwLock<String>>>,
+    mic_output_id: Option<Arc<RwLock<String>>>,
+    mic_enabled: Option<Arc<AtomicBool>>,
+    /// Levels of the two mics, for `SetMicSourceEnabled`
+    mic_levels: Option<(Arc<SourceLevel>, Option<Arc<SourceLevel>>)>,
+    mic_capture_format: Option<Arc<RwLock<Option<AudioFormat>>>>,
+    mic_render_format: Option<Arc<RwLock<Option<AudioFormat>>>>,
+    recovery: SharedRecoveryPolicy,
+    silence: SharedSilenceThreshold,
+    meter: SharedMeterSettings,
+    speaker_delay: SharedDelay,
+    mic_delay: SharedDelay,
+    speaker_chain: Arc<SharedChain>,
+    mic_chain: Arc<SharedChain>,
+    paused: Arc<AtomicBool>,
+    prefill_ms: u32,
+    injected_stall: SharedInjectedStall,
+    /// Whether testing commands like `InjectSilence` are accepted (`--debug-commands`)
+    debug_commands: bool,
+}
+
+/// Entry point of the library API
+pub struct Proxy;
+
+impl Proxy {
+    /// Builder for the common settings; everything else keeps its `ProxyConfig` default
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::default()
+    }
+
+    /// Validate `config` and start the audio threads (and the IPC server if one is
+    /// configured). The calling thread must have COM initialized with
+    /// `config.com_model`, which the spawned threads initialize with as well, unless
+    /// it's `ComModel::External`, in which case the proxy leaves COM alone entirely.
+    pub fn start(config: ProxyConfig) -> Result<ProxyHandle> {
+        config.validate()?;
+        // Nothing can loop back with one half of each path left out
+        if config.run_mode == RunMode::Full {
+            check_feedback_loops(&config)?;
+        }
+        start_threads(&config)
+    }
+}
+
+/// Builds a `ProxyConfig` and starts the proxy with it
+#[derive(Debug, Clone, Default)]
+pub struct ProxyBuilder {
+    config: ProxyConfig,
+}
+
+impl ProxyBuilder {
+    /// Capture the speaker audio from `input` and play it on `output`
+    pub fn speaker(mut self, input: &str, output: &str) -> Self {
+        self.config.speaker_in = input.to_string();
+        self.config.speaker_out = output.to_string();
+        self
+    }
+
+    /// Also proxy the mic from `input` to `output`
+    pub fn mic(mut self, input: &str, output: &str) -> Self {
+        self.config.mic_in = Some(input.to_string());
+        self.config.mic_out = Some(output.to_string());
+        self
+    }
+
+    /// Latency target; sets the prefill to match, like `--buffer` does
+    pub fn buffer_ms(mut self, ms: u32) -> Self {
+        self.config.buffer_ms = ms;
+        self.config.prefill_ms = ms;
+        self
+    }
+
+    pub fn prefill_ms(mut self, ms: u32) -> Self {
+        self.config.prefill_ms = ms;
+        self
+    }
+
+    pub fn device_buffer_ms(mut self, ms: u32) -> Self {
+        self.config.device_buffer_ms = ms;
+        self
+    }
+
+    /// Serve IPC commands on the named pipe of `instance` (`None` for the default
+    /// pipe), as the binary does
+    pub fn ipc_pipe(mut self, instance: Option<&str>) -> Self {
+        self.config.ipc_pipe = true;
+        self.config.instance = instance.map(str::to_string);
+        self
+    }
+
+    /// COM threading model of the proxy's threads; `ComModel::External` leaves COM
+    /// initialization to the host (see its contract)
+    pub fn com_model(mut self, com_model: ComModel) -> Self {
+        self.config.com_model = com_model;
+        self
+    }
+
+    /// The config built so far, for settings without a builder method
+    pub fn config(self) -> ProxyConfig {
+        self.config
+    }
+
+    pub fn start(self) -> Result<ProxyHandle> {
+        Proxy::start(self.config)
+    }
+}
+
+/// A running proxy. Commands go straight to the shared state the audio threads read,
+/// through the same handler as IPC commands, so they behave exactly like their IPC
+/// counterparts. Dropping the handle stops the proxy.
+pub struct ProxyHandle {
+    state: Arc<IpcState>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ProxyHandle {
+    /// Apply any IPC command in-process
+    pub fn command(&self, command: IpcCommand) -> IpcResponse {
+        handle_ipc_command(command, &self.state)
+    }
+
+    /// Switch the speaker output to `device_id`, like `SetOutput`
+    pub fn set_output(&self, device_id: &str) -> Result<()> {
+        let response = self.command(IpcCommand::SetOutput { device_id: device_id.to_string() });
+        if !response.success {
+            return Err(anyhow::anyhow!(response.message));
+        }
+        Ok(())
+    }
+
+    pub fn get_status(&self) -> IpcResponse {
+        self.command(IpcCommand::GetStatus)
+    }
+
+    /// False once the proxy was stopped, including by an IPC `Stop`
+    pub fn is_running(&self) -> bool {
+        self.state.running.load(Ordering::SeqCst)
+    }
+
+    /// Flag the audio threads run on; clearing it stops the proxy from anywhere, e.g. a
+    /// Ctrl+C handler
+    pub fn running(&self) -> Arc<AtomicBool> {
+        self.state.running.clone()
+    }
+
+    /// Stop the proxy and wait for its threads to finish
+    pub fn stop(mut self) {
+        self.state.running.store(false, Ordering::SeqCst);
+        self.join();
+    }
+
+    /// Block until the proxy is stopped some other way, then wait for its threads
+    pub fn wait(mut self) {
+        while self.is_running() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.join();
+    }
+
+    fn join(&mut self) {
+        if self.threads.is_empty() {
+            return;
+        }
+        info!("Shutting down...");
+        // Every thread checks the running flag, the IPC thread between polls of its
+        // pipe, so joining it also closes the pipe before another proxy starts
+        for handle in self.threads.drain(..) {
+            let _ = handle.join();
+        }
+        info!("Audio Proxy stopped.");
+    }
+}
+
+impl Drop for ProxyHandle {
+    fn drop(&mut self) {
+        self.state.running.store(false, Ordering::SeqCst);
+        self.join();
+    }
+}
+
+fn start_threads(args: &ProxyConfig) -> Result<ProxyHandle> {
+    let running = Arc::new(AtomicBool::new(true));
+
+    // Without it, streams still recover after sleep, just all at once
+    if let Err(e) = power::install() {
+        warn!("{}", e);
+    }
+
+    // Every thread joins the same apartment type as the calling one
+    let com_model = args.com_model;
+    let run_mode = args.run_mode;
+
+    // Calculate buffer size in samples (estimate - actual format comes from device)
+    let buffer_samples = (DEFAULT_SAMPLE_RATE * args.buffer_ms / 1000) as usize * DEFAULT_CHANNELS as usize;
+    // With fixed processing blocks, leave room for two of them at up to 8 channels
+    let ring_samples = (buffer_samples * 4)
+        .max(args.process_block_frames * 8 * 2)
+        .max(MIN_RING_SAMPLES);
+
+    // Create ring buffer for speaker audio data, with room for a `PrepareForStall` reserve
+    let speaker_buffer = Arc::new(AudioRingBuffer::new(ring_samples + stall::reserve_samples()));
+
+    // Create output device ID holder for hot-swapping
+    let current_output_id = Arc::new(RwLock::new(args.speaker_out.clone()));
+
+    // Shared capture format so render thread can do conversion if needed
+    let speaker_capture_format: Arc<RwLock<Option<AudioFormat>>> = Arc::new(RwLock::new(None));
+
+    // Shared render format, reported over IPC
+    let speaker_render_format: Arc<RwLock<Option<AudioFormat>>> = Arc::new(RwLock::new(None));
+
+    // Create mic state if mic proxy is configured
+    let mic_state = if let (Some(mic_in), Some(mic_out)) = (&args.mic_in, &args.mic_out) {
+        let mic_buffer = Arc::new(AudioRingBuffer::new(ring_samples));
+        Some(MicState {
+            buffer: mic_buffer,
+            input_id: Arc::new(RwLock::new(mic_in.clone())),
+            input2_id: args.mic_in2.clone(),
+            output_id: Arc::new(RwLock::new(mic_out.clone())),
+            controls: MicRenderControls {
+                enabled: Arc::new(AtomicBool::new(true)),
+                level: Arc::new(SourceLevel::new(args.mic_gain_db)),
+                secondary: args.mic_in2.as_ref().map(|_| SecondarySource {
+                    buffer: Arc::new(AudioRingBuffer::new(ring_samples)),
+                    capture_format: Arc::new(RwLock::new(None)),
+                    level: Arc::new(SourceLevel::new(args.mic_in2_gain_db)),
+                }),
+            },
+            capture_format: Arc::new(RwLock::new(None)),
+            render_format: Arc::new(RwLock::new(None)),
+        })
+    } else {
+        None
+    };
+
+    // Create glitch dumper if requested (owned by the speaker capture thread)
+    let glitch_dumper = match args.glitch_dump_dir {
+        Some(ref dir) => {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create glitch dump directory: {}", dir.display()))?;
+            Some(GlitchDumper::new(dir.clone(), args.glitch_dump_secs))
+        }
+        None => None,
+    };
+
+    // EQ and other state shared with the speaker render loop
+    let speaker_controls = RenderControls {
+        underrun_signal: glitch_dumper.as_ref().map(|d| d.underrun_signal()),
+        secondary: args.speaker_in2.as_ref().map(|_| SecondarySource {
+            buffer: Arc::new(AudioRingBuffer::new(ring_samples)),
+            capture_format: Arc::new(RwLock::new(None)),
+            level: Arc::new(SourceLevel::default()),
+        }),
+        swap_lr: Arc::new(AtomicBool::new(args.swap_lr)),
+        solo_mic: Arc::new(AtomicBool::new(false)),
+        ab_match: args.ab_loudness_match.then(SharedAbMatch::default),
+        ..Default::default()
+    };
+
+    let settings = LoopSettings {
+        prefill_ms: args.prefill_ms,
+        max_fill_ms: args.max_fill_ms,
+        device_buffer_ms: args.device_buffer_ms,
+        drain_ms: args.drain_ms,
+        start_fade_ms: args.start_fade_ms,
+        render_chunk_ms: args.render_chunk_ms,
+        capture_chunk_frames: args.capture_chunk_frames,
+        mic_follow_default: args.mic_follow_default,
+        conversion: ConversionSettings {
+            mix: ChannelMix::new(args.upmix, args.lfe_downmix_db),
+            quality: args.resample_quality,
+            no_clamp: args.no_resample_clamp,
+        },
+        channel_map: args.channel_map,
+        output_backend: args.output_backend,
+        dither: args.dither,
+        output_category: args.output_category,
+        force_rate: args.force_rate,
+        strict_rate: args.strict_rate,
+        no_convert: args.no_convert,
+        keep_alive_db: args.keep_alive_db,
+        clip_ceiling: 10f32.powf(args.clip_ceiling_db / 20.0),
+        max_output_db: args.max_output_db,
+        process_block_frames: args.process_block_frames,
+        recovery: SharedRecoveryPolicy::new(args.recovery),
+        silence: SharedSilenceThreshold::new(args.silence_threshold_db),
+        meter: SharedMeterSettings::default(),
+        speaker_delay: SharedDelay::default(),
+        mic_delay: SharedDelay::default(),
+        speaker_chain: Arc::new(SharedChain::new(RenderPath::Speaker)),
+        mic_chain: Arc::new(SharedChain::new(RenderPath::Mic)),
+        paused: Arc::new(AtomicBool::new(false)),
+        injected_stall: SharedInjectedStall::default(),
+    };
+
+    // State shared with the handle and the IPC server
+    let ipc_state = Arc::new(IpcState {
+        running: running.clone(),
+        speaker_in: args.speaker_in.clone(),
+        output_device_id: current_output_id.clone(),
+        output_selection: Mutex::new(OutputSelection {
+            a: args.speaker_out.clone(),
+            b: None,
+            b_active: false,
+        }),
+        speaker_capture_format: speaker_capture_format.clone(),
+        speaker_render_format: speaker_render_format.clone(),
+        speaker_controls: speaker_controls.clone(),
+        mic_input_id: mic_state.as_ref().map(|s| s.input_id.clone()),
+        mic_output_id: mic_state.as_ref().map(|s| s.output_id.clone()),
+        mic_enabled: mic_state.as_ref().map(|s| s.controls.enabled.clone()),
+        mic_levels: mic_state.as_ref().map(|s| {
+            (s.controls.level.clone(), s.controls.secondary.as_ref().map(|source| source.level.clone()))
+        }),
+        mic_capture_format: mic_state.as_ref().map(|s| s.capture_format.clone()),
+        mic_render_format: mic_state.as_ref().map(|s| s.render_format.clone()),
+        recovery: settings.recovery.clone(),
+        silence: settings.silence.clone(),
+        meter: settings.meter.clone(),
+        speaker_delay: settings.speaker_delay.clone(),
+        mic_delay: settings.mic_delay.clone(),
+        speaker_chain: settings.speaker_chain.clone(),
+        mic_chain: settings.mic_chain.clone(),
+        paused: settings.paused.clone(),
+        prefill_ms: args.prefill_ms,
+        injected_stall: settings.injected_stall.clone(),
+        debug_commands: args.debug_commands,
+    });
+    // Every thread goes in here as it's spawned. If starting the next one fails, dropping
+    // the handle stops and joins the ones already running.
+    let mut handle = ProxyHandle { state: ipc_state.clone(), threads: Vec::new() };
+
+    // Created here so a taken pipe name or port stops startup instead of just logging
+    // an error
+    let ipc_server = if args.ipc_pipe { Some(IpcServer::new(args.instance.as_deref())?) } else { None };
+    let ipc_tcp = match (&args.ipc_tcp, &args.ipc_token) {
+        (Some(addr), Some(token)) => Some(TcpIpcServer::bind(addr, token)?),
+        _ => None,
+    };
+    if ipc_server.is_some() || ipc_tcp.is_some() {
+        let ipc_state = ipc_state.clone();
+        handle.threads.push(thread::Builder::new().name("ipc".into()).spawn(move || {
+            // For the endpoint volume commands
+            if com_model.initialize().is_err() {
+                error!("Failed to initialize COM in IPC thread");
+                return;
+            }
+
+            if let Err(e) = run_ipc_server(&ipc_state, ipc_server, ipc_tcp) {
+                error!("IPC server error: {}", e);
+            }
+
+            com_model.uninitialize();
+        }).context("Failed to spawn IPC thread")?);
+    }
+
+    if let Some(addr) = args.metrics_addr.clone() {
+        let running = running.clone();
+        handle.threads.push(thread::Builder::new().name("metrics-http".into()).spawn(move || {
+            if let Err(e) = metrics::serve(&addr, running) {
+                error!("Metrics endpoint error: {}", e);
+            }
+        }).context("Failed to spawn metrics thread")?);
+    }
+
+    if let Some(addr) = args.rtp_out.clone() {
+        let running = running.clone();
+        let codec = args.rtp_codec;
+        let tap = speaker_controls.monitor.tap();
+        let capture_format = speaker_capture_format.clone();
+        let conversion = settings.conversion;
+        handle.threads.push(thread::Builder::new().name("rtp-out".into()).spawn(move || {
+            if let Err(e) = rtp::stream(&addr, codec, tap, capture_format, conversion, running) {
+                error!("RTP stream error: {}", e);
+            }
+        }).context("Failed to spawn RTP thread")?);
+    }
+
+    // Start speaker capture thread
+    let capture_running = running.clone();
+    let capture_buffer = speaker_buffer.clone();
+    let capture_input_id = args.speaker_in.clone();
+    let capture_format_shared = speaker_capture_format.clone();
+    let capture_primary = PrimaryCapture {
+        tap: speaker_controls.monitor.tap(),
+        heartbeat: &METRICS.speaker.capture_heartbeat,
+        meter: &METRICS.speaker.meter,
+    };
+    let capture_settings = settings.clone();
+    handle.threads.push(thread::Builder::new().name("speaker-capture".into()).spawn(move || {
+        if com_model.initialize().is_err() {
+            error!("Failed to initialize COM in speaker capture thread");
+            return;
+        }
+
+        let result = if run_mode == RunMode::RenderOnly {
+            run_tone_source(
+                capture_buffer, capture_running, &capture_settings, capture_format_shared,
+                &METRICS.speaker.capture_heartbeat,
+            )
+        } else {
+            run_speaker_capture_loop(
+                &capture_input_id, capture_buffer, capture_running, &capture_settings, capture_format_shared,
+                glitch_dumper, Some(capture_primary),
+            )
+        };
+        if let Err(e) = result {
+            error!("Speaker capture loop error: {}", e);
+        }
+
+        com_model.uninitialize();
+    }).context("Failed to spawn speaker capture thread")?);
+
+    // Start the second speaker capture thread if mixing
+    if let (Some(speaker_in2), Some(source)) = (&args.speaker_in2, speaker_controls.secondary.clone()) {
+        let capture2_running = running.clone();
+        let capture2_input_id = speaker_in2.clone();
+        let capture2_settings = settings.clone();
+        handle.threads.push(thread::Builder::new().name("speaker-capture2".into()).spawn(move || {
+            if com_model.initialize().is_err() {
+                error!("Failed to initialize COM in second speaker capture thread");
+                return;
+            }
+
+            if let Err(e) = run_speaker_capture_loop(
+                &capture2_input_id, source.buffer, capture2_running, &capture2_settings, source.capture_format,
+                None, None,
+            ) {
+                error!("Second speaker capture loop error: {}", e);
+            }
+
+            com_model.uninitialize();
+        }).context("Failed to spawn second speaker capture thread")?);
+    }
+
+    // Start speaker render thread
+    let render_running = running.clone();
+    let render_buffer = speaker_buffer.clone();
+    let render_output_id = current_output_id.clone();
+    let render_capture_format = speaker_capture_format.clone();
+    let render_format_shared = speaker_render_format.clone();
+    let render_controls = speaker_controls.clone();
+    let render_settings = settings.clone();
+    handle.threads.push(thread::Builder::new().name("speaker-render".into()).spawn(move || {
+        if com_model.initialize().is_err() {
+            error!("Failed to initialize COM in speaker render thread");
+            return;
+        }
+
+        let result = if run_mode == RunMode::CaptureOnly {
+            run_discard_sink(render_buffer, render_running, &render_settings, "Speaker", &METRICS.speaker)
+        } else {
+            run_speaker_render_loop(
+                render_buffer, render_output_id, render_running, &render_settings, render_capture_format,
+                render_format_shared, &render_controls,
+            )
+        };
+        if let Err(e) = result {
+            error!("Speaker render loop error: {}", e);
+        }
+
+        com_model.uninitialize();
+    }).context("Failed to spawn speaker render thread")?);
+
+    // Start mic threads if configured
+    if let Some(ref mic) = mic_state {
+        let mic_capture_running = running.clone();
+        let mic_capture_buffer = mic.buffer.clone();
+        let mic_capture_input_id = mic.input_id.clone();
+        let mic_capture_enabled = mic.controls.enabled.clone();
+        let mic_capture_format = mic.capture_format.clone();
+        let mic_capture_settings = settings.clone();
+        handle.threads.push(thread::Builder::new().name("mic-capture".into()).spawn(move || {
+            if com_model.initialize().is_err() {
+                error!("Failed to initialize COM in mic capture thread");
+                return;
+            }
+
+            let result = if run_mode == RunMode::RenderOnly {
+                run_tone_source(
+                    mic_capture_buffer, mic_capture_running, &mic_capture_settings, mic_capture_format,
+                    &METRICS.mic.capture_heartbeat,
+                )
+            } else {
+                run_mic_capture_loop(
+                    mic_capture_input_id, mic_capture_buffer, mic_capture_running,
+                    mic_capture_enabled, &mic_capture_settings, mic_capture_format,
+                    true,
+                )
+            };
+            if let Err(e) = result {
+                error!("Mic capture loop error: {}", e);
+            }
+
+            com_model.uninitialize();
+        }).context("Failed to spawn mic capture thread")?);
+
+        // Second mic capture thread if mixing
+        if let (Some(mic_in2), Some(source)) = (&mic.input2_id, mic.controls.secondary.clone()) {
+            let mic_capture2_running = running.clone();
+            let mic_capture2_input_id = Arc::new(RwLock::new(mic_in2.clone()));
+            let mic_capture2_enabled = mic.controls.enabled.clone();
+            let mic_capture2_settings = settings.clone();
+            handle.threads.push(thread::Builder::new().name("mic-capture2".into()).spawn(move || {
+                if com_model.initialize().is_err() {
+                    error!("Failed to initialize COM in second mic capture thread");
+                    return;
+                }
+
+                if let Err(e) = run_mic_capture_loop(
+                    mic_capture2_input_id, source.buffer, mic_capture2_running,
+                    mic_capture2_enabled, &mic_capture2_settings, source.capture_format, false,
+                ) {
+                    error!("Second mic capture loop error: {}", e);
+                }
+
+                com_model.uninitialize();
+            }).context("Failed to spawn second mic capture thread")?);
+        }
+
+        let mic_render_running = running.clone();
+        let mic_render_buffer = mic.buffer.clone();
+        let mic_render_output_id = mic.output_id.clone();
+        let mic_render_controls = mic.controls.clone();
+        let mic_render_capture_format = mic.capture_format.clone();
+        let mic_render_format = mic.render_format.clone();
+        let mic_render_settings = settings.clone();
+        handle.threads.push(thread::Builder::new().name("mic-render".into()).spawn(move || {
+            if com_model.initialize().is_err() {
+                error!("Failed to initialize COM in mic render thread");
+                return;
+            }
+
+            let result = if run_mode == RunMode::CaptureOnly {
+                run_discard_sink(mic_render_buffer, mic_render_running, &mic_render_settings, "Mic", &METRICS.mic)
+            } else {
+                run_mic_render_loop(
+                    mic_render_output_id, mic_render_buffer, mic_render_running,
+                    &mic_render_controls, &mic_render_settings, mic_render_capture_format, mic_render_format,
+                )
+            };
+            if let Err(e) = result {
+                error!("Mic render loop error: {}", e);
+            }
+
+            com_model.uninitialize();
+        }).context("Failed to spawn mic render thread")?);
+    }
+
+    Ok(handle)
+}
+
+// ── Stream creation with error recovery ────────────────────────────────────
+
+pub fn create_and_start_capture(device_id: &str, device_buffer_ms: u32, max_read_frames: usize) -> Result<CaptureStream> {
+    let mut capture = CaptureStream::new(device_id)
+        .context("Failed to create capture stream")?
+        .with_buffer_ms(device_buffer_ms)
+        .with_max_read_frames(max_read_frames);
+    capture.start().context("Failed to start capture")?;
+    Ok(capture)
+}
+
+/// After `StreamError::DeviceLost`, wait for the capture device to come back instead
+/// of spending recovery attempts on a device that isn't there. `device_id` is looked
+/// up on every check, so a `default:` selector picks up the new default straight
+/// away. Returns the ID and the reopened stream, or `None` if the proxy stopped.
+fn wait_for_capture_device(
+    device_id: impl Fn() -> String,
+    device_buffer_ms: u32,
+    max_read_frames: usize,
+    running: &AtomicBool,
+    heartbeat: Option<&Heartbeat>,
+) -> Option<(String, CaptureStream)> {
+    while running.load(Ordering::SeqCst) {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.beat();
+        }
+        let id = device_id();
+        // Cheap presence check first; opening logs every attempt
+        if resolve_capture_endpoint(&id).is_ok() {
+            match create_and_start_capture(&id, device_buffer_ms, max_read_frames) {
+                Ok(capture) => return Some((id, capture)),
+                Err(e) => warn!("Capture device is back but failed to open: {}", e),
+            }
+        }
+        thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+    None
+}
+
+pub fn create_and_start_render(device_id: &str, device_buffer_ms: u32) -> Result<RenderStream> {
+    let mut render = RenderStream::new(device_id)
+        .context("Failed to create render stream")?
+        .with_buffer_ms(device_buffer_ms);
+    render.start().context("Failed to start render")?;
+    Ok(render)
+}
+
+fn create_and_start_output(
+    device_id: &str,
+    requested: Option<RequestedFormat>,
+    settings: &LoopSettings,
+) -> Result<Box<dyn RenderBackend>> {
+    if requested.is_some() && settings.output_backend == OutputBackend::Asio {
+        warn!("ASIO output always opens at the driver's configured format, ignoring requested format");
+    }
+
+    let mut render: Box<dyn RenderBackend> = match settings.output_backend {
+        OutputBackend::Wasapi => Box::new(
+            RenderStream::with_requested_format(device_id, requested)
+                .context("Failed to create render stream")?
+                .with_category(settings.output_category)
+                .with_buffer_ms(settings.device_buffer_ms)
+                .with_forced_rate(settings.force_rate, settings.strict_rate),
+        ),
+        #[cfg(feature = "asio")]
+        OutputBackend::Asio => Box::new(
+            asio_stream::AsioRenderStream::new(device_id)
+                .context("Failed to create ASIO render stream")?
+                .with_dither(settings.dither),
+        ),
+        #[cfg(not(feature = "asio"))]
+        OutputBackend::Asio => {
+            return Err(anyhow::anyhow!(
+                "ASIO output requested but this build was compiled without the `asio` feature"
+            ));
+        }
+    };
+    render.start().context("Failed to start render")?;
+    Ok(render)
+}
+
+/// Whether the device was invalidated (e.g. its format was changed in Sound settings),
+/// in which case the stream can be reopened right away without counting as a failure
+fn is_device_invalidated(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<StreamError>(), Some(StreamError::DeviceInvalidated))
+}
+
+/// Whether another application holds the device in exclusive mode. It can be used
+/// again once that application lets go, so recovery keeps waiting instead of giving up.
+fn is_device_in_use(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<StreamError>(), Some(StreamError::DeviceInUse))
+}
+
+/// Right after a system resume, wait for this loop's turn before reopening a failed
+/// stream, so the loops don't all hit the devices at once
+fn wait_for_resume_turn(stream: &str) {
+    if let Some(wait) = RESUME_GATE.turn(Instant::now()) {
+        info!("{} failed after the system resumed, reopening in {} ms", stream, wait.as_millis());
+        thread::sleep(wait);
+    }
+}
+
+/// Log the outcome of reopening a stream after its device was invalidated
+fn log_reopened(stream: &str, old: Option<&AudioFormat>, new: Option<&AudioFormat>) {
+    match (old, new) {
+        (Some(old), Some(new)) if old != new => {
+            info!("{} format changed: {} -> {}, stream reopened", stream, old, new);
+        }
+        _ => info!("{} device was reconfigured, stream reopened", stream),
+    }
+}
+
+/// Whether a stream error means reopening the same device won't help (e.g. an
+/// unsupported format), so recovery should stop instead of burning its retries
+fn is_unrecoverable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<StreamError>().is_some_and(|se| !se.is_retryable())
+}
+
+/// Check `--channel-map` against the capture and render channel counts whenever they
+/// change. The streams the render loop starts with have to fit it. A later switch to
+/// a device with fewer channels only warns: the conversion falls back to the default
+/// up/downmix until the streams fit the map again, so switching devices never stops
+/// or silences the speaker audio.
+fn check_channel_map(
+    map: &ChannelMap,
+    capture: &AudioFormat,
+    render: &AudioFormat,
+    checked: &mut Option<(u16, u16)>,
+) -> Result<()> {
+    let channels = (capture.channels, render.channels);
+    if *checked == Some(channels) {
+        return Ok(());
+    }
+    let starting = checked.replace(channels).is_none();
+    if let Err(e) = map.validate(capture.channels as usize, render.channels as usize) {
+        if starting {
+            return Err(e);
+        }
+        warn!("{}; using the default channel mix until the streams fit it again", e);
+    }
+    Ok(())
+}
+
+/// Error that stops a render loop whose formats differ under `--no-convert`. The
+/// formats stay published, so `GetFormats` shows the mismatch.
+fn conversion_refused(path: &str, capture: &AudioFormat, render: &AudioFormat) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} capture format ({}) doesn't match the render format ({}) and --no-convert is set",
+        path, capture, render
+    )
+}
+
+/// Warn that a path is converting between `capture` and `render`, once per pair of
+/// formats. Users often don't know their devices run at different rates, and fixing
+/// that in the Windows sound settings saves the CPU and the resampling loss.
+fn warn_converting(
+    path: &str,
+    capture: &AudioFormat,
+    render: &AudioFormat,
+    settings: &ConversionSettings,
+    warned: &mut Option<(AudioFormat, AudioFormat)>,
+) {
+    if warned.as_ref().is_some_and(|(c, r)| c == capture && r == render) {
+        return;
+    }
+    warn!(
+        "{} capture ({}) and render ({}) formats differ, converting every block: {}. \
+         Set both devices to the same format to avoid it.",
+        path, capture, render, describe_conversion(capture, render, settings)
+    );
+    *warned = Some((capture.clone(), render.clone()));
+}
+
+/// Whether a converted block can be written to `render`. One that isn't whole frames
+/// would put every later frame on the wrong channels, so it's dropped instead, and
+/// the first one per loop is logged.
+fn check_converted(path: &str, converted: &[f32], render: &AudioFormat, logged: &mut bool) -> bool {
+    if is_whole_frames(converted, render) {
+        return true;
+    }
+    if !*logged {
+        error!(
+            "{} conversion returned {} samples, not whole {}-channel frames; dropping such blocks",
+            path, converted.len(), render.channels
+        );
+        *logged = true;
+    }
+    false
+}
+
+// ── Speaker loops ──────────────────────────────────────────────────────────
+
+/// What only the main speaker capture feeds, not the one `--speaker-in2` mixes in
+struct PrimaryCapture {
+    /// Copy of the raw capture for the monitor output and `GetSpectrum`
+    tap: Arc<BroadcastRingBuffer>,
+    /// Reported as the speaker capture's liveness in `GetStatus`
+    heartbeat: &'static Heartbeat,
+    /// Level reported by `GetLevels`
+    meter: &'static MeterReading,
+}
+
+fn run_speaker_capture_loop(
+    input_device_id: &str,
+    buffer: Arc<AudioRingBuffer>,
+    running: Arc<AtomicBool>,
+    settings: &LoopSettings,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    mut glitch_dumper: Option<GlitchDumper>,
+    primary: Option<PrimaryCapture>,
+) -> Result<()> {
+    info!("Starting speaker capture from device: {}", input_device_id);
+
+    let mut capture = create_and_start_capture(input_device_id, settings.device_buffer_ms, settings.capture_chunk_frames)?;
+
+    // Share the format with the render thread
+    if let Some(fmt) = capture.format() {
+        *capture_format.write().unwrap() = Some(fmt.clone());
+        if let Some(ref mut dumper) = glitch_dumper {
+            dumper.set_format(fmt);
+        }
+    }
+
+    let mut temp_buffer = vec![0.0f32; 4096];
+    let mut reblocker = Reblocker::new(settings.process_block_frames);
+    let mut backoff = Backoff::new(&settings.recovery);
+    let heartbeat = primary.as_ref().map(|p| p.heartbeat);
+    let mut meter = LevelMeter::new(settings.meter.clone());
+
+    while running.load(Ordering::SeqCst) {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.beat();
+        }
+        if settings.paused.load(Ordering::SeqCst) {
+            // Release the device while paused so other apps can use it
+            capture.stop()?;
+            drop(capture);
+            *capture_format.write().unwrap() = None;
+            reblocker.reset();
+            info!("Speaker capture paused");
+            if !wait_while_paused(settings, &running, heartbeat) {
+                info!("Speaker capture loop stopped.");
+                return Ok(());
+            }
+
+            // Reopened from scratch, the device may have been reconfigured meanwhile
+            let reopened = reopen_after_resume("Speaker capture", settings, &running, heartbeat, &mut backoff, || {
+                create_and_start_capture(input_device_id, settings.device_buffer_ms, settings.capture_chunk_frames)
+            })?;
+            let Some(reopened) = reopened else {
+                info!("Speaker capture loop stopped.");
+                return Ok(());
+            };
+            capture = reopened;
+            if let Some(fmt) = capture.format() {
+                *capture_format.write().unwrap() = Some(fmt.clone());
+                if let Some(ref mut dumper) = glitch_dumper {
+                    dumper.set_format(fmt);
+                }
+            }
+            backoff.reset();
+            info!("Speaker capture resumed");
+            continue;
+        }
+
+        match capture.read(&mut temp_buffer) {
+            Ok(samples_read) if samples_read > 0 => {
+                backoff.reset();
+                if settings.injected_stall.active(Instant::now()) {
+                    // Dropped as if the source had stopped sending
+                    continue;
+                }
+                let channels = capture.format().map_or(0, |f| f.channels as usize);
+                let (offered, written) = reblocker.push(&temp_buffer[..samples_read], channels, |block| buffer.write_frames(block, channels));
+                if let Some(ref primary) = primary {
+                    primary.tap.write(&temp_buffer[..samples_read]);
+                    let rate = capture.format().map_or(0, |f| f.sample_rate);
+                    meter.process(&temp_buffer[..samples_read], channels, rate, primary.meter);
+                }
+                METRICS.speaker.input_silent.store(
+                    settings.silence.is_silent(&temp_buffer[..samples_read]), Ordering::Relaxed,
+                );
+                METRICS.speaker.input_clips.fetch_add(
+                    count_clips(&temp_buffer[..samples_read], settings.clip_ceiling), Ordering::Relaxed,
+                );
+                if written < offered {
+                    warn!("Speaker ring buffer overflow: {} samples dropped", offered - written);
+                    METRICS.speaker.overflows.fetch_add(1, Ordering::Relaxed);
+                }
+
+                if let Some(ref mut dumper) = glitch_dumper {
+                    dumper.push(&temp_buffer[..samples_read]);
+                    if written < offered {
+                        dumper.trigger(GlitchKind::Overflow);
+                    }
+                    if capture.take_discontinuity() {
+                        dumper.trigger(GlitchKind::Discontinuity);
+                    }
+                }
+            }
+            Ok(_) => {
+                thread::sleep(Duration::from_micros(500));
+            }
+            Err(StreamError::DeviceLost) => {
+                warn!("Speaker capture device was unplugged, waiting for it to come back");
+                *capture_format.write().unwrap() = None;
+                reblocker.reset();
+                let Some((_, new_capture)) = wait_for_capture_device(
+                    || input_device_id.to_string(), settings.device_buffer_ms, settings.capture_chunk_frames, &running, heartbeat,
+                ) else {
+                    info!("Speaker capture loop stopped.");
+                    return Ok(());
+                };
+                capture = new_capture;
+                if let Some(fmt) = capture.format() {
+                    *capture_format.write().unwrap() = Some(fmt.clone());
+                    if let Some(ref mut dumper) = glitch_dumper {
+                        dumper.set_format(fmt);
+                    }
+                }
+                backoff.reset();
+                METRICS.speaker.recoveries.fetch_add(1, Ordering::Relaxed);
+                info!("Speaker capture device is back");
+            }
+            Err(e) => {
+                wait_for_resume_turn("Speaker capture");
+                // Fast path: the device was reconfigured, reopen without burning an attempt
+                if matches!(e, StreamError::DeviceInvalidated) {
+                    if let Ok(new_capture) = create_and_start_capture(input_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                        let old_format = capture.format().cloned();
+                        capture = new_capture;
+                        if let Some(fmt) = capture.format() {
+                            *capture_format.write().unwrap() = Some(fmt.clone());
+                            if let Some(ref mut dumper) = glitch_dumper {
+                                dumper.set_format(fmt);
+                            }
+                        }
+                        METRICS.speaker.recoveries.fetch_add(1, Ordering::Relaxed);
+                        log_reopened("Speaker capture", old_format.as_ref(), capture.format());
+                        continue;
+                    }
+                }
+
+                let attempt = backoff.record_failure();
+                error!("Speaker capture error (attempt {}): {}", attempt, e);
+
+                if backoff.exhausted() {
+                    return Err(anyhow::Error::new(e).context("Too many consecutive capture errors, giving up"));
+                }
+
+                warn!("Attempting to recover speaker capture stream...");
+                thread::sleep(backoff.delay());
+                match create_and_start_capture(input_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                    Ok(new_capture) => {
+                        capture = new_capture;
+                        if let Some(fmt) = capture.format() {
+                            *capture_format.write().unwrap() = Some(fmt.clone());
+                            if let Some(ref mut dumper) = glitch_dumper {
+                                dumper.set_format(fmt);
+                            }
+                        }
+                        METRICS.speaker.recoveries.fetch_add(1, Ordering::Relaxed);
+                        info!("Speaker capture stream recovered");
+                    }
+                    Err(e) => {
+                        if is_unrecoverable(&e) {
+                            return Err(e.context("Speaker capture device can't be used"));
+                        }
+                        if is_device_in_use(&e) {
+                            backoff.hold();
+                        }
+                        error!("Failed to recover speaker capture: {}", e);
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mut dumper) = glitch_dumper {
+            dumper.poll();
+        }
+    }
+
+    *capture_format.write().unwrap() = None;
+    capture.stop()?;
+    info!("Speaker capture loop stopped.");
+    Ok(())
+}
+
+/// Buffers a render loop reuses from block to block, so neither steady playback nor a
+/// device switch allocates once they have grown to the largest block
+struct LoopBuffers {
+    /// Samples read from the ring buffer
+    capture: Vec<f32>,
+    /// `capture` shortened to play a stall reserve out faster
+    compressed: Vec<f32>,
+    conversion: ConversionState,
+    /// Silence written when there's nothing to play
+    silence: Vec<f32>,
+}
+
+impl LoopBuffers {
+    fn new(conversion: ConversionSettings) -> Self {
+        Self {
+            capture: vec![0.0; 4096],
+            compressed: Vec::new(),
+            conversion: ConversionState::new(conversion),
+            silence: Vec::new(),
+        }
+    }
+
+    /// `samples` of silence, zeroed again since the last caller may have mixed into it
+    fn silence(&mut self, samples: usize) -> &mut [f32] {
+        self.silence.clear();
+        self.silence.resize(samples, 0.0);
+        &mut self.silence
+    }
+}
+
+/// The per-block format handling of the speaker render loop, and what it has logged
+struct SpeakerConversion {
+    settings: ConversionSettings,
+    no_convert: bool,
+    warned: Option<(AudioFormat, AudioFormat)>,
+    channel_map_checked: Option<(u16, u16)>,
+    partial_frames_logged: bool,
+}
+
+/// A block read by the speaker render loop, after `SpeakerConversion::block`
+enum SpeakerBlock<'a> {
+    /// In the render format, ready for the processing stages
+    Ready { capture: AudioFormat, render: AudioFormat, samples: &'a mut [f32] },
+    /// Written as is, since the capture or render format isn't known yet
+    Unformatted(&'a [f32]),
+    /// Dropped, because it didn't convert to whole render frames
+    Dropped,
+}
+
+impl SpeakerConversion {
+    fn new(settings: ConversionSettings, no_convert: bool) -> Self {
+        Self { settings, no_convert, warned: None, channel_map_checked: None, partial_frames_logged: false }
+    }
+
+    /// Bring the first `samples_read` samples of `buffers.capture` into the format
+    /// `render` is open at. The capture format is the one the capture thread last
+    /// published, so a capture hot-swap is converted from the next block on.
+    fn block<'a>(
+        &mut self,
+        buffers: &'a mut LoopBuffers,
+        mut samples_read: usize,
+        capture_format: &RwLock<Option<AudioFormat>>,
+        render: &dyn RenderBackend,
+        stall: &mut StallReserve,
+    ) -> Result<SpeakerBlock<'a>> {
+        let capture = capture_format.read().unwrap().clone();
+
+        // Playing out a leftover stall reserve a little fast
+        if let Some(ref cf) = capture {
+            if stall.compress(&buffers.capture[..samples_read], cf, &mut buffers.compressed) {
+                samples_read = buffers.compressed.len();
+                buffers.capture[..samples_read].copy_from_slice(&buffers.compressed);
+            }
+        }
+
+        let (Some(cf), Some(rf)) = (capture, render.format().cloned()) else {
+            return Ok(SpeakerBlock::Unformatted(&buffers.capture[..samples_read]));
+        };
+        if let Some(ref map) = self.settings.mix.map {
+            check_channel_map(map, &cf, &rf, &mut self.channel_map_checked)?;
+        }
+        if !buffers.conversion.needed(&cf, &rf) {
+            return Ok(SpeakerBlock::Ready { capture: cf, render: rf, samples: &mut buffers.capture[..samples_read] });
+        }
+        if self.no_convert {
+            return Err(conversion_refused("Speaker", &cf, &rf));
+        }
+        if formats_need_conversion(&cf, &rf) {
+            warn_converting("Speaker", &cf, &rf, &self.settings, &mut self.warned);
+        }
+        let converted = convert_audio(&buffers.capture[..samples_read], &cf, &rf, &mut buffers.conversion);
+        if !check_converted("Speaker", converted, &rf, &mut self.partial_frames_logged) {
+            return Ok(SpeakerBlock::Dropped);
+        }
+        Ok(SpeakerBlock::Ready { capture: cf, render: rf, samples: converted })
+    }
+}
+
+fn run_speaker_render_loop(
+    buffer: Arc<AudioRingBuffer>,
+    output_device_id: Arc<RwLock<String>>,
+    running: Arc<AtomicBool>,
+    settings: &LoopSettings,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    render_format: Arc<RwLock<Option<AudioFormat>>>,
+    controls: &RenderControls,
+) -> Result<()> {
+    let mut current = OutputTarget {
+        device_id: output_device_id.read().unwrap().clone(),
+        format: *controls.requested_format.read().unwrap(),
+    };
+    info!("Starting speaker render to device: {}", current.device_id);
+
+    let mut render = create_and_start_output(&current.device_id, current.format, settings)?;
+    *render_format.write().unwrap() = render.format().cloned();
+    *controls.opened.write().unwrap() = current.clone();
+    let conversion = settings.speaker_conversion();
+    let mut buffers = LoopBuffers::new(conversion);
+    let mut speaker_conversion = SpeakerConversion::new(conversion, settings.no_convert);
+    let mut equalizer = Equalizer::default();
+    let mut chain = Chain::new(&settings.speaker_chain);
+    let mut fade_in = FadeIn::new(settings.start_fade_ms);
+    let mut delay = DelayLine::new(settings.speaker_delay.clone());
+    let mut secondary = controls.secondary.clone().map(|source| SecondaryMix::new(source, settings.conversion));
+    let mut monitor = Monitor::new(controls.monitor.clone(), settings.conversion);
+    let mut idle_fill = IdleFill::new(settings.keep_alive_db);
+    let mut stall = StallReserve::new(controls.stall.clone(), settings.device_buffer_ms);
+    let mut fill_limit = settings.max_fill_ms.map(|ms| FillLimit::new(ms, settings.prefill_ms));
+    let mut backoff = Backoff::new(&settings.recovery);
+    let mut starved = false;
+    let mut next_metrics_update = Instant::now();
+
+    // Pre-fill buffer with silence
+    let render_channels = render.format().map(|f| f.channels as usize).unwrap_or(2);
+    let render_rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+    let mut ab_trim = controls.ab_match.clone().map(|shared| AbTrim::new(shared, render_rate));
+    let mut ceiling = GainCeiling::new("Speaker", settings.max_output_db, render_rate);
+    // Keeps reading and converting while soloed, so un-soloing picks up right where the
+    // audio is now
+    let mut solo_gain = GainRamp::new(0.0, SOLO_RAMP_MS, render_rate);
+    let prefill_samples = (render_rate * settings.prefill_ms / 1000) as usize * render_channels;
+    let silence = vec![0.0f32; prefill_samples];
+    let _ = render.write(&silence);
+
+    let heartbeat = &METRICS.speaker.render_heartbeat;
+
+    while running.load(Ordering::SeqCst) {
+        heartbeat.beat();
+        if settings.paused.load(Ordering::SeqCst) {
+            // Release the device while paused so other apps can use it
+            render.stop()?;
+            drop(render);
+            monitor.release();
+            *render_format.write().unwrap() = None;
+            info!("Speaker render paused");
+            if !wait_while_paused(settings, &running, Some(heartbeat)) {
+                info!("Speaker render loop stopped.");
+                return Ok(());
+            }
+
+            // Pick up output switches made while paused, or while retrying
+            let reopened = reopen_after_resume("Speaker render", settings, &running, Some(heartbeat), &mut backoff, || {
+                let target = OutputTarget {
+                    device_id: output_device_id.read().unwrap().clone(),
+                    format: *controls.requested_format.read().unwrap(),
+                };
+                create_and_start_output(&target.device_id, target.format, settings).map(|render| (target, render))
+            })?;
+            let Some((target, reopened)) = reopened else {
+                info!("Speaker render loop stopped.");
+                return Ok(());
+            };
+            (current, render) = (target, reopened);
+            *render_format.write().unwrap() = render.format().cloned();
+            *controls.opened.write().unwrap() = current.clone();
+            // Audio queued before the pause is stale by now
+            discard_buffered(&buffer, &mut buffers.capture);
+            if let Some(ref mut secondary) = secondary {
+                secondary.reset();
+            }
+            stall.reset();
+            fade_in.restart();
+            backoff.reset();
+            info!("Speaker render resumed on: {}", current.device_id);
+            continue;
+        }
+
+        // Check if output device or requested format changed (hot-swap)
+        {
+            let target = OutputTarget {
+                device_id: output_device_id.read().unwrap().clone(),
+                format: *controls.requested_format.read().unwrap(),
+            };
+            if target != current {
+                info!("Switching speaker output to: {}", target.device_id);
+                render.stop()?;
+
+                match create_and_start_output(&target.device_id, target.format, settings) {
+                    Ok(new_render) => {
+                        render = new_render;
+                        current = target;
+                        backoff.reset();
+                        info!("Speaker output switched successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to switch speaker output: {}", e);
+                        // Try to restart with old device
+                        render = create_and_start_output(&current.device_id, current.format, settings)
+                            .context("Failed to restart render with previous device")?;
+                    }
+                }
+                *render_format.write().unwrap() = render.format().cloned();
+                *controls.opened.write().unwrap() = current.clone();
+                fade_in.restart();
+            }
+        }
+
+        if Instant::now() >= next_metrics_update {
+            update_queue_metrics(&METRICS.speaker, &buffer, &capture_format, render.as_ref());
+            next_metrics_update = Instant::now() + METRICS_UPDATE_INTERVAL;
+        }
+
+        // Independent of the main output, whatever state it's in
+        let monitor_format = capture_format.read().unwrap().clone();
+        monitor.pump(monitor_format.as_ref());
+
+        // While a stall reserve is held, it paces the reads instead of the chunking
+        let stall_step = match (capture_format.read().unwrap().clone(), render.format()) {
+            (Some(cf), Some(rf)) if stall.active(Instant::now()) => stall.step(
+                Instant::now(), settings.prefill_ms, buffer.len(), render.buffered_frames().unwrap_or(0), &cf, rf,
+            ),
+            _ => StallStep::Normal,
+        };
+        let read_limit = match stall_step {
+            StallStep::Normal => {
+                // Not while a reserve is held, which fills the ring buffer on purpose
+                if let Some(ref mut fill_limit) = fill_limit {
+                    enforce_fill_limit(fill_limit, &buffer, &capture_format, "Speaker", &METRICS.speaker);
+                }
+                if hold_for_chunk(settings.render_chunk_ms, &buffer, &capture_format, render.as_ref()) {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                buffers.capture.len()
+            }
+            StallStep::Silence(frames) => {
+                if let Some(rf) = render.format().cloned().filter(|_| frames > 0) {
+                    let ch = rf.channels as usize;
+                    let silence = buffers.silence(frames * ch);
+                    if let Some(ref mut secondary) = secondary {
+                        secondary.mix_into(silence, &rf);
+                    }
+                    if let Ok(written) = render.write(silence) {
+                        if let Some(ref mut secondary) = secondary {
+                            secondary.consume(written);
+                        }
+                        stall.played_silence(written / ch);
+                    }
+                }
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            StallStep::Read(0) => {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            StallStep::Read(samples) => samples.min(buffers.capture.len()),
+        };
+
+        // Read from ring buffer and write to output
+        let samples_read = buffer.read_frames(&mut buffers.capture[..read_limit], capture_channels(&capture_format));
+        if samples_read > 0 {
+            starved = false;
+            equalizer.sync(&controls.eq);
+            chain.sync(&settings.speaker_chain);
+
+            let block = speaker_conversion.block(&mut buffers, samples_read, &capture_format, render.as_ref(), &mut stall)?;
+            let write_result = match block {
+                SpeakerBlock::Ready { capture: ref cf, render: ref rf, samples } => {
+                    ceiling.set_sample_rate(rf.sample_rate);
+                    solo_gain.set_sample_rate(rf.sample_rate);
+                    solo_gain.set_target(if controls.solo_mic.load(Ordering::Relaxed) { f32::NEG_INFINITY } else { 0.0 });
+                    // Only what the enabled stages add counts towards the cap
+                    ceiling.update(
+                        if chain.has(Stage::Eq) { equalizer.max_boost_db() } else { 0.0 }
+                            + channel_mix_gain_db(cf.channels as usize, rf.channels as usize, conversion.mix)
+                            + ab_trim.as_ref().filter(|_| chain.has(Stage::AbTrim)).map_or(0.0, |t| t.trim_db()),
+                    );
+                    if let Some(ref mut secondary) = secondary {
+                        secondary.mix_into(samples, rf);
+                    }
+                    let channels = rf.channels as usize;
+                    for &stage in chain.stages() {
+                        match stage {
+                            Stage::Eq => equalizer.process(samples, rf),
+                            Stage::AbTrim => {
+                                if let Some(ref mut ab_trim) = ab_trim {
+                                    ab_trim.process(&current.device_id, samples, rf);
+                                }
+                            }
+                            Stage::Ceiling => ceiling.process(samples, channels),
+                            Stage::Delay => delay.process(samples, rf),
+                            Stage::Solo => solo_gain.process(samples, channels),
+                            Stage::SwapLr => {
+                                if controls.swap_lr.load(Ordering::Relaxed) {
+                                    swap_left_right(samples, channels);
+                                }
+                            }
+                        }
+                    }
+                    fade_in.apply(samples, rf);
+                    render.write(samples)
+                }
+                SpeakerBlock::Unformatted(samples) => render.write(samples),
+                SpeakerBlock::Dropped => continue,
+            };
+            if let (Some(ref mut secondary), Ok(written)) = (&mut secondary, &write_result) {
+                secondary.consume(*written);
+            }
+
+            if let Err(e) = write_result {
+                wait_for_resume_turn("Speaker render");
+                // Fast path: the device was reconfigured, reopen without burning an attempt
+                if is_device_invalidated(&e) {
+                    if let Ok(new_render) = create_and_start_output(&current.device_id, current.format, settings) {
+                        let old_format = render.format().cloned();
+                        render = new_render;
+                        *render_format.write().unwrap() = render.format().cloned();
+                        METRICS.speaker.recoveries.fetch_add(1, Ordering::Relaxed);
+                        log_reopened("Speaker render", old_format.as_ref(), render.format());
+                        fade_in.restart();
+                        continue;
+                    }
+                }
+
+                let attempt = backoff.record_failure();
+                error!("Speaker render error (attempt {}): {}", attempt, e);
+
+                if backoff.exhausted() {
+                    return Err(e.context("Too many consecutive render errors, giving up"));
+                }
+
+                warn!("Attempting to recover speaker render stream...");
+                thread::sleep(backoff.delay());
+                match create_and_start_output(&current.device_id, current.format, settings) {
+                    Ok(new_render) => {
+                        render = new_render;
+                        *render_format.write().unwrap() = render.format().cloned();
+                        fade_in.restart();
+                        METRICS.speaker.recoveries.fetch_add(1, Ordering::Relaxed);
+                        info!("Speaker render stream recovered");
+                    }
+                    Err(re) => {
+                        if is_unrecoverable(&re) {
+                            return Err(re.context("Speaker render device can't be used"));
+                        }
+                        error!("Failed to recover speaker render: {}", re);
+                    }
+                }
+            } else {
+                backoff.reset();
+            }
+        } else {
+            // An empty ring buffer with nothing queued on the device means it starved
+            if matches!(render.buffered_frames(), Ok(0)) {
+                if !starved {
+                    starved = true;
+                    METRICS.speaker.underruns.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(ref signal) = controls.underrun_signal {
+                    signal.store(true, Ordering::Relaxed);
+                }
+            }
+
+            // No data available - write silence to prevent underrun
+            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
+            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+            let silence_ms = settings.render_chunk_ms.max(1);
+            let silence_samples = (rate * silence_ms / 1000) as usize * ch;
+            let silence = buffers.silence(silence_samples);
+            idle_fill.fill(silence);
+            if let Some(rf) = render.format().cloned() {
+                // The second source keeps playing while the primary one is idle
+                if let Some(ref mut secondary) = secondary {
+                    secondary.mix_into(silence, &rf);
+                }
+                // Keeps playing out the delayed tail
+                if chain.has(Stage::Delay) {
+                    delay.process(silence, &rf);
+                }
+            }
+            let written = render.write(silence);
+            if let (Some(ref mut secondary), Ok(written)) = (&mut secondary, written) {
+                secondary.consume(written);
+            }
+            thread::sleep(Duration::from_micros(500));
+        }
+    }
+
+    // Graceful shutdown: play out what's still buffered instead of cutting it off
+    let swap_lr = controls.swap_lr.load(Ordering::Relaxed);
+    drain_render(
+        render.as_mut(), &buffer, &capture_format, &mut buffers.conversion,
+        &mut |samples, rf| {
+            let channels = rf.channels as usize;
+            for &stage in chain.stages() {
+                match stage {
+                    Stage::Eq => equalizer.process(samples, rf),
+                    Stage::AbTrim => {
+                        if let Some(ref mut ab_trim) = ab_trim {
+                            ab_trim.process(&current.device_id, samples, rf);
+                        }
+                    }
+                    Stage::Ceiling => ceiling.process(samples, channels),
+                    Stage::Solo => solo_gain.process(samples, channels),
+                    Stage::SwapLr if swap_lr => swap_left_right(samples, channels),
+                    // Played out undelayed, like before
+                    Stage::Delay | Stage::SwapLr => {}
+                }
+            }
+        },
+        Duration::from_millis(settings.drain_ms as u64),
+    );
+
+    monitor.release();
+    *render_format.write().unwrap() = None;
+    render.stop()?;
+    info!("Speaker render loop stopped.");
+    Ok(())
+}
+
+/// Sleep while the proxy is paused, still beating `heartbeat`; returns false if it was
+/// stopped in the meantime
+fn wait_while_paused(settings: &LoopSettings, running: &AtomicBool, heartbeat: Option<&Heartbeat>) -> bool {
+    while settings.paused.load(Ordering::SeqCst) && running.load(Ordering::SeqCst) {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.beat();
+        }
+        thread::sleep(PAUSE_POLL_INTERVAL);
+    }
+    running.load(Ordering::SeqCst)
+}
+
+/// Reopen a stream released by `Pause` once the proxy is resumed. A failed open goes
+/// through the same recovery as a failed stream: retried with `backoff` until the
+/// recovery policy gives up, and for as long as it takes while another app still
+/// holds the device (`DeviceInUse`). `Ok(None)` if the proxy was stopped meanwhile.
+fn reopen_after_resume<T>(
+    stream: &str,
+    settings: &LoopSettings,
+    running: &AtomicBool,
+    heartbeat: Option<&Heartbeat>,
+    backoff: &mut Backoff,
+    mut open: impl FnMut() -> Result<T>,
+) -> Result<Option<T>> {
+    loop {
+        let e = match open() {
+            Ok(opened) => return Ok(Some(opened)),
+            Err(e) => e,
+        };
+        if is_unrecoverable(&e) {
+            return Err(e.context(format!("{} device can't be used", stream)));
+        }
+        if is_device_in_use(&e) {
+            backoff.hold();
+        }
+        let attempt = backoff.record_failure();
+        error!("{} failed to reopen after resume (attempt {}): {}", stream, attempt, e);
+        if backoff.exhausted() {
+            return Err(e.context(format!("{} failed to reopen after resume too many times, giving up", stream)));
+        }
+
+        // In short steps, so the heartbeat keeps going and a new `Pause` is waited out
+        let retry_at = Instant::now() + backoff.delay();
+        while Instant::now() < retry_at {
+            if !wait_while_paused(settings, running, heartbeat) {
+                return Ok(None);
+            }
+            if let Some(heartbeat) = heartbeat {
+                heartbeat.beat();
+            }
+            thread::sleep(PAUSE_POLL_INTERVAL.min(retry_at.saturating_duration_since(Instant::now())));
+        }
+    }
+}
+
+/// Channels of the capture format, which ring buffer reads are whole frames of
+fn capture_channels(capture_format: &RwLock<Option<AudioFormat>>) -> usize {
+    capture_format.read().unwrap().as_ref().map_or(1, |f| f.channels as usize)
+}
+
+/// Drop whatever is left in the ring buffer
+fn discard_buffered(buffer: &AudioRingBuffer, scratch: &mut [f32]) {
+    while buffer.read(scratch) > 0 {}
+}
+
+/// Publish the ring buffer fill and how much audio is queued end to end
+fn update_queue_metrics(
+    metrics: &PathMetrics,
+    buffer: &AudioRingBuffer,
+    capture_format: &RwLock<Option<AudioFormat>>,
+    render: &dyn RenderBackend,
+) {
+    let fill = buffer.len();
+    metrics.buffer_fill_samples.store(fill as u64, Ordering::Relaxed);
+
+    let mut latency_us = 0u64;
+    if let Some(cf) = capture_format.read().unwrap().as_ref() {
+        let samples_per_sec = cf.sample_rate as u64 * cf.channels as u64;
+        latency_us += (fill as u64 * 1_000_000).checked_div(samples_per_sec).unwrap_or(0);
+    }
+    if let (Some(rf), Ok(frames)) = (render.format(), render.buffered_frames()) {
+        latency_us += (frames as u64 * 1_000_000).checked_div(rf.sample_rate as u64).unwrap_or(0);
+    }
+    metrics.latency_us.store(latency_us, Ordering::Relaxed);
+}
+
+/// With `--max-fill-ms`, skip the ring buffer back to the prefill once it has stayed
+/// over the limit for a while
+fn enforce_fill_limit(
+    limit: &mut FillLimit,
+    buffer: &AudioRingBuffer,
+    capture_format: &RwLock<Option<AudioFormat>>,
+    path: &str,
+    metrics: &PathMetrics,
+) {
+    let Some(cf) = capture_format.read().unwrap().clone() else {
+        return;
+    };
+    let excess = limit.check(Instant::now(), buffer.len(), &cf);
+    if excess == 0 {
+        return;
+    }
+    let skipped = buffer.skip(excess);
+    let skipped_ms = skipped as u64 * 1000 / (cf.sample_rate as u64 * cf.channels as u64).max(1);
+    warn!("{} ring buffer stayed over --max-fill-ms, skipped {} ms of audio to get the latency back", path, skipped_ms);
+    metrics.forced_skips.fetch_add(1, Ordering::Relaxed);
+}
+
+/// With `--render-chunk-ms`, whether the render loop should wait for a full chunk to
+/// build up in the ring buffer instead of writing what's there. It only waits while
+/// the device still has at least a chunk queued, so batching never starves it.
+fn hold_for_chunk(
+    chunk_ms: u32,
+    buffer: &AudioRingBuffer,
+    capture_format: &RwLock<Option<AudioFormat>>,
+    render: &dyn RenderBackend,
+) -> bool {
+    if chunk_ms == 0 {
+        return false;
+    }
+    let (Some(cf), Some(rf)) = (capture_format.read().unwrap().clone(), render.format()) else {
+        return false;
+    };
+
+    let chunk_samples = (cf.sample_rate * chunk_ms / 1000) as usize * cf.channels as usize;
+    if buffer.len() >= chunk_samples {
+        return false;
+    }
+    let chunk_frames = rf.sample_rate * chunk_ms / 1000;
+    matches!(render.buffered_frames(), Ok(frames) if frames >= chunk_frames)
+}
+
+/// Write the remaining ring buffer contents to the device and let it play out,
+/// giving up when `timeout` elapses. `process` applies the render loop's output stages
+/// to each converted block. Errors just end the drain early.
+fn drain_render(
+    render: &mut dyn RenderBackend,
+    buffer: &AudioRingBuffer,
+    capture_format: &RwLock<Option<AudioFormat>>,
+    conversion: &mut ConversionState,
+    process: &mut dyn FnMut(&mut [f32], &AudioFormat),
+    timeout: Duration,
+) {
+    let deadline = Instant::now() + timeout;
+    let mut temp_buffer = vec![0.0f32; 4096];
+    let mut pending: Vec<f32> = Vec::new();
+    let mut drained = 0usize;
+
+    while Instant::now() < deadline {
+        if pending.is_empty() {
+            let samples_read = buffer.read_frames(&mut temp_buffer, capture_channels(capture_format));
+            if samples_read == 0 {
+                break;
+            }
+
+            let cap_fmt = capture_format.read().unwrap().clone();
+            pending.clear();
+            match (cap_fmt, render.format()) {
+                (Some(ref cf), Some(rf)) if conversion.needed(cf, rf) => {
+                    pending.extend_from_slice(convert_audio(&temp_buffer[..samples_read], cf, rf, conversion));
+                }
+                _ => pending.extend_from_slice(&temp_buffer[..samples_read]),
+            }
+            if let Some(rf) = render.format() {
+                process(&mut pending, rf);
+            }
+        }
+
+        // Unlike the main loop, wait for device space rather than dropping the remainder
+        match render.write(&pending) {
+            Ok(written) => {
+                pending.drain(..written);
+                drained += written;
+                if written == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            Err(_) => return,
+        }
+    }
+
+    // Let the device play out what is already queued
+    while Instant::now() < deadline {
+        match render.buffered_frames() {
+            Ok(frames) if frames > 0 => thread::sleep(Duration::from_millis(1)),
+            _ => break,
+        }
+    }
+
+    if drained > 0 {
+        info!("Drained {} buffered speaker samples before stopping", drained);
+    }
+}
+
+// ── Diagnostic sources and sinks ───────────────────────────────────────────
+
+/// Stand-in for a capture loop with `RunMode::RenderOnly`: feeds the ring buffer a
+/// test tone in real time, so the render loop runs just as it would on captured audio
+fn run_tone_source(
+    buffer: Arc<AudioRingBuffer>,
+    running: Arc<AtomicBool>,
+    settings: &LoopSettings,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    heartbeat: &Heartbeat,
+) -> Result<()> {
+    info!("Render only: playing a {} Hz test tone instead of capturing", TONE_HZ);
+    let channels = TONE_FORMAT.channels as usize;
+    let mut tone = Tone::new(TONE_FORMAT.sample_rate, TONE_HZ, TONE_AMPLITUDE);
+    let block_frames = (TONE_FORMAT.sample_rate as u128 * TONE_BLOCK.as_millis() / 1000) as usize;
+    let mut block = vec![0.0f32; block_frames * channels];
+    *capture_format.write().unwrap() = Some(TONE_FORMAT);
+    let mut next_block = Instant::now();
+
+    while running.load(Ordering::SeqCst) {
+        heartbeat.beat();
+        if settings.paused.load(Ordering::SeqCst) {
+            *capture_format.write().unwrap() = None;
+            if !wait_while_paused(settings, &running, Some(heartbeat)) {
+                break;
+            }
+            *capture_format.write().unwrap() = Some(TONE_FORMAT);
+            next_block = Instant::now();
+        }
+
+        // Paced like a device: one block per block length
+        let now = Instant::now();
+        if now < next_block {
+            thread::sleep(next_block - now);
+            continue;
+        }
+        tone.fill(&mut block, channels);
+        buffer.write_frames(&block, channels);
+        next_block += TONE_BLOCK;
+    }
+
+    *capture_format.write().unwrap() = None;
+    Ok(())
+}
+
+/// Stand-in for a render loop with `RunMode::CaptureOnly`: empties the ring buffer so
+/// the capture never overflows, and logs the input level since nothing can be heard
+fn run_discard_sink(
+    buffer: Arc<AudioRingBuffer>,
+    running: Arc<AtomicBool>,
+    settings: &LoopSettings,
+    path: &str,
+    metrics: &PathMetrics,
+) -> Result<()> {
+    info!("Capture only: {} audio is discarded", path.to_lowercase());
+    let mut scratch = vec![0.0f32; 4096];
+    let mut last_log = Instant::now();
+
+    while running.load(Ordering::SeqCst) {
+        discard_buffered(&buffer, &mut scratch);
+        if last_log.elapsed() >= LEVEL_LOG_INTERVAL {
+            let levels = metrics.meter.read(settings.meter.get().mode);
+            info!("{} input: {:.1} dBFS, peak {:.1} dBFS", path, levels.level_db, levels.peak_db);
+            last_log = Instant::now();
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+// ── Microphone loops ───────────────────────────────────────────────────────
+
+/// With `--mic-follow-default` and a `default:` selector as `device_id`, a watcher for
+/// changes of that default (IDs and names stay pinned). If it can't register, the mic
+/// still runs, it just doesn't follow.
+fn watch_default_mic(settings: &LoopSettings, device_id: &str) -> Option<DefaultCaptureWatcher> {
+    let role = DefaultRole::from_selector(device_id).filter(|_| settings.mic_follow_default)?;
+    match DefaultCaptureWatcher::new(role) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!("Can't watch for default mic changes, {} is only re-resolved on reopen: {}", device_id, e);
+            None
+        }
+    }
+}
+
+fn run_mic_capture_loop(
+    mic_input_id: Arc<RwLock<String>>,
+    buffer: Arc<AudioRingBuffer>,
+    running: Arc<AtomicBool>,
+    mic_enabled: Arc<AtomicBool>,
+    settings: &LoopSettings,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    // The `--mic-in` capture, which (unlike `--mic-in2`) beats the mic capture
+    // heartbeat and feeds the mic meter
+    primary: bool,
+) -> Result<()> {
+    let device_id = mic_input_id.read().unwrap().clone();
+    info!("Starting mic capture from device: {}", device_id);
+
+    let mut capture = create_and_start_capture(&device_id, settings.device_buffer_ms, settings.capture_chunk_frames)?;
+
+    if let Some(fmt) = capture.format() {
+        *capture_format.write().unwrap() = Some(fmt.clone());
+    }
+
+    let mut default_watcher = watch_default_mic(settings, &device_id);
+    let mut current_device_id = device_id;
+    let mut temp_buffer = vec![0.0f32; 4096];
+    let mut reblocker = Reblocker::new(settings.process_block_frames);
+    let mut backoff = Backoff::new(&settings.recovery);
+    let heartbeat = primary.then_some(&METRICS.mic.capture_heartbeat);
+    let mut meter = LevelMeter::new(settings.meter.clone());
+
+    while running.load(Ordering::SeqCst) {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.beat();
+        }
+        if settings.paused.load(Ordering::SeqCst) {
+            // Release the device while paused so other apps can use it
+            capture.stop()?;
+            drop(capture);
+            *capture_format.write().unwrap() = None;
+            reblocker.reset();
+            info!("Mic capture paused");
+            if !wait_while_paused(settings, &running, heartbeat) {
+                info!("Mic capture loop stopped.");
+                return Ok(());
+            }
+
+            // Pick up an input switch made while paused, or while retrying
+            let reopened = reopen_after_resume("Mic capture", settings, &running, heartbeat, &mut backoff, || {
+                let device_id = mic_input_id.read().unwrap().clone();
+                create_and_start_capture(&device_id, settings.device_buffer_ms, settings.capture_chunk_frames)
+                    .map(|capture| (device_id, capture))
+            })?;
+            let Some((device_id, reopened)) = reopened else {
+                info!("Mic capture loop stopped.");
+                return Ok(());
+            };
+            (current_device_id, capture) = (device_id, reopened);
+            default_watcher = watch_default_mic(settings, &current_device_id);
+            if let Some(fmt) = capture.format() {
+                *capture_format.write().unwrap() = Some(fmt.clone());
+            }
+            backoff.reset();
+            info!("Mic capture resumed on: {}", current_device_id);
+            continue;
+        }
+
+        if !mic_enabled.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(50));
+            continue;
+        }
+
+        // Check if input device changed (hot-swap)
+        {
+            let new_device_id = mic_input_id.read().unwrap().clone();
+            if new_device_id != current_device_id {
+                info!("Switching mic input to: {}", new_device_id);
+                capture.stop()?;
+
+                match create_and_start_capture(&new_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                    Ok(new_capture) => {
+                        capture = new_capture;
+                        if let Some(fmt) = capture.format() {
+                            *capture_format.write().unwrap() = Some(fmt.clone());
+                        }
+                        default_watcher = watch_default_mic(settings, &new_device_id);
+                        current_device_id = new_device_id;
+                        backoff.reset();
+                        info!("Mic input switched successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to switch mic input: {}", e);
+                        capture = create_and_start_capture(&current_device_id, settings.device_buffer_ms, settings.capture_chunk_frames)
+                            .context("Failed to restart mic capture with previous device")?;
+                    }
+                }
+            }
+        }
+
+        // With --mic-follow-default, move to the new default. The selector now resolves
+        // to it; the old stream keeps running until the new one has opened.
+        if default_watcher.as_ref().is_some_and(DefaultCaptureWatcher::take_changed) {
+            info!("Default mic changed, reopening {}", current_device_id);
+            match create_and_start_capture(&current_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                Ok(new_capture) => {
+                    capture = new_capture;
+                    if let Some(fmt) = capture.format() {
+                        *capture_format.write().unwrap() = Some(fmt.clone());
+                    }
+                    reblocker.reset();
+                    backoff.reset();
+                }
+                Err(e) => warn!("Failed to open the new default mic, staying on the previous one: {}", e),
+            }
+        }
+
+        match capture.read(&mut temp_buffer) {
+            Ok(samples_read) if samples_read > 0 => {
+                backoff.reset();
+                let channels = capture.format().map_or(0, |f| f.channels as usize);
+                let (offered, written) = reblocker.push(&temp_buffer[..samples_read], channels, |block| buffer.write_frames(block, channels));
+                if primary {
+                    let rate = capture.format().map_or(0, |f| f.sample_rate);
+                    meter.process(&temp_buffer[..samples_read], channels, rate, &METRICS.mic.meter);
+                }
+                METRICS.mic.input_silent.store(
+                    settings.silence.is_silent(&temp_buffer[..samples_read]), Ordering::Relaxed,
+                );
+                METRICS.mic.input_clips.fetch_add(
+                    count_clips(&temp_buffer[..samples_read], settings.clip_ceiling), Ordering::Relaxed,
+                );
+                if written < offered {
+                    warn!("Mic ring buffer overflow: {} samples dropped", offered - written);
+                    METRICS.mic.overflows.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Ok(_) => {
+                thread::sleep(Duration::from_micros(500));
+            }
+            Err(StreamError::DeviceLost) => {
+                warn!("Mic capture device was unplugged, waiting for it to come back");
+                *capture_format.write().unwrap() = None;
+                reblocker.reset();
+                // Follows input switches made while waiting
+                let Some((id, new_capture)) = wait_for_capture_device(
+                    || mic_input_id.read().unwrap().clone(), settings.device_buffer_ms, settings.capture_chunk_frames, &running, heartbeat,
+                ) else {
+                    info!("Mic capture loop stopped.");
+                    return Ok(());
+                };
+                capture = new_capture;
+                current_device_id = id;
+                if let Some(fmt) = capture.format() {
+                    *capture_format.write().unwrap() = Some(fmt.clone());
+                }
+                backoff.reset();
+                METRICS.mic.recoveries.fetch_add(1, Ordering::Relaxed);
+                info!("Mic capture device is back: {}", current_device_id);
+            }
+            Err(e) => {
+                wait_for_resume_turn("Mic capture");
+                // Fast path: the device was reconfigured, reopen without burning an attempt
+                if matches!(e, StreamError::DeviceInvalidated) {
+                    if let Ok(new_capture) = create_and_start_capture(&current_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                        let old_format = capture.format().cloned();
+                        capture = new_capture;
+                        if let Some(fmt) = capture.format() {
+                            *capture_format.write().unwrap() = Some(fmt.clone());
+                        }
+                        METRICS.mic.recoveries.fetch_add(1, Ordering::Relaxed);
+                        log_reopened("Mic capture", old_format.as_ref(), capture.format());
+                        continue;
+                    }
+                }
+
+                let attempt = backoff.record_failure();
+                error!("Mic capture error (attempt {}): {}", attempt, e);
+
+                if backoff.exhausted() {
+                    return Err(anyhow::Error::new(e).context("Too many consecutive mic capture errors, giving up"));
+                }
+
+                warn!("Attempting to recover mic capture stream...");
+                thread::sleep(backoff.delay());
+                match create_and_start_capture(&current_device_id, settings.device_buffer_ms, settings.capture_chunk_frames) {
+                    Ok(new_capture) => {
+                        capture = new_capture;
+                        if let Some(fmt) = capture.format() {
+                            *capture_format.write().unwrap() = Some(fmt.clone());
+                        }
+                        METRICS.mic.recoveries.fetch_add(1, Ordering::Relaxed);
+                        info!("Mic capture stream recovered");
+                    }
+                    Err(re) => {
+                        if is_unrecoverable(&re) {
+                            return Err(re.context("Mic capture device can't be used"));
+                        }
+                        if is_device_in_use(&re) {
+                            backoff.hold();
+                        }
+                        error!("Failed to recover mic capture: {}", re);
+                    }
+                }
+            }
+        }
+    }
+
+    *capture_format.write().unwrap() = None;
+    capture.stop()?;
+    info!("Mic capture loop stopped.");
+    Ok(())
+}
+
+fn run_mic_render_loop(
+    mic_output_id: Arc<RwLock<String>>,
+    buffer: Arc<AudioRingBuffer>,
+    running: Arc<AtomicBool>,
+    controls: &MicRenderControls,
+    settings: &LoopSettings,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    render_format: Arc<RwLock<Option<AudioFormat>>>,
+) -> Result<()> {
+    let device_id = mic_output_id.read().unwrap().clone();
+    info!("Starting mic render to device: {}", device_id);
+
+    let mut render = create_and_start_render(&device_id, settings.device_buffer_ms)?;
+    *render_format.write().unwrap() = render.format().cloned();
+    let mut current_device_id = device_id;
+    let mut buffers = LoopBuffers::new(settings.conversion);
+    let mut conversion_warned = None;
+    let mut partial_frames_logged = false;
+    let mut fade_in = FadeIn::new(settings.start_fade_ms);
+    let mut delay = DelayLine::new(settings.mic_delay.clone());
+    let mut chain = Chain::new(&settings.mic_chain);
+    let mut idle_fill = IdleFill::new(settings.keep_alive_db);
+    let mut fill_limit = settings.max_fill_ms.map(|ms| FillLimit::new(ms, settings.prefill_ms));
+    let mut backoff = Backoff::new(&settings.recovery);
+    let mut starved = false;
+    let mut next_metrics_update = Instant::now();
+
+    let render_channels = render.format().map(|f| f.channels as usize).unwrap_or(2);
+    let render_rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+    let mut gain = GainRamp::new(controls.level.target_db(), SOURCE_RAMP_MS, render_rate);
+    let mut ceiling = GainCeiling::new("Mic", settings.max_output_db, render_rate);
+    let mut secondary = controls.secondary.clone().map(|source| SecondaryMix::new(source, settings.conversion));
+    let prefill_samples = (render_rate * settings.prefill_ms / 1000) as usize * render_channels;
+    let silence = vec![0.0f32; prefill_samples];
+    let _ = render.write(&silence);
+
+    let heartbeat = &METRICS.mic.render_heartbeat;
+
+    while running.load(Ordering::SeqCst) {
+        heartbeat.beat();
+        if settings.paused.load(Ordering::SeqCst) {
+            // Release the device while paused so other apps can use it
+            render.stop()?;
+            drop(render);
+            *render_format.write().unwrap() = None;
+            info!("Mic render paused");
+            if !wait_while_paused(settings, &running, Some(heartbeat)) {
+                info!("Mic render loop stopped.");
+                return Ok(());
+            }
+
+            // Pick up an output switch made while paused, or while retrying
+            let reopened = reopen_after_resume("Mic render", settings, &running, Some(heartbeat), &mut backoff, || {
+                let device_id = mic_output_id.read().unwrap().clone();
+                create_and_start_render(&device_id, settings.device_buffer_ms).map(|render| (device_id, render))
+            })?;
+            let Some((device_id, reopened)) = reopened else {
+                info!("Mic render loop stopped.");
+                return Ok(());
+            };
+            (current_device_id, render) = (device_id, reopened);
+            *render_format.write().unwrap() = render.format().cloned();
+            // Audio queued before the pause is stale by now
+            discard_buffered(&buffer, &mut buffers.capture);
+            if let Some(ref mut secondary) = secondary {
+                secondary.reset();
+            }
+            fade_in.restart();
+            backoff.reset();
+            info!("Mic render resumed on: {}", current_device_id);
+            continue;
+        }
+
+        // Check if output device changed (hot-swap)
+        {
+            let new_device_id = mic_output_id.read().unwrap().clone();
+            if new_device_id != current_device_id {
+                info!("Switching mic output to: {}", new_device_id);
+                render.stop()?;
+
+                match create_and_start_render(&new_device_id, settings.device_buffer_ms) {
+                    Ok(new_render) => {
+                        render = new_render;
+                        current_device_id = new_device_id;
+                        backoff.reset();
+                        info!("Mic output switched successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to switch mic output: {}", e);
+                        // Try to restart with old device
+                        render = create_and_start_render(&current_device_id, settings.device_buffer_ms)
+                            .context("Failed to restart mic render with previous device")?;
+                    }
+                }
+                *render_format.write().unwrap() = render.format().cloned();
+                fade_in.restart();
+            }
+        }
+
+        if !controls.enabled.load(Ordering::SeqCst) {
+            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
+            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+            let silence_samples = (rate / 1000) as usize * ch;
+            let silence = buffers.silence(silence_samples);
+            idle_fill.fill(silence);
+            let _ = render.write(silence);
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+
+        if Instant::now() >= next_metrics_update {
+            update_queue_metrics(&METRICS.mic, &buffer, &capture_format, &render);
+            next_metrics_update = Instant::now() + METRICS_UPDATE_INTERVAL;
+        }
+
+        if let Some(ref mut fill_limit) = fill_limit {
+            enforce_fill_limit(fill_limit, &buffer, &capture_format, "Mic", &METRICS.mic);
+        }
+        if hold_for_chunk(settings.render_chunk_ms, &buffer, &capture_format, &render) {
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+
+        let samples_read = buffer.read_frames(&mut buffers.capture, capture_channels(&capture_format));
+        if samples_read > 0 {
+            starved = false;
+            let cap_fmt = capture_format.read().unwrap().clone();
+            let rnd_fmt = render.format().cloned();
+            if let Some(ref rf) = rnd_fmt {
+                gain.set_sample_rate(rf.sample_rate);
+                ceiling.set_sample_rate(rf.sample_rate);
+            }
+            gain.set_target(controls.level.target_db());
+            chain.sync(&settings.mic_chain);
+
+            let write_result = if let (Some(ref cf), Some(ref rf)) = (cap_fmt, rnd_fmt) {
+                // The louder of the two mics decides
+                let secondary_db = controls.secondary.as_ref().map_or(f32::NEG_INFINITY, |s| s.level.target_db());
+                ceiling.update(
+                    controls.level.target_db().max(secondary_db)
+                        + channel_mix_gain_db(cf.channels as usize, rf.channels as usize, settings.conversion.mix),
+                );
+                let samples = if formats_need_conversion(cf, rf) {
+                    if settings.no_convert {
+                        return Err(conversion_refused("Mic", cf, rf));
+                    }
+                    warn_converting("Mic", cf, rf, &settings.conversion, &mut conversion_warned);
+                    let converted = convert_audio(
+                        &buffers.capture[..samples_read], cf, rf, &mut buffers.conversion,
+                    );
+                    if !check_converted("Mic", converted, rf, &mut partial_frames_logged) {
+                        continue;
+                    }
+                    converted
+                } else {
+                    &mut buffers.capture[..samples_read]
+                };
+                gain.process(samples, rf.channels as usize);
+                if let Some(ref mut secondary) = secondary {
+                    secondary.mix_into(samples, rf);
+                }
+                for &stage in chain.stages() {
+                    match stage {
+                        Stage::Ceiling => ceiling.process(samples, rf.channels as usize),
+                        Stage::Delay => delay.process(samples, rf),
+                        // Not stages of the mic path
+                        _ => {}
+                    }
+                }
+                fade_in.apply(samples, rf);
+                render.write(samples)
+            } else {
+                render.write(&buffers.capture[..samples_read])
+            };
+            if let (Some(ref mut secondary), Ok(written)) = (&mut secondary, &write_result) {
+                secondary.consume(*written);
+            }
+
+            if let Err(e) = write_result {
+                wait_for_resume_turn("Mic render");
+                // Fast path: the device was reconfigured, reopen without burning an attempt
+                if matches!(e, StreamError::DeviceInvalidated) {
+                    if let Ok(new_render) = create_and_start_render(&current_device_id, settings.device_buffer_ms) {
+                        let old_format = render.format().cloned();
+                        render = new_render;
+                        *render_format.write().unwrap() = render.format().cloned();
+                        METRICS.mic.recoveries.fetch_add(1, Ordering::Relaxed);
+                        log_reopened("Mic render", old_format.as_ref(), render.format());
+                        fade_in.restart();
+                        continue;
+                    }
+                }
+
+                let attempt = backoff.record_failure();
+                error!("Mic render error (attempt {}): {}", attempt, e);
+
+                if backoff.exhausted() {
+                    return Err(anyhow::Error::new(e).context("Too many consecutive mic render errors, giving up"));
+                }
+
+                warn!("Attempting to recover mic render stream...");
+                thread::sleep(backoff.delay());
+                match create_and_start_render(&current_device_id, settings.device_buffer_ms) {
+                    Ok(new_render) => {
+                        render = new_render;
+                        *render_format.write().unwrap() = render.format().cloned();
+                        fade_in.restart();
+                        METRICS.mic.recoveries.fetch_add(1, Ordering::Relaxed);
+                        info!("Mic render stream recovered");
+                    }
+                    Err(re) => {
+                        if is_unrecoverable(&re) {
+                            return Err(re.context("Mic render device can't be used"));
+                        }
+                        error!("Failed to recover mic render: {}", re);
+                    }
+                }
+            } else {
+                backoff.reset();
+            }
+        } else {
+            if !starved && matches!(render.buffered_frames(), Ok(0)) {
+                starved = true;
+                METRICS.mic.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let ch = render.format().map(|f| f.channels as usize).unwrap_or(2);
+            let rate = render.format().map(|f| f.sample_rate).unwrap_or(DEFAULT_SAMPLE_RATE);
+            let silence_samples = (rate * settings.render_chunk_ms.max(1) / 1000) as usize * ch;
+            let silence = buffers.silence(silence_samples);
+            idle_fill.fill(silence);
+            if let Some(rf) = render.format().cloned() {
+                // The second mic keeps playing while the first one is idle
+                if let Some(ref mut secondary) = secondary {
+                    secondary.mix_into(silence, &rf);
+                }
+                ceiling.process(silence, rf.channels as usize);
+                // Keeps playing out the delayed tail
+                if chain.has(Stage::Delay) {
+                    delay.process(silence, &rf);
+                }
+            }
+            let written = render.write(silence);
+            if let (Some(ref mut secondary), Ok(written)) = (&mut secondary, written) {
+                secondary.consume(written);
+            }
+            thread::sleep(Duration::from_micros(500));
+        }
+    }
+
+    *render_format.write().unwrap() = None;
+    render.stop()?;
+    info!("Mic render loop stopped.");
+    Ok(())
+}
+
+// ── IPC server ─────────────────────────────────────────────────────────────
+
+/// Serve commands on the named pipe of `instance` if `pipe` is set, and on `tcp` if bound
+fn run_ipc_server(state: &IpcState, mut server: Option<IpcServer>, mut tcp: Option<TcpIpcServer>) -> Result<()> {
+    if let Some(ref server) = server {
+        info!("IPC server started on pipe: {}", server.name());
+    }
+    if let Some(ref tcp) = tcp {
+        info!("IPC server listening on tcp://{}", tcp.local_addr()?);
+    }
+
+    // With both transports, split the wait so neither delays the other
+    let timeout = if server.is_some() && tcp.is_some() { Duration::from_millis(50) } else { Duration::from_millis(100) };
+
+    while state.running.load(Ordering::SeqCst) {
+        if let Some(ref mut server) = server {
+            serve_ipc_client(server, state, timeout);
+        }
+        if let Some(ref mut tcp) = tcp {
+            serve_ipc_client(tcp, state, timeout);
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait up to `timeout` for one command on `transport` and answer it
+fn serve_ipc_client(transport: &mut impl IpcTransport, state: &IpcState, timeout: Duration) {
+    match transport.accept_with_timeout(timeout) {
+        Ok(Some(command)) => {
+            let response = handle_ipc_command(command, state);
+            if let Err(e) = transport.send_response(&response) {
+                warn!("Failed to send IPC response: {}", e);
+            }
+        }
+        Ok(None) => {
+            // Timeout or no client, continue loop
+        }
+        Err(e) => {
+            warn!("IPC accept error: {}", e);
+            thread::sleep(timeout);
+        }
+    }
+}
+
+fn handle_ipc_command(command: IpcCommand, state: &IpcState) -> IpcResponse {
+    let output_device_id = &state.output_device_id;
+    let running = &state.running;
+    let mic_input_id = state.mic_input_id.as_ref();
+    let mic_output_id = state.mic_output_id.as_ref();
+    let mic_enabled = state.mic_enabled.as_ref();
+
+    match command {
+        IpcCommand::SetOutput { device_id } => {
+            info!("IPC: Setting speaker output device to: {}", device_id);
+            let mut selection = state.output_selection.lock().unwrap();
+            selection.a = device_id.clone();
+            selection.b_active = false;
+            *state.speaker_controls.requested_format.write().unwrap() = None;
+            *output_device_id.write().unwrap() = device_id;
+            IpcResponse::success("Output device updated")
+        }
+        IpcCommand::GetStatus => {
+            let current_output = output_device_id.read().unwrap().clone();
+            let is_running = running.load(Ordering::SeqCst);
+
+            let mut response = if let (Some(mic_id), Some(mic_en)) = (mic_input_id, mic_enabled) {
+                let mic_input = mic_id.read().unwrap().clone();
+                let mic_output = mic_output_id.map(|id| id.read().unwrap().clone());
+                let mic_is_enabled = mic_en.load(Ordering::SeqCst);
+                IpcResponse::status_full(
+                    is_running, &current_output, mic_is_enabled, Some(&mic_input), mic_output.as_deref(),
+                )
+            } else {
+                IpcResponse::status(is_running, &current_output)
+            };
+            // Before the selection lock, which it takes itself
+            let active_features = active_features(state);
+            let selection = state.output_selection.lock().unwrap();
+            response.active_output = Some(selection.active_label().to_string());
+            response.output_device_b = selection.b.clone();
+            response.ab_trim_db = match (&state.speaker_controls.ab_match, &selection.b) {
+                (Some(ab_match), Some(b)) => Some(ab_match.trim_db(b)),
+                _ => None,
+            };
+            response.paused = Some(state.paused.load(Ordering::SeqCst));
+            response.swap_lr = Some(state.speaker_controls.swap_lr.load(Ordering::Relaxed));
+            response.solo_mic = Some(state.speaker_controls.solo_mic.load(Ordering::Relaxed));
+            response.active_features = Some(active_features);
+            response.heartbeat_age_ms = Some(METRICS.heartbeat_ages());
+            let mic_converting = match (&state.mic_capture_format, &state.mic_render_format) {
+                (Some(capture), Some(render)) => converting(capture, render),
+                _ => false,
+            };
+            response.converting = Some(
+                converting(&state.speaker_capture_format, &state.speaker_render_format) || mic_converting,
+            );
+            response
+        }
+        IpcCommand::Stop => {
+            info!("IPC: Stop command received");
+            running.store(false, Ordering::SeqCst);
+            IpcResponse::success("Stopping proxy")
+        }
+        IpcCommand::Pause => {
+            if state.paused.swap(true, Ordering::SeqCst) {
+                return IpcResponse::success("Already paused");
+            }
+            info!("IPC: Pausing, releasing audio devices");
+            IpcResponse::success("Paused")
+        }
+        IpcCommand::Resume => {
+            if !state.paused.swap(false, Ordering::SeqCst) {
+                return IpcResponse::success("Not paused");
+            }
+            info!("IPC: Resuming, reopening audio devices");
+            IpcResponse::success("Resumed")
+        }
+        IpcCommand::SetMicInput { device_id } => {
+            if let Some(mic_id) = mic_input_id {
+                info!("IPC: Setting mic input device to: {}", device_id);
+                *mic_id.write().unwrap() = device_id;
+                IpcResponse::success("Mic input device updated")
+            } else {
+                IpcResponse::error("Mic proxy not configured")
+            }
+        }
+        IpcCommand::SetMicOutput { device_id } => {
+            if let Some(mic_id) = mic_output_id {
+                info!("IPC: Setting mic output device to: {}", device_id);
+                *mic_id.write().unwrap() = device_id;
+                IpcResponse::success("Mic output device updated")
+            } else {
+                IpcResponse::error("Mic proxy not configured")
+            }
+        }
+        IpcCommand::SetChannelSwap { enabled } => {
+            info!("IPC: Setting speaker left/right swap to: {}", enabled);
+            state.speaker_controls.swap_lr.store(enabled, Ordering::Relaxed);
+            IpcResponse::success(if enabled { "Left and right channels swapped" } else { "Channel swap off" })
+        }
+        IpcCommand::SoloMic { enabled } => {
+            info!("IPC: Setting mic solo to: {}", enabled);
+            state.speaker_controls.solo_mic.store(enabled, Ordering::Relaxed);
+            IpcResponse::success(if enabled { "Mic solo on, speaker output muted" } else { "Mic solo off" })
+        }
+        IpcCommand::EnableMic { enabled } => {
+            if let Some(mic_en) = mic_enabled {
+                info!("IPC: Setting mic enabled to: {}", enabled);
+                mic_en.store(enabled, Ordering::SeqCst);
+                IpcResponse::success(if enabled { "Mic proxy enabled" } else { "Mic proxy disabled" })
+            } else {
+                IpcResponse::error("Mic proxy not configured")
+            }
+        }
+        IpcCommand::SetMicSourceEnabled { source, enabled } => {
+            let Some((primary, secondary)) = &state.mic_levels else {
+                return IpcResponse::error("Mic proxy not configured");
+            };
+            let level = match source {
+                MicSource::Primary => primary,
+                MicSource::Secondary => match secondary {
+                    Some(level) => level,
+                    None => return IpcResponse::error("No second mic configured (--mic-in2)"),
+                },
+            };
+            info!("IPC: Setting {:?} mic enabled to: {}", source, enabled);
+            level.set_enabled(enabled);
+            IpcResponse::success(if enabled { "Mic source enabled" } else { "Mic source disabled" })
+        }
+        IpcCommand::GetLevels { mode, attack_ms, release_ms, hold_ms, decay_db_per_sec } => {
+            let settings = [attack_ms, release_ms, hold_ms, decay_db_per_sec];
+            if mode.is_some() || settings.iter().any(Option::is_some) {
+                return IpcResponse::error("GetLevels doesn't set the meter; send the mode and times with SetMeter");
+            }
+            let mode = state.meter.get().mode;
+            IpcResponse::levels(
+                METRICS.speaker.meter.read(mode),
+                state.mic_enabled.is_some().then(|| METRICS.mic.meter.read(mode)),
+            )
+        }
+        IpcCommand::SetMeter { mode, attack_ms, release_ms, hold_ms, decay_db_per_sec } => {
+            let settings = MeterSettings { mode, attack_ms, release_ms, hold_ms, decay_db_per_sec };
+            if let Err(e) = state.meter.set(settings) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting level meter to {:?}", mode);
+            IpcResponse::success("Meter updated")
+        }
+        IpcCommand::GetFormats => {
+            let read_format = |slot: &Arc<RwLock<Option<AudioFormat>>>| slot.read().unwrap().clone();
+            IpcResponse::formats(
+                read_format(&state.speaker_capture_format),
+                read_format(&state.speaker_render_format),
+                state.mic_capture_format.as_ref().and_then(read_format),
+                state.mic_render_format.as_ref().and_then(read_format),
+            )
+        }
+        IpcCommand::SetOutputB { device_id } => {
+            info!("IPC: Setting speaker output B to: {}", device_id);
+            let mut selection = state.output_selection.lock().unwrap();
+            selection.b = Some(device_id);
+            if selection.b_active {
+                *output_device_id.write().unwrap() = selection.active_id().to_string();
+            }
+            IpcResponse::success("Output B updated")
+        }
+        IpcCommand::ToggleOutput => {
+            let mut selection = state.output_selection.lock().unwrap();
+            if selection.b.is_none() {
+                return IpcResponse::error("No output B set (use SetOutputB first)");
+            }
+            selection.b_active = !selection.b_active;
+            info!("IPC: Toggling speaker output to {}: {}",
+                  selection.active_label().to_uppercase(), selection.active_id());
+            // Before the switch, so the render loop opens B with its new trim
+            let ab_trim_db = state.speaker_controls.ab_match.as_ref().map(|ab_match| rematch_ab(ab_match, &selection));
+            *output_device_id.write().unwrap() = selection.active_id().to_string();
+            let mut response = IpcResponse::success(
+                if selection.b_active { "Switched to output B" } else { "Switched to output A" },
+            );
+            response.active_output = Some(selection.active_label().to_string());
+            response.ab_trim_db = ab_trim_db;
+            response
+        }
+        IpcCommand::SetEq { bands } => {
+            if let Some(e) = bands.iter().find_map(|b| b.validate().err()) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting speaker EQ ({} bands)", bands.len());
+            let message = if bands.is_empty() {
+                "EQ disabled".to_string()
+            } else {
+                format!("EQ set ({} bands)", bands.len())
+            };
+            state.speaker_controls.eq.set(bands);
+            IpcResponse::success(&message)
+        }
+        IpcCommand::SetOutputWithFormat { device_id, sample_rate, channels } => {
+            let requested = RequestedFormat { sample_rate, channels };
+            if let Err(e) = requested.validate() {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting speaker output device to: {} ({})", device_id, requested);
+            {
+                let mut selection = state.output_selection.lock().unwrap();
+                selection.a = device_id.clone();
+                selection.b_active = false;
+                *state.speaker_controls.requested_format.write().unwrap() = Some(requested);
+                *output_device_id.write().unwrap() = device_id.clone();
+            }
+
+            if state.paused.load(Ordering::SeqCst) {
+                // Nothing is opened until `Resume`, so there's no format to report yet
+                return IpcResponse::success("Output device updated, applied on resume");
+            }
+            let target = OutputTarget { device_id, format: Some(requested) };
+            if !wait_for_output(&state.speaker_controls.opened, &target) {
+                return IpcResponse::error("Timed out waiting for the speaker output to switch");
+            }
+
+            let format = state.speaker_render_format.read().unwrap().clone();
+            let applied = format.as_ref().is_some_and(|f| requested.matches(f));
+            let message = match (&format, applied) {
+                (Some(f), true) => format!("Output device updated ({})", f),
+                (Some(f), false) => format!("Requested format not supported, using mix format ({})", f),
+                (None, _) => "Output device updated".to_string(),
+            };
+            let mut response = IpcResponse::success(&message);
+            response.speaker_render_format = format;
+            response.requested_format_applied = Some(applied);
+            response
+        }
+        IpcCommand::GetRecentErrors => IpcResponse::recent_errors(RECENT_ERRORS.snapshot()),
+        IpcCommand::GetMetrics => IpcResponse::metrics(METRICS.snapshot()),
+        IpcCommand::GetAndResetMetrics => IpcResponse::metrics(METRICS.take_snapshot()),
+        IpcCommand::SetRecoveryPolicy { max_attempts, backoff_ms, max_backoff_ms } => {
+            if max_attempts == 0 {
+                return IpcResponse::error("max_attempts must be at least 1");
+            }
+            let current = state.recovery.get();
+            let policy = RecoveryPolicy {
+                max_attempts,
+                initial_backoff: Duration::from_millis(backoff_ms),
+                max_backoff: max_backoff_ms.map(Duration::from_millis).unwrap_or(current.max_backoff),
+            };
+            info!("IPC: Setting recovery policy: {} attempts, {} ms backoff (max {} ms)",
+                  policy.max_attempts, policy.initial_backoff.as_millis(), policy.max_backoff.as_millis());
+            state.recovery.set(policy);
+            IpcResponse::recovery_policy(&policy)
+        }
+        IpcCommand::SetSilenceThreshold { db } => {
+            if let Err(e) = state.silence.set_db(db) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting silence threshold to {} dBFS", db);
+            IpcResponse::success("Silence threshold updated")
+        }
+        IpcCommand::SetDelay { target, ms } => {
+            let delay = match target {
+                DelayTarget::Speaker => &state.speaker_delay,
+                DelayTarget::Mic if mic_enabled.is_none() => return IpcResponse::error("Mic proxy not configured"),
+                DelayTarget::Mic => &state.mic_delay,
+            };
+            if let Err(e) = delay.set_ms(ms) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting {:?} delay to {} ms", target, ms);
+            IpcResponse::success("Delay updated")
+        }
+        IpcCommand::SetProcessingChain { path, stages } => {
+            let chain = match path {
+                RenderPath::Speaker => &state.speaker_chain,
+                RenderPath::Mic if mic_enabled.is_none() => return IpcResponse::error("Mic proxy not configured"),
+                RenderPath::Mic => &state.mic_chain,
+            };
+            match chain.set(&stages) {
+                Ok(stages) => {
+                    info!("IPC: Setting {:?} processing chain to {}", path, chain_names(&stages));
+                    IpcResponse::processing_chain(path, stages)
+                }
+                Err(e) => IpcResponse::error(&e.to_string()),
+            }
+        }
+        IpcCommand::GetEndpointVolume => {
+            let device_id = output_device_id.read().unwrap().clone();
+            match get_endpoint_volume(&device_id) {
+                Ok(percent) => IpcResponse::endpoint_volume(percent),
+                Err(e) => IpcResponse::error(&e.to_string()),
+            }
+        }
+        IpcCommand::SetEndpointVolume { percent } => {
+            if !(0.0..=100.0).contains(&percent) {
+                return IpcResponse::error("percent must be between 0 and 100");
+            }
+            let device_id = output_device_id.read().unwrap().clone();
+            info!("IPC: Setting endpoint volume of {} to {}%", device_id, percent);
+            match set_endpoint_volume(&device_id, percent) {
+                Ok(()) => IpcResponse::endpoint_volume(percent),
+                Err(e) => IpcResponse::error(&e.to_string()),
+            }
+        }
+        IpcCommand::SetMonitor { device_id, level_db } => {
+            let target = MonitorTarget { device_id, level_db };
+            if let Err(e) = state.speaker_controls.monitor.set(target.clone()) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Setting monitor output to: {} ({:+.1} dB)", target.device_id, target.level_db);
+            IpcResponse::success("Monitor output updated")
+        }
+        IpcCommand::ClearMonitor => {
+            if !state.speaker_controls.monitor.clear() {
+                return IpcResponse::success("No monitor output set");
+            }
+            info!("IPC: Clearing monitor output");
+            IpcResponse::success("Monitor output cleared")
+        }
+        IpcCommand::ListDevices { direction } => {
+            match list_endpoints(direction) {
+                Ok(devices) => IpcResponse::devices(devices),
+                Err(e) => IpcResponse::error(&e.to_string()),
+            }
+        }
+        IpcCommand::GetSupportedFormats { device_id, direction } => {
+            match probe_supported_formats(&device_id, direction) {
+                Ok(formats) => IpcResponse::supported_formats(formats),
+                Err(e) => IpcResponse::error(&e.to_string()),
+            }
+        }
+        IpcCommand::PrepareForStall { ms } => {
+            if let Err(e) = state.speaker_controls.stall.prepare(ms) {
+                return IpcResponse::error(&e.to_string());
+            }
+            info!("IPC: Holding {} ms of speaker audio in reserve for a stall", ms);
+            IpcResponse::stall_target(state.prefill_ms + ms)
+        }
+        IpcCommand::GetSpectrum { fft_size } => {
+            if let Err(e) = spectrum::validate_fft_size(fft_size) {
+                return IpcResponse::error(&e.to_string());
+            }
+            let Some(format) = state.speaker_capture_format.read().unwrap().clone() else {
+                return IpcResponse::error("Speaker capture is not running");
+            };
+            // The FFT runs here on the IPC thread, on a copy of the latest captured audio
+            let channels = format.channels as usize;
+            let mut samples = vec![0.0; fft_size * channels];
+            let read = state.speaker_controls.monitor.tap().reader_with_history(samples.len()).read(&mut samples);
+            if read < samples.len() {
+                return IpcResponse::error("Not enough captured audio yet");
+            }
+            IpcResponse::spectrum(spectrum::compute(&samples, channels, format.sample_rate))
+        }
+        IpcCommand::Diagnostics => IpcResponse::diagnostics(diagnostics(state)),
+        IpcCommand::InjectSilence { ms } => {
+            if !state.debug_commands {
+                return IpcResponse::error("InjectSilence needs --debug-commands");
+            }
+            if let Err(e) = state.injected_stall.inject(ms, Instant::now()) {
+                return IpcResponse::error(&e.to_string());
+            }
+            warn!("IPC: Dropping the next {} ms of speaker capture to fake a stall", ms);
+            IpcResponse::success(&format!("Injecting {} ms of silence into the speaker path", ms))
+        }
+    }
+}
+
+/// Whether a path's negotiated formats make it resample or remix
+fn converting(capture: &RwLock<Option<AudioFormat>>, render: &RwLock<Option<AudioFormat>>) -> bool {
+    match (&*capture.read().unwrap(), &*render.read().unwrap()) {
+        (Some(cf), Some(rf)) => formats_need_conversion(cf, rf),
+        _ => false,
+    }
+}
+
+/// Processing stages that currently change the audio, with their key parameters, so a
+/// UI can show what's engaged. Stages at their neutral setting aren't listed.
+fn active_features(state: &IpcState) -> Vec<String> {
+    let controls = &state.speaker_controls;
+    let mut features = Vec::new();
+    let level = |name: &str, level: &SourceLevel| match level.target_db() {
+        db if db == f32::NEG_INFINITY => Some(format!("{} (off)", name)),
+        db if db != 0.0 => Some(format!("{} ({:+.1} dB)", name, db)),
+        _ => None,
+    };
+
+    if let Some(ref secondary) = controls.secondary {
+        features.extend(level("speaker_in2", &secondary.level));
+    }
+    // A stage left out of its chain doesn't run, whatever its settings
+    let speaker_chain = state.speaker_chain.stages();
+    let runs = |stage| speaker_chain.contains(&stage);
+    if speaker_chain != RenderPath::Speaker.stages() {
+        features.push(format!("speaker_chain ({})", chain_names(&speaker_chain)));
+    }
+    let bands = controls.eq.bands().len();
+    if bands > 0 && runs(Stage::Eq) {
+        features.push(format!("eq ({} band{})", bands, if bands == 1 { "" } else { "s" }));
+    }
+    if state.speaker_delay.ms() > 0 && runs(Stage::Delay) {
+        features.push(format!("speaker_delay ({} ms)", state.speaker_delay.ms()));
+    }
+    if controls.swap_lr.load(Ordering::Relaxed) && runs(Stage::SwapLr) {
+        features.push("swap_lr".to_string());
+    }
+    if controls.solo_mic.load(Ordering::Relaxed) && runs(Stage::Solo) {
+        features.push("solo_mic".to_string());
+    }
+    if let (Some(ref ab_match), true) = (&controls.ab_match, runs(Stage::AbTrim)) {
+        let selection = state.output_selection.lock().unwrap();
+        if let (Some(b), true) = (&selection.b, selection.b_active) {
+            features.push(format!("ab_loudness_match ({:+.1} dB)", ab_match.trim_db(b)));
+        }
+    }
+    if let Some(target) = controls.monitor.target() {
+        features.push(format!("monitor ({})", target.device_id));
+    }
+
+    // The mic stages only count while the mic proxy runs
+    if !state.mic_enabled.as_ref().is_some_and(|enabled| enabled.load(Ordering::SeqCst)) {
+        return features;
+    }
+    if let Some((mic, mic2)) = &state.mic_levels {
+        features.extend(level("mic_in", mic));
+        if let Some(mic2) = mic2 {
+            features.extend(level("mic_in2", mic2));
+        }
+    }
+    let mic_chain = state.mic_chain.stages();
+    if mic_chain != RenderPath::Mic.stages() {
+        features.push(format!("mic_chain ({})", chain_names(&mic_chain)));
+    }
+    if state.mic_delay.ms() > 0 && mic_chain.contains(&Stage::Delay) {
+        features.push(format!("mic_delay ({} ms)", state.mic_delay.ms()));
+    }
+    features
+}
+
+/// Snapshot of everything in `state` and the process-wide metrics for `Diagnostics`
+fn diagnostics(state: &IpcState) -> Diagnostics {
+    let speaker = PathDiagnostics {
+        input_device: state.speaker_in.clone(),
+        output_device: state.output_device_id.read().unwrap().clone(),
+        enabled: None,
+        capture_format: state.speaker_capture_format.read().unwrap().clone(),
+        render_format: state.speaker_render_format.read().unwrap().clone(),
+        converting: converting(&state.speaker_capture_format, &state.speaker_render_format),
+        delay_ms: state.speaker_delay.ms(),
+    };
+    let mic = match (
+        &state.mic_input_id, &state.mic_output_id, &state.mic_enabled, &state.mic_capture_format, &state.mic_render_format,
+    ) {
+        (Some(input), Some(output), Some(enabled), Some(capture), Some(render)) => Some(PathDiagnostics {
+            input_device: input.read().unwrap().clone(),
+            output_device: output.read().unwrap().clone(),
+            enabled: Some(enabled.load(Ordering::SeqCst)),
+            capture_format: capture.read().unwrap().clone(),
+            render_format: render.read().unwrap().clone(),
+            converting: converting(capture, render),
+            delay_ms: state.mic_delay.ms(),
+        }),
+        _ => None,
+    };
+    let active_features = active_features(state);
+    let selection = state.output_selection.lock().unwrap();
+    let settings = SettingsDiagnostics {
+        prefill_ms: state.prefill_ms,
+        active_output: selection.active_label().to_string(),
+        output_device_b: selection.b.clone(),
+        monitor_device: state.speaker_controls.monitor.target().map(|target| target.device_id),
+        eq_bands: state.speaker_controls.eq.bands(),
+        silence_threshold_db: state.silence.db(),
+        swap_lr: state.speaker_controls.swap_lr.load(Ordering::Relaxed),
+        solo_mic: state.speaker_controls.solo_mic.load(Ordering::Relaxed),
+        active_features,
+        recovery_policy: (&state.recovery.get()).into(),
+        meter: state.meter.get(),
+    };
+
+    Diagnostics {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: diagnostics::command_line(),
+        running: state.running.load(Ordering::SeqCst),
+        paused: state.paused.load(Ordering::SeqCst),
+        speaker,
+        mic,
+        settings,
+        metrics: METRICS.snapshot(),
+        heartbeat_age_ms: METRICS.heartbeat_ages(),
+        recent_errors: RECENT_ERRORS.snapshot(),
+    }
+}
+
+/// Update output B's loudness trim from what was measured so far; returns the trim
+/// B plays with
+fn rematch_ab(ab_match: &SharedAbMatch, selection: &OutputSelection) -> f32 {
+    let Some(ref b) = selection.b else {
+        return 0.0;
+    };
+    // A volume that can't be read (e.g. an ASIO output) counts as unity
+    let volume_db = |device_id: &str| get_endpoint_volume_db(device_id).unwrap_or(0.0);
+    match ab_match.rematch(&selection.a, volume_db(&selection.a), b, volume_db(b)) {
+        Some(trim_db) => {
+            info!("A/B loudness match: output B trimmed by {:+.1} dB", trim_db);
+            trim_db
+        }
+        None => ab_match.trim_db(b),
+    }
+}
+
+/// Wait until the speaker render loop has opened `target`, giving up after `OUTPUT_SWITCH_TIMEOUT`
+fn wait_for_output(opened: &RwLock<OutputTarget>, target: &OutputTarget) -> bool {
+    let deadline = Instant::now() + OUTPUT_SWITCH_TIMEOUT;
+    while *opened.read().unwrap() != *target {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_sets_config() {
+        let config = Proxy::builder()
+            .speaker("cable", "speakers")
+            .mic("headset", "cable-in")
+            .buffer_ms(20)
+            .ipc_pipe(Some("test"))
+            .config();
+        assert_eq!((config.speaker_in.as_str(), config.speaker_out.as_str()), ("cable", "speakers"));
+        assert_eq!(config.mic_in.as_deref(), Some("headset"));
+        assert_eq!(config.mic_out.as_deref(), Some("cable-in"));
+        // The prefill follows the buffer unless set after it
+        assert_eq!((config.buffer_ms, config.prefill_ms), (20, 20));
+        assert!(config.ipc_pipe);
+        assert_eq!(config.instance.as_deref(), Some("test"));
+        assert!(config.validate().is_ok());
+
+        let config = Proxy::builder().speaker("cable", "speakers").buffer_ms(20).prefill_ms(5).config();
+        assert_eq!((config.buffer_ms, config.prefill_ms), (20, 5));
+        assert!(!config.ipc_pipe);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = || ProxyConfig { speaker_in: "cable".into(), speaker_out: "speakers".into(), ..Default::default() };
+        assert!(config().validate().is_ok());
+        assert!(ProxyConfig::default().validate().is_err());
+        assert!(ProxyConfig { buffer_ms: 0, ..config() }.validate().is_err());
+        assert!(ProxyConfig { device_buffer_ms: MAX_BUFFER_MS + 1, ..config() }.validate().is_err());
+        assert!(ProxyConfig { mic_in: Some("headset".into()), ..config() }.validate().is_err());
+        assert!(ProxyConfig { ipc_tcp: Some("127.0.0.1:0".into()), ..config() }.validate().is_err());
+        assert!(ProxyConfig { force_rate: Some(96000), strict_rate: true, ..config() }.validate().is_ok());
+        assert!(ProxyConfig { force_rate: Some(1000), ..config() }.validate().is_err());
+        assert!(ProxyConfig { strict_rate: true, ..config() }.validate().is_err());
+        assert!(ProxyConfig {
+            force_rate: Some(96000), output_backend: OutputBackend::Asio, ..config()
+        }.validate().is_err());
+    }
+
+    /// Render backend that keeps what's written to it
+    struct FakeRender {
+        format: Option<AudioFormat>,
+        written: Vec<f32>,
+    }
+
+    impl FakeRender {
+        fn new(format: AudioFormat) -> Self {
+            Self { format: Some(format), written: Vec::new() }
+        }
+    }
+
+    impl RenderBackend for FakeRender {
+        fn start(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn stop(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn format(&self) -> Option<&AudioFormat> {
+            self.format.as_ref()
+        }
+        fn buffered_frames(&self) -> Result<u32> {
+            Ok(0)
+        }
+        fn write(&mut self, samples: &[f32]) -> Result<usize> {
+            self.written.extend_from_slice(samples);
+            Ok(samples.len())
+        }
+    }
+
+    fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+        AudioFormat { sample_rate, channels, bits_per_sample: 32, block_align: channels as u32 * 4 }
+    }
+
+    /// `frames` of a 1 kHz sine at `rate`, from frame `start`, on every channel
+    fn sine(rate: u32, channels: usize, start: usize, frames: usize) -> Vec<f32> {
+        (start..start + frames)
+            .flat_map(|n| {
+                let s = (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / rate as f32).sin() * 0.5;
+                std::iter::repeat_n(s, channels)
+            })
+            .collect()
+    }
+
+    /// One block through the speaker render loop's conversion step into `render`, as
+    /// the loop writes it once the processing stages are done
+    struct SpeakerBlocks {
+        conversion: SpeakerConversion,
+        buffers: LoopBuffers,
+        stall: StallReserve,
+        capture_format: RwLock<Option<AudioFormat>>,
+    }
+
+    impl SpeakerBlocks {
+        fn new(settings: ConversionSettings, capture: AudioFormat) -> Self {
+            Self {
+                conversion: SpeakerConversion::new(settings, false),
+                buffers: LoopBuffers::new(settings),
+                stall: StallReserve::new(SharedStall::default(), DEFAULT_DEVICE_BUFFER_MS),
+                capture_format: RwLock::new(Some(capture)),
+            }
+        }
+
+        fn play(&mut self, block: &[f32], render: &mut FakeRender) -> Result<usize> {
+            self.buffers.capture[..block.len()].copy_from_slice(block);
+            let converted = self.conversion.block(
+                &mut self.buffers, block.len(), &self.capture_format, &*render, &mut self.stall,
+            )?;
+            match converted {
+                SpeakerBlock::Ready { samples, .. } => render.write(samples),
+                SpeakerBlock::Unformatted(samples) => render.write(samples),
+                SpeakerBlock::Dropped => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_capture_format_change_mid_stream() {
+        let mut render = FakeRender::new(format(48000, 2));
+        let mut blocks = SpeakerBlocks::new(ConversionSettings::default(), format(48000, 2));
+
+        // Same format: played as is
+        let input = sine(48000, 2, 0, 480);
+        blocks.play(&input, &mut render).unwrap();
+        assert_eq!(render.written, input);
+
+        // The capture thread hot-swaps to a 44.1 kHz mono device: resampled and
+        // upmixed from the next block on
+        *blocks.capture_format.write().unwrap() = Some(format(44100, 1));
+        render.written.clear();
+        for block in 0..20 {
+            blocks.play(&sine(44100, 1, block * 441, 441), &mut render).unwrap();
+        }
+        assert_eq!(render.written.len(), 20 * 480 * 2);
+        assert!(render.written.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+        // Still 1 kHz at the output rate: two zero crossings per millisecond, where
+        // 44.1 kHz audio written unconverted would have about 9% more
+        let left: Vec<f32> = render.written.iter().step_by(2).skip(200).copied().collect();
+        let crossings = left.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        let expected = 2.0 * 1000.0 * left.len() as f32 / 48000.0;
+        assert!((crossings as f32 - expected).abs() <= 2.0, "{} crossings, expected {}", crossings, expected);
+
+        // And again to 96 kHz stereo: converted at the new rate
+        *blocks.capture_format.write().unwrap() = Some(format(96000, 2));
+        render.written.clear();
+        blocks.play(&sine(96000, 2, 0, 960), &mut render).unwrap();
+        assert_eq!(render.written.len(), 480 * 2);
+
+        // Back to the output's format: played as is again
+        *blocks.capture_format.write().unwrap() = Some(format(48000, 2));
+        render.written.clear();
+        let input = sine(48000, 2, 480, 480);
+        blocks.play(&input, &mut render).unwrap();
+        assert_eq!(render.written, input);
+    }
+
+    #[test]
+    fn test_channel_map_survives_output_switch() {
+        let settings = ConversionSettings {
+            mix: ChannelMix { map: Some(ChannelMap::parse("0:2,1:3").unwrap()), ..Default::default() },
+            ..Default::default()
+        };
+        let input = sine(48000, 2, 0, 480);
+
+        let mut render = FakeRender::new(format(48000, 6));
+        let mut blocks = SpeakerBlocks::new(settings, format(48000, 2));
+        blocks.play(&input, &mut render).unwrap();
+        assert!(render.written.chunks_exact(6).zip(input.chunks_exact(2)).all(|(out, inp)| out[2..4] == *inp));
+
+        // Switched to a stereo output: the map doesn't fit, so the audio plays through
+        // the default mix instead
+        render = FakeRender::new(format(48000, 2));
+        assert_eq!(blocks.play(&input, &mut render).unwrap(), 480 * 2);
+        assert_eq!(render.written, input);
+
+        // And a mono one: downmixed rather than silent
+        render = FakeRender::new(format(48000, 1));
+        assert_eq!(blocks.play(&input, &mut render).unwrap(), 480);
+        assert!(render.written.iter().any(|&s| s != 0.0));
+
+        // Back on six channels, the map applies again
+        render = FakeRender::new(format(48000, 6));
+        blocks.play(&input, &mut render).unwrap();
+        assert!(render.written.chunks_exact(6).zip(input.chunks_exact(2)).all(|(out, inp)| out[2..4] == *inp));
+
+        // Starting out on streams the map doesn't fit is an error
+        let mut blocks = SpeakerBlocks::new(settings, format(48000, 2));
+        assert!(blocks.play(&input, &mut render).is_err());
+    }
+}
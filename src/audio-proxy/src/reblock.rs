@@ -0,0 +1,135 @@
+//! Fixed-size processing blocks from variable capture reads
+//!
+//! WASAPI hands the capture loops however many frames it has, which varies from
+//! read to read. With `--process-block-frames` the loops pass their reads through a
+//! `Reblocker`, which only lets whole blocks of that size through to the ring buffer
+//! and keeps the remainder for the next read. Block-based stages further down then
+//! see a fixed granularity regardless of the device. The default of 0 passes every
+//! read through unchanged.
+
+use anyhow::Result;
+
+/// Largest block size `--process-block-frames` accepts
+pub const MAX_PROCESS_BLOCK_FRAMES: usize = 8192;
+
+/// Check that a block size is usable (0 turns re-blocking off)
+pub fn validate_block_frames(frames: usize) -> Result<()> {
+    if frames > MAX_PROCESS_BLOCK_FRAMES {
+        anyhow::bail!("--process-block-frames must be at most {}: {}", MAX_PROCESS_BLOCK_FRAMES, frames);
+    }
+    Ok(())
+}
+
+/// Accumulates interleaved reads into blocks of `block_frames` frames
+pub struct Reblocker {
+    block_frames: usize,
+    /// Partial block left over from earlier reads
+    staging: Vec<f32>,
+    /// Channel count `staging` holds frames of
+    channels: usize,
+}
+
+impl Reblocker {
+    pub fn new(block_frames: usize) -> Self {
+        Self {
+            block_frames,
+            staging: Vec::with_capacity(block_frames * 8),
+            channels: 0,
+        }
+    }
+
+    /// Pass `samples` on to `write` in whole blocks, keeping any remainder for the next
+    /// call. `write` returns how many samples it accepted; the totals offered and
+    /// accepted are returned, so overflows can be reported as before.
+    pub fn push(&mut self, samples: &[f32], channels: usize, mut write: impl FnMut(&[f32]) -> usize) -> (usize, usize) {
+        if self.block_frames == 0 || channels == 0 {
+            return (samples.len(), write(samples));
+        }
+        if channels != self.channels {
+            // Frames of another format can't be completed with these
+            self.staging.clear();
+            self.channels = channels;
+        }
+
+        let block_len = self.block_frames * channels;
+        let (mut offered, mut accepted) = (0, 0);
+        let mut rest = samples;
+
+        if !self.staging.is_empty() {
+            let take = (block_len - self.staging.len()).min(rest.len());
+            self.staging.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.staging.len() < block_len {
+                return (0, 0);
+            }
+            offered += block_len;
+            accepted += write(&self.staging);
+            self.staging.clear();
+        }
+
+        let mut blocks = rest.chunks_exact(block_len);
+        for block in &mut blocks {
+            offered += block_len;
+            accepted += write(block);
+        }
+        self.staging.extend_from_slice(blocks.remainder());
+        (offered, accepted)
+    }
+
+    /// Drop a partial block, e.g. after the capture stream was reopened
+    pub fn reset(&mut self) {
+        self.staging.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(reblocker: &mut Reblocker, samples: &[f32], channels: usize, out: &mut Vec<Vec<f32>>) -> (usize, usize) {
+        reblocker.push(samples, channels, |block| {
+            out.push(block.to_vec());
+            block.len()
+        })
+    }
+
+    #[test]
+    fn test_passthrough_by_default() {
+        let mut reblocker = Reblocker::new(0);
+        let mut out = Vec::new();
+        assert_eq!(collect(&mut reblocker, &[1.0, 2.0, 3.0], 1, &mut out), (3, 3));
+        assert_eq!(out, vec![vec![1.0, 2.0, 3.0]]);
+    }
+
+    #[test]
+    fn test_variable_reads_become_fixed_blocks() {
+        // 2 frames of stereo per block
+        let mut reblocker = Reblocker::new(2);
+        let mut out = Vec::new();
+        let samples: Vec<f32> = (0..14).map(|i| i as f32).collect();
+
+        assert_eq!(collect(&mut reblocker, &samples[..2], 2, &mut out), (0, 0));
+        assert_eq!(collect(&mut reblocker, &samples[2..12], 2, &mut out), (12, 12));
+        // The last frame waits for the next read
+        assert_eq!(collect(&mut reblocker, &samples[12..], 2, &mut out), (0, 0));
+
+        assert!(out.iter().all(|block| block.len() == 4));
+        assert_eq!(out.concat(), samples[..12].to_vec());
+    }
+
+    #[test]
+    fn test_accepted_counts_partial_writes() {
+        let mut reblocker = Reblocker::new(4);
+        let (offered, accepted) = reblocker.push(&[0.5; 8], 1, |block| block.len() / 2);
+        assert_eq!((offered, accepted), (8, 4));
+    }
+
+    #[test]
+    fn test_format_change_drops_partial_block() {
+        let mut reblocker = Reblocker::new(2);
+        let mut out = Vec::new();
+        collect(&mut reblocker, &[1.0, 1.0], 2, &mut out);
+        collect(&mut reblocker, &[2.0, 2.0], 1, &mut out);
+        assert_eq!(out, vec![vec![2.0, 2.0]]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Bounded history of recent warnings and errors for bug reports
+//!
+//! The logger's format hook records every warn/error line here, so the audio loops
+//! don't need a handle of their own and the history survives even when nobody is
+//! capturing the log output. `GetRecentErrors` returns a snapshot over IPC.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// Number of messages kept before the oldest are dropped
+pub const MAX_RECENT_ERRORS: usize = 32;
+
+/// Process-wide history fed by the logger
+pub static RECENT_ERRORS: RecentErrors = RecentErrors::new(MAX_RECENT_ERRORS);
+
+/// One recorded warning or error
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorEntry {
+    /// Log timestamp (RFC 3339, UTC)
+    pub timestamp: String,
+    pub level: String,
+    /// Name of the thread that logged it, e.g. "speaker-render"
+    pub thread: String,
+    pub message: String,
+}
+
+/// Ring of the most recent entries. Warnings and errors are rare, so a mutex is
+/// cheap enough here, even when logged from an audio thread.
+pub struct RecentErrors {
+    entries: Mutex<VecDeque<ErrorEntry>>,
+    capacity: usize,
+}
+
+impl RecentErrors {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    /// Record an entry, dropping the oldest one if full
+    pub fn push(&self, entry: ErrorEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Copy of the recorded entries, oldest first
+    pub fn snapshot(&self) -> Vec<ErrorEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message: &str) -> ErrorEntry {
+        ErrorEntry {
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            level: "ERROR".to_string(),
+            thread: "main".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_keeps_newest_entries() {
+        let errors = RecentErrors::new(3);
+        for i in 0..5 {
+            errors.push(entry(&format!("error {}", i)));
+        }
+
+        let messages: Vec<String> = errors.snapshot().into_iter().map(|e| e.message).collect();
+        assert_eq!(messages, vec!["error 2", "error 3", "error 4"]);
+    }
+
+    #[test]
+    fn test_empty_snapshot() {
+        assert!(RecentErrors::new(4).snapshot().is_empty());
+    }
+}
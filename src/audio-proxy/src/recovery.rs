@@ -0,0 +1,244 @@
+//! Retry limits and exponential backoff for stream recovery
+//!
+//! After the system wakes from sleep, every stream fails at once. Left alone, the
+//! four loops would all reopen their devices at the same moment, and keep tripping
+//! over each other for seconds. `RESUME_GATE` gives each loop its own turn instead.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Time the audio devices get to come back after a resume before the first reopen
+pub const RESUME_SETTLE: Duration = Duration::from_millis(1000);
+
+/// Spacing between the reopen turns of the loops after a resume
+pub const RESUME_STAGGER: Duration = Duration::from_millis(250);
+
+/// How long after a resume failures still count as caused by it
+pub const RESUME_WINDOW: Duration = Duration::from_secs(10);
+
+/// Set when the system resumes from sleep (see `power`)
+pub static RESUME_GATE: ResumeGate = ResumeGate::new();
+
+/// How persistently the audio loops try to reopen a failed stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// Consecutive failures before a loop gives up
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound the delay doubles up to
+    pub max_backoff: Duration,
+}
+
+/// Recovery policy shared by all loops, so it can be changed at runtime over IPC
+#[derive(Debug, Clone)]
+pub struct SharedRecoveryPolicy(Arc<RwLock<RecoveryPolicy>>);
+
+impl SharedRecoveryPolicy {
+    pub fn new(policy: RecoveryPolicy) -> Self {
+        Self(Arc::new(RwLock::new(policy)))
+    }
+
+    pub fn get(&self) -> RecoveryPolicy {
+        *self.0.read().unwrap()
+    }
+
+    pub fn set(&self, policy: RecoveryPolicy) {
+        *self.0.write().unwrap() = policy;
+    }
+}
+
+/// Per-loop recovery state: counts consecutive failures and hands out the delay
+/// before each retry, doubling from `initial_backoff` up to `max_backoff`. The
+/// policy is read on every check, so changes apply from the next recovery attempt.
+pub struct Backoff {
+    policy: SharedRecoveryPolicy,
+    failures: u32,
+    /// Set by `hold`: the stream can't be opened yet but will be eventually
+    holding: bool,
+}
+
+impl Backoff {
+    pub fn new(policy: &SharedRecoveryPolicy) -> Self {
+        Self { policy: policy.clone(), failures: 0, holding: false }
+    }
+
+    /// Record a failure and return the consecutive failure count
+    pub fn record_failure(&mut self) -> u32 {
+        self.failures = self.failures.saturating_add(1);
+        self.failures
+    }
+
+    /// Whether the loop has used up its recovery attempts
+    pub fn exhausted(&self) -> bool {
+        !self.holding && self.failures >= self.policy.get().max_attempts
+    }
+
+    /// Delay to wait before retrying after the latest failure
+    pub fn delay(&self) -> Duration {
+        let policy = self.policy.get();
+        if self.holding {
+            return policy.max_backoff;
+        }
+        let doublings = self.failures.saturating_sub(1).min(31);
+        policy.initial_backoff
+            .saturating_mul(1u32 << doublings)
+            .min(policy.max_backoff)
+    }
+
+    /// Forget past failures after a successful read/write
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.holding = false;
+    }
+
+    /// Keep retrying at `max_backoff` without ever giving up, for a device that is
+    /// busy rather than broken (held in exclusive mode by another application).
+    /// Lasts until `reset`.
+    pub fn hold(&mut self) {
+        self.holding = true;
+    }
+}
+
+/// Staggers the stream recoveries that follow a resume from sleep
+pub struct ResumeGate(Mutex<Option<Resume>>);
+
+struct Resume {
+    at: Instant,
+    /// Turns handed out so far
+    turns: u32,
+}
+
+impl ResumeGate {
+    pub const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    /// Record a resume from sleep at `now`
+    pub fn resumed(&self, now: Instant) {
+        *self.0.lock().unwrap() = Some(Resume { at: now, turns: 0 });
+    }
+
+    /// How long a loop about to reopen a failed stream at `now` should wait for its
+    /// turn, or `None` when there was no resume in the last `RESUME_WINDOW`. Turns start
+    /// `RESUME_SETTLE` after the resume and are `RESUME_STAGGER` apart.
+    pub fn turn(&self, now: Instant) -> Option<Duration> {
+        let mut resume = self.0.lock().unwrap();
+        let state = resume.as_mut()?;
+        if now.saturating_duration_since(state.at) > RESUME_WINDOW {
+            *resume = None;
+            return None;
+        }
+        let turn = state.at + RESUME_SETTLE + RESUME_STAGGER * state.turns;
+        state.turns += 1;
+        Some(turn.saturating_duration_since(now))
+    }
+}
+
+impl Default for ResumeGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RecoveryPolicy {
+        RecoveryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        }
+    }
+
+    fn shared(policy: RecoveryPolicy) -> SharedRecoveryPolicy {
+        SharedRecoveryPolicy::new(policy)
+    }
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        let mut backoff = Backoff::new(&shared(policy()));
+        let delays: Vec<u64> = (0..5)
+            .map(|_| {
+                backoff.record_failure();
+                backoff.delay().as_millis() as u64
+            })
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+        assert!(backoff.exhausted());
+    }
+
+    #[test]
+    fn test_reset_restarts_backoff() {
+        let mut backoff = Backoff::new(&shared(policy()));
+        backoff.record_failure();
+        backoff.record_failure();
+        backoff.reset();
+        assert!(!backoff.exhausted());
+        assert_eq!(backoff.record_failure(), 1);
+        assert_eq!(backoff.delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_hold_retries_at_max_backoff_until_reset() {
+        let mut backoff = Backoff::new(&shared(policy()));
+        backoff.record_failure();
+        backoff.hold();
+        for _ in 0..10 {
+            backoff.record_failure();
+        }
+        assert!(!backoff.exhausted());
+        assert_eq!(backoff.delay(), Duration::from_millis(500));
+
+        backoff.reset();
+        assert_eq!(backoff.record_failure(), 1);
+        assert_eq!(backoff.delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_resume_gate_staggers_turns() {
+        let gate = ResumeGate::new();
+        let now = Instant::now();
+        assert_eq!(gate.turn(now), None);
+
+        gate.resumed(now);
+        // Every loop failing right after the wake gets a later turn
+        let turns: Vec<Duration> = (0..4).map(|_| gate.turn(now).unwrap()).collect();
+        assert_eq!(turns, (0..4).map(|i| RESUME_SETTLE + RESUME_STAGGER * i).collect::<Vec<_>>());
+        // A turn that has already passed doesn't wait
+        assert_eq!(gate.turn(now + RESUME_SETTLE * 3), Some(Duration::ZERO));
+
+        // Failures long after the resume are ordinary again
+        assert_eq!(gate.turn(now + RESUME_WINDOW + Duration::from_millis(1)), None);
+        assert_eq!(gate.turn(now), None);
+    }
+
+    #[test]
+    fn test_large_failure_count_does_not_overflow() {
+        let mut backoff = Backoff::new(&shared(RecoveryPolicy { max_attempts: u32::MAX, ..policy() }));
+        for _ in 0..100 {
+            backoff.record_failure();
+        }
+        assert_eq!(backoff.delay(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_policy_update_applies_to_running_backoff() {
+        let shared = shared(policy());
+        let mut backoff = Backoff::new(&shared);
+        for _ in 0..5 {
+            backoff.record_failure();
+        }
+        assert!(backoff.exhausted());
+
+        shared.set(RecoveryPolicy {
+            max_attempts: 20,
+            initial_backoff: Duration::from_millis(1000),
+            max_backoff: Duration::from_millis(30_000),
+        });
+        assert!(!backoff.exhausted());
+        assert_eq!(backoff.delay(), Duration::from_millis(16_000));
+    }
+}
@@ -1,7 +1,12 @@
-//! Lock-free ring buffer for low-latency audio transfer between threads
+//! Lock-free ring buffers for low-latency audio transfer between threads
 
 use std::cell::UnsafeCell;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Fewest slots `AudioRingBuffer::new` allocates. One slot is always kept empty, so
+/// anything smaller would leave no room at all and silently drop every write.
+const MIN_CAPACITY: usize = 2;
 
 /// A lock-free single-producer single-consumer ring buffer for audio samples
 pub struct AudioRingBuffer {
@@ -13,9 +18,13 @@ pub struct AudioRingBuffer {
 
 impl AudioRingBuffer {
     /// Create a new ring buffer with the specified capacity (in samples)
+    ///
+    /// The capacity is rounded up to a power of two of at least `MIN_CAPACITY`, and
+    /// one slot of that is kept empty: `capacity()` is
+    /// `next_power_of_two(max(n, 2)) - 1`, so `new(8)` holds 7 samples.
     pub fn new(capacity: usize) -> Self {
         // Round up to power of 2 for efficient modulo operations
-        let capacity = capacity.next_power_of_two();
+        let capacity = capacity.max(MIN_CAPACITY).next_power_of_two();
 
         Self {
             buffer: UnsafeCell::new(vec![0.0f32; capacity].into_boxed_slice()),
@@ -58,6 +67,21 @@ impl AudioRingBuffer {
         to_write
     }
 
+    /// Write as many whole frames of `channels` samples as fit. A full buffer drops
+    /// whole frames rather than splitting one, which would leave every later frame
+    /// starting on the wrong channel.
+    pub fn write_frames(&self, samples: &[f32], channels: usize) -> usize {
+        let fit = samples.len().min(self.capacity() - self.len());
+        self.write(&samples[..fit - fit % channels.max(1)])
+    }
+
+    /// Read as many whole frames of `channels` samples as `samples` holds, so a read
+    /// never ends mid-frame
+    pub fn read_frames(&self, samples: &mut [f32], channels: usize) -> usize {
+        let fit = samples.len().min(self.len());
+        self.read(&mut samples[..fit - fit % channels.max(1)])
+    }
+
     /// Read samples from the buffer
     /// Returns the number of samples actually read (may be less if buffer doesn't have enough)
     pub fn read(&self, samples: &mut [f32]) -> usize {
@@ -90,8 +114,16 @@ impl AudioRingBuffer {
         to_read
     }
 
+    /// Drop up to `count` of the oldest samples without reading them (consumer side,
+    /// like `read`). Returns the number of samples skipped.
+    pub fn skip(&self, count: usize) -> usize {
+        let to_skip = count.min(self.len());
+        let read_pos = self.read_pos.load(Ordering::Acquire);
+        self.read_pos.store((read_pos + to_skip) & (self.capacity - 1), Ordering::Release);
+        to_skip
+    }
+
     /// Get the number of samples currently in the buffer
-    #[allow(dead_code)]
     pub fn len(&self) -> usize {
         let write_pos = self.write_pos.load(Ordering::Acquire);
         let read_pos = self.read_pos.load(Ordering::Acquire);
@@ -104,19 +136,16 @@ impl AudioRingBuffer {
     }
 
     /// Check if the buffer is empty
-    #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
     /// Get the capacity of the buffer
-    #[allow(dead_code)]
     pub fn capacity(&self) -> usize {
         self.capacity - 1 // One slot is always kept empty
     }
 
     /// Clear the buffer
-    #[allow(dead_code)]
     pub fn clear(&self) {
         self.read_pos.store(0, Ordering::Release);
         self.write_pos.store(0, Ordering::Release);
@@ -128,6 +157,144 @@ impl AudioRingBuffer {
 unsafe impl Send for AudioRingBuffer {}
 unsafe impl Sync for AudioRingBuffer {}
 
+/// A lock-free single-producer multi-consumer ring buffer
+///
+/// The writer never blocks: it always overwrites the oldest samples. Each consumer
+/// holds its own `BroadcastReader` cursor, and a reader that falls more than
+/// `capacity` samples behind skips ahead and loses the overwritten data without
+/// affecting the writer or any other reader.
+///
+/// Positions are absolute sample counts (never wrapped), so a reader can tell
+/// exactly how far behind it is. Samples are stored as `AtomicU32` bit patterns
+/// because a slow reader may race with the writer overwriting the same slot.
+pub struct BroadcastRingBuffer {
+    buffer: Box<[AtomicU32]>,
+    capacity: usize,
+    /// Samples fully written and visible to readers
+    write_pos: AtomicUsize,
+    /// Samples the writer has started writing (>= write_pos while a write is in progress)
+    reserve_pos: AtomicUsize,
+}
+
+impl BroadcastRingBuffer {
+    /// Create a new broadcast buffer with the specified capacity (in samples)
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        let buffer = (0..capacity).map(|_| AtomicU32::new(0)).collect();
+
+        Self {
+            buffer,
+            capacity,
+            write_pos: AtomicUsize::new(0),
+            reserve_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Write samples, overwriting the oldest data if readers haven't consumed it.
+    /// Must only be called from a single producer thread.
+    pub fn write(&self, samples: &[f32]) {
+        // Blocks larger than the buffer can only keep their tail
+        let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+        let write_pos = self.write_pos.load(Ordering::Relaxed);
+        let end = write_pos + samples.len();
+
+        // Announce the range about to be overwritten before touching the slots,
+        // so readers copying concurrently can detect torn data (seqlock-style)
+        self.reserve_pos.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        for (i, &sample) in samples.iter().enumerate() {
+            let idx = (write_pos + i) & (self.capacity - 1);
+            self.buffer[idx].store(sample.to_bits(), Ordering::Relaxed);
+        }
+
+        self.write_pos.store(end, Ordering::Release);
+    }
+
+    /// Create a reader positioned at the current write position (it only sees new data)
+    pub fn reader(self: &Arc<Self>) -> BroadcastReader {
+        BroadcastReader {
+            ring: self.clone(),
+            read_pos: self.write_pos.load(Ordering::Acquire),
+            dropped: 0,
+        }
+    }
+
+    /// Create a reader that starts `history` samples before the current write position
+    /// (at most the whole buffer), to look at the most recent audio
+    pub fn reader_with_history(self: &Arc<Self>, history: usize) -> BroadcastReader {
+        let write_pos = self.write_pos.load(Ordering::Acquire);
+        BroadcastReader {
+            ring: self.clone(),
+            read_pos: write_pos.saturating_sub(history.min(self.capacity)),
+            dropped: 0,
+        }
+    }
+
+    /// Get the capacity of the buffer
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// An independent read cursor into a `BroadcastRingBuffer`
+pub struct BroadcastReader {
+    ring: Arc<BroadcastRingBuffer>,
+    read_pos: usize,
+    dropped: u64,
+}
+
+impl BroadcastReader {
+    /// Read samples available to this reader.
+    /// Returns the number of samples read; if the reader fell behind, the oldest
+    /// unread samples are skipped and counted in `dropped`.
+    pub fn read(&mut self, samples: &mut [f32]) -> usize {
+        let ring = &self.ring;
+        let write_pos = ring.write_pos.load(Ordering::Acquire);
+
+        // Skip anything the writer has already lapped
+        let oldest = write_pos.saturating_sub(ring.capacity);
+        if self.read_pos < oldest {
+            self.dropped += (oldest - self.read_pos) as u64;
+            self.read_pos = oldest;
+        }
+
+        let to_read = samples.len().min(write_pos - self.read_pos);
+        if to_read == 0 {
+            return 0;
+        }
+
+        for (i, sample) in samples[..to_read].iter_mut().enumerate() {
+            let idx = (self.read_pos + i) & (ring.capacity - 1);
+            *sample = f32::from_bits(ring.buffer[idx].load(Ordering::Relaxed));
+        }
+
+        // If the writer started overwriting part of what we copied, discard that prefix
+        fence(Ordering::Acquire);
+        let reserve_pos = ring.reserve_pos.load(Ordering::Relaxed);
+        let valid_from = reserve_pos.saturating_sub(ring.capacity);
+        let torn = valid_from.saturating_sub(self.read_pos).min(to_read);
+        if torn > 0 {
+            samples.copy_within(torn..to_read, 0);
+            self.dropped += torn as u64;
+        }
+
+        self.read_pos += to_read;
+        to_read - torn
+    }
+
+    /// Number of samples waiting for this reader (capped at the buffer capacity)
+    pub fn available(&self) -> usize {
+        let write_pos = self.ring.write_pos.load(Ordering::Acquire);
+        (write_pos - self.read_pos).min(self.ring.capacity)
+    }
+
+    /// Total samples this reader has lost by falling behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -155,6 +322,47 @@ mod tests {
         assert!(written < samples.len());
     }
 
+    #[test]
+    fn test_skip() {
+        let buffer = AudioRingBuffer::new(8);
+        buffer.write(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(buffer.skip(3), 3);
+        let mut out = [0.0; 4];
+        assert_eq!(buffer.read(&mut out), 2);
+        assert_eq!(&out[..2], &[4.0, 5.0]);
+
+        // Wraps around, and never skips more than is there
+        buffer.write(&[6.0, 7.0, 8.0, 9.0, 10.0, 11.0]);
+        assert_eq!(buffer.skip(10), 6);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_small_capacities() {
+        // new(0) and new(1) would round up to a single slot, leaving no room at all
+        for requested in [0, 1, 2] {
+            let buffer = AudioRingBuffer::new(requested);
+            assert_eq!(buffer.capacity(), 1, "new({})", requested);
+            assert_eq!(buffer.write(&[0.5, 0.25]), 1);
+            let mut out = [0.0; 2];
+            assert_eq!(buffer.read(&mut out), 1);
+            assert_eq!(out[0], 0.5);
+        }
+        assert_eq!(AudioRingBuffer::new(3).capacity(), 3);
+    }
+
+    #[test]
+    fn test_whole_frames() {
+        let buffer = AudioRingBuffer::new(8); // Capacity is 7
+
+        // Three stereo frames fit, the fourth isn't split
+        assert_eq!(buffer.write_frames(&[1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0], 2), 6);
+        let mut output = [0.0f32; 5];
+        assert_eq!(buffer.read_frames(&mut output, 2), 4);
+        assert_eq!(&output[..4], &[1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(buffer.len(), 2);
+    }
+
     #[test]
     fn test_underflow() {
         let buffer = AudioRingBuffer::new(16);
@@ -165,4 +373,101 @@ mod tests {
         let mut output = [0.0f32; 4];
         assert_eq!(buffer.read(&mut output), 2);
     }
+
+    #[test]
+    fn test_broadcast_readers_independent() {
+        let ring = Arc::new(BroadcastRingBuffer::new(8));
+        let mut fast = ring.reader();
+        let mut slow = ring.reader();
+        let mut output = [0.0f32; 8];
+
+        // Fast reader keeps up with every block
+        for block in 0..6 {
+            let base = block as f32 * 4.0;
+            ring.write(&[base, base + 1.0, base + 2.0, base + 3.0]);
+            assert_eq!(fast.read(&mut output), 4);
+            assert_eq!(&output[..4], &[base, base + 1.0, base + 2.0, base + 3.0]);
+        }
+        assert_eq!(fast.dropped(), 0);
+
+        // Slow reader only gets the newest `capacity` samples, oldest are dropped
+        assert_eq!(slow.available(), 8);
+        assert_eq!(slow.read(&mut output), 8);
+        assert_eq!(output, [16.0, 17.0, 18.0, 19.0, 20.0, 21.0, 22.0, 23.0]);
+        assert_eq!(slow.dropped(), 16);
+        assert_eq!(slow.read(&mut output), 0);
+    }
+
+    #[test]
+    fn test_broadcast_reader_starts_at_write_pos() {
+        let ring = Arc::new(BroadcastRingBuffer::new(8));
+        ring.write(&[1.0, 2.0]);
+
+        let mut reader = ring.reader();
+        let mut output = [0.0f32; 4];
+        assert_eq!(reader.read(&mut output), 0);
+
+        ring.write(&[3.0]);
+        assert_eq!(reader.read(&mut output), 1);
+        assert_eq!(output[0], 3.0);
+    }
+
+    #[test]
+    fn test_broadcast_reader_with_history() {
+        let ring = Arc::new(BroadcastRingBuffer::new(4));
+        ring.write(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        let mut output = [0.0f32; 8];
+        assert_eq!(ring.reader_with_history(2).read(&mut output), 2);
+        assert_eq!(output[..2], [5.0, 6.0]);
+        // Capped at what the buffer still holds
+        assert_eq!(ring.reader_with_history(100).read(&mut output), 4);
+        assert_eq!(output[..4], [3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_broadcast_threaded_fast_reader_not_blocked() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+
+        const BLOCKS: usize = 2000;
+        let ring = Arc::new(BroadcastRingBuffer::new(1024));
+        let mut fast = ring.reader();
+        let mut slow = ring.reader();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer_ring = ring.clone();
+        let writer_done = done.clone();
+        let writer = thread::spawn(move || {
+            let mut next = 0.0f32;
+            for _ in 0..BLOCKS {
+                let block: Vec<f32> = (0..16).map(|i| next + i as f32).collect();
+                next += 16.0;
+                writer_ring.write(&block);
+                thread::yield_now();
+            }
+            writer_done.store(true, Ordering::SeqCst);
+        });
+
+        // Fast reader consumes continuously and must see a strictly increasing sequence
+        let mut expected = 0.0f32;
+        let mut output = [0.0f32; 64];
+        loop {
+            let finished = done.load(Ordering::SeqCst);
+            let n = fast.read(&mut output);
+            for &sample in &output[..n] {
+                assert!(sample >= expected);
+                expected = sample + 1.0;
+            }
+            if finished && n == 0 {
+                break;
+            }
+        }
+        writer.join().unwrap();
+
+        // The slow reader never read, so it lost everything but the last capacity
+        let n = slow.read(&mut [0.0f32; 2048]);
+        assert_eq!(n, 1024);
+        assert_eq!(slow.dropped() as usize, BLOCKS * 16 - 1024);
+    }
 }
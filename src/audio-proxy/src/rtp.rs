@@ -0,0 +1,318 @@
+//! Network streaming of the speaker capture as RTP over UDP
+//!
+//! `--rtp-out <addr>` sends a copy of the captured speaker audio to a receiver on the
+//! network, e.g. another machine playing it in a different room. A thread reads the
+//! capture tap the monitor output uses through a cursor of its own, converts the audio
+//! to a fixed 48 kHz stereo stream and sends it in RTP packets: uncompressed L16
+//! (RFC 3551, 16-bit big-endian) by default, or Opus (RFC 7587) in builds with the
+//! `rtp-opus` feature. Receivers need the SDP description logged at startup, e.g.
+//! `ffplay -protocol_whitelist file,udp,rtp stream.sdp`.
+//!
+//! Like the monitor, the stream is best effort: a reader that falls behind skips
+//! ahead, and packets that fail to send are dropped, so the speaker path never waits
+//! on the network.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::audio_stream::AudioFormat;
+use crate::convert::{convert_audio, formats_need_conversion, ConversionSettings, ConversionState};
+use crate::ring_buffer::BroadcastRingBuffer;
+
+/// Format every stream is sent in, whatever the capture runs at
+pub const RTP_FORMAT: AudioFormat = AudioFormat { sample_rate: 48000, channels: 2, bits_per_sample: 32, block_align: 8 };
+
+/// Dynamic payload type announced in the SDP
+const PAYLOAD_TYPE: u8 = 96;
+
+const HEADER_LEN: usize = 12;
+
+/// Largest Opus packet the encoder is given room for (the spec's maximum)
+#[cfg(feature = "rtp-opus")]
+const MAX_OPUS_PACKET: usize = 1275;
+
+/// How long the thread sleeps when the tap has nothing new
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How the audio is encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RtpCodec {
+    /// Uncompressed 16-bit PCM, about 1.5 Mbit/s
+    #[default]
+    L16,
+    /// Opus at the encoder's default bitrate; needs the `rtp-opus` feature
+    Opus,
+}
+
+impl RtpCodec {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "l16" => Ok(RtpCodec::L16),
+            "opus" if cfg!(feature = "rtp-opus") => Ok(RtpCodec::Opus),
+            "opus" => anyhow::bail!("Opus streaming needs a build with the `rtp-opus` feature"),
+            _ => Err(anyhow::anyhow!("Unknown RTP codec: {} (expected l16 or opus)", s)),
+        }
+    }
+
+    /// Frames sent per packet: 5 ms of L16 (960 bytes, within a 1500-byte MTU), or
+    /// the usual 20 ms Opus frame
+    fn packet_frames(self) -> usize {
+        match self {
+            RtpCodec::L16 => 240,
+            RtpCodec::Opus => 960,
+        }
+    }
+
+    /// `rtpmap` encoding of the SDP
+    fn encoding(self) -> &'static str {
+        match self {
+            RtpCodec::L16 => "L16/48000/2",
+            RtpCodec::Opus => "opus/48000/2",
+        }
+    }
+}
+
+impl std::fmt::Display for RtpCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RtpCodec::L16 => "l16",
+            RtpCodec::Opus => "opus",
+        })
+    }
+}
+
+/// SDP description of the stream sent to `dest`, for the receiver
+pub fn sdp(dest: SocketAddr, codec: RtpCodec) -> String {
+    let family = if dest.is_ipv6() { "IP6" } else { "IP4" };
+    let ptime = codec.packet_frames() * 1000 / RTP_FORMAT.sample_rate as usize;
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {family} {ip}\r\n\
+         s=Audio Proxy\r\n\
+         c=IN {family} {ip}\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} {encoding}\r\n\
+         a=ptime:{ptime}\r\n",
+        ip = dest.ip(),
+        port = dest.port(),
+        pt = PAYLOAD_TYPE,
+        encoding = codec.encoding(),
+    )
+}
+
+/// Builds RTP packets of one stream, numbering them as it goes
+pub struct Packetizer {
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+}
+
+impl Packetizer {
+    pub fn new(ssrc: u32) -> Self {
+        Self { ssrc, sequence: 0, timestamp: 0 }
+    }
+
+    /// RTP packet carrying `payload`, which plays for `frames` frames
+    pub fn packet(&mut self, payload: &[u8], frames: usize) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        // Version 2, no padding, extension or CSRCs, marker clear
+        packet.push(0x80);
+        packet.push(PAYLOAD_TYPE);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(frames as u32);
+        packet
+    }
+}
+
+/// Append samples as 16-bit big-endian PCM
+fn encode_l16(samples: &[f32], output: &mut Vec<u8>) {
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        output.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+enum Encoder {
+    L16,
+    #[cfg(feature = "rtp-opus")]
+    Opus(audiopus::coder::Encoder),
+}
+
+impl Encoder {
+    fn new(codec: RtpCodec) -> Result<Self> {
+        match codec {
+            RtpCodec::L16 => Ok(Encoder::L16),
+            #[cfg(feature = "rtp-opus")]
+            RtpCodec::Opus => Ok(Encoder::Opus(
+                audiopus::coder::Encoder::new(
+                    audiopus::SampleRate::Hz48000,
+                    audiopus::Channels::Stereo,
+                    audiopus::Application::Audio,
+                )
+                .context("Failed to create Opus encoder")?,
+            )),
+            #[cfg(not(feature = "rtp-opus"))]
+            RtpCodec::Opus => anyhow::bail!("Opus streaming needs a build with the `rtp-opus` feature"),
+        }
+    }
+
+    /// Encode one packet's worth of `RTP_FORMAT` samples into `output`
+    fn encode(&mut self, samples: &[f32], output: &mut Vec<u8>) -> Result<()> {
+        output.clear();
+        match self {
+            Encoder::L16 => encode_l16(samples, output),
+            #[cfg(feature = "rtp-opus")]
+            Encoder::Opus(encoder) => {
+                output.resize(MAX_OPUS_PACKET, 0);
+                let len = encoder.encode_float(samples, output).context("Opus encoding failed")?;
+                output.truncate(len);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stream whatever the speaker capture writes to `tap` to `addr` until `running` is
+/// cleared. `capture_format` is the format of the tapped audio (`None` while the
+/// capture is closed).
+pub fn stream(
+    addr: &str,
+    codec: RtpCodec,
+    tap: Arc<BroadcastRingBuffer>,
+    capture_format: Arc<RwLock<Option<AudioFormat>>>,
+    conversion_settings: ConversionSettings,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let dest = addr
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .with_context(|| format!("Failed to resolve RTP destination {}", addr))?;
+    let bind: SocketAddr = if dest.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).context("Failed to open RTP socket")?;
+    socket.connect(dest).with_context(|| format!("Failed to connect RTP socket to {}", dest))?;
+    info!("Streaming speaker audio as RTP ({}) to {}", codec, dest);
+    info!("SDP for RTP receivers:\n{}", sdp(dest, codec));
+
+    let mut encoder = Encoder::new(codec)?;
+    let mut packetizer = Packetizer::new(ssrc());
+    let packet_samples = codec.packet_frames() * RTP_FORMAT.channels as usize;
+    let mut reader = tap.reader();
+    let mut format: Option<AudioFormat> = None;
+    let mut conversion = ConversionState::new(conversion_settings);
+    let mut read_buffer = vec![0.0f32; 4096];
+    let mut pending = Vec::new();
+    let mut payload = Vec::new();
+    let mut send_failed = false;
+
+    while running.load(Ordering::SeqCst) {
+        let current = capture_format.read().unwrap().clone();
+        if current != format {
+            // New stream (or none): start from live audio with fresh conversion state
+            format = current;
+            reader = tap.reader();
+            conversion.reset(conversion_settings);
+            pending.clear();
+        }
+        let Some(ref cf) = format else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+
+        let read = reader.read(&mut read_buffer);
+        if read == 0 {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+        if formats_need_conversion(cf, &RTP_FORMAT) {
+            pending.extend_from_slice(convert_audio(&read_buffer[..read], cf, &RTP_FORMAT, &mut conversion));
+        } else {
+            pending.extend_from_slice(&read_buffer[..read]);
+        }
+
+        let mut sent = 0;
+        while pending.len() - sent >= packet_samples {
+            encoder.encode(&pending[sent..sent + packet_samples], &mut payload)?;
+            sent += packet_samples;
+            let packet = packetizer.packet(&payload, codec.packet_frames());
+            match socket.send(&packet) {
+                Ok(_) if send_failed => {
+                    info!("RTP stream to {} sending again", dest);
+                    send_failed = false;
+                }
+                Ok(_) => {}
+                Err(e) if !send_failed => {
+                    warn!("RTP send to {} failed, dropping packets until it recovers: {}", dest, e);
+                    send_failed = true;
+                }
+                Err(_) => {}
+            }
+        }
+        pending.drain(..sent);
+    }
+
+    Ok(())
+}
+
+/// Stream identifier, different for every run so receivers notice a restart
+fn ssrc() -> u32 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    nanos ^ std::process::id().rotate_left(16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!(RtpCodec::parse("L16").unwrap(), RtpCodec::L16);
+        assert_eq!(RtpCodec::parse("opus").is_ok(), cfg!(feature = "rtp-opus"));
+        assert!(RtpCodec::parse("mp3").is_err());
+    }
+
+    #[test]
+    fn test_packet_header() {
+        let mut packetizer = Packetizer::new(0x1234_5678);
+        let first = packetizer.packet(&[1, 2], 240);
+        assert_eq!(first, [0x80, 96, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 1, 2]);
+        let second = packetizer.packet(&[], 240);
+        // Sequence counts packets, the timestamp counts frames
+        assert_eq!(&second[2..8], &[0, 1, 0, 0, 0, 240]);
+
+        packetizer.sequence = u16::MAX;
+        packetizer.packet(&[], 240);
+        assert_eq!(packetizer.sequence, 0);
+    }
+
+    #[test]
+    fn test_encode_l16_is_big_endian() {
+        let mut output = Vec::new();
+        encode_l16(&[1.0, -1.0, 0.0, 2.0], &mut output);
+        assert_eq!(output, [0x7F, 0xFF, 0x80, 0x01, 0, 0, 0x7F, 0xFF]);
+    }
+
+    #[test]
+    fn test_sdp() {
+        let sdp = sdp("192.168.1.20:5004".parse().unwrap(), RtpCodec::L16);
+        assert!(sdp.contains("c=IN IP4 192.168.1.20\r\n"));
+        assert!(sdp.contains("m=audio 5004 RTP/AVP 96\r\n"));
+        assert!(sdp.contains("a=rtpmap:96 L16/48000/2\r\n"));
+        assert!(sdp.contains("a=ptime:5\r\n"));
+    }
+}
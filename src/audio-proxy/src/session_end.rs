@@ -0,0 +1,68 @@
+//! Clean shutdown when Windows ends the session
+//!
+//! On logoff, shutdown or when its console is closed, Windows sends the process a
+//! console control event and terminates it as soon as the handler returns. The
+//! `ctrlc` handler returns straight away, so the audio threads would be killed
+//! mid-write, which can leave a device in a state that needs a replug. The handler
+//! installed here clears `running` like Ctrl+C does, then holds the process until
+//! `run_proxy` reports the streams stopped, at most `STOP_GRACE` since Windows only
+//! waits a few seconds before killing it anyway.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::info;
+use windows::Win32::Foundation::{BOOL, FALSE, TRUE};
+use windows::Win32::System::Console::{
+    SetConsoleCtrlHandler, CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+};
+
+/// Longest the handler holds the process for the streams to stop
+const STOP_GRACE: Duration = Duration::from_secs(3);
+
+/// How often the handler checks whether the streams have stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Flag the handler clears to stop the proxy
+static RUNNING: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Set once the audio threads have stopped their streams
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Clear `running` on logoff, shutdown or console close, and wait for `mark_stopped`
+/// before letting Windows end the process
+pub fn install(running: Arc<AtomicBool>) -> Result<()> {
+    if RUNNING.set(running).is_err() {
+        // Already installed
+        return Ok(());
+    }
+    unsafe { SetConsoleCtrlHandler(Some(handler), TRUE) }
+        .context("Failed to register the session end handler")
+}
+
+/// Report that all streams are stopped, releasing a waiting handler
+pub fn mark_stopped() {
+    STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// Runs on a thread Windows creates for the event
+unsafe extern "system" fn handler(ctrl_type: u32) -> BOOL {
+    if !matches!(ctrl_type, CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT) {
+        // Ctrl+C and Ctrl+Break go on to the ctrlc handler
+        return FALSE;
+    }
+    let Some(running) = RUNNING.get() else {
+        return FALSE;
+    };
+
+    info!("Session ending, shutting down...");
+    running.store(false, Ordering::SeqCst);
+    let deadline = Instant::now() + STOP_GRACE;
+    while !STOPPED.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    TRUE
+}
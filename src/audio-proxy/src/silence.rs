@@ -0,0 +1,89 @@
+//! Silence detection shared by the features that react to the input going quiet
+//!
+//! Real inputs rarely read exactly zero when nothing plays: many devices sit at a
+//! noise floor around -80 dBFS. A captured block counts as silent when its peak is
+//! below `--silence-threshold-db` (changeable at runtime with `SetSilenceThreshold`).
+//!
+//! Detection runs on the captured audio before any processing, so gating or
+//! attenuation further down the chain doesn't change what counts as silence, and a
+//! noise gate can't stand in for a threshold that's too low: keep it above the
+//! input's noise floor but below the quietest content that should count as playing.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+/// Default silence threshold in dBFS
+pub const DEFAULT_SILENCE_THRESHOLD_DB: f32 = -60.0;
+
+/// Largest absolute sample value in a block
+pub fn block_peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+}
+
+/// Silence threshold shared with the IPC thread, so `SetSilenceThreshold` applies to
+/// running loops. Stored as the bits of the dBFS value.
+#[derive(Debug, Clone)]
+pub struct SharedSilenceThreshold(Arc<AtomicU32>);
+
+impl SharedSilenceThreshold {
+    pub fn new(db: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(db.to_bits())))
+    }
+
+    pub fn db(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Change the threshold; it must be a finite dBFS value at or below 0
+    pub fn set_db(&self, db: f32) -> Result<()> {
+        validate_db(db)?;
+        self.0.store(db.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the block's peak is below the threshold
+    pub fn is_silent(&self, samples: &[f32]) -> bool {
+        block_peak(samples) < 10f32.powf(self.db() / 20.0)
+    }
+}
+
+/// Check that `db` is usable as a silence threshold
+pub fn validate_db(db: f32) -> Result<()> {
+    if !db.is_finite() || db > 0.0 {
+        anyhow::bail!("Silence threshold must be a dBFS value at or below 0: {}", db);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(db: f32) -> Vec<f32> {
+        let amplitude = 10f32.powf(db / 20.0);
+        (0..480).map(|i| amplitude * (i as f32 * 0.13).sin()).collect()
+    }
+
+    #[test]
+    fn test_noise_floor_counts_as_silence() {
+        let threshold = SharedSilenceThreshold::new(DEFAULT_SILENCE_THRESHOLD_DB);
+        assert!(threshold.is_silent(&tone(-80.0)));
+        assert!(threshold.is_silent(&[0.0; 64]));
+        assert!(!threshold.is_silent(&tone(-40.0)));
+    }
+
+    #[test]
+    fn test_threshold_can_change() {
+        let threshold = SharedSilenceThreshold::new(DEFAULT_SILENCE_THRESHOLD_DB);
+        let shared = threshold.clone();
+        shared.set_db(-90.0).unwrap();
+        assert_eq!(threshold.db(), -90.0);
+        assert!(!threshold.is_silent(&tone(-80.0)));
+
+        assert!(shared.set_db(6.0).is_err());
+        assert!(shared.set_db(f32::NAN).is_err());
+        assert_eq!(threshold.db(), -90.0);
+    }
+}
@@ -0,0 +1,154 @@
+//! One-shot spectrum of the speaker capture for UI visualization
+//!
+//! `GetSpectrum` reads the most recent `fft_size` frames from the capture tap the
+//! speaker capture thread already feeds (see `SharedMonitor::tap`) and runs the FFT
+//! on the IPC thread, so the audio threads never do any of the work. The bins are
+//! reduced to the peak of each of `SPECTRUM_BANDS` log-spaced bands, ready to draw.
+
+use std::f32::consts::PI;
+
+use anyhow::Result;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+
+/// Number of bands a spectrum is reported in
+pub const SPECTRUM_BANDS: usize = 32;
+
+/// Range of FFT sizes `GetSpectrum` accepts (powers of two). The largest keeps
+/// 8-channel audio within the capture tap.
+pub const MIN_FFT_SIZE: usize = 256;
+pub const MAX_FFT_SIZE: usize = 8192;
+
+/// Lower edge of the lowest band
+const LOWEST_BAND_HZ: f32 = 20.0;
+
+/// Level reported for bands with no energy at all
+const FLOOR_DB: f32 = -120.0;
+
+/// One frequency band of a spectrum
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpectrumBand {
+    pub low_hz: f32,
+    pub high_hz: f32,
+    /// Peak level in the band in dBFS (a full-scale sine reads about 0)
+    pub db: f32,
+}
+
+/// Spectrum of the latest captured audio
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spectrum {
+    pub sample_rate: u32,
+    pub fft_size: usize,
+    pub bands: Vec<SpectrumBand>,
+}
+
+/// Check that `GetSpectrum` can use an FFT size
+pub fn validate_fft_size(fft_size: usize) -> Result<()> {
+    if !fft_size.is_power_of_two() || !(MIN_FFT_SIZE..=MAX_FFT_SIZE).contains(&fft_size) {
+        anyhow::bail!(
+            "fft_size must be a power of two between {} and {}: {}", MIN_FFT_SIZE, MAX_FFT_SIZE, fft_size
+        );
+    }
+    Ok(())
+}
+
+/// Spectrum of interleaved audio, with the channels mixed down to mono. The FFT size
+/// is the number of whole frames in `samples`.
+pub fn compute(samples: &[f32], channels: usize, sample_rate: u32) -> Spectrum {
+    let channels = channels.max(1);
+    let fft_size = samples.len() / channels;
+
+    // Mono mix under a Hann window, which keeps loud bands from leaking into quiet ones
+    let mut window_sum = 0.0;
+    let mut buffer: Vec<Complex<f32>> = samples
+        .chunks_exact(channels)
+        .enumerate()
+        .map(|(i, frame)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / fft_size as f32).cos();
+            window_sum += window;
+            Complex::new(frame.iter().sum::<f32>() / channels as f32 * window, 0.0)
+        })
+        .collect();
+    FftPlanner::new().plan_fft_forward(fft_size).process(&mut buffer);
+
+    // Amplitude per bin up to Nyquist, scaled so a full-scale sine peaks at 1
+    let scale = if window_sum > 0.0 { 2.0 / window_sum } else { 0.0 };
+    let amplitudes: Vec<f32> = buffer[..fft_size / 2 + 1].iter().map(|bin| bin.norm() * scale).collect();
+
+    Spectrum {
+        sample_rate,
+        fft_size,
+        bands: band_levels(&amplitudes, fft_size, sample_rate),
+    }
+}
+
+/// Peak amplitude of each log-spaced band in dB. Low bands narrower than one bin
+/// use the bin nearest their centre, so every band has a value.
+fn band_levels(amplitudes: &[f32], fft_size: usize, sample_rate: u32) -> Vec<SpectrumBand> {
+    let nyquist = sample_rate as f32 / 2.0;
+    let bin_hz = sample_rate as f32 / fft_size as f32;
+    let edge = |band: usize| LOWEST_BAND_HZ * (nyquist / LOWEST_BAND_HZ).powf(band as f32 / SPECTRUM_BANDS as f32);
+    let last_bin = amplitudes.len().saturating_sub(1);
+
+    (0..SPECTRUM_BANDS)
+        .map(|band| {
+            let (low_hz, high_hz) = (edge(band), edge(band + 1));
+            let first = (low_hz / bin_hz).ceil() as usize;
+            let last = ((high_hz / bin_hz).ceil() as usize).min(last_bin + 1);
+            let peak = if first < last {
+                amplitudes[first..last].iter().fold(0.0f32, |peak, &a| peak.max(a))
+            } else {
+                let centre = (low_hz * high_hz).sqrt();
+                amplitudes.get(((centre / bin_hz).round() as usize).min(last_bin)).copied().unwrap_or(0.0)
+            };
+            let db = if peak > 0.0 { (20.0 * peak.log10()).max(FLOOR_DB) } else { FLOOR_DB };
+            SpectrumBand { low_hz, high_hz, db }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, amplitude: f32, frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| std::iter::repeat_n(amplitude * (2.0 * PI * freq * i as f32 / 48000.0).sin(), channels))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_fft_size() {
+        assert!(validate_fft_size(2048).is_ok());
+        assert!(validate_fft_size(MAX_FFT_SIZE).is_ok());
+        assert!(validate_fft_size(1000).is_err());
+        assert!(validate_fft_size(MIN_FFT_SIZE / 2).is_err());
+        assert!(validate_fft_size(MAX_FFT_SIZE * 2).is_err());
+    }
+
+    #[test]
+    fn test_sine_peaks_in_its_band() {
+        let spectrum = compute(&sine(1000.0, 0.5, 4096, 2), 2, 48000);
+        assert_eq!(spectrum.fft_size, 4096);
+        assert_eq!(spectrum.bands.len(), SPECTRUM_BANDS);
+
+        let loudest = spectrum.bands.iter()
+            .max_by(|a, b| a.db.total_cmp(&b.db))
+            .unwrap();
+        assert!(loudest.low_hz <= 1000.0 && 1000.0 < loudest.high_hz, "{:?}", loudest);
+        // -6 dBFS sine, within the window's scalloping loss
+        assert!((loudest.db + 6.0).abs() < 1.5, "{}", loudest.db);
+        // Bands far away see only window leakage
+        assert!(spectrum.bands[SPECTRUM_BANDS - 1].db < -60.0);
+    }
+
+    #[test]
+    fn test_bands_cover_audible_range() {
+        let spectrum = compute(&vec![0.0; 1024], 1, 48000);
+        assert_eq!(spectrum.bands[0].low_hz, LOWEST_BAND_HZ);
+        assert!((spectrum.bands[SPECTRUM_BANDS - 1].high_hz - 24000.0).abs() < 1.0);
+        assert!(spectrum.bands.windows(2).all(|w| w[0].high_hz == w[1].low_hz));
+        assert!(spectrum.bands.iter().all(|band| band.db == FLOOR_DB));
+    }
+}
@@ -0,0 +1,307 @@
+//! Extra buffering ahead of a stall the client knows is coming
+//!
+//! Alt-tabbing out of a fullscreen game or loading a cutscene can starve the capture
+//! for a moment, which the normal buffer is too small to ride out. A client that sees
+//! it coming sends `PrepareForStall`: the speaker render loop then plays `ms` of
+//! silence while the capture keeps filling the ring buffer, and from then on only
+//! reads what the device has room for, so the ring holds `ms` of audio in reserve to
+//! bridge the stall.
+//!
+//! After `STALL_WINDOW` whatever is left of the reserve (all of it if the stall never
+//! came) is played out `CATCH_UP_PERCENT` faster than real time until the fill is
+//! back to normal. That takes a few seconds for a large reserve, but unlike dropping
+//! the surplus it can't be heard as a skip.
+//!
+//! For testing the other side of this, `InjectSilence` (only with `--debug-commands`)
+//! fakes a stall: the speaker capture loop drops what it reads for `ms`, so the render
+//! loop runs dry and its silence fill and recovery can be watched on demand.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::audio_stream::AudioFormat;
+use crate::convert::LinearResampler;
+
+/// Largest reserve `PrepareForStall` accepts
+pub const MAX_STALL_MS: u32 = 500;
+
+/// How long a reserve is held before the loop works its way back to the normal fill
+pub const STALL_WINDOW: Duration = Duration::from_secs(3);
+
+/// How much faster than real time a leftover reserve is played out
+const CATCH_UP_PERCENT: u32 = 2;
+
+/// Longest stall `InjectSilence` fakes
+pub const MAX_INJECT_MS: u32 = 10_000;
+
+/// Ring buffer room a full reserve takes, at up to 8 channels of 48 kHz audio
+pub fn reserve_samples() -> usize {
+    (48_000 * MAX_STALL_MS / 1000) as usize * 8
+}
+
+/// Check a `PrepareForStall` reserve
+pub fn validate_stall_ms(ms: u32) -> Result<()> {
+    if ms == 0 || ms > MAX_STALL_MS {
+        anyhow::bail!("Stall reserve must be between 1 and {} ms: {}", MAX_STALL_MS, ms);
+    }
+    Ok(())
+}
+
+/// Reserve in milliseconds requested with `PrepareForStall` and not yet picked up by
+/// the render loop (0 for none), shared with the IPC thread
+#[derive(Debug, Clone, Default)]
+pub struct SharedStall(Arc<AtomicU32>);
+
+impl SharedStall {
+    /// Ask the render loop to build up a reserve of `ms`. A new request replaces one
+    /// in progress and restarts the window.
+    pub fn prepare(&self, ms: u32) -> Result<()> {
+        validate_stall_ms(ms)?;
+        self.0.store(ms, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn take(&self) -> u32 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// End of a stall injected with `InjectSilence`, shared with the IPC thread
+#[derive(Debug, Clone, Default)]
+pub struct SharedInjectedStall(Arc<Mutex<Option<Instant>>>);
+
+impl SharedInjectedStall {
+    /// Have the capture drop its audio for `ms` from `now`. A new injection replaces
+    /// one in progress.
+    pub fn inject(&self, ms: u32, now: Instant) -> Result<()> {
+        if ms == 0 || ms > MAX_INJECT_MS {
+            anyhow::bail!("Injected silence must be between 1 and {} ms: {}", MAX_INJECT_MS, ms);
+        }
+        *self.0.lock().unwrap() = Some(now + Duration::from_millis(ms as u64));
+        Ok(())
+    }
+
+    /// Whether the capture should drop what it reads at `now`
+    pub fn active(&self, now: Instant) -> bool {
+        self.0.lock().unwrap().is_some_and(|until| now < until)
+    }
+}
+
+/// What the render loop does this time round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallStep {
+    /// No reserve: read and write as usual
+    Normal,
+    /// Write this many frames of silence instead of reading (0: the device is full)
+    Silence(usize),
+    /// Read at most this many samples from the ring buffer (0: the device is full)
+    Read(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Picked up, waiting for the formats to size the silence
+    Requested { ms: u32, until: Instant },
+    /// Playing silence while the capture builds up the reserve
+    Filling { silence_frames: usize, until: Instant },
+    /// Keeping the reserve in the ring buffer
+    Holding { until: Instant },
+    /// Playing out what's left of the reserve slightly fast
+    CatchingUp,
+}
+
+/// Reserve state owned by the speaker render loop
+pub struct StallReserve {
+    shared: SharedStall,
+    /// Most audio kept queued on the device while pacing reads: its buffer size, since
+    /// a larger target would only be cut off by it
+    max_device_fill_ms: u32,
+    phase: Phase,
+    catch_up: Option<LinearResampler>,
+}
+
+impl StallReserve {
+    /// `device_buffer_ms` is the buffer the render stream was opened with
+    pub fn new(shared: SharedStall, device_buffer_ms: u32) -> Self {
+        Self { shared, max_device_fill_ms: device_buffer_ms, phase: Phase::Idle, catch_up: None }
+    }
+
+    /// Drop any reserve, e.g. when the buffered audio was discarded
+    pub fn reset(&mut self) {
+        self.phase = Phase::Idle;
+        self.catch_up = None;
+    }
+
+    /// Pick up a new request. While this is false the loop runs as usual and doesn't
+    /// need to call `step`.
+    pub fn active(&mut self, now: Instant) -> bool {
+        let ms = self.shared.take();
+        if ms > 0 {
+            self.phase = Phase::Requested { ms, until: now + STALL_WINDOW };
+            self.catch_up = None;
+        }
+        self.phase != Phase::Idle
+    }
+
+    /// Decide this round's step. `buffered_samples` is the ring buffer fill and
+    /// `device_frames` what's queued on the device, which is kept at `prefill_ms` (at
+    /// most the device buffer) while a reserve is held.
+    pub fn step(
+        &mut self,
+        now: Instant,
+        prefill_ms: u32,
+        buffered_samples: usize,
+        device_frames: u32,
+        cap: &AudioFormat,
+        rnd: &AudioFormat,
+    ) -> StallStep {
+        let fill_ms = prefill_ms.min(self.max_device_fill_ms);
+        let room_frames = (rnd.sample_rate * fill_ms / 1000).saturating_sub(device_frames) as usize;
+        // Capture samples that play for as long as `frames` render frames
+        let to_capture = |frames: usize| {
+            (frames as u64 * cap.sample_rate as u64 / rnd.sample_rate.max(1) as u64) as usize * cap.channels as usize
+        };
+
+        match self.phase {
+            Phase::Idle => StallStep::Normal,
+            Phase::Requested { ms, until } => {
+                self.phase = Phase::Filling {
+                    silence_frames: (rnd.sample_rate as u64 * ms as u64 / 1000) as usize,
+                    until,
+                };
+                self.step(now, prefill_ms, buffered_samples, device_frames, cap, rnd)
+            }
+            Phase::Filling { silence_frames, until } => {
+                if silence_frames == 0 {
+                    self.phase = Phase::Holding { until };
+                    return self.step(now, prefill_ms, buffered_samples, device_frames, cap, rnd);
+                }
+                StallStep::Silence(silence_frames.min(room_frames))
+            }
+            Phase::Holding { until } => {
+                if now >= until {
+                    self.phase = Phase::CatchingUp;
+                    return self.step(now, prefill_ms, buffered_samples, device_frames, cap, rnd);
+                }
+                StallStep::Read(to_capture(room_frames))
+            }
+            Phase::CatchingUp => {
+                // Back to normal once the ring holds no more than the device does
+                let normal_fill = to_capture((rnd.sample_rate * fill_ms / 1000) as usize);
+                if buffered_samples <= normal_fill {
+                    self.reset();
+                    return StallStep::Normal;
+                }
+                StallStep::Read(to_capture(room_frames * (100 + CATCH_UP_PERCENT as usize) / 100))
+            }
+        }
+    }
+
+    /// Count silence the loop wrote for a `StallStep::Silence`
+    pub fn played_silence(&mut self, frames: usize) {
+        if let Phase::Filling { ref mut silence_frames, .. } = self.phase {
+            *silence_frames = silence_frames.saturating_sub(frames);
+        }
+    }
+
+    /// While catching up, shorten captured audio by `CATCH_UP_PERCENT` into `output`
+    /// so it plays out faster. Returns false, leaving `output` alone, when it should
+    /// play as is.
+    pub fn compress(&mut self, samples: &[f32], format: &AudioFormat, output: &mut Vec<f32>) -> bool {
+        if self.phase != Phase::CatchingUp {
+            return false;
+        }
+        let channels = format.channels as usize;
+        let out_rate = format.sample_rate * 100 / (100 + CATCH_UP_PERCENT);
+        let resampler = match self.catch_up {
+            Some(ref mut r) if r.matches(format.sample_rate, out_rate, channels) => r,
+            _ => self.catch_up.insert(LinearResampler::new(format.sample_rate, out_rate, channels)),
+        };
+        resampler.process(samples, output);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32) -> AudioFormat {
+        AudioFormat { sample_rate, channels: 2, bits_per_sample: 32, block_align: 8 }
+    }
+
+    #[test]
+    fn test_validate_stall_ms() {
+        assert!(validate_stall_ms(200).is_ok());
+        assert!(validate_stall_ms(MAX_STALL_MS).is_ok());
+        assert!(validate_stall_ms(0).is_err());
+        assert!(validate_stall_ms(MAX_STALL_MS + 1).is_err());
+        assert!(SharedStall::default().prepare(MAX_STALL_MS + 1).is_err());
+    }
+
+    #[test]
+    fn test_reserve_fills_holds_and_catches_up() {
+        let shared = SharedStall::default();
+        let mut reserve = StallReserve::new(shared.clone(), 10);
+        let fmt = format(48000);
+        let now = Instant::now();
+        assert!(!reserve.active(now));
+
+        // 100 ms of silence, written no faster than the device takes it (10 ms = 480 frames)
+        shared.prepare(100).unwrap();
+        assert!(reserve.active(now));
+        assert_eq!(reserve.step(now, 10, 0, 0, &fmt, &fmt), StallStep::Silence(480));
+        assert_eq!(reserve.step(now, 10, 0, 400, &fmt, &fmt), StallStep::Silence(80));
+        reserve.played_silence(4700);
+        assert_eq!(reserve.step(now, 10, 0, 0, &fmt, &fmt), StallStep::Silence(100));
+        reserve.played_silence(100);
+
+        // Holding: only reads what the device has room for
+        assert_eq!(reserve.step(now, 10, 9600, 300, &fmt, &fmt), StallStep::Read(180 * 2));
+        let mut compressed = Vec::new();
+        assert!(!reserve.compress(&[0.0; 64], &fmt, &mut compressed));
+
+        // After the window the leftover plays out 2% fast
+        let later = now + STALL_WINDOW + Duration::from_millis(1);
+        assert_eq!(reserve.step(later, 10, 9600, 0, &fmt, &fmt), StallStep::Read(489 * 2));
+        assert!(reserve.compress(&vec![0.5; 4800 * 2], &fmt, &mut compressed));
+        let frames = compressed.len() / 2;
+        assert!((4700..4710).contains(&frames), "{}", frames);
+
+        // Back to normal once the ring is down to a device's worth
+        assert_eq!(reserve.step(later, 10, 960, 0, &fmt, &fmt), StallStep::Normal);
+        assert!(!reserve.compress(&[0.0; 64], &fmt, &mut compressed));
+        assert!(!reserve.active(later));
+    }
+
+    #[test]
+    fn test_read_converts_between_rates() {
+        let shared = SharedStall::default();
+        let mut reserve = StallReserve::new(shared.clone(), 10);
+        shared.prepare(1).unwrap();
+        let (cap, rnd) = (format(44100), format(48000));
+        let now = Instant::now();
+        assert!(reserve.active(now));
+        reserve.step(now, 10, 0, 0, &cap, &rnd);
+        reserve.played_silence(48);
+        // 480 render frames of room are 441 capture frames
+        assert_eq!(reserve.step(now, 10, 0, 0, &cap, &rnd), StallStep::Read(441 * 2));
+    }
+
+    #[test]
+    fn test_injected_stall() {
+        let injected = SharedInjectedStall::default();
+        let now = Instant::now();
+        assert!(!injected.active(now));
+        injected.inject(200, now).unwrap();
+        assert!(injected.active(now + Duration::from_millis(199)));
+        assert!(!injected.active(now + Duration::from_millis(200)));
+
+        assert!(injected.inject(0, now).is_err());
+        assert!(injected.inject(MAX_INJECT_MS + 1, now).is_err());
+    }
+}
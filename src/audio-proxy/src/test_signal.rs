@@ -0,0 +1,154 @@
+//! Test signals for the diagnostic modes, and cross-correlation to find them again
+//! in captured audio
+
+use std::f32::consts::PI;
+
+/// Lowest frequency of the measurement chirp
+const CHIRP_START_HZ: f32 = 500.0;
+
+/// Highest frequency of the measurement chirp (kept well below 22.05 kHz Nyquist)
+const CHIRP_END_HZ: f32 = 8000.0;
+
+/// Normalized correlation a match must reach to count as found
+const MIN_CORRELATION: f32 = 0.5;
+
+/// Mono linear chirp from 500 Hz to 8 kHz with short fades at both ends.
+/// A sweep correlates to a much sharper peak than a tone, and unlike a single
+/// impulse it survives resampling and low-level noise.
+pub fn chirp(sample_rate: u32, duration_ms: u32, amplitude: f32) -> Vec<f32> {
+    let len = (sample_rate as u64 * duration_ms as u64 / 1000) as usize;
+    let duration = len as f32 / sample_rate as f32;
+    let sweep_rate = (CHIRP_END_HZ - CHIRP_START_HZ) / duration;
+    let fade = (len / 20).max(1);
+
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            let phase = 2.0 * PI * (CHIRP_START_HZ * t + 0.5 * sweep_rate * t * t);
+            let envelope = (i.min(len - 1 - i) as f32 / fade as f32).min(1.0);
+            phase.sin() * amplitude * envelope
+        })
+        .collect()
+}
+
+/// Continuous sine tone, generated block by block without phase jumps between them
+pub struct Tone {
+    /// Position in the current cycle, 0..1
+    phase: f32,
+    /// Cycles per frame
+    step: f32,
+    amplitude: f32,
+}
+
+impl Tone {
+    pub fn new(sample_rate: u32, frequency: f32, amplitude: f32) -> Self {
+        Self { phase: 0.0, step: frequency / sample_rate.max(1) as f32, amplitude }
+    }
+
+    /// Fill `output` with frames of `channels` samples, the tone on every channel
+    pub fn fill(&mut self, output: &mut [f32], channels: usize) {
+        for frame in output.chunks_exact_mut(channels.max(1)) {
+            frame.fill((2.0 * PI * self.phase).sin() * self.amplitude);
+            self.phase = (self.phase + self.step).fract();
+        }
+    }
+}
+
+/// Find where `reference` occurs in `recorded` by normalized cross-correlation.
+/// Returns the offset in samples and the correlation (0..1) of the best match,
+/// or `None` if nothing correlates well enough.
+pub fn find_delay(reference: &[f32], recorded: &[f32]) -> Option<(usize, f32)> {
+    let n = reference.len();
+    if n == 0 || recorded.len() < n {
+        return None;
+    }
+
+    let ref_energy: f32 = reference.iter().map(|s| s * s).sum();
+    if ref_energy == 0.0 {
+        return None;
+    }
+
+    // Running energy of the recorded window, so normalization is O(1) per lag
+    let mut window_energy: f32 = recorded[..n].iter().map(|s| s * s).sum();
+    let mut best: Option<(usize, f32)> = None;
+
+    for lag in 0..=recorded.len() - n {
+        if lag > 0 {
+            let out = recorded[lag - 1];
+            let inp = recorded[lag + n - 1];
+            window_energy = (window_energy - out * out + inp * inp).max(0.0);
+        }
+        if window_energy <= f32::EPSILON {
+            continue;
+        }
+
+        let dot: f32 = reference.iter().zip(&recorded[lag..lag + n]).map(|(a, b)| a * b).sum();
+        let score = dot / (ref_energy * window_energy).sqrt();
+        if best.is_none_or(|(_, s)| score > s) {
+            best = Some((lag, score));
+        }
+    }
+
+    best.filter(|&(_, score)| score >= MIN_CORRELATION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, amplitude: f32, mut seed: u32) -> Vec<f32> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                (seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_finds_delayed_chirp_in_noise() {
+        let reference = chirp(48000, 50, 0.5);
+        let mut recorded = noise(48000, 0.02, 1);
+        let delay = 12_345;
+        for (i, s) in reference.iter().enumerate() {
+            recorded[delay + i] += s * 0.3; // attenuated on the way back
+        }
+
+        let (found, score) = find_delay(&reference, &recorded).expect("chirp not found");
+        assert_eq!(found, delay);
+        assert!(score > 0.9, "score {}", score);
+    }
+
+    #[test]
+    fn test_noise_only_is_not_detected() {
+        let reference = chirp(48000, 50, 0.5);
+        let recorded = noise(24000, 0.1, 7);
+        assert!(find_delay(&reference, &recorded).is_none());
+    }
+
+    #[test]
+    fn test_tone_is_continuous_across_blocks() {
+        let mut whole = vec![0.0; 960 * 2];
+        Tone::new(48000, 440.0, 0.5).fill(&mut whole, 2);
+
+        let mut tone = Tone::new(48000, 440.0, 0.5);
+        let mut blocks = vec![0.0; 960 * 2];
+        for block in blocks.chunks_mut(250 * 2) {
+            tone.fill(block, 2);
+        }
+        assert!(whole.iter().zip(&blocks).all(|(a, b)| (a - b).abs() < 1e-4));
+
+        // Both channels carry the tone at the requested level
+        assert!(whole.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+        let peak = whole.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((0.49..=0.5).contains(&peak), "{}", peak);
+    }
+
+    #[test]
+    fn test_silence_is_not_detected() {
+        let reference = chirp(48000, 50, 0.5);
+        assert!(find_delay(&reference, &vec![0.0; 10000]).is_none());
+    }
+}
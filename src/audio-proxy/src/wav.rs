@@ -0,0 +1,76 @@
+//! Minimal WAV file writer for 32-bit float audio snapshots
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// WAVE_FORMAT_IEEE_FLOAT format tag
+const FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Write interleaved f32 samples to a 32-bit float WAV file
+pub fn write_wav_f32(path: &Path, samples: &[f32], sample_rate: u32, channels: u16) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create WAV file: {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_wav_f32_to(&mut writer, samples, sample_rate, channels)?;
+    writer.flush().context("Failed to flush WAV file")?;
+    Ok(())
+}
+
+/// Write a 32-bit float WAV stream (header + data) to any writer
+pub fn write_wav_f32_to<W: Write>(
+    writer: &mut W,
+    samples: &[f32],
+    sample_rate: u32,
+    channels: u16,
+) -> Result<()> {
+    let block_align = channels as u32 * 4;
+    let byte_rate = sample_rate * block_align;
+    let data_len = (samples.len() * 4) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&FORMAT_IEEE_FLOAT.to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&(block_align as u16).to_le_bytes())?;
+    writer.write_all(&32u16.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_layout() {
+        let mut out = Vec::new();
+        write_wav_f32_to(&mut out, &[0.5, -0.5, 0.25, -0.25], 48000, 2).unwrap();
+
+        assert_eq!(out.len(), 44 + 16);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 36 + 16);
+        assert_eq!(&out[8..12], b"WAVE");
+        assert_eq!(u16::from_le_bytes(out[20..22].try_into().unwrap()), FORMAT_IEEE_FLOAT);
+        assert_eq!(u16::from_le_bytes(out[22..24].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(out[24..28].try_into().unwrap()), 48000);
+        assert_eq!(u32::from_le_bytes(out[28..32].try_into().unwrap()), 48000 * 8);
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(out[40..44].try_into().unwrap()), 16);
+        assert_eq!(f32::from_le_bytes(out[44..48].try_into().unwrap()), 0.5);
+    }
+}
@@ -0,0 +1,107 @@
+//! End-to-end check of the IPC protocol: a real `IpcServer` on a named pipe of its
+//! own, answered by a small stand-in for the proxy's command handler, and an
+//! `IpcClient` connecting to it like the UI does. Unlike the serde tests in
+//! `ipc.rs` this covers the pipe setup, message framing and per-command reconnects.
+
+#![cfg(windows)]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use audio_proxy::ipc::{IpcClient, IpcCommand, IpcResponse, IpcServer};
+use audio_proxy::recent_errors::ErrorEntry;
+
+/// Shared state the stand-in handler reads and updates
+#[derive(Clone)]
+struct State {
+    running: Arc<AtomicBool>,
+    output_device_id: Arc<RwLock<String>>,
+}
+
+fn handle(command: IpcCommand, state: &State) -> IpcResponse {
+    match command {
+        IpcCommand::SetOutput { device_id } => {
+            *state.output_device_id.write().unwrap() = device_id;
+            IpcResponse::success("Output device updated")
+        }
+        IpcCommand::GetStatus => IpcResponse::status(
+            state.running.load(Ordering::SeqCst),
+            &state.output_device_id.read().unwrap(),
+        ),
+        IpcCommand::Stop => {
+            state.running.store(false, Ordering::SeqCst);
+            IpcResponse::success("Stopping proxy")
+        }
+        // Larger than a pipe buffer, so the client has to read it in pieces
+        IpcCommand::GetRecentErrors => IpcResponse::recent_errors(
+            (0..64)
+                .map(|i| ErrorEntry {
+                    timestamp: "2024-01-01T00:00:00Z".to_string(),
+                    level: "ERROR".to_string(),
+                    thread: "speaker-render".to_string(),
+                    message: format!("error {} {}", i, "x".repeat(100)),
+                })
+                .collect(),
+        ),
+        _ => IpcResponse::error("Not supported by the test server"),
+    }
+}
+
+/// Serve `instance` on a thread until a `Stop` was answered
+fn spawn_server(instance: String, state: State) -> thread::JoinHandle<()> {
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let mut server = IpcServer::new(Some(&instance)).expect("Failed to create IPC server");
+        ready_tx.send(()).unwrap();
+        while state.running.load(Ordering::SeqCst) {
+            if let Some(command) = server.accept_with_timeout(Duration::from_millis(50)).unwrap() {
+                let response = handle(command, &state);
+                server.send_response(&response).unwrap();
+            }
+        }
+    });
+    ready_rx.recv().expect("IPC server thread failed to start");
+    handle
+}
+
+fn send(instance: &str, command: IpcCommand) -> IpcResponse {
+    // The server disconnects after every response, like the proxy
+    IpcClient::connect_instance(Some(instance))
+        .and_then(|mut client| client.send_command(&command))
+        .unwrap()
+}
+
+#[test]
+fn test_commands_round_trip_through_pipe() {
+    let instance = format!("ipc-test-{}", std::process::id());
+    let state = State {
+        running: Arc::new(AtomicBool::new(true)),
+        output_device_id: Arc::new(RwLock::new("speakers".to_string())),
+    };
+    let server = spawn_server(instance.clone(), state.clone());
+
+    let status = send(&instance, IpcCommand::GetStatus);
+    assert!(status.success);
+    assert_eq!(status.running, Some(true));
+    assert_eq!(status.output_device.as_deref(), Some("speakers"));
+
+    let response = send(&instance, IpcCommand::SetOutput { device_id: "headphones".to_string() });
+    assert!(response.success, "{}", response.message);
+    assert_eq!(*state.output_device_id.read().unwrap(), "headphones");
+    let status = send(&instance, IpcCommand::GetStatus);
+    assert_eq!(status.output_device.as_deref(), Some("headphones"));
+
+    let errors = send(&instance, IpcCommand::GetRecentErrors);
+    assert_eq!(errors.recent_errors.map(|entries| entries.len()), Some(64));
+
+    let response = send(&instance, IpcCommand::Stop);
+    assert!(response.success);
+    server.join().unwrap();
+    assert!(!state.running.load(Ordering::SeqCst));
+
+    // Nobody is listening any more
+    assert!(IpcClient::connect_instance(Some(&instance)).is_err());
+}
@@ -0,0 +1,101 @@
+//! Allocation counts of the render loops' per-block steps once their buffers have
+//! grown to the block size. A test binary of its own, since counting needs a global
+//! allocator that would otherwise apply to every unit test in the library.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use audio_proxy::audio_stream::AudioFormat;
+use audio_proxy::convert::{convert_audio, ConversionState};
+use audio_proxy::stall::{SharedStall, StallReserve, StallStep, STALL_WINDOW};
+
+/// The system allocator, counting allocations made on each thread so tests running
+/// in parallel don't see each other's
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations `f` makes on this thread
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn format(sample_rate: u32, channels: u16) -> AudioFormat {
+    AudioFormat { sample_rate, channels, bits_per_sample: 32, block_align: channels as u32 * 4 }
+}
+
+#[test]
+fn test_steady_conversion_does_not_allocate() {
+    // Polyphase, linear and no resampling, with channel conversion and blocks that
+    // end mid-frame, so every buffer in the state is used
+    for (cap, rnd) in [
+        (format(44100, 2), format(48000, 6)),
+        (format(48000, 2), format(32000, 1)),
+        (format(48000, 6), format(48000, 2)),
+    ] {
+        let input = vec![0.1f32; 961];
+        let mut state = ConversionState::default();
+        // The first blocks size the buffers
+        for _ in 0..100 {
+            convert_audio(&input, &cap, &rnd, &mut state);
+        }
+        let count = allocations(|| {
+            for _ in 0..1000 {
+                convert_audio(&input, &cap, &rnd, &mut state);
+            }
+        });
+        assert_eq!(count, 0, "{} -> {}", cap, rnd);
+    }
+}
+
+#[test]
+fn test_stall_catch_up_does_not_allocate() {
+    let shared = SharedStall::default();
+    let mut reserve = StallReserve::new(shared.clone(), 10);
+    let fmt = format(48000, 2);
+    let now = Instant::now();
+
+    // A 100 ms reserve, filled and held, then played out fast after the window
+    shared.prepare(100).unwrap();
+    assert!(reserve.active(now));
+    assert_eq!(reserve.step(now, 10, 0, 0, &fmt, &fmt), StallStep::Silence(480));
+    reserve.played_silence(4800);
+    let later = now + STALL_WINDOW + Duration::from_millis(1);
+    assert!(matches!(reserve.step(later, 10, 9600, 0, &fmt, &fmt), StallStep::Read(_)));
+
+    let input = vec![0.1f32; 960];
+    let mut output = Vec::new();
+    for _ in 0..10 {
+        assert!(reserve.compress(&input, &fmt, &mut output));
+    }
+    let count = allocations(|| {
+        for _ in 0..1000 {
+            reserve.compress(&input, &fmt, &mut output);
+        }
+    });
+    assert_eq!(count, 0);
+}